@@ -0,0 +1,386 @@
+//! Color processing after (or around) demosaicing.
+//!
+//! A [ColorPipeline] applies, in order:
+//!
+//! 1. Black level subtraction (and scaling up to the white level)
+//! 2. White balance gains for each channel
+//! 3. A 3x3 matrix from camera RGB to linear sRGB
+//! 4. Clamping to [0.0, 1.0]
+//! 5. A [ToneCurve] (ie. the sRGB transfer function)
+//!
+//! Pipelines are written as 'key = value' lines (see [ColorPipeline::set]),
+//! so that they can be kept alongside other per-device figures:
+//!
+//! ```text
+//! black_level = 0.0039
+//! white_level = 1.0
+//! wb_gains    = 1.92 1.0 1.61
+//! matrix      = 1.6 -0.4 -0.2 -0.3 1.5 -0.2 0.0 -0.5 1.5
+//! tone        = srgb
+//! clamp       = true
+//! ```
+
+use crate::*;
+
+/// A curve mapping linear values to output values (both in [0.0, 1.0]).
+#[derive(Clone, Debug, PartialEq)]
+pub enum ToneCurve {
+    Linear,
+    /// The sRGB transfer function
+    Srgb,
+    /// A power law (ie. 'Gamma(2.2)' raises values to '1 / 2.2')
+    Gamma(f64),
+    /// Straight lines between points (x, y), sorted by 'x'
+    Points(Vec<(f64, f64)>),
+}
+impl ToneCurve {
+    pub fn eval(&self, x: f64) -> f64 {
+        match self {
+            Self::Linear => x,
+            Self::Srgb => match x <= 0.0031308 {
+                true => 12.92 * x,
+                false => 1.055 * x.powf(1.0 / 2.4) - 0.055,
+            },
+            Self::Gamma(g) => x.signum() * x.abs().powf(1.0 / g),
+            Self::Points(points) => {
+                let Some(&(x0, y0)) = points.first() else { return x };
+                if x <= x0 {
+                    return y0;
+                }
+                for w in points.windows(2) {
+                    let ((xa, ya), (xb, yb)) = (w[0], w[1]);
+                    if x <= xb {
+                        return match xb > xa {
+                            true => ya + (yb - ya) * (x - xa) / (xb - xa),
+                            false => yb,
+                        };
+                    }
+                }
+                points.last().unwrap().1
+            },
+        }
+    }
+
+    /// Parse a curve written like [ToneCurve]'s [std::fmt::Display].
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut words = s.split_whitespace();
+        let res = match words.next() {
+            Some("linear") => Self::Linear,
+            Some("srgb") => Self::Srgb,
+            Some("gamma") => {
+                let g: f64 = words.next().and_then(|g| g.parse().ok())
+                    .filter(|g| *g > 0.0)
+                    .ok_or_else(|| "expected 'gamma <positive number>'".to_string())?;
+                Self::Gamma(g)
+            },
+            Some("curve") => {
+                let points = words.by_ref().map(|p| {
+                    p.split_once(':')
+                        .and_then(|(x, y)| Some((x.parse().ok()?, y.parse().ok()?)))
+                        .ok_or_else(|| format!("invalid point '{}'", p))
+                }).collect::<Result<Vec<(f64, f64)>, String>>()?;
+                if points.windows(2).any(|w| w[1].0 < w[0].0) {
+                    return Err("curve points must be sorted".to_string());
+                }
+                Self::Points(points)
+            },
+            _ => return Err(format!("unknown tone curve '{}'", s)),
+        };
+        match words.next() {
+            Some(w) => Err(format!("unexpected '{}'", w)),
+            None => Ok(res),
+        }
+    }
+}
+impl std::fmt::Display for ToneCurve {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Linear => write!(f, "linear"),
+            Self::Srgb => write!(f, "srgb"),
+            Self::Gamma(g) => write!(f, "gamma {}", g),
+            Self::Points(points) => {
+                write!(f, "curve")?;
+                for (x, y) in points.iter() {
+                    write!(f, " {}:{}", x, y)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Parameters for turning sensor data into sRGB.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColorPipeline {
+    /// Black level, as a fraction of full scale (ie. '16 / 4095' for
+    /// 12-bit data), so the same pipeline works at any bit depth
+    pub black_level: f64,
+
+    /// Saturation level, as a fraction of full scale
+    pub white_level: f64,
+
+    /// Gains for red, green and blue
+    pub wb_gains: [f64; 3],
+
+    /// Row-major matrix from (white-balanced) camera RGB to linear sRGB
+    pub matrix: [f64; 9],
+
+    pub tone: ToneCurve,
+
+    /// Clamp the output to [0.0, 1.0] (only matters for 'RGBF32' output)
+    pub clamp: bool,
+}
+impl Default for ColorPipeline {
+    fn default() -> Self {
+        Self {
+            black_level: 0.0,
+            white_level: 1.0,
+            wb_gains: [1.0; 3],
+            matrix: Self::IDENTITY,
+            tone: ToneCurve::Srgb,
+            clamp: true,
+        }
+    }
+}
+impl ColorPipeline {
+    pub const IDENTITY: [f64; 9] = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0];
+
+    /// Set a parameter from a 'key = value' line.
+    pub fn set(&mut self, key: &str, val: &str) -> Result<(), String> {
+        fn nums<const N: usize>(key: &str, val: &str) -> Result<[f64; N], String> {
+            let vals = val.split_whitespace().map(|v| v.parse::<f64>())
+                .collect::<Result<Vec<f64>, _>>()
+                .map_err(|e| format!("invalid value for '{}': {}", key, e))?;
+            vals.try_into().map_err(|_| format!("expected {} values for '{}'", N, key))
+        }
+        match key {
+            "black_level" => self.black_level = nums::<1>(key, val)?[0],
+            "white_level" => self.white_level = nums::<1>(key, val)?[0],
+            "wb_gains" => self.wb_gains = nums(key, val)?,
+            "matrix" => self.matrix = nums(key, val)?,
+            "tone" => self.tone = ToneCurve::parse(val)?,
+            "clamp" => {
+                self.clamp = val.parse()
+                    .map_err(|e| format!("invalid value for '{}': {}", key, e))?;
+            },
+            _ => return Err(format!("unknown key '{}'", key)),
+        }
+        Ok(())
+    }
+
+    /// Parse a pipeline from 'key = value' lines (missing keys are left
+    /// at their default values).
+    pub fn parse(src: &str) -> Result<Self, String> {
+        let mut res = Self::default();
+        for (num, line) in config_lines(src) {
+            split_key_value(line)
+                .and_then(|(key, val)| res.set(key, val))
+                .map_err(|e| format!("line {}: {}", num, e))?;
+        }
+        Ok(res)
+    }
+
+    /// Black level and white balance for a single (normalized) sample.
+    fn balance(&self, val: f32, channel: usize) -> f32 {
+        let range = (self.white_level - self.black_level).max(f64::EPSILON);
+        ((val as f64 - self.black_level) / range * self.wb_gains[channel]) as f32
+    }
+
+    /// Matrix, tone curve and clamping for a (balanced) pixel.
+    fn finish(&self, rgb: [f32; 3], tone: &ToneLut) -> [f32; 3] {
+        let m = &self.matrix;
+        let [r, g, b] = rgb.map(|v| v as f64);
+        [0, 1, 2].map(|i| {
+            let v = m[i * 3] * r + m[i * 3 + 1] * g + m[i * 3 + 2] * b;
+            let v = match self.clamp {
+                true => v.clamp(0.0, 1.0),
+                false => v,
+            };
+            tone.eval(v) as f32
+        })
+    }
+
+    /// Process demosaiced (linear) RGB data, keeping the same format.
+    pub fn apply(&self, view: &PixelView) -> Result<PixelData, &'static str> {
+        let fmt = view.format();
+        if fmt.channels() < 3 || fmt.bayer_pattern().is_some() {
+            return Err("Expected RGB data");
+        }
+        let tone = ToneLut::new(&self.tone);
+        let max = fmt.max_value();
+        let mut res = PixelData::new(fmt, view.width(), view.height());
+        let mut dst = res.view_mut();
+        for y in 0..view.height() {
+            for x in 0..view.width() {
+                let rgb = [0, 1, 2].map(|c| self.balance(view.sample(x, y, c) / max, c));
+                for (c, v) in self.finish(rgb, &tone).into_iter().enumerate() {
+                    dst.set_sample(x, y, c, v * max);
+                }
+                // Alpha is passed through
+                for c in 3..fmt.channels() {
+                    dst.set_sample(x, y, c, view.sample(x, y, c));
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Process raw Bayer data: the black level and white balance are
+    /// applied before demosaicing with 'method', and the rest after.
+    ///
+    /// The result is 'RGB8' for 'Bayer8' data, or 'RGB16' otherwise.
+    ///
+    /// NOTE: The balanced samples are kept as floats until the end, so they
+    /// aren't rounded (or clipped by large gains) before the matrix.
+    pub fn develop(&self, view: &PixelView, method: DemosaicMethod)
+        -> Result<PixelData, &'static str>
+    {
+        let fmt = view.format();
+        let pattern = fmt.bayer_pattern().ok_or("Expected Bayer data")?;
+        let (width, height) = (view.width(), view.height());
+        let max = fmt.max_value();
+        let balanced: Vec<f32> = (0..height).flat_map(|y| (0..width).map(move |x| {
+            let c = pattern.channel_at(x, y).rgb_index();
+            self.balance(view.sample(x, y, 0) / max, c)
+        })).collect();
+        let mosaic = Mosaic::new(&balanced, width, height, pattern)?;
+
+        let out = match fmt.sample_type() {
+            SampleType::U8 => PixelFormat::RGB8,
+            _ => PixelFormat::RGB16,
+        };
+        let mut res = PixelData::new(out, width, height);
+        let tone = ToneLut::new(&self.tone);
+        let scale = out.max_value();
+        mosaic.run(method, &mut res.view_mut(), |rgb| {
+            self.finish(rgb, &tone).map(|v| v * scale)
+        });
+        Ok(res)
+    }
+}
+impl std::fmt::Display for ColorPipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let join = |v: &[f64]| v.iter().map(|x| x.to_string())
+            .collect::<Vec<_>>().join(" ");
+        writeln!(f, "black_level = {}", self.black_level)?;
+        writeln!(f, "white_level = {}", self.white_level)?;
+        writeln!(f, "wb_gains    = {}", join(&self.wb_gains))?;
+        writeln!(f, "matrix      = {}", join(&self.matrix))?;
+        writeln!(f, "tone        = {}", self.tone)?;
+        writeln!(f, "clamp       = {}", self.clamp)?;
+        Ok(())
+    }
+}
+
+/// A [ToneCurve] sampled over [0.0, 1.0], since evaluating it for every
+/// sample of a large frame is slow.
+struct ToneLut {
+    curve: ToneCurve,
+    table: Vec<f64>,
+}
+impl ToneLut {
+    const SIZE: usize = 4096;
+
+    fn new(curve: &ToneCurve) -> Self {
+        let table = (0..=Self::SIZE)
+            .map(|i| curve.eval(i as f64 / Self::SIZE as f64))
+            .collect();
+        Self { curve: curve.clone(), table }
+    }
+
+    fn eval(&self, x: f64) -> f64 {
+        if !(0.0..=1.0).contains(&x) {
+            return self.curve.eval(x);
+        }
+        let pos = x * Self::SIZE as f64;
+        let i = (pos as usize).min(Self::SIZE - 1);
+        let t = pos - i as f64;
+        self.table[i] + (self.table[i + 1] - self.table[i]) * t
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let src = "# A profile\nwb_gains = 2 1 1.5  # daylight\n\ntone = gamma 2.2\n";
+        let p = ColorPipeline::parse(src).unwrap();
+        assert_eq!(p.wb_gains, [2.0, 1.0, 1.5]);
+        assert_eq!(p.tone, ToneCurve::Gamma(2.2));
+        assert_eq!(p.matrix, ColorPipeline::IDENTITY);
+        assert_eq!(ColorPipeline::parse(&p.to_string()).unwrap(), p);
+
+        let errors = [
+            ("\nblack_level\n", "line 2: expected 'key = value'"),
+            ("wb_gains = 1 2", "line 1: expected 3 values for 'wb_gains'"),
+            ("# x\n\nfoo = 1", "line 3: unknown key 'foo'"),
+            ("tone = gamma -1", "line 1: expected 'gamma"),
+        ];
+        for (src, msg) in errors {
+            let err = ColorPipeline::parse(src).unwrap_err();
+            assert!(err.starts_with(msg), "{:?}: {}", src, err);
+        }
+    }
+
+    #[test]
+    fn develop() {
+        // Red, green and blue sites are 200, 100 and 50
+        let mut raw = PixelData::new(PixelFormat::Bayer8(BayerPattern::RGGB), 6, 4);
+        let mut out = raw.view_mut();
+        for y in 0..4 {
+            for x in 0..6 {
+                let c = BayerPattern::RGGB.channel_at(x, y).rgb_index();
+                out.set_sample(x, y, 0, [200.0, 100.0, 50.0][c]);
+            }
+        }
+
+        // NOTE: Red is pushed past full scale by the white balance, and 
+        // brought back by the matrix
+        let p = ColorPipeline {
+            wb_gains: [2.0, 1.0, 1.0],
+            matrix: [0.5, 0.0, 0.0, 0.0, 0.5, 0.0, 0.0, 0.0, 0.5],
+            tone: ToneCurve::Linear,
+            ..Default::default()
+        };
+        for method in DemosaicMethod::ALL {
+            let rgb = p.develop(&raw.view(), method).unwrap();
+            assert_eq!(rgb.format(), PixelFormat::RGB8);
+            let view = rgb.view();
+            for (x, y) in [(0, 0), (3, 2), (5, 3)] {
+                let px = [0, 1, 2].map(|c| view.sample(x, y, c));
+                assert_eq!(px, [200.0, 50.0, 25.0], "{:?} ({}, {})", method, x, y);
+            }
+        }
+
+        // 12-bit data comes out as RGB16
+        let fmt = PixelFormat::Bayer16(BayerPattern::RGGB, 12);
+        let raw = PixelData::new_from_u16(fmt, 4, 4, &[4095; 16]).unwrap();
+        let p = ColorPipeline { tone: ToneCurve::Linear, ..Default::default() };
+        let rgb = p.develop(&raw.view(), DemosaicMethod::Bilinear).unwrap();
+        assert_eq!(rgb.format(), PixelFormat::RGB16);
+        assert_eq!(rgb.view().sample(1, 1, 0), 65535.0);
+
+        assert!(p.develop(&rgb.view(), DemosaicMethod::Bilinear).is_err());
+    }
+
+    #[test]
+    fn tone_curves() {
+        for curve in [ToneCurve::Linear, ToneCurve::Srgb, ToneCurve::Gamma(2.2),
+            ToneCurve::Points(vec![(0.0, 0.0), (0.5, 0.8), (1.0, 1.0)])]
+        {
+            assert_eq!(ToneCurve::parse(&curve.to_string()).unwrap(), curve);
+            assert!(curve.eval(0.0).abs() < 1e-9, "{}", curve);
+            assert!((curve.eval(1.0) - 1.0).abs() < 1e-9, "{}", curve);
+            let lut = ToneLut::new(&curve);
+            for x in [0.1, 0.25, 0.7] {
+                assert!((lut.eval(x) - curve.eval(x)).abs() < 1e-4, "{}", curve);
+            }
+        }
+        let points = ToneCurve::parse("curve 0:0 0.5:0.8 1:1").unwrap();
+        assert!((points.eval(0.25) - 0.4).abs() < 1e-9);
+        assert!(ToneCurve::parse("curve 1:1 0:0").is_err());
+        assert!(ToneCurve::parse("srgb 2").is_err());
+    }
+}
@@ -0,0 +1,43 @@
+//! Line-based text formats (calibrations, profiles and scripts).
+//!
+//! Comments run from '#' to the end of a line, and blank lines are
+//! ignored. Most of these formats are made of 'key = value' lines.
+
+/// Remove a comment and surrounding whitespace from a line.
+pub fn strip_comment(line: &str) -> &str {
+    match line.split_once('#') {
+        Some((code, _comment)) => code,
+        None => line,
+    }.trim()
+}
+
+/// The lines of 'src' with anything left after [strip_comment], along
+/// with their line numbers (starting from 1).
+pub fn config_lines(src: &str) -> impl Iterator<Item = (usize, &str)> {
+    src.lines().enumerate()
+        .map(|(num, line)| (num + 1, strip_comment(line)))
+        .filter(|(_, line)| !line.is_empty())
+}
+
+/// Split a 'key = value' line into the (trimmed) key and value.
+pub fn split_key_value(line: &str) -> Result<(&str, &str), String> {
+    let (key, val) = line.split_once('=')
+        .ok_or_else(|| "expected 'key = value'".to_string())?;
+    Ok((key.trim(), val.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines() {
+        let src = "a = 1 # one\n\n  # nothing\n\tb=2=3  \n#\nc";
+        let lines: Vec<_> = config_lines(src).collect();
+        assert_eq!(lines, vec![(1, "a = 1"), (4, "b=2=3"), (6, "c")]);
+        assert_eq!(split_key_value(lines[0].1), Ok(("a", "1")));
+        assert_eq!(split_key_value(lines[1].1), Ok(("b", "2=3")));
+        assert!(split_key_value(lines[2].1).is_err());
+        assert_eq!(strip_comment(" x # y # z"), "x");
+    }
+}
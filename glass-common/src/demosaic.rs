@@ -0,0 +1,586 @@
+//! Demosaicing raw sensor data on the CPU.
+//!
+//! These are reference implementations: they're much slower than the shader
+//! in 'glass-glow', but don't need a GL context. [DemosaicMethod::Malvar]
+//! uses the same filters as the shader, so its output can be compared
+//! against frames rendered on the GPU (see [ImageDiff]).
+//!
+//! NOTE: Pixels beyond the edge of the image are mirrored about the edge
+//! (without repeating the edge pixel), which preserves the Bayer pattern.
+//! The shader clamps to the edge instead, so results within two pixels of
+//! the edge aren't expected to match.
+
+use crate::*;
+
+/// An algorithm for demosaicing Bayer data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DemosaicMethod {
+    /// Copy the missing colors from the nearest pixels in each 2x2 block
+    Nearest,
+
+    /// Average the adjacent pixels of each color
+    Bilinear,
+
+    /// Gradient-corrected bilinear interpolation (Malvar, He & Cutler),
+    /// matching the shader in 'glass-glow'
+    Malvar,
+
+    /// Threshold-based variable number of gradients (Chang et al.)
+    Vng,
+}
+impl DemosaicMethod {
+    pub const ALL: [Self; 4] = [Self::Nearest, Self::Bilinear, Self::Malvar, Self::Vng];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Nearest => "nearest",
+            Self::Bilinear => "bilinear",
+            Self::Malvar => "malvar",
+            Self::Vng => "vng",
+        }
+    }
+
+    /// The method with some [DemosaicMethod::name].
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| m.name() == name)
+    }
+}
+
+/// Demosaic 8-bit Bayer data, returning RGB8 data of the same size.
+pub fn demosaic(src: &[u8], width: usize, height: usize, pattern: BayerPattern,
+    method: DemosaicMethod) -> Result<Vec<u8>, &'static str>
+{
+    let raw = PixelData::new_from_slice(PixelFormat::Bayer8(pattern), width, height, src)?;
+    Ok(raw.demosaic(method)?.data.into_vec())
+}
+
+impl PixelData {
+    /// Demosaic Bayer data into a new image (see [PixelView::demosaic]).
+    pub fn demosaic(&self, method: DemosaicMethod) -> Result<PixelData, &'static str> {
+        let mut res = self.view().demosaic(method)?;
+        res.id = self.id;
+        Ok(res)
+    }
+}
+
+impl PixelView<'_> {
+    /// Demosaic Bayer data into a new image ('RGB8' for 'Bayer8' data, or
+    /// 'RGB16' scaled to the full 16 bits for 'Bayer16' data).
+    pub fn demosaic(&self, method: DemosaicMethod) -> Result<PixelData, &'static str> {
+        let pattern = self.format().bayer_pattern()
+            .ok_or("Only Bayer data can be demosaiced")?;
+        let (width, height) = (self.width(), self.height());
+        let fmt = match self.format().sample_type() {
+            SampleType::U8 => PixelFormat::RGB8,
+            _ => PixelFormat::RGB16,
+        };
+        let samples: Vec<f32> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| self.sample(x, y, 0)))
+            .collect();
+        let m = Mosaic::new(&samples, width, height, pattern)?;
+
+        let mut res = PixelData::new(fmt, width, height);
+        let scale = fmt.max_value() / self.format().max_value();
+        m.run(method, &mut res.view_mut(), |rgb| rgb.map(|v| v * scale));
+        Ok(res)
+    }
+}
+
+/// Bayer samples (one for each pixel, row by row), with neighbouring pixels
+/// addressed relative to (x, y).
+pub(crate) struct Mosaic<'a> {
+    src: &'a [f32],
+    width: usize,
+    height: usize,
+    pattern: BayerPattern,
+}
+impl<'a> Mosaic<'a> {
+    pub(crate) fn new(src: &'a [f32], width: usize, height: usize,
+        pattern: BayerPattern) -> Result<Self, &'static str>
+    {
+        if width < 2 || height < 2 {
+            return Err("Image must be at least 2x2 pixels");
+        }
+        if src.len() != width * height {
+            return Err("Samples don't match the image size");
+        }
+        Ok(Self { src, width, height, pattern })
+    }
+
+    /// Demosaic with 'method', writing 'post(rgb)' for each pixel to 'out'.
+    pub(crate) fn run(&self, method: DemosaicMethod, out: &mut PixelViewMut,
+        post: impl Fn([f32; 3]) -> [f32; 3])
+    {
+        match method {
+            DemosaicMethod::Nearest => self.fill(out, &post, |x, y| self.nearest(x, y)),
+            DemosaicMethod::Bilinear => self.fill(out, &post, |x, y| self.bilinear(x, y)),
+            DemosaicMethod::Malvar => self.fill(out, &post, |x, y| self.malvar(x, y)),
+            DemosaicMethod::Vng => {
+                let mut linear = vec![[0.0; 3]; self.width * self.height];
+                for y in 0..self.height {
+                    for x in 0..self.width {
+                        linear[y * self.width + x] = self.bilinear(x, y);
+                    }
+                }
+                self.fill(out, &post, |x, y| self.vng(&linear, x, y));
+            },
+        }
+    }
+
+    /// Mirror a coordinate about the edges until it's in bounds.
+    fn reflect(mut i: isize, n: usize) -> usize {
+        let n = n as isize;
+        while i < 0 || i >= n {
+            i = if i < 0 { -i } else { 2 * (n - 1) - i };
+        }
+        i as usize
+    }
+
+    /// Position of (x + dx, y + dy) within the image.
+    fn position(&self, x: usize, y: usize, dx: isize, dy: isize) -> (usize, usize) {
+        let x = Self::reflect(x as isize + dx, self.width);
+        let y = Self::reflect(y as isize + dy, self.height);
+        (x, y)
+    }
+
+    fn index(&self, x: usize, y: usize, dx: isize, dy: isize) -> usize {
+        let (x, y) = self.position(x, y, dx, dy);
+        y * self.width + x
+    }
+
+    /// Value of the pixel at (x + dx, y + dy).
+    fn get(&self, x: usize, y: usize, dx: isize, dy: isize) -> f32 {
+        self.src[self.index(x, y, dx, dy)]
+    }
+
+    /// RGB index of the color at (x + dx, y + dy).
+    fn color(&self, x: usize, y: usize, dx: isize, dy: isize) -> usize {
+        // NOTE: Only the parity of the coordinates matters here.
+        let x = (x as isize + dx).rem_euclid(2) as usize;
+        let y = (y as isize + dy).rem_euclid(2) as usize;
+        self.pattern.channel_at(x, y).rgb_index()
+    }
+
+    fn fill(&self, out: &mut PixelViewMut, post: &impl Fn([f32; 3]) -> [f32; 3],
+        f: impl Fn(usize, usize) -> [f32; 3])
+    {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                for (c, val) in post(f(x, y)).into_iter().enumerate() {
+                    out.set_sample(x, y, c, val);
+                }
+            }
+        }
+    }
+
+    fn nearest(&self, x: usize, y: usize) -> [f32; 3] {
+        // NOTE: Stay within the 2x2 block, preferring the same row.
+        let bx = if x & 1 == 0 { 1 } else { -1 };
+        let by = if y & 1 == 0 { 1 } else { -1 };
+        let mut res = [0.0; 3];
+        for (c, val) in res.iter_mut().enumerate() {
+            let (dx, dy) = [(0, 0), (bx, 0), (0, by), (bx, by)].into_iter()
+                .find(|&(dx, dy)| self.color(x, y, dx, dy) == c)
+                .unwrap();
+            *val = self.get(x, y, dx, dy);
+        }
+        res
+    }
+
+    fn bilinear(&self, x: usize, y: usize) -> [f32; 3] {
+        let mut sum = [0.0; 3];
+        let mut num = [0.0; 3];
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                let c = self.color(x, y, dx, dy);
+                sum[c] += self.get(x, y, dx, dy);
+                num[c] += 1.0;
+            }
+        }
+        let own = self.color(x, y, 0, 0);
+        let mut res = [0.0; 3];
+        for (c, val) in res.iter_mut().enumerate() {
+            *val = if c == own { self.get(x, y, 0, 0) } else { sum[c] / num[c] };
+        }
+        res
+    }
+
+    fn malvar(&self, x: usize, y: usize) -> [f32; 3] {
+        let p = |dx, dy| self.get(x, y, dx, dy);
+        let c = p(0, 0);
+        let n1 = p(0, -1) + p(0, 1);
+        let n2 = p(0, -2) + p(0, 2);
+        let w1 = p(-1, 0) + p(1, 0);
+        let w2 = p(-2, 0) + p(2, 0);
+        let diag = p(-1, -1) + p(1, -1) + p(-1, 1) + p(1, 1);
+
+        // Green at red/blue, and blue at red/red at blue
+        let cross = (4.0 * c + 2.0 * (n1 + w1) - (n2 + w2)) / 8.0;
+        let checker = (6.0 * c + 2.0 * diag - 1.5 * (n2 + w2)) / 8.0;
+        // Red/blue at green, with the color in the same row (theta) or
+        // in the same column (phi)
+        let theta = (5.0 * c + 4.0 * w1 - w2 - diag + 0.5 * n2) / 8.0;
+        let phi = (5.0 * c + 4.0 * n1 - n2 - diag + 0.5 * w2) / 8.0;
+
+        match self.pattern.channel_at(x, y) {
+            BayerChannel::Red => [c, cross, checker],
+            BayerChannel::GreenR => [theta, c, phi],
+            BayerChannel::GreenB => [phi, c, theta],
+            BayerChannel::Blue => [checker, cross, c],
+        }
+    }
+
+    /// Gradient in the direction (dx, dy) (one of the 8 neighbours).
+    fn gradient(&self, x: usize, y: usize, (dx, dy): (isize, isize)) -> f32 {
+        let diff = |(ax, ay): (isize, isize), (bx, by): (isize, isize)|
+            (self.get(x, y, ax, ay) - self.get(x, y, bx, by)).abs();
+        if dx == 0 || dy == 0 {
+            // Pairs of the same color along the center line, and
+            // (with half the weight) along the lines either side
+            let (ex, ey) = (dy.abs(), dx.abs());
+            let mut res = 0.0;
+            for i in -1..=1 {
+                let w = if i == 0 { 1.0 } else { 0.5 };
+                let (sx, sy) = (i * ex, i * ey);
+                res += w * diff((sx - dx, sy - dy), (sx + dx, sy + dy));
+                res += w * diff((sx, sy), (sx + 2 * dx, sy + 2 * dy));
+            }
+            res
+        } else {
+            let mut res = diff((-dx, -dy), (dx, dy)) + diff((0, 0), (2 * dx, 2 * dy));
+            // The diagonals either side are either all green, or
+            // alternate between red and blue
+            for (sx, sy) in [(0, -dy), (-dx, 0)] {
+                let mid = (sx + dx, sy + dy);
+                let end = (sx + 2 * dx, sy + 2 * dy);
+                if self.color(x, y, sx, sy) == self.color(x, y, mid.0, mid.1) {
+                    res += 0.5 * (diff((sx, sy), mid) + diff(mid, end));
+                } else {
+                    res += diff((sx, sy), end);
+                }
+            }
+            res
+        }
+    }
+
+    /// NOTE: Following dcraw, the color differences in each direction are
+    /// taken from a bilinear interpolation ('linear').
+    fn vng(&self, linear: &[[f32; 3]], x: usize, y: usize) -> [f32; 3] {
+        const DIRS: [(isize, isize); 8] = [
+            (0, -1), (1, -1), (1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1),
+        ];
+        let grad = DIRS.map(|d| self.gradient(x, y, d));
+        let min = grad.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = grad.iter().copied().fold(0.0, f32::max);
+        let threshold = min + max / 2.0;
+
+        let own = self.color(x, y, 0, 0);
+        let c = self.get(x, y, 0, 0);
+        let mut sum = [0.0; 3];
+        let mut num = 0.0;
+        for (&(dx, dy), &g) in DIRS.iter().zip(grad.iter()) {
+            if g > threshold {
+                continue;
+            }
+            let neighbour = &linear[self.index(x, y, dx, dy)];
+            for (k, val) in sum.iter_mut().enumerate() {
+                *val += if k == own {
+                    (c + self.get(x, y, 2 * dx, 2 * dy)) / 2.0
+                } else {
+                    neighbour[k]
+                };
+            }
+            num += 1.0;
+        }
+        let mut res = [c; 3];
+        for k in 0..3 {
+            if k != own {
+                res[k] = c + (sum[k] - sum[own]) / num;
+            }
+        }
+        res
+    }
+}
+
+/// Differences between two RGB images (ie. shader output and a reference).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ImageDiff {
+    /// Number of pixels compared
+    pub pixels: usize,
+
+    /// Mean absolute difference for each of red, green and blue
+    pub mean_abs: [f64; 3],
+
+    /// Largest absolute difference for each of red, green and blue
+    pub max_abs: [u8; 3],
+
+    /// Peak signal-to-noise ratio [in dB] (infinite when identical)
+    pub psnr: f64,
+}
+impl ImageDiff {
+    /// Compare two 'RGB8' or 'RGBA8' images of the same size, ignoring
+    /// pixels within 'margin' of the edge. Alpha is ignored.
+    pub fn compare(a: &PixelView, b: &PixelView, margin: usize)
+        -> Result<Self, &'static str>
+    {
+        fn rgb(fmt: PixelFormat) -> bool {
+            matches!(fmt, PixelFormat::RGB8 | PixelFormat::RGBA8)
+        }
+        if !rgb(a.format()) || !rgb(b.format()) {
+            return Err("Only RGB8 and RGBA8 data can be compared");
+        }
+        if a.width() != b.width() || a.height() != b.height() {
+            return Err("Images must be the same size");
+        }
+        if a.width() <= 2 * margin || a.height() <= 2 * margin {
+            return Err("Margin leaves nothing to compare");
+        }
+        let (w, h) = (a.width() - 2 * margin, a.height() - 2 * margin);
+        let a = a.sub(margin, margin, w, h)?;
+        let b = b.sub(margin, margin, w, h)?;
+        let mut res = Self::default();
+        let mut sum = [0u64; 3];
+        let mut sum_sq = 0u64;
+        for y in 0..h {
+            for x in 0..w {
+                let (pa, pb) = (&a.pixel(x, y)[..3], &b.pixel(x, y)[..3]);
+                for (c, (va, vb)) in pa.iter().zip(pb).enumerate() {
+                    let d = va.abs_diff(*vb);
+                    sum[c] += d as u64;
+                    sum_sq += (d as u64) * (d as u64);
+                    res.max_abs[c] = res.max_abs[c].max(d);
+                }
+                res.pixels += 1;
+            }
+        }
+        let n = res.pixels as f64;
+        res.mean_abs = sum.map(|s| s as f64 / n);
+        let mse = sum_sq as f64 / (n * 3.0);
+        res.psnr = 10.0 * (255.0 * 255.0 / mse).log10();
+        Ok(res)
+    }
+}
+impl std::fmt::Display for ImageDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "pixels       = {}", self.pixels)?;
+        writeln!(f, "mean_abs     = {:.3} {:.3} {:.3}",
+            self.mean_abs[0], self.mean_abs[1], self.mean_abs[2])?;
+        writeln!(f, "max_abs      = {} {} {}",
+            self.max_abs[0], self.max_abs[1], self.max_abs[2])?;
+        writeln!(f, "psnr         = {:.2} dB", self.psnr)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A Bayer8 mosaic with each pixel set to the value of its channel.
+    fn mosaic(pattern: BayerPattern, width: usize, height: usize,
+        rgb: [u8; 3]) -> PixelData
+    {
+        let mut res = PixelData::new(PixelFormat::Bayer8(pattern), width, height);
+        let mut out = res.view_mut();
+        for y in 0..height {
+            for x in 0..width {
+                let c = pattern.channel_at(x, y).rgb_index();
+                out.set_sample(x, y, 0, rgb[c] as f32);
+            }
+        }
+        res
+    }
+
+    fn pixels(img: &PixelData) -> Vec<[f32; 3]> {
+        let view = img.view();
+        let mut res = Vec::new();
+        for y in 0..img.height() {
+            for x in 0..img.width() {
+                res.push([0, 1, 2].map(|c| view.sample(x, y, c)));
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn flat_field() {
+        for pattern in BayerPattern::ALL {
+            let raw = mosaic(pattern, 8, 6, [100; 3]);
+            for method in DemosaicMethod::ALL {
+                let rgb = raw.demosaic(method).unwrap();
+                assert_eq!(rgb.format, PixelFormat::RGB8);
+                for px in pixels(&rgb) {
+                    assert_eq!(px, [100.0; 3], "{:?} {:?}", pattern, method);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn flat_field_16() {
+        let (width, height) = (8, 6);
+        let expected = 1000.0 * 65535.0 / 4095.0;
+        for pattern in BayerPattern::ALL {
+            let fmt = PixelFormat::Bayer16(pattern, 12);
+            let raw = PixelData::new_from_u16(fmt, width, height,
+                &vec![1000; width * height]).unwrap();
+            for method in DemosaicMethod::ALL {
+                let rgb = raw.demosaic(method).unwrap();
+                assert_eq!(rgb.format, PixelFormat::RGB16);
+                for px in pixels(&rgb) {
+                    for val in px {
+                        assert!((val - expected).abs() <= 0.5,
+                            "{:?} {:?}: {}", pattern, method, val);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Each channel is constant, so every method should reproduce it.
+    #[test]
+    fn flat_channels() {
+        for pattern in BayerPattern::ALL {
+            let raw = mosaic(pattern, 8, 6, [200, 100, 50]);
+            for method in DemosaicMethod::ALL {
+                let rgb = raw.demosaic(method).unwrap();
+                for px in pixels(&rgb) {
+                    assert_eq!(px, [200.0, 100.0, 50.0], "{:?} {:?}", pattern, method);
+                }
+            }
+        }
+    }
+
+    /// Pixel (x, y) of a 4x4 RGGB mosaic is 10 * (4 * y + x).
+    fn ramp() -> PixelData {
+        let src: Vec<u8> = (0..16).map(|i| 10 * i).collect();
+        PixelData::new_from_slice(PixelFormat::Bayer8(BayerPattern::RGGB), 4, 4, &src)
+            .unwrap()
+    }
+
+    #[test]
+    fn nearest() {
+        let rgb = pixels(&ramp().demosaic(DemosaicMethod::Nearest).unwrap());
+        let expected = [
+            [  0.0,  10.0,  50.0], [  0.0,  10.0,  50.0],
+            [ 20.0,  30.0,  70.0], [ 20.0,  30.0,  70.0],
+            [  0.0,  40.0,  50.0], [  0.0,  40.0,  50.0],
+            [ 20.0,  60.0,  70.0], [ 20.0,  60.0,  70.0],
+            [ 80.0,  90.0, 130.0], [ 80.0,  90.0, 130.0],
+            [100.0, 110.0, 150.0], [100.0, 110.0, 150.0],
+            [ 80.0, 120.0, 130.0], [ 80.0, 120.0, 130.0],
+            [100.0, 140.0, 150.0], [100.0, 140.0, 150.0],
+        ];
+        assert_eq!(rgb, expected);
+    }
+
+    #[test]
+    fn bilinear() {
+        let rgb = pixels(&ramp().demosaic(DemosaicMethod::Bilinear).unwrap());
+        // NOTE: The ramp is linear, so the interior is exact; the edges 
+        // are mirrored.
+        for (i, px) in rgb.iter().enumerate() {
+            let (x, y) = (i % 4, i / 4);
+            if (1..3).contains(&x) && (1..3).contains(&y) {
+                assert_eq!(*px, [10.0 * i as f32; 3], "({}, {})", x, y);
+            }
+        }
+        assert_eq!(rgb[0], [0.0, 25.0, 50.0]);
+        assert_eq!(rgb[3], [20.0, 30.0, 70.0]);
+        assert_eq!(rgb[12], [80.0, 120.0, 130.0]);
+        assert_eq!(rgb[15], [100.0, 125.0, 150.0]);
+    }
+
+    #[test]
+    fn malvar() {
+        let src: Vec<f32> = (0..16).map(|i| 10.0 * i as f32).collect();
+        let m = Mosaic::new(&src, 4, 4, BayerPattern::RGGB).unwrap();
+
+        // Red at (2, 2) = 100, with the taps at +2 mirrored back onto the
+        // center row and column:
+        //   n1 = 60 + 140 = 200,  n2 = 20 + 100 = 120
+        //   w1 = 90 + 110 = 200,  w2 = 80 + 100 = 180
+        //   diag = 50 + 70 + 130 + 150 = 400
+        //   G = (4 * 100 + 2 * (200 + 200) - (120 + 180)) / 8 = 112.5
+        //   B = (6 * 100 + 2 * 400 - 1.5 * (120 + 180)) / 8 = 118.75
+        assert_eq!(m.malvar(2, 2), [100.0, 112.5, 118.75]);
+
+        // Green at (1, 2) = 90, with red in the same row:
+        //   n1 = 50 + 130 = 180,  n2 = 10 + 90 = 100
+        //   w1 = 80 + 100 = 180,  w2 = 100 + 100 = 200
+        //   diag = 40 + 60 + 120 + 140 = 360
+        //   R = (5 * 90 + 4 * 180 - 200 - 360 + 0.5 * 100) / 8 = 82.5
+        //   B = (5 * 90 + 4 * 180 - 100 - 360 + 0.5 * 200) / 8 = 101.25
+        assert_eq!(m.malvar(1, 2), [82.5, 90.0, 101.25]);
+
+        // Green at (2, 1) = 60, with red in the same column:
+        //   n1 = 20 + 100 = 120,  n2 = 100 + 100 = 200
+        //   w1 = 50 + 70 = 120,   w2 = 40 + 60 = 100
+        //   diag = 10 + 30 + 90 + 110 = 240
+        //   R = (5 * 60 + 4 * 120 - 200 - 240 + 0.5 * 100) / 8 = 48.75
+        //   B = (5 * 60 + 4 * 120 - 100 - 240 + 0.5 * 200) / 8 = 67.5
+        assert_eq!(m.malvar(2, 1), [48.75, 60.0, 67.5]);
+
+        // Blue at (1, 1) = 50:
+        //   n1 = 10 + 90 = 100,   n2 = 90 + 90 = 180
+        //   w1 = 40 + 60 = 100,   w2 = 50 + 70 = 120
+        //   diag = 0 + 20 + 80 + 100 = 200
+        //   R = (6 * 50 + 2 * 200 - 1.5 * (180 + 120)) / 8 = 31.25
+        //   G = (4 * 50 + 2 * (100 + 100) - (180 + 120)) / 8 = 37.5
+        assert_eq!(m.malvar(1, 1), [31.25, 37.5, 50.0]);
+    }
+
+    /// VNG should interpolate along an edge rather than across it.
+    #[test]
+    fn vng_edge() {
+        let (width, height) = (8, 8);
+        // Gray, dark on one side of a vertical (or horizontal) edge
+        // between 3 and 4, and bright on the other
+        for vertical in [true, false] {
+            let value = |x: usize, y: usize| {
+                let i = if vertical { x } else { y };
+                if i < 4 { 20.0 } else { 200.0 }
+            };
+            let src: Vec<f32> = (0..width * height)
+                .map(|i| value(i % width, i / width))
+                .collect();
+            let m = Mosaic::new(&src, width, height, BayerPattern::RGGB).unwrap();
+            let linear: Vec<[f32; 3]> = (0..width * height)
+                .map(|i| m.bilinear(i % width, i / width))
+                .collect();
+
+            // Pixels on either side of the edge, away from the borders
+            for a in 2..6 {
+                for b in [3, 4] {
+                    let (x, y) = if vertical { (b, a) } else { (a, b) };
+                    let own = value(x, y);
+                    let vng = m.vng(&linear, x, y);
+                    let bilinear = linear[y * width + x];
+                    let ctx = format!("{vertical} ({x}, {y}): {vng:?}");
+
+                    // Green is sampled on both sides of the edge, so
+                    // there's always a direction along it
+                    assert_eq!(vng[1], own, "{ctx}");
+                    // Red and blue are pulled towards this side of the
+                    // edge; bilinear just averages both sides
+                    for c in [0, 2] {
+                        assert!((vng[c] - own).abs() <= (bilinear[c] - own).abs(),
+                            "{ctx}");
+                        if bilinear[c] != own {
+                            assert!((vng[c] - own).abs() < 40.0, "{ctx}");
+                            assert_eq!(bilinear[c], 110.0, "{ctx}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let rgb = PixelData::new(PixelFormat::RGB8, 4, 4);
+        assert!(rgb.demosaic(DemosaicMethod::Nearest).is_err());
+        let tiny = PixelData::new(PixelFormat::Bayer8(BayerPattern::RGGB), 1, 4);
+        assert!(tiny.demosaic(DemosaicMethod::Bilinear).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+//! Export raw Bayer data as Adobe DNG.
+//!
+//! The result is a single (uncompressed) raw image with the CFA tags from
+//! the [BayerPattern] of the data, which other raw developers (ie.
+//! darktable or RawTherapee) can demosaic on their own.
+
+use crate::*;
+use crate::tiff::*;
+
+/// Metadata for a DNG file, in addition to [ExportMetadata].
+#[derive(Clone, Debug)]
+pub struct DngMetadata {
+    pub export: ExportMetadata,
+
+    /// Camera manufacturer
+    pub make: String,
+    /// Camera model
+    pub model: String,
+
+    /// Sample value for black (ie. the sensor offset)
+    pub black_level: u32,
+    /// Sample value for saturation (defaults to the maximum for the format)
+    pub white_level: Option<u32>,
+
+    /// White balance at capture time, as the camera's response to a neutral
+    /// color (normalized so that green is 1.0)
+    pub as_shot_neutral: [f64; 3],
+
+    /// Row-major matrix from XYZ to camera RGB (under D65).
+    pub color_matrix: [f64; 9],
+
+    /// Exposure time [in seconds]
+    pub exposure_time: Option<f64>,
+    /// Gain, as an ISO speed rating
+    pub iso: Option<u32>,
+}
+impl DngMetadata {
+    /// XYZ to linear sRGB, for a sensor without a calibrated matrix.
+    pub const SRGB_MATRIX: [f64; 9] = [
+         3.2406, -1.5372, -0.4986,
+        -0.9689,  1.8758,  0.0415,
+         0.0557, -0.2040,  1.0570,
+    ];
+}
+impl Default for DngMetadata {
+    fn default() -> Self {
+        Self {
+            export: ExportMetadata::default(),
+            make: "Unknown".to_string(),
+            model: "Unknown".to_string(),
+            black_level: 0,
+            white_level: None,
+            as_shot_neutral: [1.0, 1.0, 1.0],
+            color_matrix: Self::SRGB_MATRIX,
+            exposure_time: None,
+            iso: None,
+        }
+    }
+}
+
+/// Encode raw Bayer pixels as a DNG image.
+pub fn encode_dng(view: &PixelView, meta: &DngMetadata)
+    -> Result<Vec<u8>, &'static str>
+{
+    let fmt = view.format();
+    let pattern = fmt.bayer_pattern()
+        .ok_or("DNG export expects Bayer data")?;
+    if view.width() == 0 || view.height() == 0 {
+        return Err("DNG images can't be empty");
+    }
+    let white_level = meta.white_level
+        .unwrap_or(fmt.max_value() as u32);
+
+    let mut tiff = TiffWriter::new();
+    // NOTE: Samples are stored as-is, so 12-bit data has a white level of
+    // 4095 in a 16-bit container.
+    let strip = packed_rows(view, false, false);
+    let offset = tiff.append(&strip);
+
+    let mut exif = Ifd::default();
+    if let Some(t) = meta.exposure_time {
+        exif.set(33434, TiffValue::Rational(vec![exposure_rational(t)]));
+    }
+    if let Some(iso) = meta.iso {
+        exif.set(34855, TiffValue::Short(vec![iso.min(u16::MAX as u32) as u16]));
+    }
+    exif.set(36864, TiffValue::Undefined(b"0230".to_vec()));
+    if let Some(t) = meta.export.timestamp {
+        let dt = DateTime::from_system_time(t).to_tiff();
+        exif.set(36867, TiffValue::Ascii(dt));
+    }
+    let exif_offset = tiff.write_ifd(&exif);
+
+    let mut ifd = Ifd::default();
+    // NewSubfileType (the main image)
+    ifd.set(254, TiffValue::Long(vec![0]));
+    set_image_tags(&mut ifd, view.width(), view.height(), fmt, Compression::None);
+    set_metadata_tags(&mut ifd, &meta.export);
+    // PhotometricInterpretation (CFA)
+    ifd.set(262, TiffValue::Short(vec![32803]));
+    ifd.set(271, TiffValue::Ascii(meta.make.clone()));
+    ifd.set(272, TiffValue::Ascii(meta.model.clone()));
+    ifd.set(273, TiffValue::Long(vec![offset]));
+    // Orientation (top-left)
+    ifd.set(274, TiffValue::Short(vec![1]));
+    ifd.set(278, TiffValue::Long(vec![view.height() as u32]));
+    ifd.set(279, TiffValue::Long(vec![strip.len() as u32]));
+
+    // CFARepeatPatternDim and CFAPattern (0 = red, 1 = green, 2 = blue)
+    ifd.set(33421, TiffValue::Short(vec![2, 2]));
+    let cfa = [(0, 0), (1, 0), (0, 1), (1, 1)].iter()
+        .map(|&(x, y)| pattern.channel_at(x, y).rgb_index() as u8)
+        .collect();
+    ifd.set(33422, TiffValue::Byte(cfa));
+    ifd.set(34665, TiffValue::Long(vec![exif_offset]));
+
+    // DNGVersion and DNGBackwardVersion
+    ifd.set(50706, TiffValue::Byte(vec![1, 4, 0, 0]));
+    ifd.set(50707, TiffValue::Byte(vec![1, 1, 0, 0]));
+    ifd.set(50708, TiffValue::Ascii(format!("{} {}", meta.make, meta.model)));
+    // CFAPlaneColor and CFALayout (rectangular)
+    ifd.set(50710, TiffValue::Byte(vec![0, 1, 2]));
+    ifd.set(50711, TiffValue::Short(vec![1]));
+    // BlackLevelRepeatDim, BlackLevel, WhiteLevel
+    ifd.set(50713, TiffValue::Short(vec![1, 1]));
+    ifd.set(50714, TiffValue::Long(vec![meta.black_level]));
+    ifd.set(50717, TiffValue::Long(vec![white_level]));
+    // ColorMatrix1, with CalibrationIlluminant1 (D65)
+    let matrix = meta.color_matrix.iter()
+        .map(|&v| TiffValue::srational(v)).collect();
+    ifd.set(50721, TiffValue::SRational(matrix));
+    ifd.set(50778, TiffValue::Short(vec![21]));
+    let neutral = meta.as_shot_neutral.iter()
+        .map(|&v| TiffValue::rational(v)).collect();
+    ifd.set(50728, TiffValue::Rational(neutral));
+
+    Ok(tiff.finish(&ifd))
+}
+
+/// Exposure times are conventionally written as '1/n' when shorter than a
+/// second.
+fn exposure_rational(secs: f64) -> (u32, u32) {
+    if secs > 0.0 && secs < 1.0 && (1.0 / secs).fract().abs() < 1e-6 {
+        (1, (1.0 / secs).round() as u32)
+    } else {
+        ((secs * 1_000_000.0).round() as u32, 1_000_000)
+    }
+}
+
+impl PixelView<'_> {
+    /// Write raw Bayer pixels to a DNG file.
+    pub fn save_dng(&self, path: &str, meta: &DngMetadata)
+        -> Result<(), ExportError>
+    {
+        std::fs::write(path, encode_dng(self, meta)?)?;
+        Ok(())
+    }
+}
+
+impl PixelData {
+    /// Write raw Bayer pixels to a DNG file.
+    pub fn save_dng(&self, path: &str, meta: &DngMetadata)
+        -> Result<(), ExportError>
+    {
+        self.view().save_dng(path, meta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiff::tests::ParsedIfd;
+
+    fn meta() -> DngMetadata {
+        DngMetadata {
+            export: ExportMetadata {
+                timestamp: Some(std::time::UNIX_EPOCH),
+                ..Default::default()
+            },
+            make: "Touptek".to_string(),
+            model: "MU1603".to_string(),
+            black_level: 32,
+            as_shot_neutral: [0.5, 1.0, 0.75],
+            exposure_time: Some(0.01),
+            iso: Some(200),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tags() {
+        let cfa = [
+            (BayerPattern::RGGB, [0, 1, 1, 2]),
+            (BayerPattern::GRBG, [1, 0, 2, 1]),
+            (BayerPattern::GBRG, [1, 2, 0, 1]),
+            (BayerPattern::BGGR, [2, 1, 1, 0]),
+        ];
+        for (pattern, cfa) in cfa {
+            for (fmt, bits, white) in [
+                (PixelFormat::Bayer8(pattern), 8, 255),
+                (PixelFormat::Bayer16(pattern, 12), 16, 4095),
+            ] {
+                let src: Vec<u16> = (0..6 * 4).map(|i| i * 100).collect();
+                let img = match fmt {
+                    PixelFormat::Bayer8(_) => {
+                        let src: Vec<u8> = src.iter().map(|&v| v as u8).collect();
+                        PixelData::new_from_slice(fmt, 6, 4, &src).unwrap()
+                    },
+                    _ => PixelData::new_from_u16(fmt, 6, 4, &src).unwrap(),
+                };
+                let file = encode_dng(&img.view(), &meta()).unwrap();
+                let ifd = ParsedIfd::first(&file);
+                assert_eq!(ifd.next, 0);
+
+                assert_eq!(ifd.int(254), 0);
+                assert_eq!((ifd.int(256), ifd.int(257)), (6, 4));
+                assert_eq!(ifd.ints(258), vec![bits]);
+                assert_eq!(ifd.int(259), 1);
+                assert_eq!(ifd.int(262), 32803);
+                assert_eq!(ifd.ascii(271), "Touptek");
+                assert_eq!(ifd.ascii(272), "MU1603");
+                assert_eq!(ifd.int(274), 1);
+                assert_eq!(ifd.int(277), 1);
+                assert_eq!(ifd.int(278), 4);
+                assert_eq!(ifd.ints(33421), vec![2, 2]);
+                assert_eq!(ifd.ints(33422), cfa.to_vec(), "{:?}", pattern);
+                assert_eq!(ifd.ints(50706), vec![1, 4, 0, 0]);
+                assert_eq!(ifd.ints(50707), vec![1, 1, 0, 0]);
+                assert_eq!(ifd.ascii(50708), "Touptek MU1603");
+                assert_eq!(ifd.ints(50710), vec![0, 1, 2]);
+                assert_eq!(ifd.int(50711), 1);
+                assert_eq!(ifd.ints(50713), vec![1, 1]);
+                assert_eq!(ifd.int(50714), 32);
+                assert_eq!(ifd.int(50717), white);
+                assert_eq!(ifd.int(50778), 21);
+                assert_eq!(ifd.rationals(50728), vec![0.5, 1.0, 0.75]);
+                let matrix = ifd.rationals(50721);
+                assert_eq!(matrix.len(), 9);
+                for (a, b) in matrix.iter().zip(DngMetadata::SRGB_MATRIX) {
+                    assert!((a - b).abs() < 1e-6);
+                }
+
+                // NOTE: Samples are stored as-is (not scaled to 16 bits)
+                let (off, len) = (ifd.int(273) as usize, ifd.int(279) as usize);
+                assert_eq!(&file[off..off + len], img.as_slice());
+
+                let exif = ParsedIfd::read(&file, ifd.int(34665) as usize);
+                assert_eq!(exif.rationals(33434), vec![0.01]);
+                assert_eq!(exif.int(34855), 200);
+                assert_eq!(exif.ints(36864), b"0230".map(|b| b as u32).to_vec());
+                assert_eq!(exif.ascii(36867), "1970:01:01 00:00:00");
+            }
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let img = PixelData::new(PixelFormat::Mono8, 4, 4);
+        assert!(encode_dng(&img.view(), &DngMetadata::default()).is_err());
+        let img = PixelData::new(PixelFormat::Bayer8(BayerPattern::RGGB), 0, 0);
+        assert!(encode_dng(&img.view(), &DngMetadata::default()).is_err());
+    }
+
+    #[test]
+    fn exposure() {
+        assert_eq!(exposure_rational(0.01), (1, 100));
+        assert_eq!(exposure_rational(0.25), (1, 4));
+        assert_eq!(exposure_rational(0.3), (300_000, 1_000_000));
+        assert_eq!(exposure_rational(2.0), (2_000_000, 1_000_000));
+    }
+}
@@ -0,0 +1,537 @@
+//! Encoders for writing pixel data to common image formats.
+
+use crate::*;
+use crate::tiff::*;
+use std::time::SystemTime;
+
+/// A file format that [PixelData] can be exported to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Tiff,
+    Dng,
+}
+impl ImageFormat {
+    /// Guess the format from the extension of a filename.
+    pub fn from_path(path: &str) -> Option<Self> {
+        let ext = path.rsplit_once('.')?.1.to_ascii_lowercase();
+        match ext.as_str() {
+            "png" => Some(Self::Png),
+            "tif" | "tiff" => Some(Self::Tiff),
+            "dng" => Some(Self::Dng),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Tiff => "tiff",
+            Self::Dng => "dng",
+        }
+    }
+
+    /// Encode pixels in this format.
+    ///
+    /// NOTE: DNG files are always uncompressed, and use the defaults from
+    /// [DngMetadata] (see [encode_dng] for anything else).
+    pub fn encode(&self, view: &PixelView, compression: Compression,
+        meta: &ExportMetadata) -> Result<Vec<u8>, &'static str>
+    {
+        match self {
+            Self::Png => encode_png(view, compression, meta),
+            Self::Tiff => encode_tiff(view, compression, meta),
+            Self::Dng => encode_dng(view, &DngMetadata {
+                export: meta.clone(),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+/// Compression applied to exported pixel data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Deflate,
+}
+
+/// Extra information stored alongside exported pixel data.
+#[derive(Clone, Debug)]
+pub struct ExportMetadata {
+    /// Name of the program that wrote the file
+    pub software: String,
+    /// When the image was captured
+    pub timestamp: Option<SystemTime>,
+    /// Some free-form text (ie. sensor settings)
+    pub description: Option<String>,
+    /// Pixels per centimeter
+    pub resolution: Option<f64>,
+}
+impl Default for ExportMetadata {
+    fn default() -> Self {
+        Self {
+            software: format!("glass {}", env!("CARGO_PKG_VERSION")),
+            timestamp: None,
+            description: None,
+            resolution: None,
+        }
+    }
+}
+impl ExportMetadata {
+    /// Metadata stamped with the current time.
+    pub fn now() -> Self {
+        Self { timestamp: Some(SystemTime::now()), ..Default::default() }
+    }
+}
+
+/// An error while exporting an image to a file.
+#[derive(Debug)]
+pub enum ExportError {
+    Io(std::io::Error),
+    Format(&'static str),
+}
+impl std::fmt::Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Format(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl std::error::Error for ExportError {}
+impl From<std::io::Error> for ExportError {
+    fn from(e: std::io::Error) -> Self { Self::Io(e) }
+}
+impl From<&'static str> for ExportError {
+    fn from(e: &'static str) -> Self { Self::Format(e) }
+}
+
+/// Copy the pixels into packed rows, with 16-bit samples in the requested
+/// byte order.
+///
+/// When 'full_range' is set, samples in 'Bayer16' data with fewer than 16
+/// significant bits are shifted up so that the full range is used.
+pub(crate) fn packed_rows(view: &PixelView, big_endian: bool, full_range: bool)
+    -> Vec<u8>
+{
+    let fmt = view.format();
+    let row_bytes = view.row_bytes();
+    let mut res = Vec::with_capacity(row_bytes * view.height());
+    for y in 0..view.height() {
+        let start = res.len();
+        match view.row(y) {
+            Some(row) => res.extend_from_slice(row),
+            None => (0..view.width())
+                .for_each(|x| res.extend_from_slice(view.pixel(x, y))),
+        }
+        if fmt.sample_type() != SampleType::U16 {
+            continue;
+        }
+        let shift = match full_range {
+            true => 16 - fmt.significant_bits().unwrap_or(16).min(16),
+            false => 0,
+        };
+        for s in res[start..].chunks_exact_mut(2) {
+            let v = u16::from_le_bytes([s[0], s[1]]) << shift;
+            s.copy_from_slice(&if big_endian { v.to_be_bytes() } else { v.to_le_bytes() });
+        }
+    }
+    res
+}
+
+fn zlib(data: &[u8], compression: Compression) -> Vec<u8> {
+    let level = match compression {
+        Compression::None => 0,
+        Compression::Deflate => 6,
+    };
+    miniz_oxide::deflate::compress_to_vec_zlib(data, level)
+}
+
+/// CRC-32 of each byte value (for [crc32]).
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[&[u8]]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data.iter().flat_map(|d| d.iter()) {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&crc32(&[kind, data]).to_be_bytes());
+}
+
+fn png_text(out: &mut Vec<u8>, key: &str, val: &str) {
+    // NOTE: tEXt is Latin-1, so anything else is replaced
+    let mut data = key.as_bytes().to_vec();
+    data.push(0);
+    data.extend(val.chars().map(|c| if (c as u32) < 256 { c as u8 } else { b'?' }));
+    png_chunk(out, b"tEXt", &data);
+}
+
+/// Encode pixels as a PNG image.
+///
+/// Bayer data is written as a grayscale image (without demosaicing).
+/// Floating-point data isn't supported.
+pub fn encode_png(view: &PixelView, compression: Compression,
+    meta: &ExportMetadata) -> Result<Vec<u8>, &'static str>
+{
+    let fmt = view.format();
+    let color_type: u8 = match fmt.channels() {
+        1 => 0,
+        3 => 2,
+        4 => 6,
+        _ => unreachable!(),
+    };
+    let depth: u8 = match fmt.sample_type() {
+        SampleType::U8 => 8,
+        SampleType::U16 => 16,
+        SampleType::F32 => return Err("PNG doesn't support floating-point data"),
+    };
+    if view.width() == 0 || view.height() == 0 {
+        return Err("PNG images can't be empty");
+    }
+
+    let mut out = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&(view.width() as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(view.height() as u32).to_be_bytes());
+    // Depth, color type, compression, filter, interlace
+    ihdr.extend_from_slice(&[depth, color_type, 0, 0, 0]);
+    png_chunk(&mut out, b"IHDR", &ihdr);
+
+    if let Some(bits) = fmt.significant_bits().filter(|&b| b < depth as usize) {
+        png_chunk(&mut out, b"sBIT", &vec![bits as u8; fmt.channels()]);
+    }
+    if let Some(res) = meta.resolution {
+        // Pixels per meter, with the unit set to meters
+        let ppm = ((res * 100.0).round() as u32).to_be_bytes();
+        let mut phys = [ppm, ppm].concat();
+        phys.push(1);
+        png_chunk(&mut out, b"pHYs", &phys);
+    }
+    if let Some(t) = meta.timestamp {
+        let dt = DateTime::from_system_time(t);
+        let mut time = (dt.year as u16).to_be_bytes().to_vec();
+        time.extend_from_slice(&[dt.month as u8, dt.day as u8, dt.hour as u8,
+            dt.minute as u8, dt.second as u8]);
+        png_chunk(&mut out, b"tIME", &time);
+        png_text(&mut out, "Creation Time", &dt.to_iso8601());
+    }
+    png_text(&mut out, "Software", &meta.software);
+    if let Some(desc) = &meta.description {
+        png_text(&mut out, "Description", desc);
+    }
+
+    // Every row uses the 'Up' filter (the difference from the row above),
+    // which does well enough on camera data without an adaptive search.
+    let data = packed_rows(view, true, true);
+    let row_bytes = view.row_bytes();
+    let mut filtered = Vec::with_capacity(data.len() + view.height());
+    let mut prev: &[u8] = &[];
+    for row in data.chunks_exact(row_bytes) {
+        if compression == Compression::None {
+            filtered.push(0);
+            filtered.extend_from_slice(row);
+        } else {
+            filtered.push(2);
+            if prev.is_empty() {
+                filtered.extend_from_slice(row);
+            } else {
+                filtered.extend(row.iter().zip(prev).map(|(a, b)| a.wrapping_sub(*b)));
+            }
+        }
+        prev = row;
+    }
+    png_chunk(&mut out, b"IDAT", &zlib(&filtered, compression));
+    png_chunk(&mut out, b"IEND", &[]);
+    Ok(out)
+}
+
+/// Encode pixels as a (baseline) TIFF image.
+///
+/// Bayer data is written as a grayscale image (without demosaicing).
+pub fn encode_tiff(view: &PixelView, compression: Compression,
+    meta: &ExportMetadata) -> Result<Vec<u8>, &'static str>
+{
+    let fmt = view.format();
+    if view.width() == 0 || view.height() == 0 {
+        return Err("TIFF images can't be empty");
+    }
+    let data = packed_rows(view, false, true);
+    let strip = match compression {
+        Compression::None => data,
+        Compression::Deflate => zlib(&data, compression),
+    };
+
+    let mut tiff = TiffWriter::new();
+    let offset = tiff.append(&strip);
+    let mut ifd = Ifd::default();
+    set_image_tags(&mut ifd, view.width(), view.height(), fmt, compression);
+    ifd.set(273, TiffValue::Long(vec![offset]));
+    ifd.set(278, TiffValue::Long(vec![view.height() as u32]));
+    ifd.set(279, TiffValue::Long(vec![strip.len() as u32]));
+    // PhotometricInterpretation (BlackIsZero or RGB)
+    let photometric = if fmt.channels() == 1 { 1 } else { 2 };
+    ifd.set(262, TiffValue::Short(vec![photometric]));
+    if fmt.channels() == 4 {
+        // ExtraSamples (unassociated alpha)
+        ifd.set(338, TiffValue::Short(vec![2]));
+    }
+    set_metadata_tags(&mut ifd, meta);
+    Ok(tiff.finish(&ifd))
+}
+
+/// Set tags describing the size and layout of the pixels.
+pub(crate) fn set_image_tags(ifd: &mut Ifd, width: usize, height: usize,
+    fmt: PixelFormat, compression: Compression)
+{
+    let channels = fmt.channels();
+    let bits = (fmt.sample_type().bytes() * 8) as u16;
+    ifd.set(256, TiffValue::Long(vec![width as u32]));
+    ifd.set(257, TiffValue::Long(vec![height as u32]));
+    ifd.set(258, TiffValue::Short(vec![bits; channels]));
+    // Compression (none or Adobe deflate)
+    let comp = match compression {
+        Compression::None => 1,
+        Compression::Deflate => 8,
+    };
+    ifd.set(259, TiffValue::Short(vec![comp]));
+    ifd.set(277, TiffValue::Short(vec![channels as u16]));
+    // PlanarConfiguration (chunky)
+    ifd.set(284, TiffValue::Short(vec![1]));
+    // SampleFormat (unsigned or floating-point)
+    let sample_format = match fmt.sample_type() {
+        SampleType::F32 => 3,
+        _ => 1,
+    };
+    ifd.set(339, TiffValue::Short(vec![sample_format; channels]));
+}
+
+/// Set the tags for [ExportMetadata].
+pub(crate) fn set_metadata_tags(ifd: &mut Ifd, meta: &ExportMetadata) {
+    if let Some(desc) = &meta.description {
+        ifd.set(270, TiffValue::Ascii(desc.clone()));
+    }
+    if let Some(res) = meta.resolution {
+        ifd.set(282, TiffValue::Rational(vec![TiffValue::rational(res)]));
+        ifd.set(283, TiffValue::Rational(vec![TiffValue::rational(res)]));
+        // ResolutionUnit (centimeters)
+        ifd.set(296, TiffValue::Short(vec![3]));
+    }
+    ifd.set(305, TiffValue::Ascii(meta.software.clone()));
+    if let Some(t) = meta.timestamp {
+        ifd.set(306, TiffValue::Ascii(DateTime::from_system_time(t).to_tiff()));
+    }
+}
+
+impl PixelView<'_> {
+    /// Write the pixels to an image file, choosing the format from the
+    /// extension of 'path'.
+    pub fn save(&self, path: &str, compression: Compression,
+        meta: &ExportMetadata) -> Result<(), ExportError>
+    {
+        let fmt = ImageFormat::from_path(path)
+            .ok_or("Unknown image file extension")?;
+        let data = fmt.encode(self, compression, meta)?;
+        std::fs::write(path, data)?;
+        Ok(())
+    }
+}
+
+impl PixelData {
+    /// Write the pixels to a compressed PNG or TIFF file (or a DNG file).
+    pub fn save(&self, path: &str, meta: &ExportMetadata)
+        -> Result<(), ExportError>
+    {
+        self.view().save(path, Compression::Deflate, meta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiff::tests::ParsedIfd;
+
+    /// Split a PNG file into chunks, checking the signature and each CRC.
+    fn png_chunks(file: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(&file[..8], &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']);
+        let mut res = Vec::new();
+        let mut rest = &file[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let kind: [u8; 4] = rest[4..8].try_into().unwrap();
+            let data = &rest[8..8 + len];
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc, crc32(&[&kind, data]), "{:?}", kind);
+            res.push((kind, data.to_vec()));
+            rest = &rest[12 + len..];
+        }
+        res
+    }
+
+    /// Undo the filters in the decompressed IDAT data.
+    fn unfilter(data: &[u8], row_bytes: usize, bpp: usize) -> Vec<u8> {
+        let mut res: Vec<u8> = Vec::new();
+        for (y, row) in data.chunks_exact(row_bytes + 1).enumerate() {
+            let prev = y.checked_sub(1).map(|y| res[y * row_bytes..][..row_bytes].to_vec());
+            let start = res.len();
+            for (x, &v) in row[1..].iter().enumerate() {
+                let up = prev.as_ref().map(|p| p[x]).unwrap_or(0);
+                let left = if x >= bpp { res[start + x - bpp] } else { 0 };
+                res.push(match row[0] {
+                    0 => v,
+                    1 => v.wrapping_add(left),
+                    2 => v.wrapping_add(up),
+                    f => panic!("unexpected filter {}", f),
+                });
+            }
+        }
+        res
+    }
+
+    fn ramp(fmt: PixelFormat, width: usize, height: usize) -> PixelData {
+        let mut res = PixelData::new(fmt, width, height);
+        let mut out = res.view_mut();
+        for y in 0..height {
+            for x in 0..width {
+                for c in 0..fmt.channels() {
+                    out.set_sample(x, y, c, (37 * x + 101 * y + 13 * c) as f32);
+                }
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn crc() {
+        // The check value for CRC-32
+        assert_eq!(crc32(&[b"123456789"]), 0xcbf4_3926);
+        assert_eq!(crc32(&[b"1234", b"56789"]), 0xcbf4_3926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn png() {
+        let meta = ExportMetadata {
+            timestamp: Some(std::time::UNIX_EPOCH),
+            description: Some("mode=1".to_string()),
+            resolution: Some(100.0),
+            ..Default::default()
+        };
+        for (fmt, depth, color_type) in [
+            (PixelFormat::Mono8, 8, 0),
+            (PixelFormat::Bayer16(BayerPattern::RGGB, 12), 16, 0),
+            (PixelFormat::RGB8, 8, 2),
+            (PixelFormat::RGB16, 16, 2),
+            (PixelFormat::RGBA8, 8, 6),
+        ] {
+            let img = ramp(fmt, 7, 5);
+            for compression in [Compression::None, Compression::Deflate] {
+                let file = encode_png(&img.view(), compression, &meta).unwrap();
+                let chunks = png_chunks(&file);
+                let kinds: Vec<&[u8]> = chunks.iter().map(|(k, _)| k.as_slice()).collect();
+                assert_eq!(kinds.first(), Some(&b"IHDR".as_slice()));
+                assert_eq!(kinds.last(), Some(&b"IEND".as_slice()));
+                assert!(kinds.contains(&b"pHYs".as_slice()));
+                assert!(kinds.contains(&b"tIME".as_slice()));
+                assert_eq!(kinds.contains(&b"sBIT".as_slice()), 
+                    matches!(fmt, PixelFormat::Bayer16(..)));
+
+                let ihdr = &chunks[0].1;
+                assert_eq!(&ihdr[..8], &[0, 0, 0, 7, 0, 0, 0, 5]);
+                assert_eq!(&ihdr[8..], &[depth, color_type, 0, 0, 0]);
+
+                let idat: Vec<u8> = chunks.iter()
+                    .filter(|(k, _)| k == b"IDAT")
+                    .flat_map(|(_, d)| d.iter().copied())
+                    .collect();
+                let raw = miniz_oxide::inflate::decompress_to_vec_zlib(&idat).unwrap();
+                let bpp = fmt.bytes_per_pixel();
+                let pixels = unfilter(&raw, 7 * bpp, bpp);
+                assert_eq!(pixels, packed_rows(&img.view(), true, true), "{:?}", fmt);
+            }
+        }
+    }
+
+    #[test]
+    fn png_errors() {
+        let meta = ExportMetadata::default();
+        let img = PixelData::new(PixelFormat::RGBF32, 2, 2);
+        assert!(encode_png(&img.view(), Compression::Deflate, &meta).is_err());
+        let img = PixelData::new(PixelFormat::Mono8, 0, 2);
+        assert!(encode_png(&img.view(), Compression::Deflate, &meta).is_err());
+    }
+
+    #[test]
+    fn tiff() {
+        let meta = ExportMetadata {
+            timestamp: Some(std::time::UNIX_EPOCH),
+            description: Some("mode=1".to_string()),
+            resolution: Some(100.0),
+            ..Default::default()
+        };
+        for (fmt, photometric) in [
+            (PixelFormat::Mono16, 1),
+            (PixelFormat::Bayer8(BayerPattern::GRBG), 1),
+            (PixelFormat::RGB8, 2),
+            (PixelFormat::RGBA8, 2),
+            (PixelFormat::RGBF32, 2),
+        ] {
+            let img = ramp(fmt, 7, 5);
+            for compression in [Compression::None, Compression::Deflate] {
+                let file = encode_tiff(&img.view(), compression, &meta).unwrap();
+                let ifd = ParsedIfd::first(&file);
+                let channels = fmt.channels() as u32;
+                let bits = fmt.sample_type().bytes() as u32 * 8;
+                assert_eq!(ifd.int(256), 7);
+                assert_eq!(ifd.int(257), 5);
+                assert_eq!(ifd.ints(258), vec![bits; channels as usize]);
+                assert_eq!(ifd.int(262), photometric);
+                assert_eq!(ifd.int(277), channels);
+                assert_eq!(ifd.int(278), 5);
+                assert_eq!(ifd.has(338), channels == 4);
+                assert_eq!(ifd.ascii(270), "mode=1");
+                assert_eq!(ifd.ascii(306), "1970:01:01 00:00:00");
+                assert_eq!(ifd.rationals(282), vec![100.0]);
+
+                let (off, len) = (ifd.int(273) as usize, ifd.int(279) as usize);
+                let strip = &file[off..off + len];
+                let data = match ifd.int(259) {
+                    1 => strip.to_vec(),
+                    8 => miniz_oxide::inflate::decompress_to_vec_zlib(strip).unwrap(),
+                    c => panic!("unexpected compression {}", c),
+                };
+                assert_eq!(data, packed_rows(&img.view(), false, true), "{:?}", fmt);
+            }
+        }
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(ImageFormat::from_path("a/b.PNG"), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::from_path("b.tif"), Some(ImageFormat::Tiff));
+        assert_eq!(ImageFormat::from_path("b.dng"), Some(ImageFormat::Dng));
+        assert_eq!(ImageFormat::from_path("b.raw"), None);
+        assert_eq!(ImageFormat::from_path("png"), None);
+    }
+}
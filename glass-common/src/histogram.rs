@@ -0,0 +1,393 @@
+//! Histograms of pixel values.
+//!
+//! Bayer data gets a histogram for each [BayerChannel], and RGB data gets
+//! a histogram for each color along with (Rec. 709) luminance.
+
+use crate::*;
+
+/// Options for computing a [Histogram].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HistogramOptions {
+    /// Number of bins
+    pub bins: usize,
+
+    /// Significant bits in each sample (defaults to the bits in the format,
+    /// ie. 12 for 12-bit Bayer data). Larger values land in the last bin.
+    pub bits: Option<u8>,
+
+    /// Samples at or below this value are counted as clipped to black
+    pub black_point: f64,
+
+    /// Samples at or above this value are counted as clipped to white
+    /// (defaults to the largest value)
+    pub white_point: Option<f64>,
+}
+impl Default for HistogramOptions {
+    fn default() -> Self {
+        Self { bins: 256, bits: None, black_point: 0.0, white_point: None }
+    }
+}
+impl HistogramOptions {
+    pub fn with_bins(bins: usize) -> Self {
+        Self { bins, ..Default::default() }
+    }
+}
+
+/// Counts of samples in evenly-sized bins over [0, max_value].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    pub bins: Vec<u64>,
+    /// The largest value covered by the last bin
+    pub max_value: f64,
+    /// Number of samples
+    pub total: u64,
+    /// Number of samples at or below the black point
+    pub clipped_black: u64,
+    /// Number of samples at or above the white point
+    pub clipped_white: u64,
+}
+impl Histogram {
+    /// Rebin full-resolution counts (where 'counts[v]' is the number of
+    /// samples with value 'v').
+    fn from_counts(counts: &[u64], max: usize, opts: &HistogramOptions) -> Self {
+        let nbins = opts.bins.max(1);
+        let mut res = Self {
+            bins: vec![0; nbins],
+            max_value: max as f64,
+            ..Default::default()
+        };
+        let white = opts.white_point.unwrap_or(max as f64);
+        for (v, &n) in counts.iter().enumerate().filter(|(_, &n)| n != 0) {
+            let bin = (v.min(max) * nbins / (max + 1)).min(nbins - 1);
+            res.bins[bin] += n;
+            res.total += n;
+            if v as f64 <= opts.black_point {
+                res.clipped_black += n;
+            }
+            if v as f64 >= white {
+                res.clipped_white += n;
+            }
+        }
+        res
+    }
+
+    /// Range of values [min, max) covered by some bin.
+    pub fn bin_range(&self, bin: usize) -> (f64, f64) {
+        let width = (self.max_value + 1.0) / self.bins.len() as f64;
+        (bin as f64 * width, (bin + 1) as f64 * width)
+    }
+
+    /// Fraction of samples clipped to black.
+    pub fn black_fraction(&self) -> f64 {
+        self.clipped_black as f64 / self.total.max(1) as f64
+    }
+
+    /// Fraction of samples clipped to white.
+    pub fn white_fraction(&self) -> f64 {
+        self.clipped_white as f64 / self.total.max(1) as f64
+    }
+
+    /// Index of the bin with the most samples.
+    pub fn peak(&self) -> usize {
+        self.bins.iter().enumerate().rev()
+            .max_by_key(|(_, &n)| n).map(|(i, _)| i).unwrap_or(0)
+    }
+
+    /// Mean value (from the center of each bin).
+    pub fn mean(&self) -> f64 {
+        let sum: f64 = self.bins.iter().enumerate().map(|(i, &n)| {
+            let (lo, hi) = self.bin_range(i);
+            n as f64 * (lo + hi) / 2.0
+        }).sum();
+        sum / self.total.max(1) as f64
+    }
+
+    /// Value below which a fraction 'p' of the samples fall (interpolated
+    /// within the bin), ie. 'percentile(0.5)' is the median.
+    pub fn percentile(&self, p: f64) -> f64 {
+        let target = p.clamp(0.0, 1.0) * self.total as f64;
+        let mut seen = 0.0;
+        for (i, &n) in self.bins.iter().enumerate() {
+            let next = seen + n as f64;
+            if n != 0 && next >= target {
+                let (lo, hi) = self.bin_range(i);
+                return lo + (hi - lo) * (target - seen) / n as f64;
+            }
+            seen = next;
+        }
+        self.max_value
+    }
+}
+
+/// Which channel a [Histogram] belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistogramChannel {
+    Bayer(BayerChannel),
+    Red,
+    Green,
+    Blue,
+    Luminance,
+    Mono,
+}
+
+/// Histograms for each channel of some pixels.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histograms {
+    pub channels: Vec<(HistogramChannel, Histogram)>,
+}
+impl Histograms {
+    pub fn get(&self, ch: HistogramChannel) -> Option<&Histogram> {
+        self.channels.iter().find(|(c, _)| *c == ch).map(|(_, h)| h)
+    }
+
+    /// Samples clipped to white in any channel.
+    pub fn clipped_white(&self) -> u64 {
+        self.channels.iter()
+            .filter(|(c, _)| *c != HistogramChannel::Luminance)
+            .map(|(_, h)| h.clipped_white).sum()
+    }
+}
+
+/// Compute a histogram for each channel.
+pub fn histograms(view: &PixelView, opts: &HistogramOptions) -> Histograms {
+    use HistogramChannel::*;
+    let fmt = view.format();
+    let channels: Vec<HistogramChannel> = match fmt {
+        PixelFormat::Bayer8(_) | PixelFormat::Bayer16(..) => {
+            BayerChannel::ALL.iter().map(|&c| Bayer(c)).collect()
+        },
+        PixelFormat::Mono8 | PixelFormat::Mono16 => vec![Mono],
+        _ => vec![Red, Green, Blue, Luminance],
+    };
+    if fmt.sample_type() == SampleType::F32 {
+        return float_histograms(view, channels, opts);
+    }
+
+    let sample_bits = fmt.sample_type().bytes() * 8;
+    let bits = opts.bits.map(|b| b as usize)
+        .or(view.significant_bits())
+        .unwrap_or(sample_bits)
+        .clamp(1, sample_bits);
+    let mut counts = vec![vec![0u64; 1 << sample_bits]; channels.len()];
+    match fmt.sample_type() {
+        SampleType::U8 => count_samples(view, &mut counts, |b| b[0] as usize),
+        _ => count_samples(view, &mut counts, |b| {
+            u16::from_le_bytes([b[0], b[1]]) as usize
+        }),
+    }
+    let max = (1usize << bits) - 1;
+    Histograms {
+        channels: channels.into_iter().zip(counts.iter())
+            .map(|(c, counts)| (c, Histogram::from_counts(counts, max, opts)))
+            .collect()
+    }
+}
+
+/// Count every value in integer data.
+///
+/// For Bayer data, 'counts' has an entry for each [BayerChannel]. For RGB
+/// data, the last entry is for luminance (and alpha is ignored).
+fn count_samples<F>(view: &PixelView, counts: &mut [Vec<u64>], read: F)
+    where F: Fn(&[u8]) -> usize
+{
+    let fmt = view.format();
+    let size = fmt.sample_type().bytes();
+    let bpp = fmt.bytes_per_pixel();
+    let mut buf = Vec::new();
+    for y in 0..view.height() {
+        // NOTE: Views of a single channel don't have contiguous rows
+        let row = match view.row(y) {
+            Some(row) => row,
+            None => {
+                buf.clear();
+                (0..view.width()).for_each(|x| buf.extend_from_slice(view.pixel(x, y)));
+                &buf
+            },
+        };
+        if let Some(pattern) = fmt.bayer_pattern() {
+            // The channel only depends on the parity of 'x' in each row
+            let ch = [pattern.channel_at(0, y).index(), pattern.channel_at(1, y).index()];
+            for (x, px) in row.chunks_exact(bpp).enumerate() {
+                counts[ch[x & 1]][read(px)] += 1;
+            }
+        } else if fmt.channels() == 1 {
+            for px in row.chunks_exact(bpp) {
+                counts[0][read(px)] += 1;
+            }
+        } else {
+            for px in row.chunks_exact(bpp) {
+                let r = read(px);
+                let g = read(&px[size..]);
+                let b = read(&px[2 * size..]);
+                counts[0][r] += 1;
+                counts[1][g] += 1;
+                counts[2][b] += 1;
+                // Rec. 709 luminance, in fixed-point
+                counts[3][(54 * r + 183 * g + 19 * b) >> 8] += 1;
+            }
+        }
+    }
+}
+
+/// Floating-point data is binned over [0.0, 1.0].
+fn float_histograms(view: &PixelView, channels: Vec<HistogramChannel>,
+    opts: &HistogramOptions) -> Histograms
+{
+    let nbins = opts.bins.max(1);
+    let white = opts.white_point.unwrap_or(1.0);
+    let mut res: Vec<Histogram> = channels.iter().map(|_| Histogram {
+        bins: vec![0; nbins],
+        max_value: 1.0,
+        ..Default::default()
+    }).collect();
+    let push = |h: &mut Histogram, v: f64| {
+        let bin = (v * nbins as f64).clamp(0.0, (nbins - 1) as f64) as usize;
+        h.bins[bin] += 1;
+        h.total += 1;
+        if v <= opts.black_point {
+            h.clipped_black += 1;
+        }
+        if v >= white {
+            h.clipped_white += 1;
+        }
+    };
+    for y in 0..view.height() {
+        for x in 0..view.width() {
+            let rgb = [0, 1, 2].map(|c| view.sample(x, y, c) as f64);
+            for c in 0..3 {
+                push(&mut res[c], rgb[c]);
+            }
+            push(&mut res[3], 0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2]);
+        }
+    }
+    Histograms { channels: channels.into_iter().zip(res).collect() }
+}
+
+impl PixelView<'_> {
+    /// Compute a histogram for each channel (see [histograms]).
+    pub fn histograms(&self, opts: &HistogramOptions) -> Histograms {
+        histograms(self, opts)
+    }
+}
+
+impl PixelData {
+    /// Compute a histogram for each channel (see [histograms]).
+    pub fn histograms(&self, opts: &HistogramOptions) -> Histograms {
+        histograms(&self.view(), opts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_counts() {
+        let src: Vec<u8> = (0..=255).collect();
+        let img = PixelData::new_from_slice(PixelFormat::Mono8, 16, 16, &src).unwrap();
+        let hist = img.histograms(&HistogramOptions::with_bins(4));
+        assert_eq!(hist.channels.len(), 1);
+        let mono = hist.get(HistogramChannel::Mono).unwrap();
+        assert_eq!(mono.bins, vec![64; 4]);
+        assert_eq!(mono.total, 256);
+        assert_eq!(mono.max_value, 255.0);
+        assert_eq!(mono.bin_range(1), (64.0, 128.0));
+        assert_eq!(mono.mean(), 128.0);
+        assert_eq!(mono.percentile(0.5), 128.0);
+        assert_eq!(mono.percentile(1.0), 256.0);
+        assert_eq!(mono.peak(), 0);
+        assert_eq!((mono.clipped_black, mono.clipped_white), (1, 1));
+    }
+
+    #[test]
+    fn bayer_channels() {
+        let mut img = PixelData::new(PixelFormat::Bayer8(BayerPattern::GBRG), 4, 6);
+        let mut out = img.view_mut();
+        for y in 0..6 {
+            for x in 0..4 {
+                let val = match BayerPattern::GBRG.channel_at(x, y) {
+                    BayerChannel::Red => 10,
+                    BayerChannel::GreenR => 20,
+                    BayerChannel::GreenB => 30,
+                    BayerChannel::Blue => 255,
+                };
+                out.set_sample(x, y, 0, val as f32);
+            }
+        }
+        let hist = img.histograms(&HistogramOptions::default());
+        assert_eq!(hist.channels.len(), 4);
+        for (ch, val) in BayerChannel::ALL.into_iter().zip([10, 20, 30, 255]) {
+            let h = hist.get(HistogramChannel::Bayer(ch)).unwrap();
+            assert_eq!(h.total, 6, "{:?}", ch);
+            assert_eq!(h.bins[val], 6, "{:?}", ch);
+        }
+        assert_eq!(hist.clipped_white(), 6);
+    }
+
+    #[test]
+    fn clipping() {
+        let src = [0, 5, 10, 11, 100, 199, 200, 250];
+        let img = PixelData::new_from_slice(PixelFormat::Mono8, 8, 1, &src).unwrap();
+        let opts = HistogramOptions {
+            black_point: 10.0,
+            white_point: Some(200.0),
+            ..Default::default()
+        };
+        let mono = &img.histograms(&opts).channels[0].1;
+        assert_eq!((mono.clipped_black, mono.clipped_white), (3, 2));
+        assert_eq!(mono.black_fraction(), 3.0 / 8.0);
+        assert_eq!(mono.white_fraction(), 2.0 / 8.0);
+    }
+
+    #[test]
+    fn twelve_bit() {
+        let fmt = PixelFormat::Bayer16(BayerPattern::RGGB, 12);
+        // NOTE: Only the red samples vary (values past 12 bits are clipped)
+        let src = [0, 1, 2048, 1, 4095, 1, 5000, 1, 1, 1, 1, 1, 1, 1, 1, 1];
+        let img = PixelData::new_from_u16(fmt, 8, 2, &src).unwrap();
+
+        let hist = img.histograms(&HistogramOptions::with_bins(16));
+        let red = hist.get(HistogramChannel::Bayer(BayerChannel::Red)).unwrap();
+        assert_eq!(red.max_value, 4095.0);
+        assert_eq!(red.bin_range(8), (2048.0, 2304.0));
+        let mut bins = vec![0; 16];
+        (bins[0], bins[8], bins[15]) = (1, 1, 2);
+        assert_eq!(red.bins, bins);
+        assert_eq!((red.clipped_black, red.clipped_white), (1, 2));
+
+        // Fewer bits than the format
+        let opts = HistogramOptions { bits: Some(10), ..HistogramOptions::with_bins(4) };
+        let red = img.histograms(&opts).channels[0].1.clone();
+        assert_eq!(red.max_value, 1023.0);
+        assert_eq!(red.bins, vec![1, 0, 0, 3]);
+    }
+
+    #[test]
+    fn rgb() {
+        let src = [255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 128, 0];
+        let img = PixelData::new_from_slice(PixelFormat::RGB8, 4, 1, &src).unwrap();
+        let hist = img.histograms(&HistogramOptions::default());
+        let bins = |ch| hist.get(ch).unwrap().bins.iter().enumerate()
+            .filter(|(_, &n)| n != 0)
+            .map(|(i, &n)| (i, n))
+            .collect::<Vec<_>>();
+        assert_eq!(bins(HistogramChannel::Red), vec![(0, 2), (255, 2)]);
+        assert_eq!(bins(HistogramChannel::Green), vec![(0, 2), (128, 1), (255, 1)]);
+        assert_eq!(bins(HistogramChannel::Blue), vec![(0, 3), (255, 1)]);
+        // Rec. 709 luminance
+        assert_eq!(bins(HistogramChannel::Luminance), 
+            vec![(0, 1), (53, 1), (91, 1), (255, 1)]);
+    }
+
+    #[test]
+    fn float() {
+        let src = [0.0, 0.0, 0.0, 0.5, 0.5, 0.5, 1.0, 1.0, 1.0, 2.0, 2.0, 2.0];
+        let img = PixelData::new_from_f32(PixelFormat::RGBF32, 4, 1, &src).unwrap();
+        let hist = img.histograms(&HistogramOptions::with_bins(4));
+        for (ch, h) in &hist.channels {
+            assert_eq!(h.bins, vec![1, 0, 1, 2], "{:?}", ch);
+            assert_eq!(h.max_value, 1.0);
+            assert_eq!((h.clipped_black, h.clipped_white), (1, 2));
+        }
+    }
+}
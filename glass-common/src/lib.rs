@@ -1,39 +1,266 @@
 
+mod stats;
+pub use stats::*;
+
+mod histogram;
+pub use histogram::*;
+
+mod roi;
+pub use roi::*;
+
+mod view;
+pub use view::*;
+
+mod demosaic;
+pub use demosaic::*;
+
+mod color;
+pub use color::*;
+
+mod config;
+pub use config::*;
+
+mod tiff;
+
+mod export;
+pub use export::*;
+
+mod dng;
+pub use dng::*;
+
+/// Arrangement of the color filters over each 2x2 block of pixels.
+///
+/// Each variant names the colors in the block from left-to-right and
+/// top-to-bottom (ie. 'GRBG' has red at [1,0] and blue at [0,1]).
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum BayerPattern {
     RGGB,
+    GRBG,
+    GBRG,
     BGGR,
 }
+impl BayerPattern {
+    pub const ALL: [Self; 4] = [Self::RGGB, Self::GRBG, Self::GBRG, Self::BGGR];
+
+    /// Position [x, y] of the red pixel in each 2x2 block.
+    pub fn red_offset(&self) -> (usize, usize) {
+        match self {
+            Self::RGGB => (0, 0),
+            Self::GRBG => (1, 0),
+            Self::GBRG => (0, 1),
+            Self::BGGR => (1, 1),
+        }
+    }
+
+    /// The pattern with red at [x, y] (only the parity matters).
+    pub fn from_red_offset(x: usize, y: usize) -> Self {
+        match (x & 1, y & 1) {
+            (0, 0) => Self::RGGB,
+            (1, 0) => Self::GRBG,
+            (0, 1) => Self::GBRG,
+            _ => Self::BGGR,
+        }
+    }
+
+    /// Return the color channel for the pixel at (x, y).
+    pub fn channel_at(&self, x: usize, y: usize) -> BayerChannel {
+        use BayerChannel::*;
+        let (rx, ry) = self.red_offset();
+        match ((x ^ rx) & 1 == 1, (y ^ ry) & 1 == 1) {
+            (false, false) => Red,
+            (true,  false) => GreenR,
+            (false, true)  => GreenB,
+            (true,  true)  => Blue,
+        }
+    }
+
+    /// The pattern of an image cropped to start at (x, y).
+    pub fn shifted(&self, x: usize, y: usize) -> Self {
+        let (rx, ry) = self.red_offset();
+        Self::from_red_offset(rx ^ (x & 1), ry ^ (y & 1))
+    }
+
+    /// The pattern after mirroring an image 'width' pixels wide.
+    pub fn flipped_horizontal(&self, width: usize) -> Self {
+        let (rx, ry) = self.red_offset();
+        Self::from_red_offset(rx ^ (width & 1) ^ 1, ry)
+    }
+
+    /// The pattern after flipping an image 'height' pixels tall.
+    pub fn flipped_vertical(&self, height: usize) -> Self {
+        let (rx, ry) = self.red_offset();
+        Self::from_red_offset(rx, ry ^ (height & 1) ^ 1)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::RGGB => "RGGB",
+            Self::GRBG => "GRBG",
+            Self::GBRG => "GBRG",
+            Self::BGGR => "BGGR",
+        }
+    }
+
+    /// The pattern with some [BayerPattern::name] (ignoring case).
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// One of the four color channels in a Bayer pattern.
+///
+/// NOTE: The two green channels are distinguished by the color of the 
+/// other pixels in the same row ('GreenR' shares a row with red pixels). 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BayerChannel {
+    Red,
+    GreenR,
+    GreenB,
+    Blue,
+}
+impl BayerChannel {
+    pub const ALL: [Self; 4] = [Self::Red, Self::GreenR, Self::GreenB, Self::Blue];
+
+    /// Index of this channel in arrays ordered like [BayerChannel::ALL].
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Red => "R",
+            Self::GreenR => "Gr",
+            Self::GreenB => "Gb",
+            Self::Blue => "B",
+        }
+    }
+
+    /// The channel with some [BayerChannel::name].
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|ch| ch.name() == name)
+    }
+
+    /// Index of the color of this channel in RGB data.
+    pub fn rgb_index(&self) -> usize {
+        match self {
+            Self::Red => 0,
+            Self::GreenR | Self::GreenB => 1,
+            Self::Blue => 2,
+        }
+    }
+}
+
+/// Type of each sample in [PixelData].
+///
+/// NOTE: Multi-byte samples are always stored in little-endian order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleType {
+    U8,
+    U16,
+    F32,
+}
+impl SampleType {
+    pub fn bytes(&self) -> usize {
+        match self {
+            Self::U8 => 1,
+            Self::U16 => 2,
+            Self::F32 => 4,
+        }
+    }
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum PixelFormat {
     Bayer8(BayerPattern),
+    /// 16-bit Bayer data, with the number of significant bits (ie. 12)
+    Bayer16(BayerPattern, u8),
+    Mono8,
+    Mono16,
     RGBA8,
     RGB8,
+    RGB16,
+    /// RGB with floating-point samples (for processing intermediates)
+    RGBF32,
 }
 impl PixelFormat {
     pub fn bytes_per_pixel(&self) -> usize { 
+        self.channels() * self.sample_type().bytes()
+    }
+
+    /// Number of samples in each pixel.
+    pub fn channels(&self) -> usize {
         match self {
-            Self::RGB8  => 3,
+            Self::Bayer8(_) | Self::Bayer16(..) | Self::Mono8 | Self::Mono16 => 1,
+            Self::RGB8 | Self::RGB16 | Self::RGBF32 => 3,
             Self::RGBA8 => 4,
-            Self::Bayer8(_) => 1,
+        }
+    }
+
+    pub fn sample_type(&self) -> SampleType {
+        match self {
+            Self::Bayer8(_) | Self::Mono8 | Self::RGBA8 | Self::RGB8 => SampleType::U8,
+            Self::Bayer16(..) | Self::Mono16 | Self::RGB16 => SampleType::U16,
+            Self::RGBF32 => SampleType::F32,
+        }
+    }
+
+    /// Number of bits used in each integer sample.
+    ///
+    /// NOTE: The bits given for 'Bayer16' are clamped to 1..=16.
+    pub fn significant_bits(&self) -> Option<usize> {
+        match self {
+            Self::Bayer16(_, bits) => Some((*bits).clamp(1, 16) as usize),
+            _ => match self.sample_type() {
+                SampleType::U8 => Some(8),
+                SampleType::U16 => Some(16),
+                SampleType::F32 => None,
+            },
+        }
+    }
+
+    /// The value of a sample at full scale (1.0 for floating-point).
+    pub fn max_value(&self) -> f32 {
+        match self.significant_bits() {
+            Some(bits) => ((1u32 << bits) - 1) as f32,
+            None => 1.0,
+        }
+    }
+
+    pub fn bayer_pattern(&self) -> Option<BayerPattern> {
+        match self {
+            Self::Bayer8(p) | Self::Bayer16(p, _) => Some(*p),
+            _ => None,
+        }
+    }
+
+    /// The same format with a different Bayer pattern.
+    pub fn with_pattern(&self, pattern: BayerPattern) -> Self {
+        match self {
+            Self::Bayer8(_) => Self::Bayer8(pattern),
+            Self::Bayer16(_, bits) => Self::Bayer16(pattern, *bits),
+            fmt => *fmt,
         }
     }
 }
 
 /// Container for image data
+///
+/// NOTE: Rows are 'stride' bytes apart, which may include some padding
+/// after the pixels. Use [PixelData::view] to work on a part of the image
+/// without copying it.
 pub struct PixelData { 
     pub data: Box<[u8]>,
     pub width: usize,
     pub height: usize,
+    pub stride: usize,
     pub format: PixelFormat,
     pub id: usize,
 }
 impl PixelData {
     pub fn new(fmt: PixelFormat, width: usize, height: usize) -> Self { 
-        let bpp = fmt.bytes_per_pixel();
-        let data = vec![0u8; width * height * bpp].into_boxed_slice();
-        Self { width, height, data, format: fmt, id: 0 }
+        let stride = width * fmt.bytes_per_pixel();
+        let data = vec![0u8; stride * height].into_boxed_slice();
+        Self { width, height, stride, data, format: fmt, id: 0 }
     }
 
     pub fn increment_frame_id(&mut self) {
@@ -72,15 +299,21 @@ impl PixelData {
     }
 
 
+    /// Copy tightly-packed rows from 'src' (whatever our stride is).
     pub fn fill_from_slice(&mut self, src: &[u8]) -> Result<(), &'static str> {
-        if src.len() != self.size_bytes() {
+        let row = self.width * self.format.bytes_per_pixel();
+        if src.len() != row * self.height {
             println!("mismatch source size {} and pixeldata size {}", 
-                src.len(), self.size_bytes()
+                src.len(), row * self.height
             );
             return Err("Source slice doesn't match PixelData size");
         }
-        self.data.copy_from_slice(src);
-        Ok(())
+        if self.stride == row {
+            self.data.copy_from_slice(src);
+            return Ok(());
+        }
+        let view = PixelView::new(src, self.format, self.width, self.height, row)?;
+        self.view_mut().copy_from(&view)
     }
 
     pub fn width(&self) -> usize { 
@@ -106,7 +339,253 @@ impl PixelData {
     pub fn as_mut_slice(&mut self) -> &mut [u8] {
         &mut self.data
     }
+
+    /// Number of samples (pixels times channels).
+    pub fn sample_count(&self) -> usize {
+        self.width * self.height * self.format.channels()
+    }
+
+    /// Position (x, y, channel) of sample 'idx' (counting row by row).
+    fn sample_position(&self, idx: usize) -> (usize, usize, usize) {
+        let channels = self.format.channels();
+        let pixel = idx / channels;
+        (pixel % self.width, pixel / self.width, idx % channels)
+    }
+
+    pub fn new_from_u16(
+        fmt: PixelFormat,
+        width: usize,
+        height: usize,
+        src: &[u16]
+    ) -> Result<Self, &'static str>
+    {
+        let mut res = Self::new(fmt, width, height);
+        if fmt.sample_type() != SampleType::U16 {
+            return Err("Format doesn't have 16-bit samples");
+        }
+        if src.len() != res.sample_count() {
+            return Err("Source slice doesn't match PixelData size");
+        }
+        for (dst, val) in res.data.chunks_exact_mut(2).zip(src) {
+            dst.copy_from_slice(&val.to_le_bytes());
+        }
+        Ok(res)
+    }
+
+    pub fn new_from_f32(
+        fmt: PixelFormat,
+        width: usize,
+        height: usize,
+        src: &[f32]
+    ) -> Result<Self, &'static str>
+    {
+        let mut res = Self::new(fmt, width, height);
+        if fmt.sample_type() != SampleType::F32 {
+            return Err("Format doesn't have floating-point samples");
+        }
+        if src.len() != res.sample_count() {
+            return Err("Source slice doesn't match PixelData size");
+        }
+        for (dst, val) in res.data.chunks_exact_mut(4).zip(src) {
+            dst.copy_from_slice(&val.to_le_bytes());
+        }
+        Ok(res)
+    }
+
+    /// Iterate over 16-bit samples.
+    pub fn u16_samples(&self) -> Result<impl Iterator<Item = u16> + '_, &'static str> {
+        if self.format.sample_type() != SampleType::U16 {
+            return Err("Format doesn't have 16-bit samples");
+        }
+        Ok(self.view().samples().map(|val| val as u16))
+    }
+
+    /// Iterate over floating-point samples.
+    pub fn f32_samples(&self) -> Result<impl Iterator<Item = f32> + '_, &'static str> {
+        if self.format.sample_type() != SampleType::F32 {
+            return Err("Format doesn't have floating-point samples");
+        }
+        Ok(self.view().samples())
+    }
+
+    pub fn to_u16_vec(&self) -> Result<Vec<u16>, &'static str> {
+        Ok(self.u16_samples()?.collect())
+    }
+
+    pub fn to_f32_vec(&self) -> Result<Vec<f32>, &'static str> {
+        Ok(self.f32_samples()?.collect())
+    }
+
+    /// Value of sample 'idx' (counting row by row) in any format.
+    pub fn sample(&self, idx: usize) -> f32 {
+        let (x, y, c) = self.sample_position(idx);
+        self.view().sample(x, y, c)
+    }
+
+    /// Value of sample 'idx', scaled so that full scale is 1.0.
+    pub fn normalized(&self, idx: usize) -> f32 {
+        self.sample(idx) / self.format.max_value()
+    }
+
+    /// Set sample 'idx' in any format (rounding and clamping as necessary).
+    pub fn set_sample(&mut self, idx: usize, val: f32) {
+        let (x, y, c) = self.sample_position(idx);
+        self.view_mut().set_sample(x, y, c, val);
+    }
+
+    /// Convert to another format with the same channels, rescaling samples
+    /// (ie. from 'Bayer16' to 'Bayer8' for display).
+    pub fn convert(&self, fmt: PixelFormat) -> Result<Self, &'static str> {
+        if self.format.channels() != fmt.channels()
+            || self.format.bayer_pattern() != fmt.bayer_pattern()
+        {
+            return Err("Formats have different channels");
+        }
+        let scale = fmt.max_value() / self.format.max_value();
+        let mut res = Self::new(fmt, self.width, self.height);
+        let (src, mut dst) = (self.view(), res.view_mut());
+        for y in 0..self.height {
+            for x in 0..self.width {
+                for c in 0..fmt.channels() {
+                    dst.set_sample(x, y, c, src.sample(x, y, c) * scale);
+                }
+            }
+        }
+        res.id = self.id;
+        Ok(res)
+    }
+
+    /// Copy a rectangle starting at (x, y) into a new image.
+    ///
+    /// NOTE: The Bayer pattern of the result is shifted when (x, y) is odd.
+    pub fn crop(&self, x: usize, y: usize, width: usize, height: usize)
+        -> Result<Self, &'static str>
+    {
+        let mut res = self.view().sub(x, y, width, height)?.to_pixel_data();
+        res.id = self.id;
+        Ok(res)
+    }
+
+    /// Mirror the image left-to-right (updating the Bayer pattern).
+    pub fn flip_horizontal(&mut self) {
+        let bpp = self.format.bytes_per_pixel();
+        for row in self.data.chunks_mut(self.stride).take(self.height) {
+            for x in 0..self.width / 2 {
+                let (a, b) = (x * bpp, (self.width - 1 - x) * bpp);
+                for i in 0..bpp {
+                    row.swap(a + i, b + i);
+                }
+            }
+        }
+        if let Some(p) = self.format.bayer_pattern() {
+            self.format = self.format.with_pattern(p.flipped_horizontal(self.width));
+        }
+    }
+
+    /// Flip the image top-to-bottom (updating the Bayer pattern).
+    pub fn flip_vertical(&mut self) {
+        let (stride, row) = (self.stride, self.width * self.format.bytes_per_pixel());
+        for y in 0..self.height / 2 {
+            let (top, bottom) = self.data.split_at_mut((self.height - 1 - y) * stride);
+            top[y * stride..y * stride + row].swap_with_slice(&mut bottom[..row]);
+        }
+        if let Some(p) = self.format.bayer_pattern() {
+            self.format = self.format.with_pattern(p.flipped_vertical(self.height));
+        }
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An image with each pixel set to the index of its Bayer channel.
+    fn channels(fmt: PixelFormat, width: usize, height: usize) -> PixelData {
+        let pattern = fmt.bayer_pattern().unwrap();
+        let mut res = PixelData::new(fmt, width, height);
+        let mut out = res.view_mut();
+        for y in 0..height {
+            for x in 0..width {
+                out.set_sample(x, y, 0, pattern.channel_at(x, y).index() as f32);
+            }
+        }
+        res
+    }
+
+    /// Check that the image's pattern matches its pixels.
+    fn check(img: &PixelData, what: &str) {
+        let pattern = img.format.bayer_pattern().unwrap();
+        let view = img.view();
+        for y in 0..img.height() {
+            for x in 0..img.width() {
+                let c = pattern.channel_at(x, y).index() as f32;
+                assert_eq!(view.sample(x, y, 0), c, "{} at ({}, {})", what, x, y);
+            }
+        }
+    }
+
+    const FORMATS: [fn(BayerPattern) -> PixelFormat; 2] = [
+        PixelFormat::Bayer8,
+        |p| PixelFormat::Bayer16(p, 12),
+    ];
 
+    #[test]
+    fn significant_bits() {
+        let p = BayerPattern::RGGB;
+        assert_eq!(PixelFormat::Bayer8(p).max_value(), 255.0);
+        assert_eq!(PixelFormat::Bayer16(p, 12).max_value(), 4095.0);
+        assert_eq!(PixelFormat::Mono16.max_value(), 65535.0);
+        assert_eq!(PixelFormat::RGBF32.max_value(), 1.0);
+
+        // Out of range bit counts are clamped
+        assert_eq!(PixelFormat::Bayer16(p, 0).significant_bits(), Some(1));
+        assert_eq!(PixelFormat::Bayer16(p, 0).max_value(), 1.0);
+        assert_eq!(PixelFormat::Bayer16(p, 32).significant_bits(), Some(16));
+        assert_eq!(PixelFormat::Bayer16(p, 255).max_value(), 65535.0);
+    }
 
+    #[test]
+    fn shifted() {
+        for pattern in BayerPattern::ALL {
+            for fmt in FORMATS.map(|f| f(pattern)) {
+                let img = channels(fmt, 7, 5);
+                for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1), (2, 3), (3, 2)] {
+                    let crop = img.crop(x, y, 4, 2).unwrap();
+                    let expected = fmt.with_pattern(pattern.shifted(x, y));
+                    assert_eq!(crop.format, expected);
+                    check(&crop, &format!("{:?} cropped at ({}, {})", fmt, x, y));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn flipped() {
+        for pattern in BayerPattern::ALL {
+            for fmt in FORMATS.map(|f| f(pattern)) {
+                for (width, height) in [(4, 4), (5, 4), (4, 5), (5, 3)] {
+                    let img = channels(fmt, width, height);
+                    let what = format!("{:?} {}x{}", fmt, width, height);
+
+                    let mut h = channels(fmt, width, height);
+                    h.flip_horizontal();
+                    assert_eq!(h.format.bayer_pattern(), 
+                        Some(pattern.flipped_horizontal(width)));
+                    check(&h, &format!("{} flipped horizontally", what));
+
+                    let mut v = channels(fmt, width, height);
+                    v.flip_vertical();
+                    assert_eq!(v.format.bayer_pattern(), 
+                        Some(pattern.flipped_vertical(height)));
+                    check(&v, &format!("{} flipped vertically", what));
+
+                    // Flipping twice is a no-op
+                    h.flip_horizontal();
+                    v.flip_vertical();
+                    assert_eq!((h.format, v.format), (fmt, fmt));
+                    assert_eq!((&h.data, &v.data), (&img.data, &img.data));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! Statistics over a region of interest.
+//!
+//! Each channel is measured separately: for Bayer data, that means each
+//! [BayerChannel] plane (so noise and uniformity can be measured straight
+//! from the sensor without demosaicing).
+
+use crate::*;
+
+/// A region of interest within some pixels.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Region {
+    /// Every pixel
+    Full,
+    /// A rectangle starting at (x, y)
+    Rect { x: usize, y: usize, width: usize, height: usize },
+    /// A polygon with vertices in pixel coordinates, where (0, 0) is the
+    /// top-left corner of the first pixel. A pixel is inside when its
+    /// center is inside (using the even-odd rule).
+    Polygon(Vec<(f64, f64)>),
+}
+impl Region {
+    /// Spans [x0, x1) of pixels in row 'y' that are inside the region.
+    fn spans(&self, y: usize, width: usize, height: usize) -> Vec<(usize, usize)> {
+        match self {
+            Self::Full => vec![(0, width)],
+            Self::Rect { x, y: ry, width: w, height: h } => {
+                if y < *ry || y >= ry + h || *x >= width {
+                    return Vec::new();
+                }
+                vec![(*x, (x + w).min(width))]
+            },
+            Self::Polygon(points) => {
+                if y >= height || points.len() < 3 {
+                    return Vec::new();
+                }
+                let cy = y as f64 + 0.5;
+                let mut xs: Vec<f64> = points.iter()
+                    .zip(points.iter().cycle().skip(1))
+                    .filter(|((_, y0), (_, y1))| (*y0 <= cy) != (*y1 <= cy))
+                    .map(|((x0, y0), (x1, y1))| x0 + (cy - y0) * (x1 - x0) / (y1 - y0))
+                    .collect();
+                xs.sort_by(|a, b| a.total_cmp(b));
+                // Pixels with centers in [xa, xb)
+                let col = |x: f64| (x - 0.5).ceil().clamp(0.0, width as f64) as usize;
+                xs.chunks_exact(2)
+                    .map(|pair| (col(pair[0]), col(pair[1])))
+                    .filter(|(x0, x1)| x0 < x1)
+                    .collect()
+            },
+        }
+    }
+}
+
+/// Statistics for one channel over a [Region].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RoiStats {
+    pub count: usize,
+    pub mean: f64,
+    pub std_dev: f64,
+    pub min: f64,
+    pub max: f64,
+    pub median: f64,
+    /// Number of samples at the largest value for the format
+    pub saturated: usize,
+}
+impl RoiStats {
+    /// Signal-to-noise ratio (mean over standard deviation).
+    pub fn snr(&self) -> f64 {
+        self.mean / self.std_dev
+    }
+
+    /// Signal-to-noise ratio [in decibels].
+    pub fn snr_db(&self) -> f64 {
+        20.0 * self.snr().log10()
+    }
+
+    /// Fraction of samples that are saturated.
+    pub fn saturated_fraction(&self) -> f64 {
+        self.saturated as f64 / self.count.max(1) as f64
+    }
+
+    fn from_values(values: &mut [f32], saturation: f32) -> Self {
+        if values.is_empty() {
+            return Self::default();
+        }
+        let mut acc = Accumulator::default();
+        let (mut min, mut max) = (f32::INFINITY, f32::NEG_INFINITY);
+        let mut saturated = 0;
+        for &v in values.iter() {
+            acc.push(v as f64);
+            min = min.min(v);
+            max = max.max(v);
+            if v >= saturation {
+                saturated += 1;
+            }
+        }
+        let stats = acc.finish();
+        // NOTE: With an even count, this is the mean of the middle pair
+        let (mid, even) = (values.len() / 2, values.len().is_multiple_of(2));
+        let (lower, &mut upper, _) = values.select_nth_unstable_by(mid, |a, b| a.total_cmp(b));
+        let median = match even {
+            true => (lower.iter().copied().fold(f32::NEG_INFINITY, f32::max) + upper) as f64 / 2.0,
+            false => upper as f64,
+        };
+        Self {
+            count: stats.count,
+            mean: stats.mean,
+            std_dev: stats.std_dev(),
+            min: min as f64,
+            max: max as f64,
+            median,
+            saturated,
+        }
+    }
+}
+
+/// Compute [RoiStats] for each channel of the pixels in a region.
+///
+/// Channels are labeled like [histograms] (without luminance, and alpha is
+/// ignored).
+pub fn roi_stats(view: &PixelView, region: &Region)
+    -> Result<Vec<(HistogramChannel, RoiStats)>, &'static str>
+{
+    use HistogramChannel::*;
+    let fmt = view.format();
+    let channels: Vec<HistogramChannel> = match fmt {
+        PixelFormat::Bayer8(_) | PixelFormat::Bayer16(..) => {
+            BayerChannel::ALL.iter().map(|&c| Bayer(c)).collect()
+        },
+        PixelFormat::Mono8 | PixelFormat::Mono16 => vec![Mono],
+        _ => vec![Red, Green, Blue],
+    };
+    let mut values = vec![Vec::new(); channels.len()];
+    for y in 0..view.height() {
+        for (x0, x1) in region.spans(y, view.width(), view.height()) {
+            for x in x0..x1 {
+                match fmt.bayer_pattern() {
+                    Some(p) => values[p.channel_at(x, y).index()].push(view.sample(x, y, 0)),
+                    None => for (c, vals) in values.iter_mut().enumerate() {
+                        vals.push(view.sample(x, y, c));
+                    },
+                }
+            }
+        }
+    }
+    if values.iter().all(|v| v.is_empty()) {
+        return Err("Region doesn't contain any pixels");
+    }
+    let saturation = view.max_value();
+    Ok(channels.into_iter().zip(values.iter_mut())
+        .map(|(c, vals)| (c, RoiStats::from_values(vals, saturation)))
+        .collect())
+}
+
+impl PixelView<'_> {
+    /// Compute [RoiStats] for each channel (see [roi_stats]).
+    pub fn roi_stats(&self, region: &Region)
+        -> Result<Vec<(HistogramChannel, RoiStats)>, &'static str>
+    {
+        roi_stats(self, region)
+    }
+}
+
+impl PixelData {
+    /// Compute [RoiStats] for each channel (see [roi_stats]).
+    pub fn roi_stats(&self, region: &Region)
+        -> Result<Vec<(HistogramChannel, RoiStats)>, &'static str>
+    {
+        roi_stats(&self.view(), region)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pixels inside a region of a 'width' by 'height' image.
+    fn inside(region: &Region, width: usize, height: usize) -> Vec<(usize, usize)> {
+        (0..height).flat_map(|y| {
+            region.spans(y, width, height).into_iter()
+                .flat_map(move |(x0, x1)| (x0..x1).map(move |x| (x, y)))
+        }).collect()
+    }
+
+    #[test]
+    fn rect() {
+        assert_eq!(inside(&Region::Full, 2, 2), vec![(0, 0), (1, 0), (0, 1), (1, 1)]);
+        let rect = Region::Rect { x: 1, y: 2, width: 2, height: 1 };
+        assert_eq!(inside(&rect, 4, 4), vec![(1, 2), (2, 2)]);
+
+        // Clipped to the image
+        let rect = Region::Rect { x: 2, y: 3, width: 5, height: 5 };
+        assert_eq!(inside(&rect, 4, 4), vec![(2, 3), (3, 3)]);
+        let rect = Region::Rect { x: 4, y: 0, width: 2, height: 2 };
+        assert_eq!(inside(&rect, 4, 4), vec![]);
+    }
+
+    #[test]
+    fn polygon() {
+        // The same pixels as a rectangle
+        let square = Region::Polygon(vec![(1.0, 1.0), (3.0, 1.0), (3.0, 3.0), (1.0, 3.0)]);
+        assert_eq!(inside(&square, 4, 4), vec![(1, 1), (2, 1), (1, 2), (2, 2)]);
+
+        // Only pixels with their centers inside (the centers on the
+        // diagonal edge are outside)
+        let tri = Region::Polygon(vec![(0.0, 0.0), (4.0, 0.0), (0.0, 4.0)]);
+        assert_eq!(inside(&tri, 4, 4), vec![
+            (0, 0), (1, 0), (2, 0),
+            (0, 1), (1, 1),
+            (0, 2),
+        ]);
+
+        // Even-odd rule: a square with a hole in it
+        let ring = Region::Polygon(vec![
+            (0.0, 0.0), (3.0, 0.0), (3.0, 3.0), (0.0, 3.0), (0.0, 0.0),
+            (1.0, 1.0), (2.0, 1.0), (2.0, 2.0), (1.0, 2.0), (1.0, 1.0),
+        ]);
+        let pixels = inside(&ring, 3, 3);
+        assert_eq!(pixels.len(), 8);
+        assert!(!pixels.contains(&(1, 1)));
+
+        // Clipped to the image, and degenerate polygons are empty
+        let big = Region::Polygon(vec![(-5.0, -5.0), (9.0, -5.0), (9.0, 9.0), (-5.0, 9.0)]);
+        assert_eq!(inside(&big, 3, 2).len(), 6);
+        let line = Region::Polygon(vec![(0.0, 0.0), (3.0, 3.0)]);
+        assert_eq!(inside(&line, 4, 4), vec![]);
+    }
+
+    #[test]
+    fn stats() {
+        let src = [1, 2, 3, 4, 10, 255, 6, 100];
+        let img = PixelData::new_from_slice(PixelFormat::Mono8, 4, 2, &src).unwrap();
+
+        // Odd count: the middle value
+        let rect = Region::Rect { x: 0, y: 0, width: 3, height: 1 };
+        let (ch, s) = img.roi_stats(&rect).unwrap()[0];
+        assert_eq!(ch, HistogramChannel::Mono);
+        assert_eq!((s.count, s.min, s.max, s.median), (3, 1.0, 3.0, 2.0));
+        assert_eq!(s.mean, 2.0);
+        assert!((s.std_dev - (2.0f64 / 3.0).sqrt()).abs() < 1e-9);
+
+        // Even count: the mean of the middle pair
+        let (_, s) = img.roi_stats(&Region::Full).unwrap()[0];
+        assert_eq!(s.count, 8);
+        assert_eq!(s.median, 5.0);
+        assert_eq!(s.saturated, 1);
+        assert_eq!(s.saturated_fraction(), 1.0 / 8.0);
+
+        let empty = Region::Rect { x: 0, y: 2, width: 4, height: 1 };
+        assert!(img.roi_stats(&empty).is_err());
+    }
+
+    #[test]
+    fn channels() {
+        // Each Bayer channel is measured separately
+        let fmt = PixelFormat::Bayer16(BayerPattern::RGGB, 12);
+        let src = [4095, 100, 4000, 101, 200, 300, 201, 301];
+        let img = PixelData::new_from_u16(fmt, 4, 2, &src).unwrap();
+        let stats = img.roi_stats(&Region::Full).unwrap();
+        let expected = [
+            (BayerChannel::Red, 4047.5, 1),
+            (BayerChannel::GreenR, 100.5, 0),
+            (BayerChannel::GreenB, 200.5, 0),
+            (BayerChannel::Blue, 300.5, 0),
+        ];
+        for ((ch, s), (bayer, median, saturated)) in stats.iter().zip(expected) {
+            assert_eq!(*ch, HistogramChannel::Bayer(bayer));
+            assert_eq!(s.count, 2);
+            assert_eq!(s.median, median, "{:?}", bayer);
+            assert_eq!(s.saturated, saturated, "{:?}", bayer);
+        }
+
+        // RGB gets a channel for each color (and saturates at 255)
+        let src = [255, 0, 10, 255, 2, 20];
+        let img = PixelData::new_from_slice(PixelFormat::RGB8, 2, 1, &src).unwrap();
+        let stats = img.roi_stats(&Region::Full).unwrap();
+        let channels: Vec<_> = stats.iter().map(|(c, _)| *c).collect();
+        assert_eq!(channels, vec![HistogramChannel::Red, HistogramChannel::Green,
+            HistogramChannel::Blue]);
+        assert_eq!(stats[0].1.saturated, 2);
+        assert_eq!(stats[1].1.median, 1.0);
+        assert_eq!(stats[2].1.mean, 15.0);
+    }
+}
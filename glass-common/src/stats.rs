@@ -0,0 +1,193 @@
+//! Simple statistics over raw sensor data.
+
+use crate::*;
+
+/// Mean and variance of a set of pixel values.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChannelStats {
+    pub count: usize,
+    pub mean: f64,
+    pub variance: f64,
+}
+impl ChannelStats {
+    pub fn std_dev(&self) -> f64 {
+        self.variance.sqrt()
+    }
+}
+
+/// Accumulates [ChannelStats] (without keeping the values around).
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Accumulator {
+    count: usize,
+    sum: f64,
+    sum_sq: f64,
+}
+impl Accumulator {
+    pub(crate) fn push(&mut self, val: f64) {
+        self.count += 1;
+        self.sum += val;
+        self.sum_sq += val * val;
+    }
+    pub(crate) fn finish(&self) -> ChannelStats {
+        if self.count == 0 {
+            return ChannelStats::default();
+        }
+        let n = self.count as f64;
+        let mean = self.sum / n;
+        ChannelStats {
+            count: self.count,
+            mean,
+            variance: (self.sum_sq / n - mean * mean).max(0.0),
+        }
+    }
+}
+
+/// Format of 8-bit ('bpp == 1') or 16-bit ('bpp == 2') Bayer data.
+fn bayer_format(pattern: BayerPattern, bpp: usize) -> Result<PixelFormat, &'static str> {
+    match bpp {
+        1 => Ok(PixelFormat::Bayer8(pattern)),
+        2 => Ok(PixelFormat::Bayer16(pattern, 16)),
+        _ => Err("Unsupported sample size"),
+    }
+}
+
+/// View tightly-packed Bayer data.
+fn bayer_view(data: &[u8], width: usize, height: usize, pattern: BayerPattern,
+    bpp: usize) -> Result<PixelView<'_>, &'static str>
+{
+    let fmt = bayer_format(pattern, bpp)?;
+    if data.len() != width * height * bpp {
+        return Err("Data doesn't match the dimensions");
+    }
+    PixelView::new(data, fmt, width, height, width * bpp)
+}
+
+/// Compute [ChannelStats] for each channel of 8-bit Bayer data. 
+///
+/// The results are ordered like [BayerChannel::ALL].
+pub fn bayer_stats(data: &[u8], width: usize, height: usize, 
+    pattern: BayerPattern) -> Result<[ChannelStats; 4], &'static str>
+{
+    bayer_stats_bpp(data, width, height, pattern, 1)
+}
+
+/// Like [bayer_stats], but for 8-bit ('bpp == 1') or 16-bit little-endian 
+/// ('bpp == 2') samples.
+pub fn bayer_stats_bpp(data: &[u8], width: usize, height: usize, 
+    pattern: BayerPattern, bpp: usize) -> Result<[ChannelStats; 4], &'static str>
+{
+    bayer_stats_view(&bayer_view(data, width, height, pattern, bpp)?)
+}
+
+/// Like [bayer_stats], for a view of Bayer data in any format.
+pub fn bayer_stats_view(view: &PixelView) -> Result<[ChannelStats; 4], &'static str> {
+    let pattern = view.format().bayer_pattern().ok_or("Not Bayer data")?;
+    bayer_stats_with(view.width(), view.height(), pattern, |x, y| {
+        view.sample(x, y, 0) as f64
+    })
+}
+
+/// Compute [ChannelStats] for each channel of the per-pixel difference 
+/// between two frames of 8-bit Bayer data (ie. 'a - b'). 
+///
+/// This is useful for measuring temporal noise: fixed-pattern noise is 
+/// the same in both frames, and cancels out. 
+pub fn bayer_diff_stats(a: &[u8], b: &[u8], width: usize, height: usize, 
+    pattern: BayerPattern) -> Result<[ChannelStats; 4], &'static str>
+{
+    bayer_diff_stats_bpp(a, b, width, height, pattern, 1)
+}
+
+/// Like [bayer_diff_stats], but for 8-bit or 16-bit samples
+/// (see [bayer_stats_bpp]).
+pub fn bayer_diff_stats_bpp(a: &[u8], b: &[u8], width: usize, height: usize, 
+    pattern: BayerPattern, bpp: usize) -> Result<[ChannelStats; 4], &'static str>
+{
+    if a.len() != b.len() {
+        return Err("Frames have different sizes");
+    }
+    bayer_diff_stats_view(
+        &bayer_view(a, width, height, pattern, bpp)?,
+        &bayer_view(b, width, height, pattern, bpp)?,
+    )
+}
+
+/// Like [bayer_diff_stats], for views of Bayer data in any format.
+pub fn bayer_diff_stats_view(a: &PixelView, b: &PixelView)
+    -> Result<[ChannelStats; 4], &'static str>
+{
+    let pattern = a.format().bayer_pattern().ok_or("Not Bayer data")?;
+    if a.width() != b.width() || a.height() != b.height() {
+        return Err("Frames have different sizes");
+    }
+    if b.format().bayer_pattern() != Some(pattern) {
+        return Err("Frames have different Bayer patterns");
+    }
+    bayer_stats_with(a.width(), a.height(), pattern, |x, y| {
+        a.sample(x, y, 0) as f64 - b.sample(x, y, 0) as f64
+    })
+}
+
+fn bayer_stats_with<F>(width: usize, height: usize, pattern: BayerPattern,
+    mut value: F) -> Result<[ChannelStats; 4], &'static str>
+    where F: FnMut(usize, usize) -> f64
+{
+    let mut acc = [Accumulator::default(); 4];
+    for y in 0..height {
+        for x in 0..width {
+            let ch = pattern.channel_at(x, y);
+            acc[ch.index()].push(value(x, y));
+        }
+    }
+    Ok(acc.map(|a| a.finish()))
+}
+
+impl PixelData {
+    /// Compute [ChannelStats] for each channel (see [bayer_stats]).
+    pub fn bayer_stats(&self) -> Result<[ChannelStats; 4], &'static str> {
+        bayer_stats_view(&self.view())
+    }
+}
+
+/// A least-squares fit of 'y = slope * x + intercept'.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinearFit {
+    pub slope: f64,
+    pub intercept: f64,
+    /// Coefficient of determination
+    pub r_squared: f64,
+    /// Number of points used for the fit
+    pub count: usize,
+}
+impl LinearFit {
+    /// Fit a line through some points. 
+    ///
+    /// Returns 'None' if there are less than two distinct values of 'x'.
+    pub fn new(points: &[(f64, f64)]) -> Option<Self> {
+        let n = points.len() as f64;
+        if points.len() < 2 {
+            return None;
+        }
+        let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+        let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+        let sxx = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum::<f64>();
+        let sxy = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum::<f64>();
+        let syy = points.iter().map(|p| (p.1 - mean_y).powi(2)).sum::<f64>();
+        if sxx == 0.0 {
+            return None;
+        }
+        let slope = sxy / sxx;
+        let intercept = mean_y - slope * mean_x;
+        let r_squared = if syy == 0.0 { 1.0 } else { (sxy * sxy) / (sxx * syy) };
+        Some(Self { slope, intercept, r_squared, count: points.len() })
+    }
+
+    pub fn eval(&self, x: f64) -> f64 {
+        self.slope * x + self.intercept
+    }
+
+    /// The value of 'x' where the line crosses zero.
+    pub fn x_intercept(&self) -> f64 {
+        -self.intercept / self.slope
+    }
+}
@@ -0,0 +1,333 @@
+//! A minimal (little-endian) TIFF writer, shared by the TIFF and DNG
+//! exporters.
+//!
+//! Pixel data is appended to the file first, and then each IFD is written
+//! after it (so that strip offsets are known when the IFD is written).
+
+use std::collections::BTreeMap;
+
+/// The value of a TIFF tag.
+#[derive(Clone, Debug)]
+pub(crate) enum TiffValue {
+    Byte(Vec<u8>),
+    Ascii(String),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    Rational(Vec<(u32, u32)>),
+    Undefined(Vec<u8>),
+    SRational(Vec<(i32, i32)>),
+}
+impl TiffValue {
+    fn type_code(&self) -> u16 {
+        match self {
+            Self::Byte(_) => 1,
+            Self::Ascii(_) => 2,
+            Self::Short(_) => 3,
+            Self::Long(_) => 4,
+            Self::Rational(_) => 5,
+            Self::Undefined(_) => 7,
+            Self::SRational(_) => 10,
+        }
+    }
+
+    /// Number of values, and the values in little-endian order.
+    fn encode(&self) -> (u32, Vec<u8>) {
+        let mut res = Vec::new();
+        let count = match self {
+            Self::Byte(v) | Self::Undefined(v) => {
+                res.extend_from_slice(v);
+                v.len()
+            },
+            Self::Ascii(s) => {
+                // NOTE: Includes the terminating NUL
+                res.extend_from_slice(s.as_bytes());
+                res.push(0);
+                res.len()
+            },
+            Self::Short(v) => {
+                v.iter().for_each(|x| res.extend_from_slice(&x.to_le_bytes()));
+                v.len()
+            },
+            Self::Long(v) => {
+                v.iter().for_each(|x| res.extend_from_slice(&x.to_le_bytes()));
+                v.len()
+            },
+            Self::Rational(v) => {
+                for (n, d) in v {
+                    res.extend_from_slice(&n.to_le_bytes());
+                    res.extend_from_slice(&d.to_le_bytes());
+                }
+                v.len()
+            },
+            Self::SRational(v) => {
+                for (n, d) in v {
+                    res.extend_from_slice(&n.to_le_bytes());
+                    res.extend_from_slice(&d.to_le_bytes());
+                }
+                v.len()
+            },
+        };
+        (count as u32, res)
+    }
+
+    /// An unsigned rational approximating 'val'.
+    pub(crate) fn rational(val: f64) -> (u32, u32) {
+        let den = 1_000_000u32;
+        ((val.max(0.0) * den as f64).round().min(u32::MAX as f64) as u32, den)
+    }
+
+    /// A signed rational approximating 'val'.
+    pub(crate) fn srational(val: f64) -> (i32, i32) {
+        let den = 10_000i32;
+        ((val * den as f64).round().clamp(i32::MIN as f64, i32::MAX as f64) as i32, den)
+    }
+}
+
+/// An image file directory (a set of tags).
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Ifd {
+    entries: BTreeMap<u16, TiffValue>,
+}
+impl Ifd {
+    pub(crate) fn set(&mut self, tag: u16, val: TiffValue) {
+        self.entries.insert(tag, val);
+    }
+}
+
+/// A TIFF file under construction.
+pub(crate) struct TiffWriter {
+    buf: Vec<u8>,
+}
+impl TiffWriter {
+    pub(crate) fn new() -> Self {
+        // Little-endian, magic number, and a placeholder for the offset of
+        // the first IFD.
+        Self { buf: vec![b'I', b'I', 42, 0, 0, 0, 0, 0] }
+    }
+
+    fn align(&mut self) {
+        if self.buf.len() % 2 == 1 {
+            self.buf.push(0);
+        }
+    }
+
+    /// Append some data (ie. a strip of pixels), returning its offset.
+    pub(crate) fn append(&mut self, data: &[u8]) -> u32 {
+        self.align();
+        let off = self.buf.len() as u32;
+        self.buf.extend_from_slice(data);
+        off
+    }
+
+    /// Append an IFD, returning its offset (for use in a pointer tag).
+    pub(crate) fn write_ifd(&mut self, ifd: &Ifd) -> u32 {
+        self.align();
+        let off = self.buf.len();
+        let n = ifd.entries.len();
+        // Values that don't fit in an entry go after the directory
+        let mut extra_off = off + 2 + n * 12 + 4;
+        let mut extra = Vec::new();
+        self.buf.extend_from_slice(&(n as u16).to_le_bytes());
+        for (tag, val) in ifd.entries.iter() {
+            let (count, mut bytes) = val.encode();
+            self.buf.extend_from_slice(&tag.to_le_bytes());
+            self.buf.extend_from_slice(&val.type_code().to_le_bytes());
+            self.buf.extend_from_slice(&count.to_le_bytes());
+            if bytes.len() <= 4 {
+                bytes.resize(4, 0);
+                self.buf.extend_from_slice(&bytes);
+            } else {
+                self.buf.extend_from_slice(&(extra_off as u32).to_le_bytes());
+                if bytes.len() % 2 == 1 {
+                    bytes.push(0);
+                }
+                extra_off += bytes.len();
+                extra.extend_from_slice(&bytes);
+            }
+        }
+        // No next IFD
+        self.buf.extend_from_slice(&0u32.to_le_bytes());
+        self.buf.extend_from_slice(&extra);
+        off as u32
+    }
+
+    /// Write 'ifd' as the first IFD, and return the whole file.
+    pub(crate) fn finish(mut self, ifd: &Ifd) -> Vec<u8> {
+        let off = self.write_ifd(ifd);
+        self.buf[4..8].copy_from_slice(&off.to_le_bytes());
+        self.buf
+    }
+}
+
+/// A UTC date and time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct DateTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+impl DateTime {
+    pub(crate) fn from_system_time(t: std::time::SystemTime) -> Self {
+        let secs = t.duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs()).unwrap_or(0);
+        // See Howard Hinnant's 'civil_from_days'
+        let z = (secs / 86400) as i64 + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        let rem = secs % 86400;
+        Self {
+            year, month, day,
+            hour: (rem / 3600) as u32,
+            minute: (rem / 60 % 60) as u32,
+            second: (rem % 60) as u32,
+        }
+    }
+
+    /// Format used by TIFF and EXIF ('YYYY:MM:DD HH:MM:SS').
+    pub(crate) fn to_tiff(self) -> String {
+        format!("{:04}:{:02}:{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+
+    /// ISO 8601 format.
+    pub(crate) fn to_iso8601(self) -> String {
+        format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// An IFD read back from a file: the type, count and value bytes of 
+    /// each tag.
+    pub(crate) struct ParsedIfd {
+        pub entries: BTreeMap<u16, (u16, u32, Vec<u8>)>,
+        pub next: u32,
+    }
+    impl ParsedIfd {
+        /// Read the IFD at 'off', checking that every value is in bounds.
+        pub(crate) fn read(file: &[u8], off: usize) -> Self {
+            let u16_at = |i: usize| u16::from_le_bytes([file[i], file[i + 1]]);
+            let u32_at = |i: usize| u32::from_le_bytes(file[i..i + 4].try_into().unwrap());
+            assert_eq!(off % 2, 0, "IFD isn't word-aligned");
+            let n = u16_at(off) as usize;
+            let mut entries = BTreeMap::new();
+            let mut prev = None;
+            for i in 0..n {
+                let e = off + 2 + i * 12;
+                let (tag, ty, count) = (u16_at(e), u16_at(e + 2), u32_at(e + 4));
+                assert!(prev < Some(tag), "tags aren't sorted at {}", tag);
+                prev = Some(tag);
+                let size = match ty {
+                    1 | 2 | 7 => 1,
+                    3 => 2,
+                    4 => 4,
+                    5 | 10 => 8,
+                    _ => panic!("tag {} has unknown type {}", tag, ty),
+                } * count as usize;
+                let start = if size <= 4 { e + 8 } else { u32_at(e + 8) as usize };
+                assert!(start + size <= file.len(), "tag {} is out of bounds", tag);
+                entries.insert(tag, (ty, count, file[start..start + size].to_vec()));
+            }
+            Self { entries, next: u32_at(off + 2 + n * 12) }
+        }
+
+        /// Read the first IFD of a file, checking the header.
+        pub(crate) fn first(file: &[u8]) -> Self {
+            assert_eq!(&file[..4], &[b'I', b'I', 42, 0]);
+            let off = u32::from_le_bytes(file[4..8].try_into().unwrap());
+            Self::read(file, off as usize)
+        }
+
+        pub(crate) fn has(&self, tag: u16) -> bool {
+            self.entries.contains_key(&tag)
+        }
+
+        /// The values of a BYTE, SHORT or LONG tag.
+        pub(crate) fn ints(&self, tag: u16) -> Vec<u32> {
+            let (ty, _, bytes) = self.entries.get(&tag)
+                .unwrap_or_else(|| panic!("missing tag {}", tag));
+            match ty {
+                1 | 7 => bytes.iter().map(|&b| b as u32).collect(),
+                3 => bytes.chunks_exact(2)
+                    .map(|b| u16::from_le_bytes([b[0], b[1]]) as u32).collect(),
+                4 => bytes.chunks_exact(4)
+                    .map(|b| u32::from_le_bytes(b.try_into().unwrap())).collect(),
+                _ => panic!("tag {} isn't an integer", tag),
+            }
+        }
+
+        pub(crate) fn int(&self, tag: u16) -> u32 {
+            let vals = self.ints(tag);
+            assert_eq!(vals.len(), 1, "tag {}", tag);
+            vals[0]
+        }
+
+        /// The values of a RATIONAL or SRATIONAL tag.
+        pub(crate) fn rationals(&self, tag: u16) -> Vec<f64> {
+            let (ty, _, bytes) = &self.entries[&tag];
+            bytes.chunks_exact(8).map(|b| {
+                let (n, d) = (b[..4].try_into().unwrap(), b[4..].try_into().unwrap());
+                match ty {
+                    5 => u32::from_le_bytes(n) as f64 / u32::from_le_bytes(d) as f64,
+                    10 => i32::from_le_bytes(n) as f64 / i32::from_le_bytes(d) as f64,
+                    _ => panic!("tag {} isn't a rational", tag),
+                }
+            }).collect()
+        }
+
+        /// The value of an ASCII tag (without the terminating NUL).
+        pub(crate) fn ascii(&self, tag: u16) -> String {
+            let (ty, _, bytes) = &self.entries[&tag];
+            assert_eq!(*ty, 2, "tag {} isn't ASCII", tag);
+            assert_eq!(bytes.last(), Some(&0), "tag {} isn't terminated", tag);
+            String::from_utf8(bytes[..bytes.len() - 1].to_vec()).unwrap()
+        }
+    }
+
+    #[test]
+    fn write_ifd() {
+        let mut ifd = Ifd::default();
+        ifd.set(305, TiffValue::Ascii("glass".to_string()));
+        ifd.set(256, TiffValue::Long(vec![640]));
+        ifd.set(258, TiffValue::Short(vec![8, 8, 8]));
+        ifd.set(282, TiffValue::Rational(vec![TiffValue::rational(2.5)]));
+        ifd.set(50723, TiffValue::SRational(vec![TiffValue::srational(-0.25)]));
+        ifd.set(50706, TiffValue::Byte(vec![1, 4, 0, 0]));
+
+        let mut tiff = TiffWriter::new();
+        assert_eq!(tiff.append(&[1, 2, 3]), 8);
+        assert_eq!(tiff.append(&[4]), 12);
+        let file = tiff.finish(&ifd);
+        assert_eq!(&file[8..11], &[1, 2, 3]);
+
+        let parsed = ParsedIfd::first(&file);
+        assert_eq!(parsed.entries.len(), 6);
+        assert_eq!(parsed.next, 0);
+        assert_eq!(parsed.int(256), 640);
+        assert_eq!(parsed.ints(258), vec![8, 8, 8]);
+        assert_eq!(parsed.ints(50706), vec![1, 4, 0, 0]);
+        assert_eq!(parsed.rationals(282), vec![2.5]);
+        assert_eq!(parsed.rationals(50723), vec![-0.25]);
+        assert_eq!(parsed.ascii(305), "glass");
+    }
+
+    #[test]
+    fn date_time() {
+        let t = std::time::UNIX_EPOCH + std::time::Duration::from_secs(951_827_696);
+        let dt = DateTime::from_system_time(t);
+        assert_eq!(dt.to_tiff(), "2000:02:29 12:34:56");
+        assert_eq!(dt.to_iso8601(), "2000-02-29T12:34:56Z");
+    }
+}
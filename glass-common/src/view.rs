@@ -0,0 +1,437 @@
+//! Borrowed views into pixel data.
+//!
+//! A view addresses pixels with a row stride and a pixel step [in bytes],
+//! so a sub-rectangle, a single channel of RGB data, or a single plane of
+//! Bayer data can all be described without copying anything.
+
+use crate::*;
+
+/// Number of bytes needed to hold a view with this layout.
+fn required_len(width: usize, height: usize, stride: usize, step: usize,
+    bpp: usize) -> usize
+{
+    if width == 0 || height == 0 {
+        return 0;
+    }
+    (height - 1) * stride + (width - 1) * step + bpp
+}
+
+fn read_sample(bytes: &[u8], ty: SampleType) -> f32 {
+    match ty {
+        SampleType::U8 => bytes[0] as f32,
+        SampleType::U16 => u16::from_le_bytes([bytes[0], bytes[1]]) as f32,
+        SampleType::F32 => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    }
+}
+
+fn write_sample(bytes: &mut [u8], ty: SampleType, max: f32, val: f32) {
+    match ty {
+        SampleType::U8 => bytes[0] = val.round().clamp(0.0, max) as u8,
+        SampleType::U16 => {
+            let val = val.round().clamp(0.0, max) as u16;
+            bytes[..2].copy_from_slice(&val.to_le_bytes());
+        },
+        SampleType::F32 => bytes[..4].copy_from_slice(&val.to_le_bytes()),
+    }
+}
+
+/// Layout of a view (shared by [PixelView] and [PixelViewMut]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Layout {
+    width: usize,
+    height: usize,
+    stride: usize,
+    step: usize,
+    format: PixelFormat,
+
+    /// Significant bits in each integer sample. This is kept when taking
+    /// a single plane of Bayer data (ie. 12-bit samples stay 12-bit even
+    /// though the plane is 'Mono16').
+    bits: Option<usize>,
+}
+impl Layout {
+    fn offset(&self, x: usize, y: usize) -> usize {
+        y * self.stride + x * self.step
+    }
+
+    /// The value of a sample at full scale (see [PixelFormat::max_value]).
+    fn max_value(&self) -> f32 {
+        match self.bits {
+            Some(bits) => ((1u32 << bits) - 1) as f32,
+            None => 1.0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        required_len(self.width, self.height, self.stride, self.step,
+            self.format.bytes_per_pixel())
+    }
+
+    /// Offset and layout of the rectangle at (x, y).
+    fn sub(&self, x: usize, y: usize, width: usize, height: usize)
+        -> Result<(usize, Self), &'static str>
+    {
+        if x + width > self.width || y + height > self.height {
+            return Err("Rectangle is outside the view");
+        }
+        let format = match self.format.bayer_pattern() {
+            Some(p) => self.format.with_pattern(p.shifted(x, y)),
+            None => self.format,
+        };
+        Ok((self.offset(x, y), Self { width, height, format, ..*self }))
+    }
+
+    /// Offset and layout of channel 'c' (as monochrome data).
+    fn channel(&self, c: usize) -> Result<(usize, Self), &'static str> {
+        if c >= self.format.channels() {
+            return Err("No such channel");
+        }
+        let format = match self.format.sample_type() {
+            SampleType::U8 => PixelFormat::Mono8,
+            SampleType::U16 => PixelFormat::Mono16,
+            SampleType::F32 => return Err("No monochrome floating-point format"),
+        };
+        let offset = c * self.format.sample_type().bytes();
+        Ok((offset, Self { format, ..*self }))
+    }
+
+    /// Offset and layout of the pixels with one color in Bayer data.
+    fn bayer_plane(&self, ch: BayerChannel) -> Result<(usize, Self), &'static str> {
+        let pattern = self.format.bayer_pattern().ok_or("Not Bayer data")?;
+        let (x, y) = [(0, 0), (1, 0), (0, 1), (1, 1)].into_iter()
+            .find(|&(x, y)| pattern.channel_at(x, y) == ch)
+            .unwrap();
+        let format = match self.format.sample_type() {
+            SampleType::U8 => PixelFormat::Mono8,
+            _ => PixelFormat::Mono16,
+        };
+        Ok((self.offset(x, y), Self {
+            width: (self.width + 1 - x) / 2,
+            height: (self.height + 1 - y) / 2,
+            stride: self.stride * 2,
+            step: self.step * 2,
+            format,
+            bits: self.bits,
+        }))
+    }
+}
+
+/// A read-only view of some pixels.
+#[derive(Clone, Copy, Debug)]
+pub struct PixelView<'a> {
+    data: &'a [u8],
+    layout: Layout,
+}
+impl<'a> PixelView<'a> {
+    /// View rows of 'stride' bytes in 'data'.
+    pub fn new(data: &'a [u8], format: PixelFormat, width: usize, height: usize,
+        stride: usize) -> Result<Self, &'static str>
+    {
+        let bpp = format.bytes_per_pixel();
+        if stride < width * bpp {
+            return Err("Stride is shorter than a row");
+        }
+        if data.len() < required_len(width, height, stride, bpp, bpp) {
+            return Err("Data is too short for the view");
+        }
+        let layout = Layout { width, height, stride, step: bpp, format,
+            bits: format.significant_bits() };
+        Ok(Self { data, layout })
+    }
+
+    pub fn width(&self) -> usize { self.layout.width }
+    pub fn height(&self) -> usize { self.layout.height }
+    pub fn format(&self) -> PixelFormat { self.layout.format }
+
+    /// Number of bits used in each integer sample. Unlike the format, this
+    /// is preserved by [PixelView::bayer_plane].
+    pub fn significant_bits(&self) -> Option<usize> { self.layout.bits }
+
+    /// The value of a sample at full scale (see [PixelView::significant_bits]).
+    pub fn max_value(&self) -> f32 { self.layout.max_value() }
+
+    /// Bytes between the start of each row.
+    pub fn stride(&self) -> usize { self.layout.stride }
+
+    /// Bytes between the start of each pixel.
+    pub fn step(&self) -> usize { self.layout.step }
+
+    /// Returns 'true' if each row is a contiguous run of pixels.
+    pub fn has_contiguous_rows(&self) -> bool {
+        self.layout.step == self.layout.format.bytes_per_pixel()
+    }
+
+    /// Returns 'true' if all of the pixels are contiguous.
+    pub fn is_packed(&self) -> bool {
+        self.has_contiguous_rows()
+            && (self.layout.height <= 1 || self.layout.stride == self.row_bytes())
+    }
+
+    /// The underlying bytes, starting with the first pixel.
+    pub fn bytes(&self) -> &'a [u8] {
+        &self.data[..self.layout.len()]
+    }
+
+    /// Bytes used by the pixels in each row (when rows are contiguous).
+    pub fn row_bytes(&self) -> usize {
+        self.layout.width * self.layout.format.bytes_per_pixel()
+    }
+
+    /// The bytes of the pixel at (x, y).
+    pub fn pixel(&self, x: usize, y: usize) -> &'a [u8] {
+        let off = self.layout.offset(x, y);
+        &self.data[off..off + self.layout.format.bytes_per_pixel()]
+    }
+
+    /// Value of channel 'c' of the pixel at (x, y).
+    pub fn sample(&self, x: usize, y: usize, c: usize) -> f32 {
+        let ty = self.layout.format.sample_type();
+        read_sample(&self.pixel(x, y)[c * ty.bytes()..], ty)
+    }
+
+    /// The pixels in row 'y', if they're contiguous.
+    pub fn row(&self, y: usize) -> Option<&'a [u8]> {
+        if !self.has_contiguous_rows() || y >= self.layout.height {
+            return None;
+        }
+        let off = self.layout.offset(0, y);
+        Some(&self.data[off..off + self.row_bytes()])
+    }
+
+    /// Iterate over every sample, row by row.
+    pub fn samples(&self) -> impl Iterator<Item = f32> + 'a {
+        let view = *self;
+        let channels = self.layout.format.channels();
+        (0..self.layout.height).flat_map(move |y| (0..view.layout.width)
+            .flat_map(move |x| (0..channels).map(move |c| view.sample(x, y, c))))
+    }
+
+    /// A rectangle starting at (x, y).
+    ///
+    /// NOTE: The Bayer pattern of the result is shifted when (x, y) is odd.
+    pub fn sub(&self, x: usize, y: usize, width: usize, height: usize)
+        -> Result<Self, &'static str>
+    {
+        let (off, layout) = self.layout.sub(x, y, width, height)?;
+        Ok(Self { data: &self.data[off.min(self.data.len())..], layout })
+    }
+
+    /// A single channel (ie. green in RGB data), as monochrome data.
+    pub fn channel(&self, c: usize) -> Result<Self, &'static str> {
+        let (off, layout) = self.layout.channel(c)?;
+        Ok(Self { data: &self.data[off..], layout })
+    }
+
+    /// The pixels with one color in Bayer data, as monochrome data.
+    pub fn bayer_plane(&self, ch: BayerChannel) -> Result<Self, &'static str> {
+        let (off, layout) = self.layout.bayer_plane(ch)?;
+        Ok(Self { data: &self.data[off.min(self.data.len())..], layout })
+    }
+
+    /// Split into tiles of (at most) 'width' by 'height' pixels, returning
+    /// the position of each tile along with the tile.
+    pub fn tiles(&self, width: usize, height: usize)
+        -> impl Iterator<Item = (usize, usize, PixelView<'a>)>
+    {
+        let view = *self;
+        let (w, h) = (width.max(1), height.max(1));
+        (0..self.layout.height).step_by(h).flat_map(move |y| {
+            (0..view.layout.width).step_by(w).map(move |x| {
+                let tw = w.min(view.layout.width - x);
+                let th = h.min(view.layout.height - y);
+                (x, y, view.sub(x, y, tw, th).unwrap())
+            })
+        })
+    }
+
+    /// Copy the pixels into a new (packed) [PixelData].
+    pub fn to_pixel_data(&self) -> PixelData {
+        let mut res = PixelData::new(self.layout.format, self.layout.width,
+            self.layout.height);
+        res.view_mut().copy_from(self).unwrap();
+        res
+    }
+}
+
+/// A view of some pixels that can be modified.
+#[derive(Debug)]
+pub struct PixelViewMut<'a> {
+    data: &'a mut [u8],
+    layout: Layout,
+}
+impl<'a> PixelViewMut<'a> {
+    /// View rows of 'stride' bytes in 'data'.
+    pub fn new(data: &'a mut [u8], format: PixelFormat, width: usize,
+        height: usize, stride: usize) -> Result<Self, &'static str>
+    {
+        let layout = PixelView::new(data, format, width, height, stride)?.layout;
+        Ok(Self { data, layout })
+    }
+
+    /// Borrow as a read-only view.
+    pub fn as_view(&self) -> PixelView<'_> {
+        PixelView { data: self.data, layout: self.layout }
+    }
+
+    pub fn width(&self) -> usize { self.layout.width }
+    pub fn height(&self) -> usize { self.layout.height }
+    pub fn format(&self) -> PixelFormat { self.layout.format }
+    pub fn significant_bits(&self) -> Option<usize> { self.layout.bits }
+    pub fn max_value(&self) -> f32 { self.layout.max_value() }
+    pub fn stride(&self) -> usize { self.layout.stride }
+    pub fn step(&self) -> usize { self.layout.step }
+
+    /// The bytes of the pixel at (x, y).
+    pub fn pixel_mut(&mut self, x: usize, y: usize) -> &mut [u8] {
+        let off = self.layout.offset(x, y);
+        &mut self.data[off..off + self.layout.format.bytes_per_pixel()]
+    }
+
+    /// Set channel 'c' of the pixel at (x, y) (rounding and clamping as
+    /// necessary).
+    pub fn set_sample(&mut self, x: usize, y: usize, c: usize, val: f32) {
+        let (ty, max) = (self.layout.format.sample_type(), self.layout.max_value());
+        let off = c * ty.bytes();
+        write_sample(&mut self.pixel_mut(x, y)[off..], ty, max, val);
+    }
+
+    /// The pixels in row 'y', if they're contiguous.
+    pub fn row_mut(&mut self, y: usize) -> Option<&mut [u8]> {
+        let len = self.as_view().row(y)?.len();
+        let off = self.layout.offset(0, y);
+        Some(&mut self.data[off..off + len])
+    }
+
+    /// A rectangle starting at (x, y) (see [PixelView::sub]).
+    pub fn sub_mut(&mut self, x: usize, y: usize, width: usize, height: usize)
+        -> Result<PixelViewMut<'_>, &'static str>
+    {
+        let (off, layout) = self.layout.sub(x, y, width, height)?;
+        let off = off.min(self.data.len());
+        Ok(PixelViewMut { data: &mut self.data[off..], layout })
+    }
+
+    /// A single channel, as monochrome data.
+    pub fn channel_mut(&mut self, c: usize) -> Result<PixelViewMut<'_>, &'static str> {
+        let (off, layout) = self.layout.channel(c)?;
+        Ok(PixelViewMut { data: &mut self.data[off..], layout })
+    }
+
+    /// The pixels with one color in Bayer data, as monochrome data.
+    pub fn bayer_plane_mut(&mut self, ch: BayerChannel)
+        -> Result<PixelViewMut<'_>, &'static str>
+    {
+        let (off, layout) = self.layout.bayer_plane(ch)?;
+        let off = off.min(self.data.len());
+        Ok(PixelViewMut { data: &mut self.data[off..], layout })
+    }
+
+    /// Copy pixels from a view with the same size and format.
+    pub fn copy_from(&mut self, src: &PixelView) -> Result<(), &'static str> {
+        if src.width() != self.width() || src.height() != self.height() {
+            return Err("Views are different sizes");
+        }
+        if src.format() != self.format() {
+            return Err("Views have different formats");
+        }
+        for y in 0..self.height() {
+            match (src.row(y), self.as_view().has_contiguous_rows()) {
+                (Some(row), true) => self.row_mut(y).unwrap().copy_from_slice(row),
+                _ => for x in 0..self.width() {
+                    self.pixel_mut(x, y).copy_from_slice(src.pixel(x, y));
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Set every sample to 'val'.
+    pub fn fill(&mut self, val: f32) {
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                for c in 0..self.format().channels() {
+                    self.set_sample(x, y, c, val);
+                }
+            }
+        }
+    }
+}
+
+impl PixelData {
+    /// Allocate an image with rows of 'stride' bytes.
+    pub fn new_with_stride(fmt: PixelFormat, width: usize, height: usize,
+        stride: usize) -> Result<Self, &'static str>
+    {
+        if stride < width * fmt.bytes_per_pixel() {
+            return Err("Stride is shorter than a row");
+        }
+        let data = vec![0u8; stride * height].into_boxed_slice();
+        Ok(Self { width, height, stride, data, format: fmt, id: 0 })
+    }
+
+    /// Bytes between the start of each row.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn view(&self) -> PixelView<'_> {
+        let layout = Layout {
+            width: self.width,
+            height: self.height,
+            stride: self.stride,
+            step: self.format.bytes_per_pixel(),
+            format: self.format,
+            bits: self.format.significant_bits(),
+        };
+        debug_assert!(self.data.len() >= layout.len());
+        PixelView { data: &self.data, layout }
+    }
+
+    pub fn view_mut(&mut self) -> PixelViewMut<'_> {
+        let layout = self.view().layout;
+        PixelViewMut { data: &mut self.data, layout }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bayer_plane_bits() {
+        let fmt = PixelFormat::Bayer16(BayerPattern::RGGB, 12);
+        let mut img = PixelData::new_from_u16(fmt, 4, 4, &[4095; 16]).unwrap();
+        {
+            // NOTE: Writes are clamped to 12 bits too
+            let mut img = img.view_mut();
+            let mut red = img.bayer_plane_mut(BayerChannel::Red).unwrap();
+            assert_eq!(red.significant_bits(), Some(12));
+            red.set_sample(0, 0, 0, 5000.0);
+            red.set_sample(1, 1, 0, 100.0);
+        }
+
+        let view = img.view();
+        let red = view.bayer_plane(BayerChannel::Red).unwrap();
+        assert_eq!(red.format(), PixelFormat::Mono16);
+        assert_eq!((red.width(), red.height()), (2, 2));
+        assert_eq!(red.significant_bits(), Some(12));
+        assert_eq!(red.max_value(), 4095.0);
+        assert_eq!(red.sample(0, 0, 0), 4095.0);
+        assert_eq!(red.sample(1, 1, 0), 100.0);
+
+        let hist = histograms(&red, &HistogramOptions::default());
+        let (_, mono) = &hist.channels[0];
+        assert_eq!(mono.max_value, 4095.0);
+        assert_eq!(mono.clipped_white, 3);
+
+        let stats = red.roi_stats(&Region::Full).unwrap();
+        assert_eq!(stats[0].1.saturated, 3);
+
+        // Other channels and sub-views keep the bits as well
+        let sub = view.sub(1, 1, 3, 3).unwrap();
+        let blue = sub.bayer_plane(BayerChannel::Blue).unwrap();
+        assert_eq!(blue.max_value(), 4095.0);
+        assert_eq!(PixelData::new(PixelFormat::RGB8, 2, 2).view()
+            .channel(1).unwrap().significant_bits(), Some(8));
+    }
+}
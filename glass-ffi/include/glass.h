@@ -0,0 +1,221 @@
+/*
+ * C interface to the MU1603 (Touptek U3CMOS16000KPA) driver.
+ *
+ * Link against 'libglass' (built from the 'glass-ffi' crate).
+ *
+ * All functions returning 'int32_t' return GLASS_OK on success, or one of
+ * the (negative) 'glass_error' codes. The values of these codes won't change
+ * between versions; new codes may be added.
+ *
+ * A 'glass_camera' handle must only be used by one thread at a time.
+ * Reading a frame blocks until the frame arrives (for long exposures, this
+ * is at least the exposure time).
+ *
+ * Example:
+ *
+ *     glass_camera *cam;
+ *     if (glass_open_first(&cam) != GLASS_OK) { ... }
+ *     glass_start_stream(cam, GLASS_MODE_1);
+ *     glass_set_exposure_us(cam, 50000);
+ *
+ *     size_t len;
+ *     glass_frame_size(cam, &len);
+ *     uint8_t *buf = malloc(len);
+ *     glass_frame_info info;
+ *     int32_t res;
+ *     do {
+ *         res = glass_read_frame(cam, buf, len, &info);
+ *     } while (res == GLASS_ERR_TRUNCATED || res == GLASS_ERR_TIMEOUT);
+ *
+ *     glass_stop_stream(cam);
+ *     glass_close(cam);
+ */
+
+#ifndef GLASS_H
+#define GLASS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Version of this interface */
+#define GLASS_API_VERSION 1
+
+typedef enum glass_error {
+    GLASS_OK                    = 0,
+    /* A required pointer argument was NULL */
+    GLASS_ERR_NULL              = -1,
+    /* An argument was out of range */
+    GLASS_ERR_INVALID_ARG       = -2,
+    /* The caller's buffer is too small (see glass_frame_size()) */
+    GLASS_ERR_BUFFER_TOO_SMALL  = -3,
+    /* No camera was found, or the camera was unplugged */
+    GLASS_ERR_NO_DEVICE         = -4,
+    /* Permission denied while opening the camera */
+    GLASS_ERR_ACCESS            = -5,
+    /* The camera is in use by something else */
+    GLASS_ERR_BUSY              = -6,
+    /* A USB transfer timed out */
+    GLASS_ERR_TIMEOUT           = -7,
+    /* Some other USB error */
+    GLASS_ERR_USB               = -8,
+    /* A frame was truncated and discarded (expected after starting a stream) */
+    GLASS_ERR_TRUNCATED         = -9,
+    /* The camera isn't streaming */
+    GLASS_ERR_NOT_STREAMING     = -10,
+    /* The sensor rejected a command */
+    GLASS_ERR_SENSOR_CMD        = -11,
+    /* The driver doesn't support this yet */
+    GLASS_ERR_UNIMPLEMENTED     = -12,
+    /* The frame was dropped to meet the requested frame rate */
+    GLASS_ERR_SKIPPED           = -13,
+    /* A frame couldn't be interpreted (ie. it has the wrong size) */
+    GLASS_ERR_INVALID_FRAME     = -14,
+    /* An internal error (this is a bug) */
+    GLASS_ERR_INTERNAL          = -100,
+} glass_error;
+
+/* Sensor modes */
+typedef enum glass_mode {
+    /* 4632x3488 */
+    GLASS_MODE_0 = 0,
+    /* 2320x1740 */
+    GLASS_MODE_1 = 1,
+    /* 1536x1160 */
+    GLASS_MODE_2 = 2,
+} glass_mode;
+
+/*
+ * Layout of pixel data.
+ *
+ * 16-bit samples are little-endian. For Bayer16 formats, only the low
+ * 'glass_options.bit_depth' bits are used (ie. 12).
+ */
+typedef enum glass_pixel_format {
+    GLASS_FORMAT_BAYER8_RGGB = 0,
+    GLASS_FORMAT_BAYER8_BGGR = 1,
+    GLASS_FORMAT_RGBA8       = 2,
+    GLASS_FORMAT_RGB8        = 3,
+    GLASS_FORMAT_BAYER8_GRBG = 4,
+    GLASS_FORMAT_BAYER8_GBRG = 5,
+    GLASS_FORMAT_BAYER16_RGGB = 6,
+    GLASS_FORMAT_BAYER16_GRBG = 7,
+    GLASS_FORMAT_BAYER16_GBRG = 8,
+    GLASS_FORMAT_BAYER16_BGGR = 9,
+    GLASS_FORMAT_MONO8       = 10,
+    GLASS_FORMAT_MONO16      = 11,
+    GLASS_FORMAT_RGB16       = 12,
+    /* 32-bit floats */
+    GLASS_FORMAT_RGBF32      = 13,
+} glass_pixel_format;
+
+/* Information identifying a camera */
+typedef struct glass_device_info {
+    uint16_t vendor_id;
+    uint16_t product_id;
+    /* Bus number and address (see glass_open_at()) */
+    uint8_t bus;
+    uint8_t address;
+    /* Device release number (major, minor, sub-minor) */
+    uint8_t version[3];
+    /* Negotiated link speed [in Mbps], or 0 if unknown */
+    uint32_t speed_mbps;
+    /* NUL-terminated strings (empty when not available) */
+    char manufacturer[64];
+    char product[64];
+    char serial[64];
+} glass_device_info;
+
+/* Current camera settings */
+typedef struct glass_options {
+    /* Non-zero when the camera is streaming; the rest is only valid then */
+    int32_t streaming;
+    glass_mode mode;
+    uint32_t exposure_us;
+    uint32_t gain_percent;
+    uint32_t bit_depth;
+} glass_options;
+
+/* Describes a frame read with glass_read_frame() */
+typedef struct glass_frame_info {
+    /* Sequence number [since the camera was opened] */
+    uint64_t id;
+    uint32_t width;
+    uint32_t height;
+    glass_pixel_format format;
+    uint32_t bytes_per_pixel;
+    /* Number of bytes written to the caller's buffer */
+    size_t size;
+    /* Settings used to capture the frame */
+    uint32_t exposure_us;
+    uint32_t gain_percent;
+} glass_frame_info;
+
+typedef struct glass_camera glass_camera;
+
+/* Return a static, NUL-terminated description of an error code. */
+const char *glass_error_string(int32_t err);
+
+/* Return GLASS_API_VERSION for the library in use. */
+uint32_t glass_api_version(void);
+
+/*
+ * List the cameras that are plugged in.
+ *
+ * Writes up to 'capacity' entries to 'out' (which may be NULL if 'capacity'
+ * is zero), and the number of cameras found to 'count'.
+ */
+int32_t glass_enumerate(glass_device_info *out, size_t capacity, size_t *count);
+
+/* Open the first camera found. */
+int32_t glass_open_first(glass_camera **out);
+
+/* Open the camera at a particular bus number and address. */
+int32_t glass_open_at(uint8_t bus, uint8_t address, glass_camera **out);
+
+/* Stop streaming (if necessary) and release the camera. NULL is ignored. */
+void glass_close(glass_camera *cam);
+
+/* Get information identifying an open camera. */
+int32_t glass_get_device_info(const glass_camera *cam, glass_device_info *out);
+
+/* Start streaming in the requested mode. */
+int32_t glass_start_stream(glass_camera *cam, glass_mode mode);
+
+/* Stop streaming. */
+int32_t glass_stop_stream(glass_camera *cam);
+
+/* Get the current settings. */
+int32_t glass_get_options(const glass_camera *cam, glass_options *out);
+
+/* Set the exposure time [in microseconds] while streaming. */
+int32_t glass_set_exposure_us(glass_camera *cam, uint32_t exposure_us);
+
+/*
+ * Set the analog gain [in percent, from 100 to 300] while streaming.
+ *
+ * NOTE: The driver doesn't know how to change the gain yet, so this
+ * returns GLASS_ERR_UNIMPLEMENTED for anything other than the current gain.
+ */
+int32_t glass_set_gain_percent(glass_camera *cam, uint32_t gain_percent);
+
+/* Get the size of a frame [in bytes] with the current settings. */
+int32_t glass_frame_size(const glass_camera *cam, size_t *len);
+
+/*
+ * Read the next frame into a caller-provided buffer of 'len' bytes.
+ *
+ * 'info' may be NULL. GLASS_ERR_TRUNCATED and GLASS_ERR_TIMEOUT are
+ * expected occasionally, and the caller can just try again.
+ */
+int32_t glass_read_frame(glass_camera *cam, uint8_t *buf, size_t len,
+    glass_frame_info *info);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* GLASS_H */
@@ -0,0 +1,618 @@
+//! C interface to the MU1603 driver.
+//!
+//! See 'include/glass.h' for the documentation. Everything here needs to
+//! be kept in sync with the header by hand.
+//!
+//! NOTE: Panics must not unwind across the FFI boundary, so each entry
+//! point runs inside [ffi_call], which turns a panic into
+//! [GLASS_ERR_INTERNAL].
+
+// NOTE: The requirements on pointer arguments are documented in the header
+#![allow(clippy::missing_safety_doc)]
+
+use glass_common::*;
+use glass_mu1603::*;
+use rusb::Context;
+use std::ffi::c_char;
+use std::panic::{ catch_unwind, AssertUnwindSafe };
+
+pub const GLASS_API_VERSION: u32 = 1;
+
+pub const GLASS_OK: i32                    = 0;
+pub const GLASS_ERR_NULL: i32              = -1;
+pub const GLASS_ERR_INVALID_ARG: i32       = -2;
+pub const GLASS_ERR_BUFFER_TOO_SMALL: i32  = -3;
+pub const GLASS_ERR_NO_DEVICE: i32         = -4;
+pub const GLASS_ERR_ACCESS: i32            = -5;
+pub const GLASS_ERR_BUSY: i32              = -6;
+pub const GLASS_ERR_TIMEOUT: i32           = -7;
+pub const GLASS_ERR_USB: i32               = -8;
+pub const GLASS_ERR_TRUNCATED: i32         = -9;
+pub const GLASS_ERR_NOT_STREAMING: i32     = -10;
+pub const GLASS_ERR_SENSOR_CMD: i32        = -11;
+pub const GLASS_ERR_UNIMPLEMENTED: i32     = -12;
+pub const GLASS_ERR_SKIPPED: i32           = -13;
+pub const GLASS_ERR_INVALID_FRAME: i32     = -14;
+pub const GLASS_ERR_INTERNAL: i32          = -100;
+
+fn usb_error_code(e: rusb::Error) -> i32 {
+    match e {
+        rusb::Error::NoDevice | rusb::Error::NotFound => GLASS_ERR_NO_DEVICE,
+        rusb::Error::Access => GLASS_ERR_ACCESS,
+        rusb::Error::Busy => GLASS_ERR_BUSY,
+        rusb::Error::Timeout => GLASS_ERR_TIMEOUT,
+        _ => GLASS_ERR_USB,
+    }
+}
+
+/// Map a [Mu1603Error] onto one of the stable error codes.
+fn error_code(e: Mu1603Error) -> i32 {
+    match e {
+        Mu1603Error::Rusb(e) => usb_error_code(e),
+        Mu1603Error::FirstFrame => GLASS_ERR_TRUNCATED,
+        Mu1603Error::Unimplemented => GLASS_ERR_UNIMPLEMENTED,
+        Mu1603Error::NotStreaming => GLASS_ERR_NOT_STREAMING,
+        Mu1603Error::NotConnected => GLASS_ERR_NO_DEVICE,
+        Mu1603Error::FailedSensorCmd(..) => GLASS_ERR_SENSOR_CMD,
+        Mu1603Error::Skipped => GLASS_ERR_SKIPPED,
+        Mu1603Error::InvalidFrame(_) => GLASS_ERR_INVALID_FRAME,
+        // NOTE: We don't use the worker thread here
+        Mu1603Error::WorkerTerminated => GLASS_ERR_INTERNAL,
+    }
+}
+
+/// Run the body of an entry point, catching panics.
+fn ffi_call<F>(f: F) -> i32 where F: FnOnce() -> Result<(), i32> {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => GLASS_OK,
+        Ok(Err(code)) => code,
+        Err(_) => GLASS_ERR_INTERNAL,
+    }
+}
+
+/// Convert a nullable pointer from the caller into a reference.
+unsafe fn ptr_mut<'a, T>(ptr: *mut T) -> Result<&'a mut T, i32> {
+    ptr.as_mut().ok_or(GLASS_ERR_NULL)
+}
+unsafe fn ptr_ref<'a, T>(ptr: *const T) -> Result<&'a T, i32> {
+    ptr.as_ref().ok_or(GLASS_ERR_NULL)
+}
+
+#[no_mangle]
+pub extern "C" fn glass_error_string(err: i32) -> *const c_char {
+    let s: &'static [u8] = match err {
+        GLASS_OK => b"success\0",
+        GLASS_ERR_NULL => b"null pointer argument\0",
+        GLASS_ERR_INVALID_ARG => b"invalid argument\0",
+        GLASS_ERR_BUFFER_TOO_SMALL => b"buffer too small\0",
+        GLASS_ERR_NO_DEVICE => b"no device\0",
+        GLASS_ERR_ACCESS => b"access denied\0",
+        GLASS_ERR_BUSY => b"device busy\0",
+        GLASS_ERR_TIMEOUT => b"timed out\0",
+        GLASS_ERR_USB => b"usb error\0",
+        GLASS_ERR_TRUNCATED => b"truncated frame\0",
+        GLASS_ERR_NOT_STREAMING => b"not streaming\0",
+        GLASS_ERR_SENSOR_CMD => b"sensor command failed\0",
+        GLASS_ERR_UNIMPLEMENTED => b"not implemented\0",
+        GLASS_ERR_SKIPPED => b"frame skipped\0",
+        GLASS_ERR_INVALID_FRAME => b"invalid frame\0",
+        GLASS_ERR_INTERNAL => b"internal error\0",
+        _ => b"unknown error\0",
+    };
+    s.as_ptr() as *const c_char
+}
+
+#[no_mangle]
+pub extern "C" fn glass_api_version() -> u32 {
+    GLASS_API_VERSION
+}
+
+/// See 'glass_mode' in the header.
+fn mode_from_c(mode: i32) -> Result<Mu1603Mode, i32> {
+    match mode {
+        0 => Ok(Mu1603Mode::Mode0),
+        1 => Ok(Mu1603Mode::Mode1),
+        2 => Ok(Mu1603Mode::Mode2),
+        _ => Err(GLASS_ERR_INVALID_ARG),
+    }
+}
+fn mode_to_c(mode: Mu1603Mode) -> i32 {
+    match mode {
+        Mu1603Mode::Mode0 => 0,
+        Mu1603Mode::Mode1 => 1,
+        Mu1603Mode::Mode2 => 2,
+    }
+}
+
+/// See 'glass_pixel_format' in the header.
+fn format_to_c(fmt: PixelFormat) -> i32 {
+    match fmt {
+        PixelFormat::Bayer8(BayerPattern::RGGB) => 0,
+        PixelFormat::Bayer8(BayerPattern::BGGR) => 1,
+        PixelFormat::RGBA8 => 2,
+        PixelFormat::RGB8 => 3,
+        PixelFormat::Bayer8(BayerPattern::GRBG) => 4,
+        PixelFormat::Bayer8(BayerPattern::GBRG) => 5,
+        PixelFormat::Bayer16(BayerPattern::RGGB, _) => 6,
+        PixelFormat::Bayer16(BayerPattern::GRBG, _) => 7,
+        PixelFormat::Bayer16(BayerPattern::GBRG, _) => 8,
+        PixelFormat::Bayer16(BayerPattern::BGGR, _) => 9,
+        PixelFormat::Mono8 => 10,
+        PixelFormat::Mono16 => 11,
+        PixelFormat::RGB16 => 12,
+        PixelFormat::RGBF32 => 13,
+    }
+}
+
+#[repr(C)]
+pub struct GlassDeviceInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bus: u8,
+    pub address: u8,
+    pub version: [u8; 3],
+    pub speed_mbps: u32,
+    pub manufacturer: [c_char; 64],
+    pub product: [c_char; 64],
+    pub serial: [c_char; 64],
+}
+impl From<&DeviceInfo> for GlassDeviceInfo {
+    fn from(info: &DeviceInfo) -> Self {
+        // Copy into a fixed-size buffer, truncating and NUL-terminating
+        fn c_str(s: &Option<String>) -> [c_char; 64] {
+            let mut res = [0; 64];
+            let bytes = s.as_deref().unwrap_or("").as_bytes();
+            for (dst, src) in res.iter_mut().zip(bytes.iter().take(63)) {
+                *dst = *src as c_char;
+            }
+            res
+        }
+        let speed_mbps = match info.speed {
+            rusb::Speed::Low => 1,
+            rusb::Speed::Full => 12,
+            rusb::Speed::High => 480,
+            rusb::Speed::Super => 5000,
+            rusb::Speed::SuperPlus => 10000,
+            _ => 0,
+        };
+        let (major, minor, sub) = info.device_version;
+        Self {
+            vendor_id: info.vendor_id,
+            product_id: info.product_id,
+            bus: info.bus,
+            address: info.address,
+            version: [major, minor, sub],
+            speed_mbps,
+            manufacturer: c_str(&info.manufacturer),
+            product: c_str(&info.product),
+            serial: c_str(&info.serial),
+        }
+    }
+}
+
+#[repr(C)]
+pub struct GlassOptions {
+    pub streaming: i32,
+    pub mode: i32,
+    pub exposure_us: u32,
+    pub gain_percent: u32,
+    pub bit_depth: u32,
+}
+
+#[repr(C)]
+pub struct GlassFrameInfo {
+    pub id: u64,
+    pub width: u32,
+    pub height: u32,
+    pub format: i32,
+    pub bytes_per_pixel: u32,
+    pub size: usize,
+    pub exposure_us: u32,
+    pub gain_percent: u32,
+}
+
+/// An open camera (opaque to C).
+///
+/// NOTE: This holds any [FrameSource] so that the tests can run against 
+/// the emulator.
+pub struct GlassCamera {
+    cam: Box<dyn FrameSource>,
+
+    /// Number of frames we've read so far
+    frames: u64,
+}
+impl GlassCamera {
+    fn new(cam: impl FrameSource + 'static) -> Self {
+        Self { cam: Box::new(cam), frames: 0 }
+    }
+
+    fn streaming_state(&self) -> Result<Mu1603Options, i32> {
+        self.cam.state().ok_or(GLASS_ERR_NOT_STREAMING)
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn glass_enumerate(out: *mut GlassDeviceInfo,
+    capacity: usize, count: *mut usize) -> i32
+{
+    ffi_call(|| {
+        let count = ptr_mut(count)?;
+        if out.is_null() && capacity != 0 {
+            return Err(GLASS_ERR_NULL);
+        }
+        let ctx = Context::new().map_err(usb_error_code)?;
+        let devices = Mu1603::enumerate(&ctx).map_err(usb_error_code)?;
+        for (idx, info) in devices.iter().take(capacity).enumerate() {
+            out.add(idx).write(GlassDeviceInfo::from(info));
+        }
+        *count = devices.len();
+        Ok(())
+    })
+}
+
+unsafe fn open_with<F>(out: *mut *mut GlassCamera, open: F) -> i32
+    where F: FnOnce(&mut Context) -> rusb::Result<Mu1603>
+{
+    ffi_call(|| {
+        let out = ptr_mut(out)?;
+        let mut ctx = Context::new().map_err(usb_error_code)?;
+        let cam = open(&mut ctx).map_err(usb_error_code)?;
+        *out = Box::into_raw(Box::new(GlassCamera::new(cam)));
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn glass_open_first(out: *mut *mut GlassCamera) -> i32 {
+    open_with(out, |ctx| Mu1603::builder().open(ctx))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn glass_open_at(bus: u8, address: u8,
+    out: *mut *mut GlassCamera) -> i32
+{
+    open_with(out, |ctx| Mu1603::builder().open_at(ctx, bus, address))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn glass_close(cam: *mut GlassCamera) {
+    if cam.is_null() {
+        return;
+    }
+    let _ = catch_unwind(AssertUnwindSafe(|| {
+        let mut cam = Box::from_raw(cam);
+        let _ = cam.cam.stop_stream();
+    }));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn glass_get_device_info(cam: *const GlassCamera,
+    out: *mut GlassDeviceInfo) -> i32
+{
+    ffi_call(|| {
+        let cam = ptr_ref(cam)?;
+        let out = ptr_mut(out)?;
+        *out = GlassDeviceInfo::from(&cam.cam.device_info());
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn glass_start_stream(cam: *mut GlassCamera, mode: i32) -> i32 {
+    ffi_call(|| {
+        let cam = ptr_mut(cam)?;
+        let mode = mode_from_c(mode)?;
+        if let Some(state) = cam.cam.state() {
+            // NOTE: Changing the mode requires restarting the stream
+            if state.mode == mode {
+                return Ok(());
+            }
+            cam.cam.stop_stream().map_err(error_code)?;
+        }
+        cam.cam.start_stream(mode).map_err(error_code)?;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn glass_stop_stream(cam: *mut GlassCamera) -> i32 {
+    ffi_call(|| {
+        let cam = ptr_mut(cam)?;
+        cam.cam.stop_stream().map_err(error_code)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn glass_get_options(cam: *const GlassCamera,
+    out: *mut GlassOptions) -> i32
+{
+    ffi_call(|| {
+        let cam = ptr_ref(cam)?;
+        let out = ptr_mut(out)?;
+        *out = match cam.cam.state() {
+            Some(state) => GlassOptions {
+                streaming: 1,
+                mode: mode_to_c(state.mode),
+                exposure_us: state.exposure_us() as u32,
+                gain_percent: state.analog_gain_percent() as u32,
+                bit_depth: state.bitdepth.bits() as u32,
+            },
+            None => GlassOptions {
+                streaming: 0, mode: 0, exposure_us: 0, gain_percent: 0, bit_depth: 0,
+            },
+        };
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn glass_set_exposure_us(cam: *mut GlassCamera,
+    exposure_us: u32) -> i32
+{
+    ffi_call(|| {
+        let cam = ptr_mut(cam)?;
+        let us = exposure_us as usize;
+        if !(ExposureTime::MIN..=ExposureTime::MAX).contains(&us) {
+            return Err(GLASS_ERR_INVALID_ARG);
+        }
+        let mut opts = cam.streaming_state()?;
+        opts.exposure = ExposureTime::new_from_us(us);
+        cam.cam.apply_state(opts).map_err(error_code)?;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn glass_set_gain_percent(cam: *mut GlassCamera,
+    gain_percent: u32) -> i32
+{
+    ffi_call(|| {
+        let cam = ptr_mut(cam)?;
+        let percent = gain_percent as usize;
+        if !(AnalogGain::MIN..=AnalogGain::MAX).contains(&percent) {
+            return Err(GLASS_ERR_INVALID_ARG);
+        }
+        let mut opts = cam.streaming_state()?;
+        opts.analog_gain = AnalogGain::new_from_percent(percent);
+        let state = cam.cam.apply_state(opts).map_err(error_code)?;
+        // NOTE: The driver silently ignores gain changes for now
+        if state.analog_gain != opts.analog_gain {
+            return Err(GLASS_ERR_UNIMPLEMENTED);
+        }
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn glass_frame_size(cam: *const GlassCamera,
+    len: *mut usize) -> i32
+{
+    ffi_call(|| {
+        let cam = ptr_ref(cam)?;
+        let len = ptr_mut(len)?;
+        let state = cam.streaming_state()?;
+        let (w, h) = state.mode.dimensions();
+        *len = w * h * state.bitdepth.bpp();
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn glass_read_frame(cam: *mut GlassCamera, buf: *mut u8,
+    len: usize, info: *mut GlassFrameInfo) -> i32
+{
+    ffi_call(|| {
+        let cam = ptr_mut(cam)?;
+        if buf.is_null() {
+            return Err(GLASS_ERR_NULL);
+        }
+        let state = cam.streaming_state()?;
+        let (w, h) = state.mode.dimensions();
+        let format = state.bitdepth.format();
+        let size = w * h * format.bytes_per_pixel();
+        if len < size {
+            return Err(GLASS_ERR_BUFFER_TOO_SMALL);
+        }
+
+        let data = cam.cam.try_read_frame().map_err(error_code)?;
+        if data.len() != size {
+            return Err(GLASS_ERR_INVALID_FRAME);
+        }
+        std::slice::from_raw_parts_mut(buf, size).copy_from_slice(&data);
+        cam.frames += 1;
+
+        if let Some(info) = info.as_mut() {
+            *info = GlassFrameInfo {
+                id: cam.frames,
+                width: w as u32,
+                height: h as u32,
+                format: format_to_c(format),
+                bytes_per_pixel: format.bytes_per_pixel() as u32,
+                size,
+                exposure_us: state.exposure_us() as u32,
+                gain_percent: state.analog_gain_percent() as u32,
+            };
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::ptr::{ null, null_mut };
+
+    const ALL_CODES: &[i32] = &[
+        GLASS_OK, GLASS_ERR_NULL, GLASS_ERR_INVALID_ARG,
+        GLASS_ERR_BUFFER_TOO_SMALL, GLASS_ERR_NO_DEVICE, GLASS_ERR_ACCESS,
+        GLASS_ERR_BUSY, GLASS_ERR_TIMEOUT, GLASS_ERR_USB, GLASS_ERR_TRUNCATED,
+        GLASS_ERR_NOT_STREAMING, GLASS_ERR_SENSOR_CMD, GLASS_ERR_UNIMPLEMENTED,
+        GLASS_ERR_SKIPPED, GLASS_ERR_INVALID_FRAME, GLASS_ERR_INTERNAL,
+    ];
+
+    fn emulator_camera() -> GlassCamera {
+        let emu = Mu1603Emulator::new(
+            EmulatorSensor::default(), EmulatorScene::default()
+        ).realtime(false);
+        GlassCamera::new(emu)
+    }
+
+    #[test]
+    fn error_codes() {
+        let cases = [
+            (Mu1603Error::Rusb(rusb::Error::NoDevice), GLASS_ERR_NO_DEVICE),
+            (Mu1603Error::Rusb(rusb::Error::NotFound), GLASS_ERR_NO_DEVICE),
+            (Mu1603Error::Rusb(rusb::Error::Access), GLASS_ERR_ACCESS),
+            (Mu1603Error::Rusb(rusb::Error::Busy), GLASS_ERR_BUSY),
+            (Mu1603Error::Rusb(rusb::Error::Timeout), GLASS_ERR_TIMEOUT),
+            (Mu1603Error::Rusb(rusb::Error::Pipe), GLASS_ERR_USB),
+            (Mu1603Error::FirstFrame, GLASS_ERR_TRUNCATED),
+            (Mu1603Error::Unimplemented, GLASS_ERR_UNIMPLEMENTED),
+            (Mu1603Error::NotStreaming, GLASS_ERR_NOT_STREAMING),
+            (Mu1603Error::NotConnected, GLASS_ERR_NO_DEVICE),
+            (Mu1603Error::Skipped, GLASS_ERR_SKIPPED),
+            (Mu1603Error::InvalidFrame("size"), GLASS_ERR_INVALID_FRAME),
+            (Mu1603Error::WorkerTerminated, GLASS_ERR_INTERNAL),
+        ];
+        for (err, code) in cases {
+            assert_eq!(error_code(err.clone()), code, "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn error_strings() {
+        let unknown = unsafe { CStr::from_ptr(glass_error_string(1)) };
+        assert_eq!(unknown.to_str().unwrap(), "unknown error");
+
+        let mut seen = Vec::new();
+        for &code in ALL_CODES {
+            let s = unsafe { CStr::from_ptr(glass_error_string(code)) };
+            let s = s.to_str().unwrap();
+            assert!(!s.is_empty() && s != "unknown error", "{}", code);
+            assert!(!seen.contains(&s), "{} is reused", s);
+            seen.push(s);
+        }
+    }
+
+    #[test]
+    fn null_arguments() {
+        let mut len = 0;
+        let mut buf = [0u8; 16];
+        unsafe {
+            assert_eq!(glass_enumerate(null_mut(), 0, null_mut()), GLASS_ERR_NULL);
+            assert_eq!(glass_enumerate(null_mut(), 1, &mut len), GLASS_ERR_NULL);
+            assert_eq!(glass_open_first(null_mut()), GLASS_ERR_NULL);
+            assert_eq!(glass_get_device_info(null(), null_mut()), GLASS_ERR_NULL);
+            assert_eq!(glass_start_stream(null_mut(), 0), GLASS_ERR_NULL);
+            assert_eq!(glass_stop_stream(null_mut()), GLASS_ERR_NULL);
+            assert_eq!(glass_get_options(null(), null_mut()), GLASS_ERR_NULL);
+            assert_eq!(glass_frame_size(null(), &mut len), GLASS_ERR_NULL);
+            assert_eq!(glass_read_frame(null_mut(), buf.as_mut_ptr(), 
+                buf.len(), null_mut()), GLASS_ERR_NULL);
+            glass_close(null_mut());
+
+            let mut cam = emulator_camera();
+            assert_eq!(glass_frame_size(&cam, null_mut()), GLASS_ERR_NULL);
+            assert_eq!(glass_get_options(&cam, null_mut()), GLASS_ERR_NULL);
+            assert_eq!(glass_read_frame(&mut cam, null_mut(), 16, null_mut()),
+                GLASS_ERR_NULL);
+        }
+    }
+
+    #[test]
+    fn read_frames() {
+        let mut cam = emulator_camera();
+        let mut len = 0;
+        let mut buf = vec![0u8; 16];
+        let mut info: GlassFrameInfo = unsafe { std::mem::zeroed() };
+        unsafe {
+            assert_eq!(glass_frame_size(&cam, &mut len), GLASS_ERR_NOT_STREAMING);
+            assert_eq!(glass_read_frame(&mut cam, buf.as_mut_ptr(), buf.len(),
+                &mut info), GLASS_ERR_NOT_STREAMING);
+            assert_eq!(glass_start_stream(&mut cam, 3), GLASS_ERR_INVALID_ARG);
+            assert_eq!(glass_start_stream(&mut cam, 2), GLASS_OK);
+
+            assert_eq!(glass_frame_size(&cam, &mut len), GLASS_OK);
+            assert_eq!(len, 1536 * 1160);
+            assert_eq!(glass_read_frame(&mut cam, buf.as_mut_ptr(), buf.len(),
+                &mut info), GLASS_ERR_BUFFER_TOO_SMALL);
+
+            // NOTE: A larger buffer is fine, and the rest is left alone
+            buf = vec![0xaa; len + 1];
+            for id in 1..=2 {
+                assert_eq!(glass_read_frame(&mut cam, buf.as_mut_ptr(), 
+                    buf.len(), &mut info), GLASS_OK);
+                assert_eq!(info.id, id);
+            }
+            assert_eq!((info.width, info.height), (1536, 1160));
+            let format = PixelFormat::Bayer8(Mu1603::PATTERN);
+            assert_eq!(info.format, format_to_c(format));
+            assert_eq!(info.bytes_per_pixel, 1);
+            assert_eq!(info.size, len);
+            assert_eq!(buf[len], 0xaa);
+            assert_eq!(glass_read_frame(&mut cam, buf.as_mut_ptr(), buf.len(),
+                null_mut()), GLASS_OK);
+
+            assert_eq!(glass_stop_stream(&mut cam), GLASS_OK);
+            assert_eq!(glass_frame_size(&cam, &mut len), GLASS_ERR_NOT_STREAMING);
+        }
+    }
+
+    /// Check that the header compiles, and agrees with the constants and 
+    /// struct layouts here.
+    #[test]
+    fn header() {
+        let dir = std::env::temp_dir().join(format!("glass-ffi-{}", 
+            std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("header.c");
+
+        let mut checks = String::new();
+        let names = [
+            "GLASS_OK", "GLASS_ERR_NULL", "GLASS_ERR_INVALID_ARG",
+            "GLASS_ERR_BUFFER_TOO_SMALL", "GLASS_ERR_NO_DEVICE",
+            "GLASS_ERR_ACCESS", "GLASS_ERR_BUSY", "GLASS_ERR_TIMEOUT",
+            "GLASS_ERR_USB", "GLASS_ERR_TRUNCATED", "GLASS_ERR_NOT_STREAMING",
+            "GLASS_ERR_SENSOR_CMD", "GLASS_ERR_UNIMPLEMENTED",
+            "GLASS_ERR_SKIPPED", "GLASS_ERR_INVALID_FRAME", "GLASS_ERR_INTERNAL",
+        ];
+        for (name, code) in names.iter().zip(ALL_CODES) {
+            checks += &format!("_Static_assert({} == {}, \"{}\");\n", 
+                name, code, name);
+        }
+        checks += &format!("_Static_assert(GLASS_API_VERSION == {}, \"version\");\n",
+            GLASS_API_VERSION);
+        for (name, size) in [
+            ("glass_device_info", std::mem::size_of::<GlassDeviceInfo>()),
+            ("glass_options", std::mem::size_of::<GlassOptions>()),
+            ("glass_frame_info", std::mem::size_of::<GlassFrameInfo>()),
+        ] {
+            checks += &format!("_Static_assert(sizeof({}) == {}, \"{}\");\n",
+                name, size, name);
+        }
+        checks += &format!(
+            "_Static_assert(offsetof(glass_frame_info, size) == {}, \"size\");\n",
+            std::mem::offset_of!(GlassFrameInfo, size));
+        checks += &format!(
+            "_Static_assert(offsetof(glass_device_info, serial) == {}, \"serial\");\n",
+            std::mem::offset_of!(GlassDeviceInfo, serial));
+
+        let include = concat!(env!("CARGO_MANIFEST_DIR"), "/include/glass.h");
+        std::fs::write(&src, format!("#include \"{}\"\n\n{}", include, checks))
+            .unwrap();
+
+        let cc = std::env::var("CC").unwrap_or("cc".to_string());
+        let res = std::process::Command::new(&cc)
+            .args([ "-std=c11", "-Wall", "-Werror", "-fsyntax-only" ])
+            .arg(&src)
+            .output();
+        let _ = std::fs::remove_dir_all(&dir);
+        match res {
+            Ok(out) => assert!(out.status.success(), "{}",
+                String::from_utf8_lossy(&out.stderr)),
+            // NOTE: Nothing to check with if there's no C compiler
+            Err(e) => eprintln!("[!] Couldn't run {}: {}", cc, e),
+        }
+    }
+}
@@ -4,6 +4,7 @@ use glow::HasContext;
 use crate::*;
 use std::sync::{Arc, RwLock};
 use crate::PixelData;
+use tracing::{ debug, warn, debug_span };
 
 /// OpenGL program used to recover an RGB image from raw sensor data. 
 ///
@@ -11,7 +12,7 @@ use crate::PixelData;
 ///
 /// - Your image sensor has a "Bayer filter", where each pixel of the 
 ///   output only represents the intensity of *a single color*
-/// - The pattern on your sensor is probably "RGGB" 
+/// - The pattern on your sensor might be any of the four [BayerPattern]s
 /// - We have to use some kind of algorithm (a "debayering" or "demosaicing" 
 ///   algorithm) to recover the full RGB values for each pixel
 ///
@@ -45,10 +46,13 @@ pub struct DemosaicQuad {
 
     width: usize,
     height: usize,
+    pattern: BayerPattern,
     initialized: bool,
 }
 impl DemosaicQuad {
-    pub fn new(width: usize, height: usize, capture: Arc<RwLock<PixelData>>) -> Self { 
+    pub fn new(width: usize, height: usize, pattern: BayerPattern,
+        capture: Arc<RwLock<PixelData>>) -> Self 
+    { 
         Self { 
             program: None,
             capture,
@@ -59,21 +63,36 @@ impl DemosaicQuad {
             output_texture: None,
             width, 
             height,
+            pattern,
             initialized: false,
         }
     }
 
-    /// Upload data to the input texture. 
-    ///
-    /// FIXME: We aren't validating the size of 'data' right now ...
-    pub fn update_input_texture(&mut self, gl: &glow::Context, data: &[u8]) {
+    /// Upload a frame to the input texture. 
+    pub fn update_input_texture(&mut self, gl: &glow::Context, frame: &PixelView) {
         if !self.is_initialized() {
             return;
         }
+        if frame.width() != self.width || frame.height() != self.height 
+            || frame.format() != PixelFormat::Bayer8(self.pattern)
+        {
+            warn!(width = frame.width(), height = frame.height(), 
+                format = ?frame.format(), "frame doesn't match the input texture");
+            return;
+        }
+        // NOTE: GL can skip padding between rows, but not between pixels
+        if !frame.has_contiguous_rows() {
+            warn!("can't upload a frame with gaps between pixels");
+            return;
+        }
+        let data = frame.bytes();
         unsafe { 
             gl.use_program(self.program);
             gl.active_texture(glow::TEXTURE0);
             gl.bind_texture(glow::TEXTURE_2D, self.input_texture);
+            gl.pixel_store_i32(glow::UNPACK_ALIGNMENT, 1);
+            let bpp = frame.format().bytes_per_pixel();
+            gl.pixel_store_i32(glow::UNPACK_ROW_LENGTH, (frame.stride() / bpp) as i32);
             gl.tex_sub_image_2d(
                 glow::TEXTURE_2D,
                 0,
@@ -85,10 +104,12 @@ impl DemosaicQuad {
                 glow::UNSIGNED_BYTE,
                 glow::PixelUnpackData::Slice(data)
             );
+            gl.pixel_store_i32(glow::UNPACK_ROW_LENGTH, 0);
         }
     }
 
     pub fn paint_to_fbo(&mut self, gl: &glow::Context, data: &[u8]) {
+        let _span = debug_span!("paint_to_fbo", len = data.len()).entered();
         unsafe { 
             gl.use_program(self.program);
 
@@ -144,7 +165,7 @@ impl DemosaicQuad {
 
             gl.draw_buffers(&[glow::COLOR_ATTACHMENT0]);
             if gl.check_framebuffer_status(glow::FRAMEBUFFER) != glow::FRAMEBUFFER_COMPLETE {
-                println!("framebuffer incomplete?");
+                warn!("framebuffer incomplete?");
                 return;
             }
 
@@ -156,7 +177,7 @@ impl DemosaicQuad {
             gl.draw_arrays(glow::TRIANGLE_STRIP, 0, 4);
 
             if let Ok(mut data) = self.capture.write() {
-                println!("reading fbo");
+                debug!(width = self.width, height = self.height, "reading fbo");
                 gl.bind_texture(glow::TEXTURE_2D, self.output_texture);
                 gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::LINEAR as _);
                 gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::LINEAR as _);
@@ -204,18 +225,21 @@ impl GlowProgram for DemosaicQuad {
 
     fn is_initialized(&self) -> bool { self.initialized }
 
-    // NOTE: This sensor is apparently BGGR (red component at [1,1]).
-    // This is configured with the 'firstRed' uniform. 
-    // Some other patterns for reference: 
+    // NOTE: The shader only needs to know where the red component is in
+    // each 2x2 block. This is configured with the 'firstRed' uniform [x, y]
+    // (see 'BayerPattern::red_offset'):
     //
-    // RGGB | GRGB | BGRG | BGGR
+    // RGGB | GRBG | GBRG | BGGR
     // -----+------+------+------
-    //  R G | G R  | B G  | B G
-    //  G B | G B  | R G  | G R
+    //  R G | G R  | G B  | B G
+    //  G B | B G  | R G  | G R
     // -----+------+------+------
-    // [0,0]| [0,1]| [1,0]| [1,1]
+    // [0,0]| [1,0]| [0,1]| [1,1]
     //
     fn init(&mut self, gl: &glow::Context) -> Result<(), String> {
+        let _span = debug_span!("demosaic_init", 
+            width = self.width, height = self.height
+        ).entered();
         let program = unsafe { 
             GlowHelper::compile_and_link(gl, Self::VERT_SRC, Self::FRAG_SRC)
         }?;
@@ -223,18 +247,17 @@ impl GlowProgram for DemosaicQuad {
         unsafe { 
             gl.use_program(self.program);
 
-            println!("DRAW_FRAMEBUFFER_BINDING = {}", 
-                gl.get_parameter_i32(glow::DRAW_FRAMEBUFFER_BINDING)
-            );
-            println!("READ_FRAMEBUFFER_BINDING = {}", 
-                gl.get_parameter_i32(glow::READ_FRAMEBUFFER_BINDING)
+            debug!(
+                draw = gl.get_parameter_i32(glow::DRAW_FRAMEBUFFER_BINDING),
+                read = gl.get_parameter_i32(glow::READ_FRAMEBUFFER_BINDING),
+                "framebuffer bindings"
             );
 
             // Allocate new framebuffer.
             // Allocate output texture [to-be-attached to framebuffer].
 
             let framebuffer = gl.create_framebuffer()?;
-            println!("fbo = {}", framebuffer.0);
+            debug!(fbo = framebuffer.0, "allocated framebuffer");
             self.fbo = Some(framebuffer);
             let output_texture = GlowHelper::allocate_bind_texture(&gl,
                 PixelFormat::RGB8,
@@ -280,7 +303,7 @@ impl GlowProgram for DemosaicQuad {
             // Create a texture for the input data
 
             let input_texture = GlowHelper::allocate_bind_texture(&gl,
-                PixelFormat::Bayer8(BayerPattern::BGGR), 
+                PixelFormat::Bayer8(self.pattern), 
                 self.width, self.height
             )?;
             self.input_texture = Some(input_texture);
@@ -290,7 +313,8 @@ impl GlowProgram for DemosaicQuad {
             let u_firstred = gl.get_uniform_location(
                 self.program.unwrap(), "firstRed"
             ).expect("");
-            gl.uniform_2_f32(Some(&u_firstred), 1.0, 1.0);
+            let (red_x, red_y) = self.pattern.red_offset();
+            gl.uniform_2_f32(Some(&u_firstred), red_x as f32, red_y as f32);
 
             let u_sourcesize = gl.get_uniform_location(
                 self.program.unwrap(), "sourceSize"
@@ -94,10 +94,13 @@ impl GlowHelper {
         width: usize,
     ) -> Result<glow::Texture, String>
     {
+        // NOTE: Only 8-bit formats for now (convert anything else first)
         let format = match fmt {
             PixelFormat::RGB8      => glow::RGB,
             PixelFormat::Bayer8(_) => glow::RED,
+            PixelFormat::Mono8     => glow::RED,
             PixelFormat::RGBA8     => glow::RGBA,
+            fmt => return Err(format!("Unsupported texture format {:?}", fmt)),
         };
 
         let texture = gl.create_texture()?;
@@ -1,6 +1,7 @@
 use std::sync::{Arc, RwLock};
 use std::sync::atomic::AtomicBool;
 use glass_common::*;
+use glass_mu1603::*;
 use chrono;
 
 pub struct AcquisitionState { 
@@ -40,7 +41,27 @@ impl AcquisitionState {
     }
 
     pub fn next_filename(&self) -> String { 
-        format!("/tmp/{}-{:04}.rgb8.raw", self.session_start.format("%d%m%y-%H%M"), self.count)
+        format!("/tmp/{}-{:04}.png", self.session_start.format("%d%m%y-%H%M"), self.count)
+    }
+
+    /// Describe an acquired image (written alongside the image itself). 
+    pub fn metadata(&self, data: &PixelData, info: Option<&DeviceInfo>, 
+        opts: Option<&Mu1603Options>) -> String
+    {
+        let mut res = String::new();
+        res.push_str(&format!("time         = {}\n", chrono::Local::now().to_rfc3339()));
+        res.push_str(&format!("format       = {:?}\n", data.format()));
+        res.push_str(&format!("width        = {}\n", data.width()));
+        res.push_str(&format!("height       = {}\n", data.height()));
+        if let Some(opts) = opts {
+            res.push_str(&format!("mode         = {:?}\n", opts.mode()));
+            res.push_str(&format!("exposure     = {}\n", opts.exposure()));
+            res.push_str(&format!("analog_gain  = {}%\n", opts.analog_gain_percent()));
+        }
+        if let Some(info) = info {
+            res.push_str(&info.to_string());
+        }
+        res
     }
 
 }
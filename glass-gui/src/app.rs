@@ -10,43 +10,78 @@ use std::collections::*;
 
 use crate::log::*;
 use crate::glow::*;
-use crate::ipc::*;
 use crate::acquire::*;
+use crate::ptc::*;
 use glass_mu1603::*;
 use glass_common::*;
+use tracing::{ debug, info, warn };
 
 #[derive(Debug)]
 pub enum AppError {
-    IpcFailure(ControlMessage),
+    IpcFailure(ControllerCommand),
+}
+
+/// Parse an exposure time typed into the exposure slider. 
+/// Accepts 'us', 'ms', and 's' suffixes; bare numbers are in microseconds. 
+fn parse_exposure_us(s: &str) -> Option<f64> {
+    let s = s.trim();
+    let (num, scale) = if let Some(n) = s.strip_suffix("us") {
+        (n, 1.0)
+    } else if let Some(n) = s.strip_suffix("ms") {
+        (n, 1e3)
+    } else if let Some(n) = s.strip_suffix('s') {
+        (n, 1e6)
+    } else {
+        (s, 1.0)
+    };
+    num.trim().parse::<f64>().ok().map(|v| v * scale)
 }
 
 /// Ephemeral state of the UI elements. 
 #[derive(Debug)]
 struct RequestedSettings { 
     pub mode: Mu1603Mode,
-    pub exposure_ms: usize,
+    pub exposure_us: usize,
     pub analog_gain_percent: usize,
+    /// Limit on the rate of frames read out [0 for no limit]
+    pub max_fps: f64,
 }
 impl Default for RequestedSettings { 
     fn default() -> Self { 
         Self { 
-            exposure_ms: 94,
+            exposure_us: ExposureTime::DEFAULT,
             analog_gain_percent: 100,
+            max_fps: 0.0,
             mode: Mu1603Mode::Mode1
         }
     }
 }
 
 pub struct MyApp {
-    // Channels used to communicate with the camera thread
-    chan: EguiThreadChannels,
+    /// Handle to the camera thread
+    controller: Mu1603Controller,
 
     // Queue of log entries to display in the UI
     log_entries: VecDeque<LogEntry>,
 
+    /// Tracing events to be added to the log panel
+    trace_rx: Receiver<TraceEvent>,
+
     /// The current state of the camera.
     cam_options: Option<Mu1603Options>,
 
+    /// Which frames the camera thread is reading out
+    frame_rate: FrameRate,
+
+    /// The exposure model used by the camera thread
+    exposure_model: ExposureModel,
+
+    /// Progress of the current long exposure
+    exposure_progress: Option<ExposureProgress>,
+
+    /// Information identifying the connected camera
+    device_info: Option<DeviceInfo>,
+
     /// Reflecting the state of requested camera settings [shown in the UI]
     req_settings: RequestedSettings,
 
@@ -56,6 +91,9 @@ pub struct MyApp {
     /// State associated with image acquisition
     acquire: AcquisitionState,
 
+    /// Viewer for photon transfer curves
+    ptc: PtcViewer,
+
     // FIXME: Replace use of these with 'acquire'
     /// Container for a demosaiced image acquired from the renderer
     acquire_data: Arc<RwLock<PixelData>>,
@@ -64,8 +102,8 @@ pub struct MyApp {
 
 }
 impl MyApp {
-    pub fn new(cc: &eframe::CreationContext<'_>, chan: EguiThreadChannels,
-        rgb_data: Arc<RwLock<PixelData>>,
+    pub fn new(cc: &eframe::CreationContext<'_>, controller: Mu1603Controller,
+        trace_rx: Receiver<TraceEvent>, exposure_model: ExposureModel,
     ) -> Self 
     { 
         // Adjust text size so I don't have to scale up the DPI
@@ -84,7 +122,17 @@ impl MyApp {
         //// State for glow usage via paint callbacks
         //let gl = cc.gl.as_ref().expect("No glow backend?");
 
-        // FIXME: This needs to match the dimensions of 'rgb_data'
+        // The preview only cares about the most recent frame.
+        // FIXME: This needs to scale with the configured camera resolution. 
+        // We are assuming use of mode 1. 
+        let preview_frames = controller.subscribe(DropPolicy::LatestOnly);
+        let preview_data = PixelData::new(
+            PixelFormat::Bayer8(Mu1603::PATTERN), 
+            Mu1603Mode::Mode1.width(), 
+            Mu1603Mode::Mode1.height()
+        );
+
+        // FIXME: This needs to match the dimensions of 'preview_data'
         // FIXME: Replace these with [AcquisitionState]
         let acquire_data = Arc::new(RwLock::new(PixelData::new(
             PixelFormat::RGB8, 
@@ -95,16 +143,24 @@ impl MyApp {
         let acquire_pending = Arc::new(AtomicBool::new(false));
 
         Self {
-            chan,
+            controller,
             req_settings: RequestedSettings::default(),
             log_entries: VecDeque::new(),
+            trace_rx,
             cam_options: None,
-            preview_glow: PreviewGlow::new(rgb_data, acquire_data_clone, acquire_pending.clone()),
+            frame_rate: FrameRate::All,
+            exposure_model,
+            exposure_progress: None,
+            device_info: None,
+            preview_glow: PreviewGlow::new(
+                preview_frames, preview_data, acquire_data_clone, acquire_pending.clone()
+            ),
             acquire: AcquisitionState::new(
                 PixelFormat::RGB8, 
                 Mu1603Mode::Mode1.width(),
                 Mu1603Mode::Mode1.height(),
             ),
+            ptc: PtcViewer::new(),
             // FIXME: Replace these with [AcquisitionState]
             acquire_data,
             acquire_pending,
@@ -116,9 +172,16 @@ impl MyApp {
     }
 
     pub fn push_log(&mut self, evt: LogEvent) {
-        println!("log: {:?}", evt);
+        info!(target: PANEL_TARGET, "{:?}", evt);
         self.log_entries.push_back(LogEntry::new(evt))
     }
+
+    /// Move pending tracing events into the log panel.
+    pub fn check_trace_events(&mut self) {
+        while let Ok(evt) = self.trace_rx.try_recv() {
+            self.log_entries.push_back(LogEntry::new(LogEvent::Trace(evt)));
+        }
+    }
 }
 
 
@@ -129,61 +192,81 @@ impl MyApp {
     //
     // FIXME: What if we never acquire the read lock? :x
     //
-    // FIXME: Maybe you should do this in a different thread?
-    //        Right now, we're just doing it in the egui thread.
-    //
     pub fn check_acquisition_thread(&mut self) {
         if self.acquire_pending.load(Ordering::Relaxed) { 
-            use std::io::Write;
             if let Ok(acquire_data) = self.acquire_data.read() {
                 let filename = self.acquire.next_filename();
-                let mut f = std::fs::File::create(&filename).unwrap();
-                f.write_all(&acquire_data.data).unwrap();
+
+                // Record where this image came from
+                let meta_filename = format!("{}.txt", filename);
+                let meta = self.acquire.metadata(&acquire_data,
+                    self.device_info.as_ref(), self.cam_options.as_ref()
+                );
+                let export_meta = ExportMetadata {
+                    description: Some(meta.clone()),
+                    ..ExportMetadata::now()
+                };
+
+                // NOTE: Compressing a full-size frame takes long enough to
+                // stall the UI, so only the copy happens on this thread.
+                let mut data = acquire_data.view().to_pixel_data();
+                data.id = acquire_data.id;
+                std::thread::spawn(move || {
+                    match data.save(&filename, &export_meta) {
+                        Ok(()) => info!(%filename, "wrote acquisition"),
+                        Err(e) => warn!(%filename, "couldn't write acquisition: {}", e),
+                    }
+                    if let Err(e) = std::fs::write(&meta_filename, meta) {
+                        warn!(%meta_filename, "couldn't write metadata: {}", e);
+                    }
+                });
+
                 self.acquire_pending.store(false, Ordering::Relaxed);
-                println!("wrote {}", filename);
             }
         }
     }
 
-    // FIXME: Both of these only consume at most *one* message.
+    // FIXME: This only consumes at most *one* event.
     //        Are there any cases where we might want to handle many at once?
     pub fn check_camera_thread(&mut self) {
 
-        // Receive frames from the camera thread.
-        match self.chan.frame_rx.try_recv() {
-            Ok(msg) => {
-                // NOTE: This is just simulating some work for now
-                self.log_entries.push_back(LogEntry::new(LogEvent::Msg(msg)));
-            },
-            Err(TryRecvError::Empty) => {},
-            Err(TryRecvError::Disconnected) => {
-                self.log_entries.push_back(
-                    LogEntry::new(LogEvent::LostThread)
-                );
-            },
-        }
-
         // Receive updates about the state of the camera thread.
-        match self.chan.state_rx.try_recv() {
-            Ok(msg) => {
-                self.push_log(LogEvent::CameraMsg(msg));
-                match msg { 
-                    CameraMessage::Connected(state) => {
+        match self.controller.events().try_recv() {
+            Ok(evt) => {
+                // Progress updates are too frequent to be worth logging
+                if !matches!(evt, ControllerEvent::ExposureProgress(_)) {
+                    self.push_log(LogEvent::CameraMsg(evt.clone()));
+                }
+                match evt { 
+                    ControllerEvent::Connected(info) => {
+                        self.device_info = Some(info);
+                    },
+                    ControllerEvent::Disconnected => {
+                        self.cam_options = None;
+                        self.device_info = None;
+                    },
+                    ControllerEvent::StreamStarted(state) => {
                         self.cam_options = Some(state);
                     },
-                    CameraMessage::Disconnected => {
+                    ControllerEvent::StreamStopped => {
                         self.cam_options = None;
                     },
-                    CameraMessage::ThreadInit => {},
-                    CameraMessage::StartStreaming => {},
-                    CameraMessage::UpdateAck(state) => {},
-                    CameraMessage::ConnectFailure(e) => {
-                        println!("connect failure: {:?}", e);
+                    ControllerEvent::ThreadInit => {},
+                    ControllerEvent::UpdateAck(state) => {
+                        self.cam_options = Some(state);
+                    },
+                    ControllerEvent::FrameRateAck(rate) => {
+                        self.frame_rate = rate;
+                    },
+                    ControllerEvent::ExposureProgress(p) => {
+                        self.exposure_progress = Some(p);
+                    },
+                    ControllerEvent::ConnectFailure(_) => {
                         self.cam_options = None;
                     },
-                    CameraMessage::Debug(msg) => {
-                        println!("{}", msg);
-                    }
+                    ControllerEvent::Captured(_) => {},
+                    ControllerEvent::Failure(_) => {},
+                    ControllerEvent::ReadFailure(_) => {},
                 }
             },
             Err(TryRecvError::Empty) => {},
@@ -227,9 +310,9 @@ impl MyApp {
 
             if connect_button_resp.clicked() {
                 if !camera_connected {
-                    self.chan.send_connect_request().unwrap();
+                    self.controller.connect().unwrap();
                 } else {
-                    self.chan.send_disconnect_request().unwrap();
+                    self.controller.disconnect().unwrap();
                 }
             }
         });
@@ -241,7 +324,7 @@ impl MyApp {
         let camera_connected = self.camera_connected();
         let (gain_desync, exp_desync, mode_desync) = if let Some(state) = self.cam_options {
             (state.analog_gain_percent() != self.req_settings.analog_gain_percent,
-             state.exposure_ms() != self.req_settings.exposure_ms,
+             state.exposure_us() != self.req_settings.exposure_us,
              state.mode != self.req_settings.mode)
         } 
         else { 
@@ -279,14 +362,17 @@ impl MyApp {
             });
             ui.add_space(20.0);
 
-            let exp_range  = 32..=256;
-            let exp_mut = &mut self.req_settings.exposure_ms;
+            let exp_range  = ExposureTime::MIN..=ExposureTime::MAX;
+            let exp_mut = &mut self.req_settings.exposure_us;
             let exp_slider = egui::Slider::new(exp_mut, exp_range)
-                .suffix("ms")
+                .logarithmic(true)
                 .text("Exposure")
                 .drag_value_speed(0.25)
                 .trailing_fill(true)
-                .custom_formatter(|val, _| format!("{:3}", val));
+                .custom_formatter(|val, _| {
+                    format!("{}", ExposureTime::new_from_us(val as usize))
+                })
+                .custom_parser(parse_exposure_us);
 
             let gain_range = 100..=300;
             let gain_mut = &mut self.req_settings.analog_gain_percent;
@@ -299,13 +385,49 @@ impl MyApp {
 
             ui.add(exp_slider);
             ui.add(again_slider);
+
+            // NOTE: Useful for time-lapse, or just to save some CPU
+            let fps_mut = &mut self.req_settings.max_fps;
+            ui.add(egui::Slider::new(fps_mut, 0.0..=30.0)
+                .text("Frame Limit")
+                .suffix(" fps")
+                .custom_formatter(|val, _| if val <= 0.0 {
+                    "off".to_string()
+                } else {
+                    format!("{:.2}", val)
+                })
+            );
+
+            // Show what we expect from the sensor before applying anything
+            let timing = SensorTiming::new(self.req_settings.mode, 
+                ExposureTime::new_from_us(self.req_settings.exposure_us),
+                &self.exposure_model,
+            );
+            ui.label(format!("Expected: {:.2} fps (max {:.2} fps)", 
+                timing.fps(), timing.max_fps()
+            ));
+            ui.small(format!("line {:.2}us, frame {:.1}ms, readout {:.1}ms", 
+                timing.line_time.as_secs_f64() * 1e6,
+                timing.frame_time.as_secs_f64() * 1e3,
+                timing.readout_time.as_secs_f64() * 1e3,
+            ));
             ui.add_space(20.0);
 
             let apply_button = egui::Button::new("Apply")
                 .min_size([100.0,50.0].into());
             let apply_button_resp = ui.add_enabled(camera_connected, apply_button);
             if apply_button_resp.enabled() && apply_button_resp.clicked() {
-                println!("{:?}", self.req_settings);
+                debug!(settings = ?self.req_settings, "apply");
+                if let Some(mut opts) = self.cam_options {
+                    *opts.exposure_mut() = ExposureTime::new_from_us(
+                        self.req_settings.exposure_us
+                    );
+                    self.controller.apply(opts).unwrap();
+                }
+                let rate = FrameRate::max_fps(self.req_settings.max_fps);
+                if rate != self.frame_rate {
+                    self.controller.set_frame_rate(rate).unwrap();
+                }
                 apply_button_resp.highlight();
             }
             if camera_connected {
@@ -333,6 +455,18 @@ impl MyApp {
                 self.push_log(LogEvent::Acquire);
                 self.acquire_pending.store(true, Ordering::Relaxed);
             }
+
+            // Only bother showing progress for long exposures
+            let long_exposure = self.cam_options
+                .map(|opts| opts.exposure().is_long())
+                .unwrap_or(false);
+            if let (true, Some(p)) = (long_exposure, self.exposure_progress) {
+                let bar = egui::ProgressBar::new(p.fraction()).text(format!(
+                    "Exposure {:.1}s / {:.1}s", 
+                    p.elapsed.as_secs_f32(), p.total.as_secs_f32()
+                ));
+                ui.add(bar);
+            }
         });
         ui.separator();
     }
@@ -379,11 +513,42 @@ impl MyApp {
             self.draw_camera_control(panel);
             self.draw_settings_control(panel);
             self.draw_acquisition_control(panel);
+            self.ptc.draw_control(panel);
 
             panel.heading("Info");
             panel.monospace(format!("egui frame: {:010}", ctx.frame_nr()));
+            if let Some(info) = &self.device_info {
+                panel.monospace(format!("serial: {}", 
+                    info.serial.as_deref().unwrap_or("?")
+                ));
+                panel.monospace(format!("frames: {}", self.frame_rate));
+                if self.camera_connected() {
+                    let stats = self.controller.stream_stats();
+                    panel.monospace(format!("{:.2} fps, {:.1} MB/s, jitter {:.2}ms",
+                        stats.fps, stats.mb_per_sec(),
+                        stats.jitter.unwrap_or_default().as_secs_f64() * 1e3
+                    ));
+                    let failures = egui::RichText::new(format!(
+                        "truncated {}, timeouts {}, errors {}, recovered {}",
+                        stats.truncated, stats.timeouts, stats.errors, stats.recoveries
+                    )).monospace();
+                    panel.label(if stats.failures() > 0 {
+                        failures.color(egui::Color32::YELLOW)
+                    } else {
+                        failures
+                    });
+                }
+                let speed = egui::RichText::new(info.speed_description());
+                panel.label(if info.is_bandwidth_limited() {
+                    speed.color(egui::Color32::YELLOW)
+                } else {
+                    speed
+                });
+            }
 
         });
+        self.ptc.draw_window(ctx);
+
         // Draw the log on the bottom panel
         egui::TopBottomPanel::bottom("Log").show(ctx, |log| 
         {
@@ -407,7 +572,7 @@ impl eframe::App for MyApp {
         if let Some(gl) = gl {
             self.preview_glow.destroy(gl);
         }
-        self.chan.ctl_tx.send(ControlMessage::Shutdown).unwrap();
+        self.controller.shutdown().unwrap();
     }
 
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
@@ -422,6 +587,9 @@ impl eframe::App for MyApp {
         // Handle pending messages from the acquisition thread
         self.check_acquisition_thread();
 
+        // Handle pending log messages
+        self.check_trace_events();
+
         // Draw the UI
         self.draw_ui(ctx);
     }
@@ -7,6 +7,8 @@ use eframe::glow::HasContext;
 
 use glass_common::*;
 use glass_glow::*;
+use glass_mu1603::*;
+use tracing::{ debug, warn };
 
 /// Container for all of the state associated with the camera preview.
 pub struct PreviewGlow {
@@ -18,13 +20,14 @@ pub struct PreviewGlow {
 }
 impl PreviewGlow {
     pub fn new(
-        rgb_data: Arc<RwLock<PixelData>>, 
+        frames: FrameSubscriber, 
+        last_frame: PixelData,
         acquire_data: Arc<RwLock<PixelData>>,
         acquire_pending: Arc<AtomicBool>,
     ) -> Self
     { 
         let preview = Arc::new(Mutex::new(Preview::new(
-                    rgb_data, acquire_data, acquire_pending
+                    frames, last_frame, acquire_data, acquire_pending
         )));
 
         let p: Arc<Mutex<Preview>> = preview.clone();
@@ -76,30 +79,36 @@ pub struct Preview {
     /// Shader used to demosaic raw data from the sensor
     program: DemosaicQuad,
 
-    /// Most-recent raw data from the sensor
-    pub raw_data: Arc<RwLock<PixelData>>,
+    /// Frames from the camera thread
+    pub frames: FrameSubscriber,
 
     pub acquire_data: Arc<RwLock<PixelData>>,
     pub acquire_pending: Arc<AtomicBool>,
+
+    /// Most-recent raw data from the sensor
     pub last_frame: PixelData,
 }
 impl Preview {
 
     pub fn new(
-        raw_data: Arc<RwLock<PixelData>>, 
+        frames: FrameSubscriber, 
+        last_frame: PixelData,
         acquire_data: Arc<RwLock<PixelData>>,
         acquire_pending: Arc<AtomicBool>,
     ) -> Self
     { 
-        let w = raw_data.read().unwrap().width();
-        let h = raw_data.read().unwrap().height();
-        let format = raw_data.read().unwrap().format();
+        let w = last_frame.width();
+        let h = last_frame.height();
+        let pattern = match last_frame.format() {
+            PixelFormat::Bayer8(pattern) => pattern,
+            fmt => panic!("Preview expects Bayer data, got {:?}", fmt),
+        };
         Self { 
-            program: DemosaicQuad::new(w, h, acquire_data.clone()),
-            last_frame: PixelData::new(format, w, h),
+            program: DemosaicQuad::new(w, h, pattern, acquire_data.clone()),
+            last_frame,
             acquire_data,
             acquire_pending,
-            raw_data,
+            frames,
         }
     }
 
@@ -122,17 +131,17 @@ impl Preview {
             }
         }
 
-        // Get read access to data from the sensor. 
-        // If the data has been updated, update our local copy.
-        if let Ok(lock) = self.raw_data.read() {
-            let remote_id = lock.frame_id();
-            if self.last_frame.frame_id() != remote_id {
-                self.last_frame.fill_from_slice(&lock.data).unwrap();
+        // If there's a new frame from the sensor, update our local copy.
+        if let Some(frame) = self.frames.latest() {
+            if let Err(e) = self.last_frame.fill_from_slice(&frame.data) {
+                warn!(frame = frame.id, "{}", e);
+            } else {
+                self.last_frame.increment_frame_id();
             }
         }
 
         // Upload new data to the texture and actually run the shader
-        self.program.update_input_texture(&gl, &self.last_frame.data);
+        self.program.update_input_texture(&gl, &self.last_frame.view());
         //self.program.clear_output_texture(&gl);
         self.program.paint(&gl);
 
@@ -140,7 +149,7 @@ impl Preview {
             return;
         } 
         else { 
-            println!("calling paint_to_fbo");
+            debug!("calling paint_to_fbo");
             self.program.paint_to_fbo(&gl, &self.last_frame.data);
         }
     }
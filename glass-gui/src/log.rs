@@ -1,7 +1,18 @@
 
 use chrono::{Utc, Local, DateTime, Datelike, Timelike};
 
-use crate::ipc::CameraMessage;
+use glass_mu1603::ControllerEvent;
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::mpsc::{ self, Sender, Receiver };
+use tracing::{ Event, Level, Subscriber };
+use tracing::field::{ Field, Visit };
+use tracing_subscriber::{ fmt, EnvFilter, Layer };
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::{ Context, SubscriberExt };
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
 
 pub struct LogEntry { 
     time: DateTime<Local>,
@@ -14,12 +25,15 @@ impl LogEntry {
 }
 impl std::fmt::Display for LogEntry { 
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:02}:{:02}.{:02}| {:?}", 
+        write!(f, "{:02}:{:02}.{:02}| ", 
             self.time.hour(),
             self.time.minute(),
             self.time.second(),
-            self.event
-        )
+        )?;
+        match &self.event { 
+            LogEvent::Trace(evt) => write!(f, "{}", evt),
+            evt => write!(f, "{:?}", evt),
+        }
     }
 }
 
@@ -30,8 +44,145 @@ pub enum LogEvent {
     Exposure(usize),
     AnalogGain(usize),
     Msg(usize),
-    CameraMsg(CameraMessage),
+    CameraMsg(ControllerEvent),
     LostThread,
+    Trace(TraceEvent),
+}
+
+
+
+/// Target used for events that are already in the log panel.
+pub const PANEL_TARGET: &str = "glass_gui::panel";
+
+/// Where log messages end up (see [init_tracing]).
+///
+/// These are read from the environment: 
+///
+/// - 'GLASS_LOG' sets the filter for stderr and the log file, ie. 'debug' 
+///   or 'info,glass_mu1603=trace' (defaults to 'info')
+/// - 'GLASS_LOG_PANEL' sets the level for the log panel (defaults to 'info')
+/// - 'GLASS_LOG_FILE' also writes messages to a file
+/// - 'GLASS_LOG_STDERR=0' stops writing messages to stderr
+///
+pub struct LogConfig { 
+    pub filter: String,
+    pub panel_level: LevelFilter,
+    pub file: Option<PathBuf>,
+    pub stderr: bool,
+}
+impl LogConfig { 
+    pub fn from_env() -> Self { 
+        let var = |name| std::env::var(name).ok();
+        Self { 
+            filter: var("GLASS_LOG").unwrap_or("info".to_string()),
+            panel_level: var("GLASS_LOG_PANEL")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(LevelFilter::INFO),
+            file: var("GLASS_LOG_FILE").map(PathBuf::from),
+            stderr: var("GLASS_LOG_STDERR").map(|s| s != "0").unwrap_or(true),
+        }
+    }
 }
 
+/// Install the global tracing subscriber. 
+///
+/// Returns a channel for receiving the events meant for the log panel. 
+pub fn init_tracing(cfg: &LogConfig) -> Receiver<TraceEvent> {
+    let (tx, rx) = mpsc::channel();
+
+    let env_filter = || EnvFilter::try_new(&cfg.filter)
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let stderr = cfg.stderr.then(|| {
+        fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_filter(env_filter())
+    });
+
+    let file = cfg.file.as_ref().and_then(|path| {
+        match std::fs::File::create(path) { 
+            Ok(f) => Some(fmt::layer()
+                .with_ansi(false)
+                .with_writer(Mutex::new(f))
+                .with_filter(env_filter())
+            ),
+            Err(e) => {
+                eprintln!("Couldn't open log file {}: {}", path.display(), e);
+                None
+            },
+        }
+    });
+
+    let panel = PanelLayer { tx }.with_filter(cfg.panel_level);
+
+    tracing_subscriber::registry()
+        .with(stderr)
+        .with(file)
+        .with(panel)
+        .init();
+    rx
+}
 
+/// A tracing event forwarded to the log panel.
+#[derive(Debug)]
+pub struct TraceEvent { 
+    pub level: Level,
+    /// Names of the enclosing spans, ie. 'start_stream:phase'
+    pub spans: String,
+    pub message: String,
+}
+impl std::fmt::Display for TraceEvent { 
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:5} ", self.level)?;
+        if !self.spans.is_empty() { 
+            write!(f, "{}: ", self.spans)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Collects the fields of an event into a single line.
+#[derive(Default)]
+struct FieldVisitor { 
+    message: String,
+    fields: String,
+}
+impl Visit for FieldVisitor { 
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        use std::fmt::Write;
+        if field.name() == "message" { 
+            let _ = write!(self.message, "{:?}", value);
+        } else { 
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Layer that forwards events to the log panel.
+struct PanelLayer { 
+    tx: Sender<TraceEvent>,
+}
+impl<S> Layer<S> for PanelLayer 
+    where S: Subscriber + for<'a> LookupSpan<'a>
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        // These were put in the log panel directly
+        if event.metadata().target() == PANEL_TARGET { 
+            return;
+        }
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let spans = ctx.event_scope(event)
+            .map(|scope| scope.from_root()
+                .map(|span| span.name())
+                .collect::<Vec<_>>()
+                .join(":")
+            )
+            .unwrap_or_default();
+        let _ = self.tx.send(TraceEvent { 
+            level: *event.metadata().level(),
+            spans,
+            message: visitor.message + &visitor.fields,
+        });
+    }
+}
@@ -0,0 +1,165 @@
+//! Viewer for photon transfer curves captured with 'glass-ptc'.
+
+use eframe::egui;
+use egui_plot::{ Legend, Line, Plot, PlotPoints, Points };
+use glass_common::*;
+use glass_mu1603::*;
+
+fn channel_color(ch: BayerChannel) -> egui::Color32 {
+    match ch {
+        BayerChannel::Red => egui::Color32::from_rgb(0xe0, 0x40, 0x40),
+        BayerChannel::GreenR => egui::Color32::from_rgb(0x40, 0xc0, 0x40),
+        BayerChannel::GreenB => egui::Color32::from_rgb(0x20, 0x80, 0x60),
+        BayerChannel::Blue => egui::Color32::from_rgb(0x40, 0x80, 0xf0),
+    }
+}
+
+pub struct PtcViewer {
+    /// Path to the CSV file [shown in the UI]
+    pub path: String,
+
+    /// Whether or not the plot window is open
+    pub open: bool,
+
+    capture: PtcCapture,
+    noise: Vec<NoiseProfile>,
+
+    /// Index of the series being plotted
+    selected: usize,
+
+    /// The error from the last attempt to load a file
+    error: Option<String>,
+}
+impl PtcViewer {
+    pub fn new() -> Self {
+        Self {
+            path: "ptc.csv".to_string(),
+            open: false,
+            capture: PtcCapture::default(),
+            noise: Vec::new(),
+            selected: 0,
+            error: None,
+        }
+    }
+
+    pub fn load(&mut self) {
+        let res = std::fs::read_to_string(&self.path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| PtcCapture::parse_csv(&s).map_err(|e| e.to_string()));
+        match res {
+            Ok(capture) => {
+                self.noise = capture.analyse();
+                self.capture = capture;
+                self.selected = 0;
+                self.error = None;
+                self.open = true;
+            },
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    /// Draw the controls for loading a file.
+    pub fn draw_control(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Photon Transfer");
+        ui.horizontal(|ui| {
+            ui.text_edit_singleline(&mut self.path);
+            if ui.button("Load").clicked() {
+                self.load();
+            }
+        });
+        if let Some(e) = &self.error {
+            ui.colored_label(egui::Color32::RED, e);
+        }
+        ui.separator();
+    }
+
+    /// Draw the plot window.
+    pub fn draw_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.open;
+        egui::Window::new("Photon Transfer Curve")
+            .open(&mut open)
+            .default_size([1200.0, 900.0])
+            .show(ctx, |ui| self.draw_plot(ui));
+        self.open = open;
+    }
+
+    fn draw_plot(&mut self, ui: &mut egui::Ui) {
+        if self.capture.flat.is_empty() {
+            ui.label("No flat series in this file");
+            return;
+        }
+        ui.horizontal(|ui| {
+            for (idx, s) in self.capture.flat.iter().enumerate() {
+                let text = format!("gain {}%, {}-bit",
+                    s.analog_gain.percent(), s.bitdepth.bits());
+                ui.selectable_value(&mut self.selected, idx, text);
+            }
+        });
+
+        let flat = &self.capture.flat[self.selected];
+        let dark = self.capture.dark_for(flat);
+        let noise = &self.noise[self.selected];
+
+        egui::Grid::new("ptc_figures").striped(true).show(ui, |ui| {
+            for h in ["", "e-/ADU", "read [e-]", "full well [e-]", "dark [e-/s]", "black [ADU]"] {
+                ui.strong(h);
+            }
+            ui.end_row();
+            let opt = |v: Option<f64>| v.map(|v| format!("{:.2}", v))
+                .unwrap_or("-".to_string());
+            for (ch, c) in BayerChannel::ALL.iter().zip(noise.channels.iter()) {
+                ui.label(ch.name());
+                match c {
+                    Some(c) => {
+                        ui.monospace(format!("{:.3}", c.conversion_gain));
+                        ui.monospace(format!("{:.2}", c.read_noise));
+                        ui.monospace(opt(c.full_well));
+                        ui.monospace(opt(c.dark_current));
+                        ui.monospace(format!("{:.2}", c.black_level));
+                    },
+                    None => { ui.label("not enough usable points"); },
+                }
+                ui.end_row();
+            }
+        });
+
+        // NOTE: egui_plot doesn't have log axes, so we plot log10 values.
+        Plot::new("ptc_plot")
+            .legend(Legend::default())
+            .x_axis_label("log10 signal [ADU]")
+            .y_axis_label("log10 noise [ADU RMS]")
+            .show(ui, |plot_ui|
+        {
+            for ch in BayerChannel::ALL {
+                let i = ch.index();
+                let black = noise.channels[i].map(|c| c.black_level).unwrap_or(0.0);
+                let points: Vec<[f64; 2]> = flat.points.iter().filter_map(|p| {
+                    let dark_mean = dark
+                        .and_then(|d| d.points.iter().find(|d| d.exposure == p.exposure))
+                        .map(|d| d.means[i])
+                        .unwrap_or(black);
+                    let signal = p.means[i] - dark_mean;
+                    (signal > 0.0 && p.variances[i] > 0.0).then(|| {
+                        [signal.log10(), p.variances[i].sqrt().log10()]
+                    })
+                }).collect();
+                let color = channel_color(ch);
+                let max_signal = points.iter().map(|p| p[0]).fold(0.0, f64::max);
+                plot_ui.points(Points::new(PlotPoints::new(points))
+                    .color(color).radius(4.0).name(ch.name())
+                );
+
+                // The model: shot noise and read noise
+                if let Some(c) = noise.channels[i] {
+                    let read_adu = c.read_noise / c.conversion_gain;
+                    let model: PlotPoints = (0..=100).map(|n| {
+                        let x = -1.0 + (max_signal + 1.0) * n as f64 / 100.0;
+                        let s = 10f64.powf(x);
+                        [x, (s / c.conversion_gain + read_adu.powi(2)).sqrt().log10()]
+                    }).collect();
+                    plot_ui.line(Line::new(model).color(color).name(ch.name()));
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,377 @@
+//! Delivering frames to any number of consumers.
+//!
+//! Each consumer registers a [FrameSubscriber] with its own [DropPolicy].
+//! Frames are shared between subscribers with [Arc], so the data is never
+//! copied no matter how many consumers there are.
+
+use super::*;
+use std::collections::VecDeque;
+use std::sync::{ Arc, Weak, Mutex, Condvar };
+use std::sync::mpsc::{ RecvError, TryRecvError, RecvTimeoutError };
+use std::task::{ Context as TaskContext, Poll, Waker };
+
+/// What to do when a subscriber isn't keeping up with the camera.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Only keep the most recent frame (ie. for a preview)
+    LatestOnly,
+
+    /// Keep up to this many frames, dropping the oldest ones first
+    Bounded(usize),
+
+    /// Never drop frames.
+    ///
+    /// NOTE: The queue grows without bound if the consumer stalls,
+    /// so this should only be used by consumers that can keep up
+    /// (ie. a recorder writing to a fast disk).
+    Lossless,
+}
+impl DropPolicy {
+    /// Maximum number of queued frames, if any.
+    pub fn capacity(&self) -> Option<usize> {
+        match self {
+            Self::LatestOnly => Some(1),
+            Self::Bounded(n) => Some((*n).max(1)),
+            Self::Lossless => None,
+        }
+    }
+}
+
+/// Queue of frames for a single subscriber.
+struct FrameQueue {
+    frames: VecDeque<Arc<Frame>>,
+    /// Number of frames dropped because of the [DropPolicy]
+    dropped: usize,
+    /// Set when no more frames will be published
+    closed: bool,
+    /// Task waiting in [FrameSubscriber::poll_recv]
+    waker: Option<Waker>,
+}
+
+/// State shared between a [FrameSubscriber] and the [FrameBroadcast].
+struct Shared {
+    policy: DropPolicy,
+    queue: Mutex<FrameQueue>,
+    ready: Condvar,
+}
+impl Shared {
+    fn push(&self, frame: Arc<Frame>) {
+        let mut q = self.queue.lock().unwrap();
+        if let Some(cap) = self.policy.capacity() {
+            while q.frames.len() >= cap {
+                q.frames.pop_front();
+                q.dropped += 1;
+            }
+        }
+        q.frames.push_back(frame);
+        self.ready.notify_one();
+        let waker = q.waker.take();
+        drop(q);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    fn close(&self) {
+        let mut q = self.queue.lock().unwrap();
+        q.closed = true;
+        self.ready.notify_all();
+        let waker = q.waker.take();
+        drop(q);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// The set of subscribers registered with a [FrameBroadcast].
+#[derive(Default)]
+struct Subscribers {
+    list: Vec<Weak<Shared>>,
+    /// Set by [FrameBroadcast::close]
+    closed: bool,
+}
+
+/// The sending half of a frame subscription.
+///
+/// Clones refer to the same set of subscribers.
+#[derive(Clone, Default)]
+pub struct FrameBroadcast {
+    subscribers: Arc<Mutex<Subscribers>>,
+}
+impl FrameBroadcast {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a new consumer.
+    ///
+    /// NOTE: After [FrameBroadcast::close], this returns a subscriber that
+    /// is already closed.
+    pub fn subscribe(&self, policy: DropPolicy) -> FrameSubscriber {
+        let mut subs = self.subscribers.lock().unwrap();
+        let shared = Arc::new(Shared {
+            policy,
+            queue: Mutex::new(FrameQueue {
+                frames: VecDeque::new(),
+                dropped: 0,
+                closed: subs.closed,
+                waker: None,
+            }),
+            ready: Condvar::new(),
+        });
+        if !subs.closed {
+            subs.list.push(Arc::downgrade(&shared));
+        }
+        FrameSubscriber { shared }
+    }
+
+    /// Number of live subscribers.
+    pub fn subscriber_count(&self) -> usize {
+        let mut subs = self.subscribers.lock().unwrap();
+        subs.list.retain(|s| s.strong_count() > 0);
+        subs.list.len()
+    }
+
+    /// Deliver a frame to all subscribers.
+    pub fn publish(&self, frame: Arc<Frame>) {
+        let mut subs = self.subscribers.lock().unwrap();
+        subs.list.retain(|s| match s.upgrade() {
+            Some(shared) => { shared.push(frame.clone()); true },
+            // The subscriber was dropped
+            None => false,
+        });
+    }
+
+    /// Tell all subscribers that no more frames are coming.
+    ///
+    /// Subscribers can still drain any frames that are already queued.
+    pub fn close(&self) {
+        let mut subs = self.subscribers.lock().unwrap();
+        subs.closed = true;
+        for shared in subs.list.drain(..).filter_map(|s| s.upgrade()) {
+            shared.close();
+        }
+    }
+}
+
+/// The receiving half of a frame subscription (see [FrameBroadcast]).
+///
+/// This behaves like a [std::sync::mpsc::Receiver].
+/// Dropping this unsubscribes from the broadcast.
+pub struct FrameSubscriber {
+    shared: Arc<Shared>,
+}
+impl FrameSubscriber {
+    /// The [DropPolicy] for this subscriber.
+    pub fn policy(&self) -> DropPolicy {
+        self.shared.policy
+    }
+
+    /// Number of frames dropped because this subscriber wasn't keeping up.
+    pub fn dropped(&self) -> usize {
+        self.shared.queue.lock().unwrap().dropped
+    }
+
+    /// Number of frames waiting to be received.
+    pub fn len(&self) -> usize {
+        self.shared.queue.lock().unwrap().frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the next frame without blocking.
+    pub fn try_recv(&self) -> Result<Arc<Frame>, TryRecvError> {
+        let mut q = self.shared.queue.lock().unwrap();
+        match q.frames.pop_front() {
+            Some(frame) => Ok(frame),
+            None if q.closed => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Block until the next frame is available.
+    pub fn recv(&self) -> Result<Arc<Frame>, RecvError> {
+        let mut q = self.shared.queue.lock().unwrap();
+        loop {
+            if let Some(frame) = q.frames.pop_front() {
+                return Ok(frame);
+            }
+            if q.closed {
+                return Err(RecvError);
+            }
+            q = self.shared.ready.wait(q).unwrap();
+        }
+    }
+
+    /// Block until the next frame is available, or until the timeout expires.
+    pub fn recv_timeout(&self, timeout: Duration)
+        -> Result<Arc<Frame>, RecvTimeoutError>
+    {
+        let deadline = Instant::now() + timeout;
+        let mut q = self.shared.queue.lock().unwrap();
+        loop {
+            if let Some(frame) = q.frames.pop_front() {
+                return Ok(frame);
+            }
+            if q.closed {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            q = self.shared.ready.wait_timeout(q, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Poll for the next frame from an async task.
+    ///
+    /// Returns 'None' once the broadcast is closed and the queue is empty.
+    /// Otherwise, the task is woken when the next frame is published.
+    pub fn poll_recv(&self, cx: &mut TaskContext<'_>) 
+        -> Poll<Option<Arc<Frame>>>
+    {
+        let mut q = self.shared.queue.lock().unwrap();
+        if let Some(frame) = q.frames.pop_front() {
+            return Poll::Ready(Some(frame));
+        }
+        if q.closed {
+            return Poll::Ready(None);
+        }
+        q.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Return the most recent frame (without blocking), discarding any
+    /// older frames that are still queued.
+    pub fn latest(&self) -> Option<Arc<Frame>> {
+        let mut q = self.shared.queue.lock().unwrap();
+        let frame = q.frames.pop_back();
+        q.frames.clear();
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(id: usize) -> Arc<Frame> {
+        Arc::new(Frame {
+            id,
+            timestamp: Instant::now(),
+            options: Mu1603Config::default().options,
+            skipped: 0,
+            data: Vec::new(),
+        })
+    }
+
+    fn ids(sub: &FrameSubscriber) -> Vec<usize> {
+        std::iter::from_fn(|| sub.try_recv().ok()).map(|f| f.id).collect()
+    }
+
+    #[test]
+    fn latest_only() {
+        let tx = FrameBroadcast::new();
+        let rx = tx.subscribe(DropPolicy::LatestOnly);
+        for id in 0..5 {
+            tx.publish(frame(id));
+        }
+        assert_eq!(rx.len(), 1);
+        assert_eq!(rx.dropped(), 4);
+        assert_eq!(ids(&rx), vec![4]);
+    }
+
+    #[test]
+    fn bounded() {
+        let tx = FrameBroadcast::new();
+        let rx = tx.subscribe(DropPolicy::Bounded(3));
+        let all = tx.subscribe(DropPolicy::Lossless);
+        for id in 0..5 {
+            tx.publish(frame(id));
+        }
+        assert_eq!(rx.dropped(), 2);
+        assert_eq!(ids(&rx), vec![2, 3, 4]);
+        assert_eq!(all.dropped(), 0);
+        assert_eq!(ids(&all), vec![0, 1, 2, 3, 4]);
+
+        // Frames are shared between subscribers
+        tx.publish(frame(5));
+        let a = rx.recv().unwrap();
+        let b = all.recv().unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+    }
+
+    #[test]
+    fn dropped_subscribers() {
+        let tx = FrameBroadcast::new();
+        let rx = tx.subscribe(DropPolicy::Lossless);
+        drop(tx.subscribe(DropPolicy::Lossless));
+        assert_eq!(tx.subscriber_count(), 1);
+        drop(rx);
+        tx.publish(frame(0));
+        assert_eq!(tx.subscriber_count(), 0);
+    }
+
+    #[test]
+    fn close_drains_queue() {
+        let tx = FrameBroadcast::new();
+        let rx = tx.subscribe(DropPolicy::Lossless);
+        tx.publish(frame(0));
+        tx.close();
+        assert_eq!(rx.recv().unwrap().id, 0);
+        assert!(rx.recv().is_err());
+        assert!(matches!(rx.try_recv(), Err(TryRecvError::Disconnected)));
+    }
+
+    #[test]
+    fn poll_recv() {
+        use std::sync::atomic::{ AtomicUsize, Ordering };
+        use std::task::Wake;
+
+        #[derive(Default)]
+        struct Count(AtomicUsize);
+        impl Wake for Count {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let count = Arc::new(Count::default());
+        let waker = Waker::from(count.clone());
+        let mut cx = TaskContext::from_waker(&waker);
+        let woken = || count.0.load(Ordering::SeqCst);
+
+        let tx = FrameBroadcast::new();
+        let rx = tx.subscribe(DropPolicy::Lossless);
+        assert!(rx.poll_recv(&mut cx).is_pending());
+        tx.publish(frame(0));
+        assert_eq!(woken(), 1);
+        // Publishing again doesn't wake a task that isn't waiting
+        tx.publish(frame(1));
+        assert_eq!(woken(), 1);
+        assert!(matches!(rx.poll_recv(&mut cx), Poll::Ready(Some(f)) if f.id == 0));
+        assert!(matches!(rx.poll_recv(&mut cx), Poll::Ready(Some(f)) if f.id == 1));
+
+        assert!(rx.poll_recv(&mut cx).is_pending());
+        tx.close();
+        assert_eq!(woken(), 2);
+        assert!(matches!(rx.poll_recv(&mut cx), Poll::Ready(None)));
+    }
+
+    #[test]
+    fn subscribe_after_close() {
+        let tx = FrameBroadcast::new();
+        tx.close();
+        let rx = tx.subscribe(DropPolicy::LatestOnly);
+        assert_eq!(tx.subscriber_count(), 0);
+        assert!(matches!(rx.try_recv(), Err(TryRecvError::Disconnected)));
+        assert!(matches!(rx.recv_timeout(Duration::from_secs(1)),
+            Err(RecvTimeoutError::Disconnected)
+        ));
+        assert!(rx.recv().is_err());
+    }
+}
@@ -0,0 +1,204 @@
+//! Configuring the driver before opening the device.
+
+use super::*;
+
+/// Configuration used when opening and driving the camera.
+///
+/// The defaults match the associated constants on [Mu1603].
+/// See [Mu1603Builder] for a description of each option.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Mu1603Config {
+    pub control_timeout: Duration,
+    pub bulk_timeout: Duration,
+    pub progress_interval: Duration,
+    pub chunk_size: usize,
+    pub configuration: u8,
+    pub interface: u8,
+    pub detach_kernel_driver: bool,
+    pub options: Mu1603Options,
+    pub exposure_model: ExposureModel,
+    pub frame_rate: FrameRate,
+}
+impl Default for Mu1603Config {
+    fn default() -> Self {
+        Self {
+            control_timeout: Mu1603::TIMEOUT,
+            bulk_timeout: Mu1603::BULK_TIMEOUT,
+            progress_interval: Mu1603::PROGRESS_INTERVAL,
+            chunk_size: Mu1603::CHUNK,
+            configuration: Mu1603::CONFIGURATION,
+            interface: Mu1603::INTERFACE,
+            detach_kernel_driver: true,
+            options: Mu1603Options {
+                id: 0,
+                mode: Mu1603::DEFAULT_MODE,
+                exposure: ExposureTime::default(),
+                analog_gain: AnalogGain::default(),
+                bitdepth: Mu1603BitDepth::Depth8,
+            },
+            exposure_model: ExposureModel::default(),
+            frame_rate: FrameRate::All,
+        }
+    }
+}
+
+/// Builder for opening a [Mu1603] with non-default settings.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Mu1603Builder {
+    cfg: Mu1603Config,
+}
+impl Mu1603Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Timeout for USB control transfers.
+    pub fn control_timeout(mut self, timeout: Duration) -> Self {
+        self.cfg.control_timeout = timeout;
+        self
+    }
+
+    /// Timeout for USB bulk transfers while reading a frame.
+    ///
+    /// NOTE: This is extended by the exposure time while we're waiting for
+    /// the first chunk of a frame.
+    pub fn bulk_timeout(mut self, timeout: Duration) -> Self {
+        self.cfg.bulk_timeout = timeout;
+        self
+    }
+
+    /// Polling interval for reporting progress during long exposures.
+    pub fn progress_interval(mut self, interval: Duration) -> Self {
+        self.cfg.progress_interval = interval;
+        self
+    }
+
+    /// Size of each bulk transfer while reading a frame [in bytes].
+    ///
+    /// NOTE: A short transfer marks the end of a frame, so this needs to
+    /// be a multiple of the endpoint's maximum packet size. Opening the
+    /// device fails with [rusb::Error::InvalidParam] otherwise.
+    pub fn chunk_size(mut self, len: usize) -> Self {
+        self.cfg.chunk_size = len;
+        self
+    }
+
+    /// USB configuration to activate.
+    pub fn configuration(mut self, num: u8) -> Self {
+        self.cfg.configuration = num;
+        self
+    }
+
+    /// USB interface to claim.
+    pub fn interface(mut self, num: u8) -> Self {
+        self.cfg.interface = num;
+        self
+    }
+
+    /// Whether or not to detach an active kernel driver from the interface.
+    pub fn detach_kernel_driver(mut self, detach: bool) -> Self {
+        self.cfg.detach_kernel_driver = detach;
+        self
+    }
+
+    /// Initial settings used by [Mu1603::start].
+    pub fn options(mut self, options: Mu1603Options) -> Self {
+        self.cfg.options = options;
+        self
+    }
+
+    /// Initial mode used by [Mu1603::start].
+    pub fn mode(mut self, mode: Mu1603Mode) -> Self {
+        self.cfg.options.mode = mode;
+        self
+    }
+
+    /// Parameters used to convert exposure times into register values.
+    ///
+    /// See [ExposureModel::load] for using a calibration file.
+    pub fn exposure_model(mut self, model: ExposureModel) -> Self {
+        self.cfg.exposure_model = model;
+        self
+    }
+
+    /// Which frames are delivered while streaming (see [Mu1603::set_frame_rate]).
+    pub fn frame_rate(mut self, rate: FrameRate) -> Self {
+        self.cfg.frame_rate = rate;
+        self
+    }
+
+    /// Return the resulting configuration.
+    pub fn config(&self) -> Mu1603Config {
+        self.cfg
+    }
+
+    /// Try to obtain a handle to the camera.
+    ///
+    /// If there's more than one, this opens whichever one libusb finds 
+    /// first (see [Mu1603Builder::open_at]).
+    pub fn open(self, ctx: &mut Context) -> rusb::Result<Mu1603> {
+        match ctx.open_device_with_vid_pid(Mu1603::VID, Mu1603::PID) {
+            Some(handle) => self.open_handle(handle),
+            None => Err(rusb::Error::NoDevice),
+        }
+    }
+
+    /// Try to obtain a handle to the camera at a particular bus number and 
+    /// address (see [Mu1603::enumerate]).
+    pub fn open_at(self, ctx: &mut Context, bus: u8, address: u8) 
+        -> rusb::Result<Mu1603> 
+    {
+        let device = ctx.devices()?.iter()
+            .find(|d| d.bus_number() == bus && d.address() == address)
+            .ok_or(rusb::Error::NoDevice)?;
+        let desc = device.device_descriptor()?;
+        if desc.vendor_id() != Mu1603::VID || desc.product_id() != Mu1603::PID {
+            return Err(rusb::Error::NoDevice);
+        }
+        self.open_handle(device.open()?)
+    }
+
+    fn open_handle(self, handle: DeviceHandle<Context>) -> rusb::Result<Mu1603> {
+        let cfg = self.cfg;
+        if cfg.detach_kernel_driver {
+            if let Ok(true) = handle.kernel_driver_active(cfg.interface) {
+                handle.detach_kernel_driver(cfg.interface)?;
+            }
+        }
+        handle.set_active_configuration(cfg.configuration)?;
+        let max_packet_size = Self::max_packet_size(&handle, cfg.interface)?;
+        if cfg.chunk_size == 0 || !cfg.chunk_size.is_multiple_of(max_packet_size) {
+            warn!(chunk_size = cfg.chunk_size, max_packet_size,
+                "chunk size must be a multiple of the max packet size"
+            );
+            return Err(rusb::Error::InvalidParam);
+        }
+        handle.claim_interface(cfg.interface)?;
+        let info = Mu1603::read_device_info(&handle, cfg.control_timeout)?;
+        Ok(Mu1603 {
+            handle,
+            cfg,
+            state: None,
+            prev_state: None,
+            exposure_start: Instant::now(),
+            limiter: FrameLimiter::new(cfg.frame_rate),
+            monitor: StreamMonitor::default(),
+            info,
+        })
+    }
+
+    /// Return the maximum packet size for [Mu1603::ENDPOINT].
+    fn max_packet_size(handle: &DeviceHandle<Context>, interface: u8)
+        -> rusb::Result<usize>
+    {
+        let config = handle.device().active_config_descriptor()?;
+        config.interfaces()
+            .filter(|i| i.number() == interface)
+            .flat_map(|i| i.descriptors())
+            .flat_map(|d| d.endpoint_descriptors())
+            .find(|e| e.address() == Mu1603::ENDPOINT)
+            .map(|e| e.max_packet_size() as usize)
+            .filter(|len| *len != 0)
+            .ok_or(rusb::Error::NotFound)
+    }
+}
@@ -0,0 +1,174 @@
+//! Loading and saving the parameters used by the exposure model.
+//!
+//! The defaults are the educated guesses from [ExposureTime]; a calibrated
+//! model can be produced by running an [ExposureSweep] against the device.
+//! Calibration files use a simple 'key = value' format:
+//!
+//! ```text
+//! # Comments start with '#'
+//! cycles_per_us = 54.0
+//! min_lines     = 10
+//! line_offset   = 0.0
+//! ```
+
+use super::*;
+use glass_common::{ config_lines, split_key_value };
+
+/// An error that occurred while loading a calibration file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CalibrationError {
+    /// Line number (starting from 1)
+    pub line: usize,
+    pub msg: String,
+}
+impl std::fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+/// Parameters relating exposure times to the sensor registers
+/// (see [ExposureTime::convert_with]).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ExposureModel {
+    /// Sensor clock cycles per microsecond
+    pub cycles_per_us: f64,
+
+    /// Minimum number of lines between the shutter and vsync
+    pub min_lines: usize,
+
+    /// Lines of integration that aren't accounted for by the registers.
+    ///
+    /// NOTE: A positive value means that the sensor integrates for longer
+    /// than the register values suggest.
+    pub line_offset: f64,
+}
+impl Default for ExposureModel {
+    fn default() -> Self {
+        Self {
+            cycles_per_us: ExposureTime::CYCLES_PER_US as f64,
+            min_lines: ExposureTime::MIN_CYCLES_PER_LINE,
+            line_offset: 0.0,
+        }
+    }
+}
+impl ExposureModel {
+    /// Time spent on a single line in this mode [in microseconds].
+    pub fn line_time_us(&self, mode: Mu1603Mode) -> f64 {
+        mode.cycles_per_line() as f64 / self.cycles_per_us
+    }
+
+    /// Number of lines integrated for some register values
+    /// (see [ExposureTime::convert_with]).
+    pub fn integration_lines(&self, regs: (u16, u16, u16)) -> f64 {
+        let (val1064, val4000, val5000) = regs;
+        let vsync = ((val4000 as usize) << 16) | val5000 as usize;
+        vsync.saturating_sub(val1064 as usize) as f64 + self.line_offset
+    }
+
+    /// Integration time for some register values [in microseconds].
+    pub fn integration_time_us(&self, mode: Mu1603Mode, regs: (u16, u16, u16))
+        -> f64
+    {
+        self.integration_lines(regs) * self.line_time_us(mode)
+    }
+
+    /// Parse a calibration from a string.
+    ///
+    /// Missing keys are left at their default values.
+    pub fn parse(src: &str) -> Result<Self, CalibrationError> {
+        let mut res = Self::default();
+        for (num, line) in config_lines(src) {
+            let err = |msg: String| CalibrationError { line: num, msg };
+            let (key, val) = split_key_value(line).map_err(err)?;
+            let bad_value = |e: &dyn std::fmt::Display| {
+                err(format!("invalid value for '{}': {}", key, e))
+            };
+            match key {
+                "cycles_per_us" => {
+                    res.cycles_per_us = val.parse().map_err(|e| bad_value(&e))?;
+                },
+                "min_lines" => {
+                    res.min_lines = val.parse().map_err(|e| bad_value(&e))?;
+                },
+                "line_offset" => {
+                    res.line_offset = val.parse().map_err(|e| bad_value(&e))?;
+                },
+                key => return Err(err(format!("unknown key '{}'", key))),
+            }
+        }
+        if res.cycles_per_us.is_nan() || res.cycles_per_us <= 0.0 {
+            return Err(CalibrationError {
+                line: 0, msg: "'cycles_per_us' must be positive".to_string()
+            });
+        }
+        Ok(res)
+    }
+
+    /// Read and parse a calibration file.
+    pub fn load(filename: &str) -> Result<Self, CalibrationError> {
+        let src = std::fs::read_to_string(filename).map_err(|e| {
+            CalibrationError { line: 0, msg: format!("{}: {}", filename, e) }
+        })?;
+        Self::parse(&src)
+    }
+
+    /// Write this calibration to a file.
+    pub fn save(&self, filename: &str) -> std::io::Result<()> {
+        std::fs::write(filename, self.to_string())
+    }
+}
+impl std::fmt::Display for ExposureModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "cycles_per_us = {}", self.cycles_per_us)?;
+        writeln!(f, "min_lines     = {}", self.min_lines)?;
+        writeln!(f, "line_offset   = {}", self.line_offset)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_model() {
+        let src = "\
+# Fitted with glass-sweep
+cycles_per_us = 60.5   # trailing comments are fine
+
+line_offset   = -1.25
+";
+        let model = ExposureModel::parse(src).unwrap();
+        assert_eq!(model.cycles_per_us, 60.5);
+        assert_eq!(model.line_offset, -1.25);
+        // Missing keys keep their defaults
+        assert_eq!(model.min_lines, ExposureModel::default().min_lines);
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let model = ExposureModel {
+            cycles_per_us: 57.125,
+            min_lines: 12,
+            line_offset: 0.5,
+        };
+        assert_eq!(ExposureModel::parse(&model.to_string()).unwrap(), model);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases = [
+            ("cycles_per_us 54\n", 1, "expected 'key = value'"),
+            ("\nmin_lines = ten\n", 2, "invalid value for 'min_lines'"),
+            ("line_offset = 0\nbogus = 1\n", 2, "unknown key 'bogus'"),
+            ("cycles_per_us = 0\n", 0, "must be positive"),
+            ("cycles_per_us = NaN\n", 0, "must be positive"),
+        ];
+        for (src, line, msg) in cases {
+            let err = ExposureModel::parse(src).unwrap_err();
+            assert_eq!(err.line, line, "{:?}", src);
+            assert!(err.msg.contains(msg), "{:?}: {}", src, err.msg);
+        }
+    }
+}
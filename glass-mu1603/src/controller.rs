@@ -0,0 +1,628 @@
+//! A thread that owns the camera and accepts commands over a channel.
+//!
+//! Frontends shouldn't have to care about the details of driving the
+//! device: they send a [ControllerCommand], and eventually receive a
+//! [ControllerEvent] acknowledging it (along with the resulting state).
+//! Frames are delivered separately to each subscriber (see [FrameBroadcast]).
+//!
+//! The controller can drive any [FrameSource], so frontends can also be
+//! run against the [Mu1603Emulator] (see [Mu1603Controller::spawn_with]).
+
+use super::*;
+use std::sync::{ Arc, Mutex };
+use std::sync::mpsc::{ self, Sender, Receiver, SendError, TryRecvError };
+use std::thread::JoinHandle;
+
+/// A frame read from the camera.
+#[derive(Clone)]
+pub struct Frame {
+    /// Sequence number [since the controller was started]
+    pub id: usize,
+    /// Time when we finished reading the frame
+    pub timestamp: Instant,
+    /// Camera settings used to capture the frame
+    pub options: Mu1603Options,
+    /// Number of frames dropped since the previous one (see [FrameRate])
+    pub skipped: usize,
+    /// Raw data from the sensor
+    pub data: Vec<u8>,
+}
+
+impl std::fmt::Debug for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Frame")
+            .field("id", &self.id)
+            .field("timestamp", &self.timestamp)
+            .field("options", &self.options)
+            .field("skipped", &self.skipped)
+            .field("len", &self.data.len())
+            .finish()
+    }
+}
+
+/// Requests to the controller thread.
+#[derive(Clone, Debug)]
+pub enum ControllerCommand {
+    /// Open the device and start streaming with the initial settings
+    /// (see [Mu1603Builder::options])
+    Connect,
+
+    /// Open the device without starting the stream
+    Open,
+
+    /// Stop streaming and release the device
+    Disconnect,
+
+    /// Start streaming in the requested mode
+    Start(Mu1603Mode),
+
+    /// Start streaming with a user-provided initialization sequence
+    /// (see [Mu1603::start_stream_with_script])
+    StartWithScript(Mu1603Mode, Mu1603Script),
+
+    /// Stop streaming
+    Stop,
+
+    /// Update camera settings
+    Apply(Mu1603Options),
+
+    /// Deliver the next frame as a [ControllerEvent::Captured]
+    Capture,
+
+    /// Change which frames are read out (see [Mu1603::set_frame_rate])
+    SetFrameRate(FrameRate),
+
+    /// Shutdown the controller thread
+    Shutdown,
+}
+
+/// Updates from the controller thread.
+#[derive(Clone, Debug)]
+pub enum ControllerEvent {
+    /// The controller thread has started
+    ThreadInit,
+
+    /// The controller has connected to the device
+    Connected(DeviceInfo),
+
+    /// The controller failed to connect to the device
+    ConnectFailure(rusb::Error),
+
+    /// The controller has released the device
+    Disconnected,
+
+    /// The camera has started streaming with these settings
+    StreamStarted(Mu1603Options),
+
+    /// The camera has stopped streaming (or wasn't streaming when
+    /// [ControllerCommand::Stop] was sent)
+    StreamStopped,
+
+    /// The controller has applied an update to the camera settings
+    UpdateAck(Mu1603Options),
+
+    /// The controller has changed the frame rate
+    FrameRateAck(FrameRate),
+
+    /// The controller is waiting on a long exposure
+    ExposureProgress(ExposureProgress),
+
+    /// A frame requested with [ControllerCommand::Capture]
+    Captured(Arc<Frame>),
+
+    /// Some request to the device failed
+    Failure(Mu1603Error),
+
+    /// Reading a frame failed while streaming
+    ///
+    /// NOTE: Timeouts and skipped frames are expected, and aren't reported.
+    ReadFailure(Mu1603Error),
+}
+
+#[derive(Debug)]
+pub enum ControllerError {
+    Terminated,
+}
+
+/// Handle to a thread which owns a [Mu1603] (or some other [FrameSource]).
+pub struct Mu1603Controller {
+    cmd_tx: Sender<ControllerCommand>,
+    event_rx: Receiver<ControllerEvent>,
+    frames: FrameBroadcast,
+    stats: Arc<Mutex<StreamStats>>,
+    thread: Option<JoinHandle<Result<(), ControllerError>>>,
+}
+impl Mu1603Controller {
+    /// Spawn the controller thread.
+    ///
+    /// The device isn't opened until [ControllerCommand::Connect] is sent;
+    /// 'builder' is used to open it.
+    pub fn spawn(builder: Mu1603Builder) -> Self {
+        Self::spawn_with(builder.config().options, move || {
+            builder.open(&mut Context::new()?)
+        })
+    }
+
+    /// Spawn the controller thread for some other [FrameSource].
+    ///
+    /// The device isn't opened until [ControllerCommand::Connect] is sent;
+    /// 'open' is called (on the controller thread) to open it, and
+    /// 'options' are the initial settings used when streaming starts.
+    pub fn spawn_with<S, F>(options: Mu1603Options, open: F) -> Self
+        where S: FrameSource + 'static,
+              F: FnMut() -> rusb::Result<S> + Send + 'static
+    {
+        let (cmd_tx, cmd_rx) = mpsc::channel();
+        let (event_tx, event_rx) = mpsc::channel();
+        let frames = FrameBroadcast::new();
+        let frame_tx = frames.clone();
+        let stats = Arc::new(Mutex::new(StreamStats::default()));
+        let stats_tx = stats.clone();
+        let thread = std::thread::spawn(move || {
+            let mut state = ControllerState::new(Box::new(open), options,
+                cmd_rx, event_tx, frame_tx, stats_tx
+            );
+            state.main_loop()
+        });
+        Self { cmd_tx, event_rx, frames, stats, thread: Some(thread) }
+    }
+
+    pub fn send(&self, cmd: ControllerCommand)
+        -> Result<(), SendError<ControllerCommand>>
+    {
+        self.cmd_tx.send(cmd)
+    }
+
+    pub fn connect(&self) -> Result<(), SendError<ControllerCommand>> {
+        self.send(ControllerCommand::Connect)
+    }
+
+    pub fn open(&self) -> Result<(), SendError<ControllerCommand>> {
+        self.send(ControllerCommand::Open)
+    }
+
+    pub fn disconnect(&self) -> Result<(), SendError<ControllerCommand>> {
+        self.send(ControllerCommand::Disconnect)
+    }
+
+    pub fn start(&self, mode: Mu1603Mode)
+        -> Result<(), SendError<ControllerCommand>>
+    {
+        self.send(ControllerCommand::Start(mode))
+    }
+
+    pub fn start_with_script(&self, mode: Mu1603Mode, script: Mu1603Script)
+        -> Result<(), SendError<ControllerCommand>>
+    {
+        self.send(ControllerCommand::StartWithScript(mode, script))
+    }
+
+    pub fn stop(&self) -> Result<(), SendError<ControllerCommand>> {
+        self.send(ControllerCommand::Stop)
+    }
+
+    pub fn apply(&self, opts: Mu1603Options)
+        -> Result<(), SendError<ControllerCommand>>
+    {
+        self.send(ControllerCommand::Apply(opts))
+    }
+
+    pub fn capture(&self) -> Result<(), SendError<ControllerCommand>> {
+        self.send(ControllerCommand::Capture)
+    }
+
+    pub fn set_frame_rate(&self, rate: FrameRate)
+        -> Result<(), SendError<ControllerCommand>>
+    {
+        self.send(ControllerCommand::SetFrameRate(rate))
+    }
+
+    /// Channel for receiving updates from the controller thread.
+    pub fn events(&self) -> &Receiver<ControllerEvent> {
+        &self.event_rx
+    }
+
+    /// Subscribe to frames from the controller thread.
+    ///
+    /// Each subscriber has its own queue, and frames are only dropped
+    /// when the subscriber falls behind (according to 'policy').
+    pub fn subscribe(&self, policy: DropPolicy) -> FrameSubscriber {
+        self.frames.subscribe(policy)
+    }
+
+    /// Statistics about the current stream (see [Mu1603::stream_stats]).
+    ///
+    /// This is updated after every attempt to read a frame, and keeps the
+    /// last values after the stream stops.
+    pub fn stream_stats(&self) -> StreamStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// Ask the controller thread to stop, and wait for it to exit.
+    pub fn shutdown(&mut self) -> Result<(), ControllerError> {
+        let _ = self.send(ControllerCommand::Shutdown);
+        match self.thread.take() {
+            Some(thread) => thread.join().map_err(|_| ControllerError::Terminated)?,
+            None => Ok(()),
+        }
+    }
+}
+impl Drop for Mu1603Controller {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+/// State owned by the controller thread.
+struct ControllerState<S: FrameSource> {
+    /// Used to open the device
+    open: Box<dyn FnMut() -> rusb::Result<S>>,
+
+    /// Initial settings used by [ControllerCommand::Connect]
+    options: Mu1603Options,
+
+    /// Frame rate requested with [ControllerCommand::SetFrameRate]
+    frame_rate: Option<FrameRate>,
+
+    cmd_rx: Receiver<ControllerCommand>,
+    event_tx: Sender<ControllerEvent>,
+    frame_tx: FrameBroadcast,
+    stats_tx: Arc<Mutex<StreamStats>>,
+
+    /// Object used to control the camera
+    cam: Option<S>,
+
+    /// Number of frames read from the camera
+    frame_id: usize,
+
+    /// Number of skipped frames when the last frame was delivered
+    prev_skipped: usize,
+
+    /// Set when the next frame should be sent as a capture
+    capture_pending: bool,
+}
+impl<S: FrameSource> ControllerState<S> {
+    fn new(
+        open: Box<dyn FnMut() -> rusb::Result<S>>,
+        options: Mu1603Options,
+        cmd_rx: Receiver<ControllerCommand>,
+        event_tx: Sender<ControllerEvent>,
+        frame_tx: FrameBroadcast,
+        stats_tx: Arc<Mutex<StreamStats>>,
+    ) -> Self {
+        Self {
+            open,
+            options,
+            frame_rate: None,
+            cmd_rx,
+            event_tx,
+            frame_tx,
+            stats_tx,
+            cam: None,
+            frame_id: 0,
+            prev_skipped: 0,
+            capture_pending: false,
+        }
+    }
+
+    fn send_event(&self, evt: ControllerEvent) {
+        match &evt {
+            ControllerEvent::Failure(e) => warn!(error = ?e, "request failed"),
+            ControllerEvent::ReadFailure(e) => warn!(error = ?e, "read failed"),
+            ControllerEvent::ConnectFailure(e) => warn!(error = ?e, "connect failed"),
+            _ => debug!(event = ?evt),
+        }
+        // Nobody listening is not our problem
+        let _ = self.event_tx.send(evt);
+    }
+
+    fn main_loop(&mut self) -> Result<(), ControllerError> {
+        self.send_event(ControllerEvent::ThreadInit);
+        loop {
+            match self.cmd_rx.try_recv() {
+                Ok(ControllerCommand::Shutdown) => break,
+                Ok(cmd) => self.handle_cmd(cmd),
+                Err(TryRecvError::Empty) => {},
+                // Our handle is gone, guess I'll die ¯\_(ツ)_/¯
+                Err(TryRecvError::Disconnected) => break,
+            }
+
+            let streaming = self.cam.as_ref()
+                .map(|cam| cam.is_streaming())
+                .unwrap_or(false);
+            if streaming {
+                self.read_frame();
+            } else {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+        self.handle_disconnect();
+        self.frame_tx.close();
+        Ok(())
+    }
+
+    fn read_frame(&mut self) {
+        let cam = self.cam.as_mut().unwrap();
+        let event_tx = &self.event_tx;
+        let res = cam.try_read_frame_with_progress(&mut |p| {
+            let _ = event_tx.send(ControllerEvent::ExposureProgress(p));
+        });
+        let options = cam.state().unwrap();
+        let skipped = cam.frame_counts().skipped;
+        *self.stats_tx.lock().unwrap() = cam.stream_stats();
+        match res {
+            Ok(data) => {
+                let frame = Arc::new(Frame {
+                    id: self.frame_id,
+                    timestamp: Instant::now(),
+                    options,
+                    skipped: skipped.saturating_sub(self.prev_skipped),
+                    data,
+                });
+                self.frame_id += 1;
+                self.prev_skipped = skipped;
+                if self.capture_pending {
+                    self.capture_pending = false;
+                    self.send_event(ControllerEvent::Captured(frame.clone()));
+                }
+                self.frame_tx.publish(frame);
+            },
+            // Expected while streaming
+            Err(Mu1603Error::FirstFrame) |
+            Err(Mu1603Error::Skipped) |
+            Err(Mu1603Error::Rusb(rusb::Error::Timeout)) => {},
+            // The device is gone
+            Err(Mu1603Error::Rusb(rusb::Error::NoDevice)) => {
+                self.cam = None;
+                self.send_event(ControllerEvent::Disconnected);
+            },
+            Err(e) => self.send_event(ControllerEvent::ReadFailure(e)),
+        }
+    }
+
+    fn handle_cmd(&mut self, cmd: ControllerCommand) {
+        debug!(?cmd, "command");
+        match cmd {
+            ControllerCommand::Connect => self.handle_connect(),
+            ControllerCommand::Open => { self.handle_open(); },
+            ControllerCommand::Disconnect => self.handle_disconnect(),
+            ControllerCommand::Start(mode) => self.handle_start(mode, None),
+            ControllerCommand::StartWithScript(mode, script) => {
+                self.handle_start(mode, Some(&script))
+            },
+            ControllerCommand::Stop => self.handle_stop(),
+            ControllerCommand::Apply(opts) => self.handle_apply(opts),
+            ControllerCommand::Capture => self.capture_pending = true,
+            ControllerCommand::SetFrameRate(rate) => self.handle_frame_rate(rate),
+            ControllerCommand::Shutdown => unreachable!(),
+        }
+    }
+
+    /// Open the device (if necessary), returning 'false' if that failed.
+    fn handle_open(&mut self) -> bool {
+        if self.cam.is_some() {
+            return true;
+        }
+        match (self.open)() {
+            Ok(mut cam) => {
+                if let Some(rate) = self.frame_rate {
+                    cam.set_frame_rate(rate);
+                }
+                let info = cam.device_info();
+                self.cam = Some(cam);
+                self.send_event(ControllerEvent::Connected(info));
+                true
+            },
+            Err(e) => {
+                self.send_event(ControllerEvent::ConnectFailure(e));
+                false
+            },
+        }
+    }
+
+    /// Open the device (if necessary) and start streaming.
+    fn handle_connect(&mut self) {
+        if !self.handle_open() {
+            return;
+        }
+        let cam = self.cam.as_mut().unwrap();
+        if cam.is_streaming() {
+            return;
+        }
+        self.prev_skipped = 0;
+        match cam.start_with_options(self.options) {
+            Ok(state) => self.send_event(ControllerEvent::StreamStarted(state)),
+            Err(e) => self.send_event(ControllerEvent::Failure(e)),
+        }
+    }
+
+    fn handle_disconnect(&mut self) {
+        let streaming = match self.cam.as_ref() {
+            Some(cam) => cam.is_streaming(),
+            None => return,
+        };
+        if streaming {
+            self.handle_stop();
+        }
+        self.cam = None;
+        self.send_event(ControllerEvent::Disconnected);
+    }
+
+    fn handle_frame_rate(&mut self, rate: FrameRate) {
+        // NOTE: Keep this for the next time we open the device
+        self.frame_rate = Some(rate);
+        if let Some(cam) = self.cam.as_mut() {
+            cam.set_frame_rate(rate);
+        }
+        self.send_event(ControllerEvent::FrameRateAck(rate));
+    }
+
+    fn handle_start(&mut self, mode: Mu1603Mode, script: Option<&Mu1603Script>) {
+        let res = match (self.cam.as_mut(), script) {
+            (Some(cam), Some(script)) => cam.start_stream_with_script(mode, script),
+            (Some(cam), None) => cam.start_stream(mode),
+            (None, _) => Err(Mu1603Error::NotConnected),
+        };
+        let evt = match res {
+            Ok(state) => {
+                self.prev_skipped = 0;
+                ControllerEvent::StreamStarted(state)
+            },
+            Err(e) => ControllerEvent::Failure(e),
+        };
+        self.send_event(evt);
+    }
+
+    fn handle_stop(&mut self) {
+        let evt = match self.cam.as_mut() {
+            Some(cam) => match cam.stop_stream() {
+                Ok(()) => ControllerEvent::StreamStopped,
+                Err(e) => ControllerEvent::Failure(e),
+            },
+            None => ControllerEvent::Failure(Mu1603Error::NotConnected),
+        };
+        self.send_event(evt);
+    }
+
+    fn handle_apply(&mut self, opts: Mu1603Options) {
+        let evt = match self.cam.as_mut() {
+            Some(cam) => match cam.apply_state(opts) {
+                Ok(state) => ControllerEvent::UpdateAck(state),
+                Err(e) => ControllerEvent::Failure(e),
+            },
+            None => ControllerEvent::Failure(Mu1603Error::NotConnected),
+        };
+        self.send_event(evt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn options() -> Mu1603Options {
+        Mu1603Options {
+            id: 0,
+            mode: Mu1603Mode::Mode2,
+            exposure: ExposureTime::new_from_us(1_000),
+            analog_gain: AnalogGain::default(),
+            bitdepth: Mu1603BitDepth::Depth8,
+        }
+    }
+
+    fn spawn() -> Mu1603Controller {
+        Mu1603Controller::spawn_with(options(), || {
+            Ok(Mu1603Emulator::new(
+                EmulatorSensor::default(), EmulatorScene::default()
+            ).realtime(false))
+        })
+    }
+
+    /// Wait for the next event that isn't just reporting progress.
+    fn next_event(ctl: &Mu1603Controller) -> ControllerEvent {
+        loop {
+            match ctl.events().recv_timeout(TIMEOUT).unwrap() {
+                ControllerEvent::ThreadInit |
+                ControllerEvent::ExposureProgress(_) => continue,
+                evt => return evt,
+            }
+        }
+    }
+
+    #[test]
+    fn requires_connection() {
+        let ctl = spawn();
+        ctl.start(Mu1603Mode::Mode2).unwrap();
+        assert!(matches!(next_event(&ctl),
+            ControllerEvent::Failure(Mu1603Error::NotConnected)
+        ));
+        ctl.apply(options()).unwrap();
+        assert!(matches!(next_event(&ctl),
+            ControllerEvent::Failure(Mu1603Error::NotConnected)
+        ));
+        ctl.stop().unwrap();
+        assert!(matches!(next_event(&ctl),
+            ControllerEvent::Failure(Mu1603Error::NotConnected)
+        ));
+    }
+
+    #[test]
+    fn stop_is_acknowledged() {
+        let ctl = spawn();
+        ctl.open().unwrap();
+        assert!(matches!(next_event(&ctl), ControllerEvent::Connected(_)));
+        // Not streaming yet, but every command gets an answer
+        ctl.stop().unwrap();
+        assert!(matches!(next_event(&ctl), ControllerEvent::StreamStopped));
+
+        ctl.start(Mu1603Mode::Mode2).unwrap();
+        assert!(matches!(next_event(&ctl), ControllerEvent::StreamStarted(_)));
+        ctl.stop().unwrap();
+        assert!(matches!(next_event(&ctl), ControllerEvent::StreamStopped));
+    }
+
+    #[test]
+    fn stream_and_capture() {
+        let mut ctl = spawn();
+        let frames = ctl.subscribe(DropPolicy::Bounded(2));
+        ctl.connect().unwrap();
+        match next_event(&ctl) {
+            ControllerEvent::Connected(info) => {
+                assert_eq!(info.serial.as_deref(), Some("emulator"));
+            },
+            evt => panic!("unexpected event {:?}", evt),
+        }
+        match next_event(&ctl) {
+            ControllerEvent::StreamStarted(state) => {
+                assert_eq!(state.mode, Mu1603Mode::Mode2);
+                assert_eq!(state.exposure, options().exposure);
+            },
+            evt => panic!("unexpected event {:?}", evt),
+        }
+
+        let frame = frames.recv_timeout(TIMEOUT).unwrap();
+        let (width, height) = Mu1603Mode::Mode2.dimensions();
+        assert_eq!(frame.data.len(), width * height);
+
+        ctl.capture().unwrap();
+        assert!(matches!(next_event(&ctl), ControllerEvent::Captured(_)));
+
+        let mut opts = options();
+        opts.id = 1;
+        opts.exposure = ExposureTime::new_from_us(2_000);
+        ctl.apply(opts).unwrap();
+        match next_event(&ctl) {
+            ControllerEvent::UpdateAck(state) => {
+                assert_eq!(state.id, 1);
+                assert_eq!(state.exposure, opts.exposure);
+            },
+            evt => panic!("unexpected event {:?}", evt),
+        }
+
+        ctl.disconnect().unwrap();
+        assert!(matches!(next_event(&ctl), ControllerEvent::StreamStopped));
+        assert!(matches!(next_event(&ctl), ControllerEvent::Disconnected));
+
+        // Subscribers are closed when the controller exits
+        ctl.shutdown().unwrap();
+        while frames.try_recv().is_ok() {}
+        assert!(matches!(frames.try_recv(), Err(TryRecvError::Disconnected)));
+    }
+
+    #[test]
+    fn frame_rate_applies_when_opened() {
+        let ctl = spawn();
+        ctl.set_frame_rate(FrameRate::Decimate(3)).unwrap();
+        assert!(matches!(next_event(&ctl),
+            ControllerEvent::FrameRateAck(FrameRate::Decimate(3))
+        ));
+        let frames = ctl.subscribe(DropPolicy::Lossless);
+        ctl.connect().unwrap();
+        let first = frames.recv_timeout(TIMEOUT).unwrap();
+        let second = frames.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(second.id, first.id + 1);
+        assert_eq!(second.skipped, 2);
+    }
+}
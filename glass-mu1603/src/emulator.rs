@@ -0,0 +1,335 @@
+//! A synthetic stand-in for the camera.
+//!
+//! The emulator produces Bayer frames of a uniformly lit scene, with the
+//! usual sources of noise (shot noise, read noise, and dark current).
+//! Exposure times are converted into register values with the driver's
+//! [ExposureModel], but the integration time is computed with the
+//! emulated sensor's own model: this lets us check that a calibration
+//! procedure actually recovers the parameters of the sensor.
+
+use super::*;
+use glass_common::*;
+
+/// Properties of the emulated sensor.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct EmulatorSensor {
+    /// How the emulated sensor actually behaves
+    pub exposure_model: ExposureModel,
+
+    pub pattern: BayerPattern,
+
+    /// Conversion gain at 100% analog gain [in e-/ADU, for 8-bit data]
+    pub conversion_gain: f64,
+
+    /// Read noise [in e- RMS]
+    pub read_noise: f64,
+
+    /// Full-well capacity [in e-]
+    pub full_well: f64,
+
+    /// Dark current [in e-/s]
+    pub dark_current: f64,
+
+    /// Offset added to every pixel [in ADU, for 8-bit data]
+    pub black_level: f64,
+}
+impl Default for EmulatorSensor {
+    fn default() -> Self {
+        Self {
+            exposure_model: ExposureModel::default(),
+            pattern: Mu1603::PATTERN,
+            conversion_gain: 40.0,
+            read_noise: 3.0,
+            full_well: 10_000.0,
+            dark_current: 5.0,
+            black_level: 2.0,
+        }
+    }
+}
+
+/// The scene in front of the emulated sensor.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct EmulatorScene {
+    /// Photoelectrons per second for each channel
+    /// (ordered like [BayerChannel::ALL])
+    pub flux: [f64; 4],
+}
+impl Default for EmulatorScene {
+    fn default() -> Self {
+        Self { flux: [40_000.0, 60_000.0, 60_000.0, 30_000.0] }
+    }
+}
+
+/// A small, deterministic random number generator (xorshift64*).
+struct Rng { 
+    state: u64,
+    /// The second sample from the last call to [Rng::normal_pair]
+    spare: Option<f64>,
+}
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self { state: seed, spare: None }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in (0, 1]
+    fn uniform(&mut self) -> f64 {
+        ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A pair of independent samples from the standard normal distribution
+    fn normal_pair(&mut self) -> (f64, f64) {
+        let r = (-2.0 * self.uniform().ln()).sqrt();
+        let theta = std::f64::consts::TAU * self.uniform();
+        (r * theta.cos(), r * theta.sin())
+    }
+
+    /// A sample from the standard normal distribution
+    fn normal(&mut self) -> f64 {
+        if let Some(n) = self.spare.take() {
+            return n;
+        }
+        let (n1, n2) = self.normal_pair();
+        self.spare = Some(n2);
+        n1
+    }
+}
+
+/// An emulated camera (see [FrameSource]).
+pub struct Mu1603Emulator {
+    sensor: EmulatorSensor,
+    scene: EmulatorScene,
+
+    /// Model used by the "driver" to pick register values
+    driver_model: ExposureModel,
+
+    /// Sleep until each frame would have been read out
+    realtime: bool,
+
+    state: Option<Mu1603Options>,
+    regs: (u16, u16, u16),
+    next_frame: Instant,
+    /// When the last frame would have arrived
+    last_frame: Instant,
+    limiter: FrameLimiter,
+    rng: Rng,
+}
+impl Mu1603Emulator {
+    pub fn new(sensor: EmulatorSensor, scene: EmulatorScene) -> Self {
+        Self {
+            sensor,
+            scene,
+            driver_model: ExposureModel::default(),
+            realtime: true,
+            state: None,
+            regs: (0, 0, 0),
+            next_frame: Instant::now(),
+            last_frame: Instant::now(),
+            limiter: FrameLimiter::default(),
+            rng: Rng::new(0x9e37_79b9_7f4a_7c15),
+        }
+    }
+
+    /// Use a different model for converting exposure times into registers.
+    pub fn driver_model(mut self, model: ExposureModel) -> Self {
+        self.driver_model = model;
+        self
+    }
+
+    /// Whether or not frames are delivered at the rate the sensor would
+    /// produce them (defaults to 'true').
+    ///
+    /// NOTE: Either way, [FrameSource::frame_timestamp] reports when each
+    /// frame would have arrived from the sensor.
+    pub fn realtime(mut self, realtime: bool) -> Self {
+        self.realtime = realtime;
+        self
+    }
+
+    pub fn sensor(&self) -> &EmulatorSensor {
+        &self.sensor
+    }
+
+    pub fn scene_mut(&mut self) -> &mut EmulatorScene {
+        &mut self.scene
+    }
+
+    fn set_exposure(&mut self, mode: Mu1603Mode, exp: ExposureTime)
+        -> Result<(), Mu1603Error>
+    {
+        self.regs = exp.convert_with(&self.driver_model, mode,
+            mode.cycles_per_line()
+        ).ok_or(Mu1603Error::Unimplemented)?;
+        Ok(())
+    }
+
+    /// Time between frames with the current register values.
+    fn frame_time(&self, mode: Mu1603Mode) -> Duration {
+        let (_, hi, lo) = self.regs;
+        let vsync = ((hi as usize) << 16) | lo as usize;
+        let line_us = self.sensor.exposure_model.line_time_us(mode);
+        Duration::from_secs_f64(vsync as f64 * line_us / 1e6)
+    }
+
+    fn render(&mut self, state: &Mu1603Options) -> Vec<u8> {
+        let sensor = self.sensor;
+        let (width, height) = state.mode.dimensions();
+        let t = sensor.exposure_model
+            .integration_time_us(state.mode, self.regs).max(0.0) / 1e6;
+
+        // Scale 8-bit ADU for higher bit depths
+        let (scale, max_adu) = match state.bitdepth {
+            Mu1603BitDepth::Depth8 => (1.0, 255.0),
+            Mu1603BitDepth::Depth12 => (16.0, 4095.0),
+        };
+        let gain = state.analog_gain.percent() as f64 / 100.0;
+        let adu_per_e = scale * gain / sensor.conversion_gain;
+        let black = sensor.black_level * scale;
+        let mean_e = self.scene.flux.map(|f| (f + sensor.dark_current) * t);
+
+        // NOTE: Shot noise and read noise are combined into a single 
+        // gaussian (which is a fine approximation for more than a few e-)
+        let sigma_e = mean_e.map(|m| (m + sensor.read_noise.powi(2)).sqrt());
+
+        let bpp = state.bitdepth.bpp();
+        let mut data = vec![0u8; width * height * bpp];
+        for y in 0..height {
+            for x in 0..width {
+                let ch = sensor.pattern.channel_at(x, y).index();
+                // NOTE: Read noise is added on top of the black level, so
+                // it isn't clipped at zero electrons.
+                let e = (mean_e[ch] + sigma_e[ch] * self.rng.normal())
+                    .min(sensor.full_well);
+                let adu = (e * adu_per_e + black).round().clamp(0.0, max_adu);
+                let idx = (y * width + x) * bpp;
+                match state.bitdepth {
+                    Mu1603BitDepth::Depth8 => data[idx] = adu as u8,
+                    Mu1603BitDepth::Depth12 => {
+                        data[idx..idx + 2].copy_from_slice(
+                            &(adu as u16).to_le_bytes()
+                        );
+                    },
+                }
+            }
+        }
+        data
+    }
+}
+
+impl FrameSource for Mu1603Emulator {
+    fn state(&self) -> Option<Mu1603Options> {
+        self.state
+    }
+
+    fn start_stream(&mut self, mode: Mu1603Mode)
+        -> Result<Mu1603Options, Mu1603Error>
+    {
+        if let Some(state) = self.state {
+            return Ok(state);
+        }
+        let state = Mu1603Options {
+            id: 0,
+            mode,
+            analog_gain: AnalogGain::new_from_percent(100),
+            exposure: ExposureTime::new_from_us(94_000),
+            bitdepth: Mu1603BitDepth::Depth8,
+        };
+        self.set_exposure(mode, state.exposure)?;
+        self.next_frame = Instant::now() + self.frame_time(mode);
+        self.limiter.reset();
+        self.state = Some(state);
+        Ok(state)
+    }
+
+    /// NOTE: The emulator doesn't have any registers, so the script is
+    /// ignored.
+    fn start_stream_with_script(&mut self, mode: Mu1603Mode,
+        _script: &Mu1603Script) -> Result<Mu1603Options, Mu1603Error>
+    {
+        self.start_stream(mode)
+    }
+
+    fn stop_stream(&mut self) -> Result<(), Mu1603Error> {
+        self.state = None;
+        Ok(())
+    }
+
+    /// NOTE: Unlike the real device, the emulator can also change the
+    /// analog gain and bit depth while streaming.
+    fn apply_state(&mut self, next_state: Mu1603Options)
+        -> Result<Mu1603Options, Mu1603Error>
+    {
+        let mut state = self.state.ok_or(Mu1603Error::NotStreaming)?;
+        if state.exposure != next_state.exposure {
+            self.set_exposure(state.mode, next_state.exposure)?;
+            state.exposure = next_state.exposure;
+        }
+        state.analog_gain = next_state.analog_gain;
+        state.bitdepth = next_state.bitdepth;
+        state.id = next_state.id;
+        self.state = Some(state);
+        Ok(state)
+    }
+
+    fn try_read_frame(&mut self) -> Result<Vec<u8>, Mu1603Error> {
+        let state = self.state.ok_or(Mu1603Error::NotStreaming)?;
+        let now = if self.realtime {
+            let now = Instant::now();
+            if self.next_frame > now {
+                std::thread::sleep(self.next_frame - now);
+            }
+            // NOTE: If rendering is slower than the sensor, we just fall
+            // behind instead of trying to catch up.
+            Instant::now().max(self.next_frame)
+        } else {
+            self.next_frame
+        };
+        self.last_frame = now;
+        self.next_frame = now + self.frame_time(state.mode);
+
+        // Skipped frames aren't rendered at all
+        let keep = self.limiter.admit(now);
+        self.limiter.record(keep, now);
+        if !keep {
+            return Err(Mu1603Error::Skipped);
+        }
+        Ok(self.render(&state))
+    }
+
+    fn frame_timestamp(&self) -> Instant {
+        self.last_frame
+    }
+
+    fn exposure_model(&self) -> ExposureModel {
+        self.driver_model
+    }
+
+    fn set_frame_rate(&mut self, rate: FrameRate) {
+        self.limiter.set_rate(rate);
+    }
+
+    fn frame_counts(&self) -> FrameCounts {
+        self.limiter.counts()
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            vendor_id: Mu1603::VID,
+            product_id: Mu1603::PID,
+            manufacturer: None,
+            product: Some("MU1603 emulator".to_string()),
+            serial: Some("emulator".to_string()),
+            device_version: (0, 0, 0),
+            speed: rusb::Speed::Super,
+            bus: 0,
+            address: 0,
+            vendor_0x17: None,
+        }
+    }
+}
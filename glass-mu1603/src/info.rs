@@ -0,0 +1,150 @@
+//! Identifying a particular camera.
+
+use super::*;
+use rusb::Speed;
+
+/// Information identifying a particular device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+
+    /// Device release number from the device descriptor (bcdDevice),
+    /// presumably the firmware version.
+    pub device_version: (u8, u8, u8),
+
+    /// Negotiated USB link speed
+    pub speed: Speed,
+
+    /// Bus number and address
+    pub bus: u8,
+    pub address: u8,
+
+    /// Response to vendor request 0x17.
+    ///
+    /// NOTE: The vendor software issues this while stopping the stream;
+    /// we don't know what it means yet, but it's recorded here in case it
+    /// turns out to be a version or revision number.
+    pub vendor_0x17: Option<[u8; 4]>,
+}
+impl DeviceInfo {
+    /// Return a short description of the link speed.
+    pub fn speed_description(&self) -> &'static str {
+        match self.speed {
+            Speed::Low => "USB 1.x (1.5 Mbps)",
+            Speed::Full => "USB 1.x (12 Mbps)",
+            Speed::High => "USB 2.0 (480 Mbps)",
+            Speed::Super => "USB 3.x (5 Gbps)",
+            Speed::SuperPlus => "USB 3.x (10 Gbps)",
+            _ => "unknown",
+        }
+    }
+
+    /// Return 'true' if the device is connected at less than USB 3 speeds.
+    ///
+    /// NOTE: The sensor produces data much faster than a USB 2 link can
+    /// carry it, so expect a much lower frame rate in this case.
+    pub fn is_bandwidth_limited(&self) -> bool {
+        matches!(self.speed, Speed::Low | Speed::Full | Speed::High)
+    }
+}
+impl std::fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let opt = |s: &Option<String>| s.clone().unwrap_or("?".to_string());
+        writeln!(f, "vid:pid      = {:04x}:{:04x}", self.vendor_id, self.product_id)?;
+        writeln!(f, "manufacturer = {}", opt(&self.manufacturer))?;
+        writeln!(f, "product      = {}", opt(&self.product))?;
+        writeln!(f, "serial       = {}", opt(&self.serial))?;
+        writeln!(f, "version      = {}.{}.{}", self.device_version.0,
+            self.device_version.1, self.device_version.2)?;
+        writeln!(f, "speed        = {}", self.speed_description())?;
+        writeln!(f, "bus/address  = {:03}/{:03}", self.bus, self.address)?;
+        if let Some(v) = self.vendor_0x17 {
+            writeln!(f, "vendor_0x17  = {}", simple_hex(&v))?;
+        }
+        Ok(())
+    }
+}
+
+impl Mu1603 {
+    /// Return information identifying this device.
+    ///
+    /// This is queried once when the device is opened.
+    pub fn device_info(&self) -> &DeviceInfo {
+        &self.info
+    }
+
+    /// Return information about each camera that's plugged in. 
+    ///
+    /// NOTE: This briefly opens each device (without claiming it) in order 
+    /// to read the string descriptors. Devices that can't be opened (ie. 
+    /// because we lack permission) are still listed, but without strings.
+    /// No vendor requests are sent to the devices.
+    pub fn enumerate(ctx: &Context) -> rusb::Result<Vec<DeviceInfo>> {
+        let mut res = Vec::new();
+        for device in ctx.devices()?.iter() {
+            // NOTE: Skip anything we can't identify rather than failing
+            let Ok(desc) = device.device_descriptor() else {
+                continue;
+            };
+            if desc.vendor_id() != Self::VID || desc.product_id() != Self::PID {
+                continue;
+            }
+            let mut info = Self::descriptor_info(&device, &desc);
+            if let Ok(handle) = device.open() {
+                Self::read_strings(&handle, &desc, &mut info);
+            }
+            res.push(info);
+        }
+        Ok(res)
+    }
+
+    pub(crate) fn read_device_info(handle: &DeviceHandle<Context>, 
+        timeout: Duration) -> rusb::Result<DeviceInfo>
+    {
+        let device = handle.device();
+        let desc = device.device_descriptor()?;
+        let mut info = Self::descriptor_info(&device, &desc);
+        Self::read_strings(handle, &desc, &mut info);
+
+        let mut buf: [u8; 4] = [0; 4];
+        info.vendor_0x17 = match handle.read_control(Self::REQ_TYPE_IN,
+            0x17, 0x0000, 0x0000, &mut buf, timeout)
+        {
+            Ok(4) => Some(buf),
+            _ => None,
+        };
+        Ok(info)
+    }
+
+    /// Information available without opening the device.
+    fn descriptor_info(device: &Device<Context>, desc: &DeviceDescriptor) 
+        -> DeviceInfo
+    {
+        let version = desc.device_version();
+        DeviceInfo {
+            vendor_id: desc.vendor_id(),
+            product_id: desc.product_id(),
+            manufacturer: None,
+            product: None,
+            serial: None,
+            device_version: (version.major(), version.minor(), version.sub_minor()),
+            speed: device.speed(),
+            bus: device.bus_number(),
+            address: device.address(),
+            vendor_0x17: None,
+        }
+    }
+
+    fn read_strings(handle: &DeviceHandle<Context>, desc: &DeviceDescriptor,
+        info: &mut DeviceInfo)
+    {
+        // NOTE: Some of these might not be present, which isn't an error
+        info.manufacturer = handle.read_manufacturer_string_ascii(desc).ok();
+        info.product = handle.read_product_string_ascii(desc).ok();
+        info.serial = handle.read_serial_number_string_ascii(desc).ok();
+    }
+}
@@ -1,34 +1,108 @@
 
 mod usb;
 mod state;
+mod script;
+mod timing;
+mod info;
+mod builder;
+mod calibration;
+mod source;
+mod emulator;
+mod rate;
+mod monitor;
+mod sweep;
+mod ptc;
+mod profile;
+mod broadcast;
+mod controller;
+mod rawfile;
+#[cfg(feature = "async")]
+mod nonblocking;
 
 pub use state::*;
+pub use script::*;
+pub use timing::*;
+pub use info::*;
+pub use builder::*;
+pub use calibration::*;
+pub use source::*;
+pub use emulator::*;
+pub use rate::*;
+pub use monitor::*;
+pub use sweep::*;
+pub use ptc::*;
+pub use profile::*;
+pub use broadcast::*;
+pub use controller::*;
+pub use rawfile::*;
+#[cfg(feature = "async")]
+pub use nonblocking::*;
 
 use pretty_hex::*;
-use std::time::Duration;
+use std::time::{ Duration, Instant };
+use tracing::{ debug, info, trace, warn, debug_span, info_span };
 use rusb::{ 
     Context, UsbContext, Device, DeviceHandle, DeviceDescriptor,
     request_type, Direction, RequestType, Recipient,
 };
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Mu1603Error { 
     Rusb(rusb::Error),
     FirstFrame,
     Unimplemented,
     NotStreaming,
+    /// No device has been opened (see [Mu1603Controller])
+    NotConnected,
+    /// A frame couldn't be interpreted (ie. it has the wrong size)
+    InvalidFrame(&'static str),
     FailedSensorCmd(u16, u16),
+    /// The worker thread owning the device has exited
+    WorkerTerminated,
+    /// The frame was dropped to meet the requested [FrameRate]
+    Skipped,
 }
 impl From<rusb::Error> for Mu1603Error {
     fn from(e: rusb::Error) -> Self { Self::Rusb(e) }
 }
 
 
+/// Progress of a long exposure (see [Mu1603::try_read_frame_with_progress]).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ExposureProgress {
+    /// Time since the exposure started [approximately]
+    pub elapsed: Duration,
+    /// Total exposure time
+    pub total: Duration,
+}
+impl ExposureProgress {
+    /// Return the fraction of the exposure that has elapsed (from 0 to 1).
+    pub fn fraction(&self) -> f32 { 
+        if self.total.is_zero() {
+            return 1.0;
+        }
+        (self.elapsed.as_secs_f32() / self.total.as_secs_f32()).min(1.0)
+    }
+}
+
 pub struct Mu1603 {
     /// libusb handle to the device
     handle: DeviceHandle<Context>,
+    cfg: Mu1603Config,
     state: Option<Mu1603Options>,
     prev_state: Option<Mu1603Options>,
+
+    /// Approximate start of the exposure for the next frame
+    exposure_start: Instant,
+
+    /// Decides which frames are delivered
+    limiter: FrameLimiter,
+
+    /// Statistics about the stream
+    monitor: StreamMonitor,
+
+    /// Information identifying the device
+    info: DeviceInfo,
 }
 impl Mu1603 {
     /// USB Vendor ID
@@ -43,6 +117,32 @@ impl Mu1603 {
     /// Default mode for initialization
     pub const DEFAULT_MODE: Mu1603Mode = Mu1603Mode::Mode1;
 
+    /// Arrangement of the color filters on the sensor (red at [1,1]).
+    ///
+    /// NOTE: This is the same in every mode.
+    pub const PATTERN: glass_common::BayerPattern = glass_common::BayerPattern::BGGR;
+
+    /// Default timeout for USB bulk transfers.
+    ///
+    /// NOTE: This is extended by the exposure time while we're waiting for
+    /// the first chunk of a frame. 
+    pub const BULK_TIMEOUT: Duration = Duration::from_millis(500);
+
+    /// Polling interval for reporting progress during long exposures
+    pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+    /// Default size of each bulk transfer while reading a frame
+    pub const CHUNK: usize = 0x0010_0000;
+
+    /// Default USB configuration
+    pub const CONFIGURATION: u8 = 1;
+
+    /// Default USB interface
+    pub const INTERFACE: u8 = 0;
+
+    /// Bulk endpoint used for reading frames
+    pub const ENDPOINT: u8 = 0x81;
+
     /// Input vendor request type
     pub const REQ_TYPE_IN: u8 = request_type(
         Direction::In, RequestType::Vendor, Recipient::Device
@@ -62,41 +162,69 @@ impl Mu1603 {
         self.state.is_some()
     }
 
-    /// Try to obtain a handle to the camera. 
+    /// Change which frames are delivered while streaming.
+    ///
+    /// Frames that aren't delivered are still read from the device, but 
+    /// aren't copied: [Mu1603::try_read_frame] returns [Mu1603Error::Skipped]
+    /// instead.
+    pub fn set_frame_rate(&mut self, rate: FrameRate) {
+        self.limiter.set_rate(rate);
+    }
+
+    pub fn frame_rate(&self) -> FrameRate {
+        self.limiter.rate()
+    }
+
+    /// Number of frames delivered and skipped since the stream started.
+    pub fn frame_counts(&self) -> FrameCounts {
+        self.limiter.counts()
+    }
+
+    /// Statistics about the stream since it was started.
+    pub fn stream_stats(&self) -> StreamStats {
+        self.monitor.stats()
+    }
+
+    /// Return the configuration used to open this device.
+    pub fn config(&self) -> &Mu1603Config {
+        &self.cfg
+    }
+
+    /// Return a builder for opening the camera with non-default settings.
+    pub fn builder() -> Mu1603Builder {
+        Mu1603Builder::new()
+    }
+
+    /// Try to obtain a handle to the camera [with the default settings]. 
     pub fn try_open(ctx: &mut Context) -> rusb::Result<Self> {
-        let res = ctx.open_device_with_vid_pid(Self::VID, Self::PID);
-        if let Some(mut handle) = res {
-            if let Ok(true) = handle.kernel_driver_active(0) {
-                handle.detach_kernel_driver(0)?;
-            }
-            handle.set_active_configuration(1)?;
-            handle.claim_interface(0)?;
-            Ok(Self { 
-                handle, 
-                state: None,
-                prev_state: None,
-            })
-        } else { 
-            Err(rusb::Error::NoDevice)
-        }
+        Mu1603Builder::new().open(ctx)
     }
 }
 
 impl Mu1603 {
+    /// Apply new settings while streaming. 
+    ///
+    /// Returns the resulting state of the camera; settings that we don't 
+    /// know how to change yet are left alone.
     pub fn apply_state(&mut self, next_state: Mu1603Options) 
+        -> Result<Mu1603Options, Mu1603Error>
     {
-        if let Some(this_state) = self.state {
-            if this_state.mode() != next_state.mode() {
-            }
-            if this_state.exposure() != next_state.exposure() {
-            }
-            if this_state.analog_gain() != next_state.analog_gain() {
-            }
-        } 
-        else {
+        let mut state = self.state.ok_or(Mu1603Error::NotStreaming)?;
+
+        if state.mode() != next_state.mode() {
+            // NOTE: Changing the mode requires restarting the stream. 
+        }
+        if state.exposure() != next_state.exposure() {
+            self.set_exposure_time(state.mode, next_state.exposure)?;
+            state.exposure = next_state.exposure;
+        }
+        if state.analog_gain() != next_state.analog_gain() {
+            // NOTE: We don't know how to convert gain to register values yet.
         }
 
-        self.state = Some(next_state);
+        state.id = next_state.id;
+        self.state = Some(state);
+        Ok(state)
     }
 
     // self.sys_write(0x0200, 0x0001)?; // 12-bit depth?
@@ -142,107 +270,69 @@ impl Mu1603 {
 
 
 
-    pub fn start_stream(&mut self, init_mode: Mu1603Mode) 
+    /// Start streaming with the initial settings from [Mu1603Config].
+    ///
+    /// NOTE: We don't know how to change the analog gain or bit depth yet,
+    /// so these must match the settings left behind by the initialization
+    /// sequence. Otherwise, this fails with [Mu1603Error::Unimplemented].
+    pub fn start(&mut self) -> Result<Mu1603Options, Mu1603Error> {
+        self.start_with_options(self.cfg.options)
+    }
+
+    /// Start streaming with some initial settings (see [Mu1603::start]).
+    pub fn start_with_options(&mut self, opts: Mu1603Options)
         -> Result<Mu1603Options, Mu1603Error>
     {
-        // We're already streaming
-        if let Some(state) = self.state {
-            return Ok(self.state().unwrap());
-        }
-
-        // 1. Send the key `0x0000` to the device. 
-        //
-        // NOTE: There's some kind of challenge-response handshake that occurs 
-        // after this (according to 'drivers/media/usb/gspca/touptek.c') in my 
-        // packet captures, but we can apparently just ignore it? 
-        self.set_null_key()?;
-
-        // 2. I have no idea what this does.
-        // Probably related to enabling the sensor. 
-        self.ven_write(0x01, 0x000f, 0x0001, &[])?;
-        self.ven_write(0x01, 0x000f, 0x0000, &[])?;
-        self.ven_write(0x01, 0x000f, 0x0001, &[])?;
-
-        // 3. I have no idea what this does. 
-        let mut hbuf: [u8; 2] = [0x00, 0x00];
-        self.ven_read(0x0a, 0xffff, 0x0000, &mut hbuf)?;
-        self.ven_read(0x0a, 0xffff, 0x0000, &mut hbuf)?;
-        self.ven_read(0x0a, 0xfeff, 0x0000, &mut hbuf)?;
-        self.ven_read(0x0a, 0xfeff, 0x0000, &mut hbuf)?;
-
-        // 4. Do some fixed initialization sequence. 
-        // 
-        // NOTE: It seems like this starts the sensor in mode 0.
-        self.sensor_program_sequence(0x0087, 0x1104)?;
-
-        self.system_cmd(0x1200, 0x0001)?;
-        std::thread::sleep(Duration::from_millis(20));
-        self.system_cmd(0x2000, 0x0000)?;
-        self.system_cmd(0x1200, 0x0002)?;
-        std::thread::sleep(Duration::from_millis(20));
-
-        self.system_cmd(0x0200, 0x0000)?; // Bit-depth?
-        self.system_cmd(0x0a00, 0x0001)?;
-        self.system_cmd(0x0a00, 0x0000)?;
-        std::thread::sleep(Duration::from_millis(20));
-
-        // 6. Setup the requested mode/resolution? 
-        //
-        // NOTE: The value for 0x8000 seems to depend on the mode and 
-        // something else (maybe the bitdepth?)  
-        //
-        // NOTE: This is sensitive to timing; the 10ms sleep is *required*.
-        match init_mode { 
-            Mu1603Mode::Mode0 => {
-                self.sensor_program_sequence(0x0087, 0x1104)?;
-                self.sensor_cmd(0x103b, 0x0000)?;
-                self.system_cmd(0x2000, 0x0000)?;
-                self.system_cmd(0x1200, 0x0002)?;
-                std::thread::sleep(Duration::from_millis(10));
-                self.system_cmd(0x8000, 0x09b0)?;
-            },
-            Mu1603Mode::Mode1 => {
-                self.sensor_program_sequence(0x0083, 0x11dc)?;
-                self.sensor_cmd(0x103b, 0x0000)?;
-                self.system_cmd(0x2000, 0x0001)?;
-                self.system_cmd(0x1200, 0x0003)?;
-                std::thread::sleep(Duration::from_millis(10));
-                self.system_cmd(0x8000, 0x060c)?;
-            },
-            Mu1603Mode::Mode2 => {
-                self.sensor_program_sequence(0x0083, 0x11dc)?;
-                self.sensor_cmd(0x103b, 0x0000)?;
-                self.system_cmd(0x2000, 0x0002)?;
-                self.system_cmd(0x1200, 0x0004)?;
-                std::thread::sleep(Duration::from_millis(10));
-                self.system_cmd(0x8000, 0x0666)?;
-            },
+        let init = Self::initial_state(opts.mode);
+        if opts.analog_gain != init.analog_gain || opts.bitdepth != init.bitdepth {
+            return Err(Mu1603Error::Unimplemented);
         }
+        self.start_stream(opts.mode)?;
+        self.apply_state(opts)
+    }
 
-
-        // 7. Set exposure and analog gain
-        self.set_exposure(0x000a, 0x0cbd)?;
-        self.system_cmd(0x0a00, 0x0001)?;
-        self.set_exposure(0x000a, 0x0cbd)?;
-        self.set_analog_gain(0x610c)?;
-
-        // 7. Start streaming. 
-        // After this, frames should be available to read with bulk transfers 
-        // on endpoint 0x81.
-
-        self.ven_write(0x01, 0x000f, 0x0003, &[])?;
-        std::thread::sleep(Duration::from_millis(10));
-
-        let state = Mu1603Options {
+    /// Settings used by the built-in initialization sequences.
+    fn initial_state(mode: Mu1603Mode) -> Mu1603Options {
+        Mu1603Options {
             id: 0,
-            mode: init_mode,
+            mode,
             analog_gain: AnalogGain::new_from_percent(100),
             exposure: ExposureTime::new_from_us(94_000),
             bitdepth: Mu1603BitDepth::Depth8,
-        };
+        }
+    }
+
+    /// Start streaming with the built-in initialization sequence for the 
+    /// requested mode (see [Mu1603Script::builtin]).
+    pub fn start_stream(&mut self, init_mode: Mu1603Mode) 
+        -> Result<Mu1603Options, Mu1603Error>
+    {
+        let script = Mu1603Script::builtin(init_mode);
+        self.start_stream_with_script(init_mode, &script)
+    }
+
+    /// Start streaming with a user-provided initialization sequence.
+    ///
+    /// The script is expected to leave the device streaming frames in 
+    /// the requested mode (see the built-in scripts for reference). 
+    pub fn start_stream_with_script(&mut self, init_mode: Mu1603Mode, 
+        script: &Mu1603Script) -> Result<Mu1603Options, Mu1603Error>
+    {
+        // We're already streaming
+        if let Some(state) = self.state {
+            return Ok(state);
+        }
+        let _span = info_span!("start_stream", mode = ?init_mode).entered();
+        let start = Instant::now();
+
+        self.run_script(script)?;
+        self.exposure_start = Instant::now();
+        self.limiter.reset();
+        self.monitor.reset();
 
+        let state = Self::initial_state(init_mode);
         self.state = Some(state);
-        println!("[*] Driver started streaming");
+        info!(elapsed = ?start.elapsed(), "started streaming");
         Ok(state)
     }
 
@@ -253,6 +343,7 @@ impl Mu1603 {
         if !self.is_streaming() { 
             return Ok(()); 
         }
+        let _span = info_span!("stop_stream").entered();
 
         self.system_cmd(0x0a00, 0x0000)?;
         self.sensor_cmd(0x1000, 0x0000)?;
@@ -264,7 +355,7 @@ impl Mu1603 {
 
         self.prev_state = self.state;
         self.state = None;
-        println!("[*] Driver stopped streaming");
+        info!("stopped streaming");
         Ok(())
     }
 }
@@ -273,30 +364,106 @@ impl Mu1603 {
 impl Mu1603 {
     /// Try to read a frame from the camera. 
     pub fn try_read_frame(&mut self) -> Result<Vec<u8>, Mu1603Error>
+    {
+        self.try_read_frame_with_progress(|_| {})
+    }
+
+    /// Try to read a frame from the camera. 
+    ///
+    /// For long exposures (see [ExposureTime::is_long]), 'progress' is 
+    /// called periodically while we're waiting for the frame.
+    pub fn try_read_frame_with_progress<F>(&mut self, mut progress: F) 
+        -> Result<Vec<u8>, Mu1603Error>
+        where F: FnMut(ExposureProgress)
     {
         if let Some(state) = self.state { 
-            Self::read_frame(&mut self.handle, &state)
+            let res = Self::read_frame(&mut self.handle, &self.cfg, &state, 
+                self.exposure_start, &mut self.limiter, &mut self.monitor, 
+                &mut progress
+            );
+            // The next exposure is (approximately) underway by now
+            self.exposure_start = Instant::now();
+            res
         } else { 
             Err(Mu1603Error::NotStreaming)
         }
     }
 
+    /// Wait for the first chunk of a frame. 
+    ///
+    /// During long exposures, we poll with a short timeout in order to 
+    /// report progress. We stop polling a bit before the exposure is 
+    /// expected to end: a timeout that races with incoming data would 
+    /// cause us to lose part of the frame.
+    fn wait_first_chunk(
+        handle: &mut DeviceHandle<Context>, 
+        cfg: &Mu1603Config,
+        state: &Mu1603Options,
+        exposure_start: Instant,
+        chunk: &mut [u8],
+        progress: &mut dyn FnMut(ExposureProgress),
+    ) -> Result<usize, Mu1603Error>
+    {
+        let total = state.exposure.duration();
+        if state.exposure.is_long() {
+            let poll_until = total.saturating_sub(Duration::from_secs(1));
+            while exposure_start.elapsed() < poll_until {
+                match handle.read_bulk(Self::ENDPOINT, chunk, cfg.progress_interval) {
+                    Ok(rlen) => return Ok(rlen),
+                    Err(rusb::Error::Timeout) => {
+                        progress(ExposureProgress { 
+                            elapsed: exposure_start.elapsed(), total
+                        });
+                    },
+                    Err(e) => return Err(Mu1603Error::from(e)),
+                }
+            }
+        }
+        let timeout = cfg.bulk_timeout + total;
+        let rlen = handle.read_bulk(Self::ENDPOINT, chunk, timeout)?;
+        if state.exposure.is_long() {
+            progress(ExposureProgress { elapsed: total, total });
+        }
+        Ok(rlen)
+    }
+
     /// Read an entire frame from the camera. 
-    fn read_frame(handle: &mut DeviceHandle<Context>, state: &Mu1603Options)
-        -> Result<Vec<u8>, Mu1603Error>
+    fn read_frame(
+        handle: &mut DeviceHandle<Context>, 
+        cfg: &Mu1603Config,
+        state: &Mu1603Options,
+        exposure_start: Instant,
+        limiter: &mut FrameLimiter,
+        monitor: &mut StreamMonitor,
+        progress: &mut dyn FnMut(ExposureProgress),
+    ) -> Result<Vec<u8>, Mu1603Error>
     {
-        const CHUNK: usize = 0x0010_0000;
+        let chunk_size = cfg.chunk_size;
         let (width, height) = state.mode.dimensions();
         let bpp = state.bitdepth.bpp();
         let frame_len = (width * height) * bpp;
 
-        let mut data = vec![0u8; frame_len];
-        let mut chunk = vec![0u8; CHUNK];
+        let _span = debug_span!("read_frame", frame_len).entered();
+        let start = Instant::now();
+
+        let mut chunk = vec![0u8; chunk_size];
         let mut cur  = 0;
+        let mut chunks = 0;
+
+        // The first chunk only arrives after the exposure has finished
+        let mut res = Self::wait_first_chunk(handle, cfg, state, 
+            exposure_start, &mut chunk, progress
+        );
+        let first_chunk = Instant::now();
+
+        // NOTE: Frames we don't want still have to be read from the device, 
+        // but there's no need to copy them anywhere.
+        let keep = limiter.admit(first_chunk);
+        let mut data = if keep { vec![0u8; frame_len] } else { Vec::new() };
 
         // Issue bulk reads until we've received an entire frame
         loop {
-            match handle.read_bulk(0x81, &mut chunk, Duration::from_millis(500)) {
+            match res {
                 Ok(rlen) => {
                     // If the incoming data would overflow the buffer,
                     // just truncate it and copy the remaining bytes
@@ -304,28 +471,48 @@ impl Mu1603 {
                     let len = if rlen > rem { rem } else { rlen };
 
                     // Copy into frame buffer
-                    data[cur..cur+len].copy_from_slice(&chunk[..len]);
+                    if keep {
+                        data[cur..cur+len].copy_from_slice(&chunk[..len]);
+                    }
                     cur += len;
+                    chunks += 1;
+                    trace!(chunk = chunks, len = rlen, "bulk read");
 
                     // If we get less bytes than we requested, this indicates
                     // that the device has finished reading out a frame.
-                    if rlen < CHUNK { 
+                    if rlen < chunk_size { 
                         break; 
                     }
                 },
-                Err(e) => return Err(Mu1603Error::from(e)),
+                Err(e) => {
+                    debug!(bytes = cur, chunks, error = ?e, "frame read failed");
+                    monitor.error(Instant::now(), &e, cur);
+                    return Err(e);
+                },
             }
+            res = handle.read_bulk(Self::ENDPOINT, &mut chunk, cfg.bulk_timeout)
+                .map_err(Mu1603Error::from);
         }
 
         // This really only occurs on the first frame after initialization; 
         // the data is typically truncated, and we can just discard it.
+        let latency = first_chunk - start;
+        let readout = first_chunk.elapsed();
         if cur < frame_len {
-            Err(Mu1603Error::FirstFrame)
+            warn!(bytes = cur, chunks, ?latency, ?readout, "discarding short frame");
+            monitor.truncated(Instant::now(), cur);
+            return Err(Mu1603Error::FirstFrame);
+        }
+        monitor.frame(Instant::now(), cur, chunks, latency, readout);
+        if !keep {
+            limiter.record(false, first_chunk);
+            trace!(bytes = cur, chunks, ?latency, ?readout, "skipped frame");
+            Err(Mu1603Error::Skipped)
         } else {
+            limiter.record(true, first_chunk);
+            debug!(bytes = cur, chunks, ?latency, ?readout, "read frame");
             Ok(data)
         }
     }
 
 }
-
-
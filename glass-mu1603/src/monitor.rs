@@ -0,0 +1,370 @@
+//! Keeping track of how well the stream is performing.
+//!
+//! [Mu1603] records every attempt to read a frame with a [StreamMonitor].
+//! Totals are kept since the stream started, and rates are computed over
+//! a rolling window of recent frames. Use [Mu1603::stream_stats] to get
+//! a [StreamStats] snapshot.
+//!
+//! NOTE: The window ends when the snapshot is taken, so the rates drop
+//! to zero if the stream stalls.
+
+use super::*;
+use std::collections::VecDeque;
+
+/// A snapshot of the stream statistics.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct StreamStats {
+    /// Complete frames read from the device (including skipped frames)
+    pub frames: usize,
+
+    /// Bytes read from the device (including truncated frames)
+    pub bytes: u64,
+
+    /// Frames discarded because they were truncated
+    pub truncated: usize,
+
+    /// Bulk transfers that timed out
+    pub timeouts: usize,
+
+    /// Reads that failed for some other reason
+    pub errors: usize,
+
+    /// Number of times a complete frame was read after a failure
+    pub recoveries: usize,
+
+    /// Frames per second [over the rolling window]
+    pub fps: f64,
+
+    /// Bytes per second [over the rolling window]
+    pub throughput: f64,
+
+    /// Mean time between frames [over the rolling window]
+    ///
+    /// NOTE: This is 'None' until there are two frames in the window.
+    pub interval: Option<Duration>,
+
+    /// Standard deviation of the time between frames
+    pub jitter: Option<Duration>,
+
+    /// Bulk transfers used for the last frame
+    pub chunks: usize,
+
+    /// Time spent waiting for the first chunk of the last frame
+    pub latency: Option<Duration>,
+
+    /// Time spent reading out the last frame
+    pub readout: Option<Duration>,
+}
+impl StreamStats {
+    /// Throughput [in MB/s]
+    pub fn mb_per_sec(&self) -> f64 {
+        self.throughput / 1e6
+    }
+
+    /// Frames that didn't make it, for any reason.
+    pub fn failures(&self) -> usize {
+        self.truncated + self.timeouts + self.errors
+    }
+
+    /// Name and value of each statistic, for exporting as metrics.
+    ///
+    /// Durations are in seconds, and missing values are left out.
+    pub fn metrics(&self) -> Vec<(&'static str, f64)> {
+        let mut res = vec![
+            ("frames_total", self.frames as f64),
+            ("bytes_total", self.bytes as f64),
+            ("truncated_total", self.truncated as f64),
+            ("timeouts_total", self.timeouts as f64),
+            ("errors_total", self.errors as f64),
+            ("recoveries_total", self.recoveries as f64),
+            ("frames_per_second", self.fps),
+            ("bytes_per_second", self.throughput),
+            ("chunks_per_frame", self.chunks as f64),
+        ];
+        let durations = [
+            ("interval_seconds", self.interval),
+            ("jitter_seconds", self.jitter),
+            ("latency_seconds", self.latency),
+            ("readout_seconds", self.readout),
+        ];
+        for (name, d) in durations {
+            if let Some(d) = d {
+                res.push((name, d.as_secs_f64()));
+            }
+        }
+        res
+    }
+}
+impl std::fmt::Display for StreamStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |d: Option<Duration>| d
+            .map(|d| format!("{:.2}ms", d.as_secs_f64() * 1e3))
+            .unwrap_or("-".to_string());
+        writeln!(f, "frames       = {} ({:.2} fps)", self.frames, self.fps)?;
+        writeln!(f, "throughput   = {:.2} MB/s ({} bytes total)",
+            self.mb_per_sec(), self.bytes)?;
+        writeln!(f, "interval     = {} (jitter {})",
+            ms(self.interval), ms(self.jitter))?;
+        writeln!(f, "last frame   = {} chunks, latency {}, readout {}",
+            self.chunks, ms(self.latency), ms(self.readout))?;
+        writeln!(f, "truncated    = {}", self.truncated)?;
+        writeln!(f, "timeouts     = {}", self.timeouts)?;
+        writeln!(f, "errors       = {}", self.errors)?;
+        writeln!(f, "recoveries   = {}", self.recoveries)?;
+        Ok(())
+    }
+}
+
+/// Collects [StreamStats] while streaming.
+#[derive(Clone, Debug)]
+pub struct StreamMonitor {
+    /// Rates are computed over frames within this long of the snapshot
+    window: Duration,
+
+    /// Completion time and size of recent frames
+    recent: VecDeque<(Instant, usize)>,
+
+    /// Set after a failure, until the next complete frame
+    failing: bool,
+
+    stats: StreamStats,
+}
+impl Default for StreamMonitor {
+    fn default() -> Self {
+        Self::new(Self::WINDOW)
+    }
+}
+impl StreamMonitor {
+    /// Default length of the rolling window
+    pub const WINDOW: Duration = Duration::from_secs(5);
+
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            recent: VecDeque::new(),
+            failing: false,
+            stats: StreamStats::default(),
+        }
+    }
+
+    /// Forget everything (ie. when a new stream is started).
+    pub fn reset(&mut self) {
+        self.recent.clear();
+        self.failing = false;
+        self.stats = StreamStats::default();
+    }
+
+    /// Return a snapshot of the statistics.
+    pub fn stats(&self) -> StreamStats {
+        self.stats_at(Instant::now())
+    }
+
+    /// Return a snapshot of the statistics, with the window ending at 'now'.
+    pub fn stats_at(&self, now: Instant) -> StreamStats {
+        let mut stats = self.stats;
+        let recent = self.recent.iter()
+            .skip_while(|(t, _)| !self.in_window(*t, now));
+        Self::update_rates(&mut stats, recent);
+        stats
+    }
+
+    fn in_window(&self, t: Instant, now: Instant) -> bool {
+        now.saturating_duration_since(t) <= self.window
+    }
+
+    /// Forget frames that have fallen out of the window.
+    fn prune(&mut self, now: Instant) {
+        while let Some((t, _)) = self.recent.front() {
+            if self.in_window(*t, now) {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+
+    /// Record a complete frame.
+    pub fn frame(&mut self, now: Instant, bytes: usize, chunks: usize,
+        latency: Duration, readout: Duration)
+    {
+        let s = &mut self.stats;
+        s.frames += 1;
+        s.bytes += bytes as u64;
+        s.chunks = chunks;
+        s.latency = Some(latency);
+        s.readout = Some(readout);
+        if self.failing {
+            self.failing = false;
+            s.recoveries += 1;
+        }
+
+        self.prune(now);
+        self.recent.push_back((now, bytes));
+    }
+
+    /// Record a frame that was discarded because it was truncated.
+    pub fn truncated(&mut self, now: Instant, bytes: usize) {
+        self.stats.truncated += 1;
+        self.stats.bytes += bytes as u64;
+        self.failing = true;
+        self.prune(now);
+    }
+
+    /// Record a failed read.
+    pub fn error(&mut self, now: Instant, e: &Mu1603Error, bytes: usize) {
+        match e {
+            Mu1603Error::Rusb(rusb::Error::Timeout) => self.stats.timeouts += 1,
+            _ => self.stats.errors += 1,
+        }
+        self.stats.bytes += bytes as u64;
+        self.failing = true;
+        self.prune(now);
+    }
+
+    /// Compute the rates over the frames in the window.
+    fn update_rates<'a>(s: &mut StreamStats, 
+        recent: impl Iterator<Item = &'a (Instant, usize)> + Clone)
+    {
+        let mut times = recent.clone().map(|(t, _)| *t);
+        let (first, last) = match (times.next(), times.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return,
+        };
+        // NOTE: The first frame in the window only marks the start; its
+        // bytes arrived before the window.
+        let span = last.duration_since(first).as_secs_f64();
+        let n = (recent.clone().count() - 1) as f64;
+        let bytes: usize = recent.clone().skip(1).map(|(_, b)| b).sum();
+        if span > 0.0 {
+            s.fps = n / span;
+            s.throughput = bytes as f64 / span;
+        }
+
+        let intervals = recent.clone().zip(recent.skip(1))
+            .map(|(a, b)| b.0.duration_since(a.0).as_secs_f64());
+        let mean = span / n;
+        let var = intervals.map(|i| (i - mean).powi(2)).sum::<f64>() / n;
+        s.interval = Some(Duration::from_secs_f64(mean));
+        s.jitter = Some(Duration::from_secs_f64(var.sqrt()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frames at these times [in ms] after 'start', each 1000 bytes.
+    fn monitor(start: Instant, times: &[u64]) -> StreamMonitor {
+        let mut mon = StreamMonitor::new(Duration::from_secs(1));
+        for &t in times {
+            mon.frame(start + Duration::from_millis(t), 1000, 4,
+                Duration::from_millis(1), Duration::from_millis(2));
+        }
+        mon
+    }
+
+    fn ms(d: Option<Duration>) -> f64 {
+        d.unwrap().as_secs_f64() * 1e3
+    }
+
+    #[test]
+    fn rates() {
+        let start = Instant::now();
+        let at = |t: u64| start + Duration::from_millis(t);
+
+        let mon = monitor(start, &[0, 100, 200, 300, 400]);
+        let s = mon.stats_at(at(400));
+        assert_eq!(s.frames, 5);
+        assert_eq!(s.bytes, 5000);
+        assert_eq!(s.chunks, 4);
+        assert_eq!(s.latency, Some(Duration::from_millis(1)));
+        assert_eq!(s.readout, Some(Duration::from_millis(2)));
+        // The first frame only marks the start of the window
+        assert!((s.fps - 10.0).abs() < 1e-9, "{}", s.fps);
+        assert!((s.throughput - 10_000.0).abs() < 1e-6, "{}", s.throughput);
+        assert!((s.mb_per_sec() - 0.01).abs() < 1e-9);
+        assert!((ms(s.interval) - 100.0).abs() < 1e-6);
+        assert!(ms(s.jitter) < 1e-6);
+
+        // Intervals of 100ms and 200ms
+        let s = monitor(start, &[0, 100, 300]).stats_at(at(300));
+        assert!((ms(s.interval) - 150.0).abs() < 1e-6);
+        assert!((ms(s.jitter) - 50.0).abs() < 1e-6);
+
+        // A single frame isn't enough for any rates
+        let s = monitor(start, &[0]).stats_at(at(0));
+        assert_eq!((s.fps, s.throughput), (0.0, 0.0));
+        assert_eq!((s.interval, s.jitter), (None, None));
+    }
+
+    #[test]
+    fn stalled() {
+        let start = Instant::now();
+        let at = |t: u64| start + Duration::from_millis(t);
+        let mut mon = monitor(start, &[0, 100, 200, 300, 400]);
+
+        // 0ms and 100ms have fallen out of the window
+        let s = mon.stats_at(at(1200));
+        assert!((s.fps - 10.0).abs() < 1e-9, "{}", s.fps);
+        // Nothing left in the window
+        let s = mon.stats_at(at(1500));
+        assert_eq!((s.fps, s.throughput), (0.0, 0.0));
+        assert_eq!((s.interval, s.jitter), (None, None));
+        // Totals are kept
+        assert_eq!((s.frames, s.bytes), (5, 5000));
+
+        // Failures prune the window too
+        mon.error(at(1500), &Mu1603Error::Rusb(rusb::Error::Timeout), 0);
+        assert!(mon.recent.is_empty());
+        mon.frame(at(1600), 1000, 4, Duration::ZERO, Duration::ZERO);
+        mon.frame(at(1700), 1000, 4, Duration::ZERO, Duration::ZERO);
+        let s = mon.stats_at(at(1700));
+        assert!((s.fps - 10.0).abs() < 1e-9, "{}", s.fps);
+        assert_eq!(s.recoveries, 1);
+
+        // Snapshots use the current time
+        let mut mon = StreamMonitor::new(Duration::from_millis(1));
+        let now = Instant::now();
+        mon.frame(now, 1000, 1, Duration::ZERO, Duration::ZERO);
+        mon.frame(now + Duration::from_micros(100), 1000, 1, 
+            Duration::ZERO, Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(mon.stats().fps, 0.0);
+        assert_eq!(mon.stats().frames, 2);
+    }
+
+    #[test]
+    fn failures() {
+        let start = Instant::now();
+        let at = |t: u64| start + Duration::from_millis(t);
+        let mut mon = monitor(start, &[0]);
+
+        mon.error(at(10), &Mu1603Error::Rusb(rusb::Error::Timeout), 0);
+        mon.error(at(20), &Mu1603Error::Rusb(rusb::Error::Timeout), 100);
+        mon.error(at(30), &Mu1603Error::Rusb(rusb::Error::Pipe), 200);
+        mon.error(at(40), &Mu1603Error::InvalidFrame("short"), 0);
+        mon.truncated(at(50), 300);
+        let s = mon.stats_at(at(50));
+        assert_eq!(s.timeouts, 2);
+        assert_eq!(s.errors, 2);
+        assert_eq!(s.truncated, 1);
+        assert_eq!(s.failures(), 5);
+        // Partial frames are still counted as bytes read
+        assert_eq!(s.bytes, 1600);
+        assert_eq!(s.frames, 1);
+        assert_eq!(s.recoveries, 0);
+
+        // Only the first complete frame after the failures is a recovery
+        mon.frame(at(60), 1000, 4, Duration::ZERO, Duration::ZERO);
+        mon.frame(at(70), 1000, 4, Duration::ZERO, Duration::ZERO);
+        assert_eq!(mon.stats_at(at(70)).recoveries, 1);
+        mon.truncated(at(80), 0);
+        mon.frame(at(90), 1000, 4, Duration::ZERO, Duration::ZERO);
+        let s = mon.stats_at(at(90));
+        assert_eq!(s.recoveries, 2);
+        assert_eq!(s.truncated, 2);
+        assert_eq!(s.frames, 4);
+
+        mon.reset();
+        assert_eq!(mon.stats_at(at(90)), StreamStats::default());
+    }
+}
@@ -0,0 +1,427 @@
+//! Async (Tokio-compatible) interface to the camera.
+//!
+//! This is a thin layer over [Mu1603Controller]: requests are sent to the
+//! controller thread as [ControllerCommand]s, and a second thread picks
+//! the replies out of the [ControllerEvent]s and completes the matching
+//! futures. Frames are delivered by a [FrameSubscriber].
+//! None of this depends on a particular executor.
+
+use super::*;
+use std::collections::VecDeque;
+use std::future::{ Future, poll_fn };
+use std::pin::Pin;
+use std::sync::{ Arc, Weak, Mutex };
+use std::task::{ Context as TaskContext, Poll, Waker };
+use tokio::sync::oneshot;
+
+type Reply<T> = oneshot::Sender<Result<T, Mu1603Error>>;
+
+/// A request waiting for a reply from the controller thread.
+enum Pending {
+    Open(Reply<DeviceInfo>),
+    Start(Reply<Mu1603Options>),
+    Stop(Reply<()>),
+    Apply(Reply<Mu1603Options>),
+    SetFrameRate(Reply<()>),
+}
+impl Pending {
+    /// Complete the request with the event that answers it.
+    ///
+    /// NOTE: The requester may have given up waiting; that's fine.
+    fn complete(self, evt: ControllerEvent) {
+        match (self, evt) {
+            (Self::Open(tx), ControllerEvent::Connected(info)) => {
+                let _ = tx.send(Ok(info));
+            },
+            (Self::Open(tx), ControllerEvent::ConnectFailure(e)) => {
+                let _ = tx.send(Err(Mu1603Error::from(e)));
+            },
+            (Self::Start(tx), ControllerEvent::StreamStarted(state)) => {
+                let _ = tx.send(Ok(state));
+            },
+            (Self::Stop(tx), ControllerEvent::StreamStopped) => {
+                let _ = tx.send(Ok(()));
+            },
+            (Self::Apply(tx), ControllerEvent::UpdateAck(state)) => {
+                let _ = tx.send(Ok(state));
+            },
+            (Self::SetFrameRate(tx), ControllerEvent::FrameRateAck(_)) => {
+                let _ = tx.send(Ok(()));
+            },
+            (req, ControllerEvent::Failure(e)) => req.fail(e),
+            // The controller answers every command in order, so this
+            // shouldn't happen
+            (req, evt) => {
+                warn!(event = ?evt, "unexpected reply");
+                req.fail(Mu1603Error::WorkerTerminated);
+            },
+        }
+    }
+
+    fn fail(self, e: Mu1603Error) {
+        match self {
+            Self::Open(tx) => { let _ = tx.send(Err(e)); },
+            Self::Start(tx) => { let _ = tx.send(Err(e)); },
+            Self::Stop(tx) => { let _ = tx.send(Err(e)); },
+            Self::Apply(tx) => { let _ = tx.send(Err(e)); },
+            Self::SetFrameRate(tx) => { let _ = tx.send(Err(e)); },
+        }
+    }
+}
+
+/// State shared between a [FrameStream] and the event thread.
+#[derive(Default)]
+struct StreamState {
+    /// Read errors which haven't been delivered yet
+    errors: VecDeque<Mu1603Error>,
+    /// Set when the device is gone
+    ended: bool,
+    /// Task waiting in [FrameStream::poll_next]
+    waker: Option<Waker>,
+}
+
+/// State shared between an [AsyncMu1603] and the event thread.
+struct Shared {
+    ctl: Mu1603Controller,
+    /// Requests in the order they were sent to the controller
+    pending: VecDeque<Pending>,
+    streams: Vec<Weak<Mutex<StreamState>>>,
+}
+impl Shared {
+    fn handle_event(&mut self, evt: ControllerEvent) {
+        match evt {
+            ControllerEvent::ThreadInit |
+            ControllerEvent::ExposureProgress(_) |
+            ControllerEvent::Captured(_) => {},
+            ControllerEvent::ReadFailure(e) => {
+                self.notify_streams(|s| s.errors.push_back(e.clone()));
+            },
+            // The device is gone: end all of the streams
+            ControllerEvent::Disconnected => {
+                self.notify_streams(|s| s.ended = true);
+            },
+            evt => match self.pending.pop_front() {
+                Some(req) => req.complete(evt),
+                None => warn!(event = ?evt, "unexpected reply"),
+            },
+        }
+    }
+
+    fn notify_streams(&mut self, f: impl Fn(&mut StreamState)) {
+        self.streams.retain(|s| match s.upgrade() {
+            Some(state) => {
+                let mut state = state.lock().unwrap();
+                f(&mut state);
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+                true
+            },
+            // The stream was dropped
+            None => false,
+        });
+    }
+}
+
+/// Async handle to a [Mu1603] (or some other [FrameSource]) owned by a
+/// [Mu1603Controller].
+pub struct AsyncMu1603 {
+    shared: Arc<Mutex<Shared>>,
+    info: DeviceInfo,
+}
+impl AsyncMu1603 {
+    /// Open the camera on a new controller thread.
+    pub async fn open(builder: Mu1603Builder) -> Result<Self, Mu1603Error> {
+        Self::from_controller(Mu1603Controller::spawn(builder)).await
+    }
+
+    /// Open some other [FrameSource] (see [Mu1603Controller::spawn_with]).
+    pub async fn open_with<S, F>(options: Mu1603Options, open: F)
+        -> Result<Self, Mu1603Error>
+        where S: FrameSource + 'static,
+              F: FnMut() -> rusb::Result<S> + Send + 'static
+    {
+        Self::from_controller(Mu1603Controller::spawn_with(options, open)).await
+    }
+
+    async fn from_controller(ctl: Mu1603Controller) -> Result<Self, Mu1603Error> {
+        let shared = Arc::new(Mutex::new(Shared {
+            ctl,
+            pending: VecDeque::new(),
+            streams: Vec::new(),
+        }));
+        let events = shared.clone();
+        std::thread::spawn(move || Self::event_loop(events));
+
+        // NOTE: If this fails, the event thread shuts the controller down
+        let info = Self::call(&shared, ControllerCommand::Open, Pending::Open)
+            .await?;
+        Ok(Self { shared, info })
+    }
+
+    /// Deliver events from the controller until the handle is dropped.
+    ///
+    /// NOTE: This thread holds the last reference to the controller, so
+    /// we never block an executor thread waiting for it to shut down.
+    fn event_loop(shared: Arc<Mutex<Shared>>) {
+        while Arc::strong_count(&shared) > 1 {
+            {
+                let mut shared = shared.lock().unwrap();
+                while let Ok(evt) = shared.ctl.events().try_recv() {
+                    shared.handle_event(evt);
+                }
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Send a command to the controller and wait for the reply.
+    fn call<T>(shared: &Mutex<Shared>, cmd: ControllerCommand,
+        req: impl FnOnce(Reply<T>) -> Pending)
+        -> impl Future<Output = Result<T, Mu1603Error>>
+    {
+        let (tx, rx) = oneshot::channel();
+        {
+            // NOTE: The request is queued while holding the lock so that
+            // replies are matched up in the same order as the commands.
+            let mut shared = shared.lock().unwrap();
+            if shared.ctl.send(cmd).is_ok() {
+                shared.pending.push_back(req(tx));
+            }
+        }
+        async move {
+            rx.await.map_err(|_| Mu1603Error::WorkerTerminated)?
+        }
+    }
+
+    /// Return information identifying this device.
+    pub fn device_info(&self) -> &DeviceInfo {
+        &self.info
+    }
+
+    /// See [Mu1603::start_stream].
+    pub async fn start_stream(&self, mode: Mu1603Mode)
+        -> Result<Mu1603Options, Mu1603Error>
+    {
+        Self::call(&self.shared, ControllerCommand::Start(mode), Pending::Start)
+            .await
+    }
+
+    /// See [Mu1603::stop_stream].
+    pub async fn stop_stream(&self) -> Result<(), Mu1603Error> {
+        Self::call(&self.shared, ControllerCommand::Stop, Pending::Stop).await
+    }
+
+    /// See [Mu1603::apply_state].
+    pub async fn apply_state(&self, opts: Mu1603Options)
+        -> Result<Mu1603Options, Mu1603Error>
+    {
+        Self::call(&self.shared, ControllerCommand::Apply(opts), Pending::Apply)
+            .await
+    }
+
+    /// See [Mu1603::set_frame_rate].
+    pub async fn set_frame_rate(&self, rate: FrameRate)
+        -> Result<(), Mu1603Error>
+    {
+        Self::call(&self.shared, ControllerCommand::SetFrameRate(rate),
+            Pending::SetFrameRate
+        ).await
+    }
+
+    /// Statistics about the current stream
+    /// (see [Mu1603Controller::stream_stats]).
+    pub fn stream_stats(&self) -> StreamStats {
+        self.shared.lock().unwrap().ctl.stream_stats()
+    }
+
+    /// Subscribe to frames from the camera.
+    ///
+    /// Frames are dropped according to 'policy' if the stream isn't
+    /// polled often enough (see [Mu1603Controller::subscribe]).
+    pub fn frames(&self, policy: DropPolicy) -> FrameStream {
+        let state = Arc::new(Mutex::new(StreamState::default()));
+        let mut shared = self.shared.lock().unwrap();
+        shared.streams.push(Arc::downgrade(&state));
+        FrameStream { frames: shared.ctl.subscribe(policy), state }
+    }
+}
+
+/// A [futures_core::Stream] of frames from an [AsyncMu1603].
+///
+/// Errors while reading frames are delivered in between the frames.
+/// The stream ends when the [AsyncMu1603] is dropped, or when the device
+/// is disconnected.
+pub struct FrameStream {
+    frames: FrameSubscriber,
+    state: Arc<Mutex<StreamState>>,
+}
+impl FrameStream {
+    /// Wait for the next frame.
+    pub async fn next(&mut self) -> Option<Result<Arc<Frame>, Mu1603Error>> {
+        poll_fn(|cx| self.poll_frame(cx)).await
+    }
+
+    /// Number of frames dropped because this stream wasn't keeping up.
+    pub fn dropped(&self) -> usize {
+        self.frames.dropped()
+    }
+
+    fn poll_frame(&self, cx: &mut TaskContext<'_>)
+        -> Poll<Option<Result<Arc<Frame>, Mu1603Error>>>
+    {
+        let ended = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(e) = state.errors.pop_front() {
+                return Poll::Ready(Some(Err(e)));
+            }
+            state.waker = Some(cx.waker().clone());
+            state.ended
+        };
+        match self.frames.poll_recv(cx) {
+            Poll::Ready(frame) => Poll::Ready(frame.map(Ok)),
+            // NOTE: Frames that arrived before the device went away are
+            // still delivered.
+            Poll::Pending if ended => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+impl futures_core::Stream for FrameStream {
+    type Item = Result<Arc<Frame>, Mu1603Error>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext<'_>)
+        -> Poll<Option<Self::Item>>
+    {
+        self.poll_frame(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn options() -> Mu1603Options {
+        Mu1603Options {
+            id: 0,
+            mode: Mu1603Mode::Mode2,
+            exposure: ExposureTime::new_from_us(1_000),
+            analog_gain: AnalogGain::default(),
+            bitdepth: Mu1603BitDepth::Depth8,
+        }
+    }
+
+    /// Run a future to completion on this thread.
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        struct Unpark(std::thread::Thread);
+        impl std::task::Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+        let mut cx = TaskContext::from_waker(&waker);
+        let mut fut = std::pin::pin!(fut);
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            if let Poll::Ready(res) = fut.as_mut().poll(&mut cx) {
+                return res;
+            }
+            assert!(Instant::now() < deadline, "timed out");
+            std::thread::park_timeout(Duration::from_millis(100));
+        }
+    }
+
+    fn open() -> AsyncMu1603 {
+        block_on(AsyncMu1603::open_with(options(), || {
+            Ok(Mu1603Emulator::new(
+                EmulatorSensor::default(), EmulatorScene::default()
+            ).realtime(false))
+        })).unwrap()
+    }
+
+    #[test]
+    fn open_failure() {
+        let res = block_on(AsyncMu1603::open_with(options(), || {
+            Err::<Mu1603Emulator, _>(rusb::Error::NoDevice)
+        }));
+        assert!(matches!(res, Err(Mu1603Error::Rusb(rusb::Error::NoDevice))));
+    }
+
+    #[test]
+    fn requests() {
+        let cam = open();
+        assert_eq!(cam.device_info().serial.as_deref(), Some("emulator"));
+
+        // Not streaming yet
+        assert!(matches!(block_on(cam.apply_state(options())),
+            Err(Mu1603Error::NotStreaming)
+        ));
+
+        let state = block_on(cam.start_stream(Mu1603Mode::Mode2)).unwrap();
+        assert_eq!(state.mode, Mu1603Mode::Mode2);
+
+        let mut opts = state;
+        opts.id = 1;
+        opts.exposure = ExposureTime::new_from_us(2_000);
+        let state = block_on(cam.apply_state(opts)).unwrap();
+        assert_eq!(state.id, 1);
+        assert_eq!(state.exposure, opts.exposure);
+
+        block_on(cam.set_frame_rate(FrameRate::Decimate(2))).unwrap();
+        block_on(cam.stop_stream()).unwrap();
+        // Stopping twice is fine
+        block_on(cam.stop_stream()).unwrap();
+    }
+
+    #[test]
+    fn frames() {
+        let cam = open();
+        let mut frames = cam.frames(DropPolicy::Lossless);
+        block_on(cam.set_frame_rate(FrameRate::Decimate(3))).unwrap();
+        block_on(cam.start_stream(Mu1603Mode::Mode2)).unwrap();
+
+        let first = block_on(frames.next()).unwrap().unwrap();
+        let second = block_on(frames.next()).unwrap().unwrap();
+        let (width, height) = Mu1603Mode::Mode2.dimensions();
+        assert_eq!(first.data.len(), width * height);
+        assert_eq!(second.id, first.id + 1);
+        assert_eq!(second.skipped, 2);
+    }
+
+    #[test]
+    fn drop_ends_streams() {
+        let cam = open();
+        let mut frames = cam.frames(DropPolicy::LatestOnly);
+        block_on(cam.start_stream(Mu1603Mode::Mode2)).unwrap();
+        assert!(block_on(frames.next()).unwrap().is_ok());
+
+        drop(cam);
+        // Whatever was already queued, the stream ends
+        while let Some(frame) = block_on(frames.next()) {
+            assert!(frame.is_ok());
+        }
+    }
+
+    #[test]
+    fn read_errors() {
+        let cam = open();
+        let mut frames = cam.frames(DropPolicy::Lossless);
+
+        // Stand in for the controller reporting a failed read
+        {
+            let mut shared = cam.shared.lock().unwrap();
+            shared.handle_event(ControllerEvent::ReadFailure(
+                Mu1603Error::InvalidFrame("short")
+            ));
+        }
+        assert!(matches!(block_on(frames.next()),
+            Some(Err(Mu1603Error::InvalidFrame("short")))
+        ));
+
+        // A disconnect ends the stream
+        cam.shared.lock().unwrap().handle_event(ControllerEvent::Disconnected);
+        assert!(block_on(frames.next()).is_none());
+    }
+}
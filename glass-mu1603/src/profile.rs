@@ -0,0 +1,289 @@
+//! Per-device camera profiles.
+//!
+//! A [CameraProfile] records the [NoiseProfile] measured for each gain and
+//! bit depth (see [PhotonTransfer]), tied to the serial number of the
+//! device it was measured on. Profiles use the same 'key = value' format
+//! as calibration files, with a section for each gain and bit depth:
+//!
+//! ```text
+//! serial = 0123456789
+//!
+//! [gain 100, 8-bit]
+//! R.conversion_gain = 40.1
+//! R.read_noise      = 3.02
+//! R.full_well       = 10012
+//! R.dark_current    = 5.1
+//! R.black_level     = 2.0
+//! Gr.conversion_gain = 39.8
+//! ...
+//! ```
+//!
+//! Figures that couldn't be measured are left out.
+//!
+//! A profile may also have a '[color]' section with the [ColorPipeline] used
+//! to develop frames from the device.
+
+use super::*;
+use glass_common::*;
+
+/// Noise figures measured on a particular device.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CameraProfile {
+    /// Serial number of the device (see [DeviceInfo])
+    pub serial: String,
+
+    pub noise: Vec<NoiseProfile>,
+
+    /// Color processing for frames from this device
+    pub color: Option<ColorPipeline>,
+}
+impl CameraProfile {
+    pub fn new(serial: &str) -> Self {
+        Self { serial: serial.to_string(), noise: Vec::new(), color: None }
+    }
+
+    /// The usual name of the profile for some device, ie. '<serial>.profile'.
+    pub fn filename(serial: &str) -> String {
+        // NOTE: Don't trust the device to give us a sensible file name
+        let serial: String = serial.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+        format!("{}.profile", serial)
+    }
+
+    /// Noise figures for some gain and bit depth.
+    pub fn noise_for(&self, gain: AnalogGain, bitdepth: Mu1603BitDepth)
+        -> Option<&NoiseProfile>
+    {
+        self.noise.iter().find(|n| n.analog_gain == gain && n.bitdepth == bitdepth)
+    }
+
+    /// Add noise figures, replacing any with the same gain and bit depth.
+    pub fn update(&mut self, noise: NoiseProfile) {
+        match self.noise.iter_mut().find(|n| {
+            n.analog_gain == noise.analog_gain && n.bitdepth == noise.bitdepth
+        }) {
+            Some(n) => *n = noise,
+            None => self.noise.push(noise),
+        }
+        self.noise.sort_by_key(|n| (n.bitdepth.bits(), n.analog_gain.percent()));
+    }
+
+    /// Parse a profile from a string.
+    pub fn parse(src: &str) -> Result<Self, CalibrationError> {
+        let mut res = Self::default();
+        let mut in_color = false;
+        for (num, line) in config_lines(src) {
+            let err = |msg: String| CalibrationError { line: num, msg };
+
+            if line == "[color]" {
+                res.color.get_or_insert_with(ColorPipeline::default);
+                in_color = true;
+                continue;
+            }
+
+            // Section headers look like '[gain 100, 8-bit]'
+            if let Some(section) = line.strip_prefix('[') {
+                in_color = false;
+                let bad_section = || err(format!("invalid section '{}'", line));
+                let (gain, bits) = section.strip_suffix(']')
+                    .and_then(|s| s.split_once(','))
+                    .ok_or_else(bad_section)?;
+                let gain = gain.trim().strip_prefix("gain ")
+                    .and_then(|g| g.trim().parse().ok())
+                    .ok_or_else(bad_section)?;
+                let bits = bits.trim().strip_suffix("-bit")
+                    .and_then(|b| b.parse().ok())
+                    .and_then(Mu1603BitDepth::from_bits)
+                    .ok_or_else(bad_section)?;
+                res.noise.push(NoiseProfile {
+                    analog_gain: AnalogGain::new_from_percent(gain),
+                    bitdepth: bits,
+                    channels: [None; 4],
+                });
+                continue;
+            }
+
+            let (key, val) = split_key_value(line).map_err(err)?;
+            if let (true, Some(color)) = (in_color, res.color.as_mut()) {
+                color.set(key, val).map_err(err)?;
+                continue;
+            }
+            if key == "serial" {
+                res.serial = val.to_string();
+                continue;
+            }
+
+            let (ch, name) = key.split_once('.')
+                .ok_or_else(|| err(format!("unknown key '{}'", key)))?;
+            let ch = BayerChannel::from_name(ch)
+                .ok_or_else(|| err(format!("unknown channel '{}'", ch)))?;
+            let val: f64 = val.parse()
+                .map_err(|e| err(format!("invalid value for '{}': {}", key, e)))?;
+            let noise = res.noise.last_mut()
+                .ok_or_else(|| err("expected a section before this key".to_string()))?;
+            let c = noise.channels[ch.index()].get_or_insert(ChannelNoise {
+                conversion_gain: f64::NAN,
+                read_noise: f64::NAN,
+                full_well: None,
+                dark_current: None,
+                black_level: 0.0,
+            });
+            match name {
+                "conversion_gain" => c.conversion_gain = val,
+                "read_noise" => c.read_noise = val,
+                "full_well" => c.full_well = Some(val),
+                "dark_current" => c.dark_current = Some(val),
+                "black_level" => c.black_level = val,
+                _ => return Err(err(format!("unknown key '{}'", key))),
+            }
+        }
+        Ok(res)
+    }
+
+    /// Read and parse a profile.
+    pub fn load(filename: &str) -> Result<Self, CalibrationError> {
+        let src = std::fs::read_to_string(filename).map_err(|e| {
+            CalibrationError { line: 0, msg: format!("{}: {}", filename, e) }
+        })?;
+        Self::parse(&src)
+    }
+
+    /// Write this profile to a file.
+    pub fn save(&self, filename: &str) -> std::io::Result<()> {
+        std::fs::write(filename, self.to_string())
+    }
+}
+impl std::fmt::Display for CameraProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "serial = {}", self.serial)?;
+        for noise in self.noise.iter() {
+            writeln!(f)?;
+            writeln!(f, "[gain {}, {}-bit]",
+                noise.analog_gain.percent(), noise.bitdepth.bits())?;
+            for (ch, c) in BayerChannel::ALL.iter().zip(noise.channels.iter()) {
+                let Some(c) = c else { continue };
+                let name = ch.name();
+                writeln!(f, "{}.conversion_gain = {:.4}", name, c.conversion_gain)?;
+                writeln!(f, "{}.read_noise = {:.4}", name, c.read_noise)?;
+                if let Some(fw) = c.full_well {
+                    writeln!(f, "{}.full_well = {:.1}", name, fw)?;
+                }
+                if let Some(dc) = c.dark_current {
+                    writeln!(f, "{}.dark_current = {:.4}", name, dc)?;
+                }
+                writeln!(f, "{}.black_level = {:.4}", name, c.black_level)?;
+            }
+        }
+        if let Some(color) = &self.color {
+            writeln!(f)?;
+            writeln!(f, "[color]")?;
+            write!(f, "{}", color)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILE: &str = "\
+serial = 0123456789
+
+# Measured with glass-ptc
+[gain 100, 8-bit]
+R.conversion_gain = 40.1
+R.read_noise      = 3.02
+R.full_well       = 10012
+R.black_level     = 2.0
+Gb.conversion_gain = 39.8
+Gb.read_noise      = 3.1
+Gb.dark_current    = 5.1
+Gb.black_level     = 2.5
+
+[gain 200, 12-bit]
+B.conversion_gain = 1.25
+B.read_noise      = 3.0
+B.black_level     = 32
+
+[color]
+wb_gains = 2.0 1.0 1.5
+";
+
+    #[test]
+    fn parse_profile() {
+        let profile = CameraProfile::parse(PROFILE).unwrap();
+        assert_eq!(profile.serial, "0123456789");
+        assert_eq!(profile.noise.len(), 2);
+
+        let noise = profile.noise_for(AnalogGain::new_from_percent(100),
+            Mu1603BitDepth::Depth8).unwrap();
+        let r = noise.channels[BayerChannel::Red.index()].unwrap();
+        assert_eq!(r.conversion_gain, 40.1);
+        assert_eq!(r.read_noise, 3.02);
+        assert_eq!(r.full_well, Some(10012.0));
+        assert_eq!(r.dark_current, None);
+        assert_eq!(r.black_level, 2.0);
+        let gb = noise.channels[BayerChannel::GreenB.index()].unwrap();
+        assert_eq!(gb.full_well, None);
+        assert_eq!(gb.dark_current, Some(5.1));
+        assert!(noise.channels[BayerChannel::GreenR.index()].is_none());
+        assert!(noise.channels[BayerChannel::Blue.index()].is_none());
+
+        let noise = profile.noise_for(AnalogGain::new_from_percent(200),
+            Mu1603BitDepth::Depth12).unwrap();
+        let b = noise.channels[BayerChannel::Blue.index()].unwrap();
+        assert_eq!(b.conversion_gain, 1.25);
+        assert_eq!(b.black_level, 32.0);
+
+        let color = profile.color.unwrap();
+        assert_eq!(color.wb_gains, [2.0, 1.0, 1.5]);
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let profile = CameraProfile::parse(PROFILE).unwrap();
+        assert_eq!(CameraProfile::parse(&profile.to_string()).unwrap(), profile);
+    }
+
+    #[test]
+    fn update_replaces_figures() {
+        let mut profile = CameraProfile::parse(PROFILE).unwrap();
+        let noise = NoiseProfile {
+            analog_gain: AnalogGain::new_from_percent(100),
+            bitdepth: Mu1603BitDepth::Depth12,
+            channels: [None; 4],
+        };
+        profile.update(noise);
+        profile.update(noise);
+        assert_eq!(profile.noise.len(), 3);
+        // Sorted by bit depth, then gain
+        assert_eq!(profile.noise[1], noise);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases = [
+            ("serial = 1\nR.read_noise = 3\n", 2, "expected a section"),
+            ("[gain 100]\n", 1, "invalid section"),
+            ("[gain 100, 10-bit]\n", 1, "invalid section"),
+            ("[gain 100, 8-bit]\nR.read_noise\n", 2, "expected 'key = value'"),
+            ("[gain 100, 8-bit]\nG.read_noise = 3\n", 2, "unknown channel 'G'"),
+            ("[gain 100, 8-bit]\nR.noise = 3\n", 2, "unknown key 'R.noise'"),
+            ("[gain 100, 8-bit]\nR.read_noise = x\n", 2, "invalid value"),
+            ("[color]\nmatrix = 1 2\n", 2, "expected 9 values"),
+        ];
+        for (src, line, msg) in cases {
+            let err = CameraProfile::parse(src).unwrap_err();
+            assert_eq!(err.line, line, "{:?}", src);
+            assert!(err.msg.contains(msg), "{:?}: {}", src, err.msg);
+        }
+    }
+
+    #[test]
+    fn filename() {
+        assert_eq!(CameraProfile::filename("AB-12"), "AB-12.profile");
+        assert_eq!(CameraProfile::filename("../x y"), "___x_y.profile");
+    }
+}
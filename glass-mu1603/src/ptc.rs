@@ -0,0 +1,490 @@
+//! Noise characterisation with a photon transfer curve (PTC).
+//!
+//! The camera is pointed at a uniformly lit target (a "flat field"), and
+//! we capture pairs of frames over a range of exposure times. For each
+//! pair, the mean gives the signal, and half the variance of the difference
+//! gives the temporal noise (fixed-pattern noise cancels out). Then:
+//!
+//! - Shot noise has a variance equal to the number of electrons, so the
+//!   slope of variance vs. signal [in ADU] is the inverse of the
+//!   conversion gain [in e-/ADU].
+//!
+//! - Once the sensor (or the ADC) saturates, the variance collapses. The
+//!   signal where the variance peaks is the full-well capacity.
+//!
+//! The same capture with the lens capped (the "dark" series) gives the
+//! read noise, the black level, and the dark current (the slope of the
+//! dark signal vs. exposure time).
+//!
+//! All of this depends on the analog gain and bit depth, so the capture
+//! is repeated for each combination. The results are collected in a
+//! [CameraProfile].
+
+use super::*;
+use glass_common::*;
+
+/// Settings for capturing a photon transfer curve.
+#[derive(Clone, Debug)]
+pub struct PhotonTransfer {
+    pub mode: Mu1603Mode,
+
+    /// Exposure times to capture (see [ExposureSweep::geometric])
+    pub exposures: Vec<ExposureTime>,
+
+    /// Analog gain settings to capture
+    pub gains: Vec<AnalogGain>,
+
+    /// Bit depths to capture
+    pub bitdepths: Vec<Mu1603BitDepth>,
+
+    /// Number of frame pairs to average for each exposure
+    pub pairs_per_step: usize,
+
+    /// Number of frames to discard after changing the settings
+    pub settle_frames: usize,
+
+    /// Bayer pattern of the sensor
+    pub pattern: BayerPattern,
+
+    /// Number of failed reads to tolerate for each frame
+    pub max_retries: usize,
+}
+impl PhotonTransfer {
+    pub fn new(mode: Mu1603Mode) -> Self {
+        Self {
+            mode,
+            exposures: ExposureSweep::geometric(
+                ExposureTime::new_from_us(1_000),
+                ExposureTime::new_from_us(500_000),
+                24
+            ),
+            gains: vec![AnalogGain::default()],
+            bitdepths: vec![Mu1603BitDepth::Depth8],
+            pairs_per_step: 2,
+            settle_frames: 2,
+            pattern: Mu1603::PATTERN,
+            max_retries: 10,
+        }
+    }
+
+    /// Capture one [PtcSeries] for each combination of gain and bit depth.
+    ///
+    /// Run this once with the target lit (the flat series), and once with
+    /// the lens capped (the dark series).
+    ///
+    /// NOTE: The real device can't change the gain or bit depth yet.
+    /// Combinations that the source doesn't accept are skipped.
+    pub fn run<S: FrameSource>(&self, cam: &mut S)
+        -> Result<Vec<PtcSeries>, Mu1603Error>
+    {
+        let mut opts = cam.start_stream(self.mode)?;
+        let (width, height) = self.mode.dimensions();
+
+        let mut res = Vec::new();
+        for bitdepth in self.bitdepths.iter() {
+            for gain in self.gains.iter() {
+                opts.bitdepth = *bitdepth;
+                opts.analog_gain = *gain;
+                opts = cam.apply_state(opts)?;
+                if opts.bitdepth != *bitdepth || opts.analog_gain != *gain {
+                    tracing::warn!(gain = gain.percent(), bits = bitdepth.bits(),
+                        "settings not supported by this source, skipping"
+                    );
+                    continue;
+                }
+                let _span = tracing::info_span!("ptc_series",
+                    gain = gain.percent(), bits = bitdepth.bits()
+                ).entered();
+
+                let bpp = bitdepth.bpp();
+                let mut points = Vec::with_capacity(self.exposures.len());
+                for exposure in self.exposures.iter() {
+                    opts.exposure = *exposure;
+                    opts = cam.apply_state(opts)?;
+                    for _ in 0..self.settle_frames {
+                        cam.read_frame_with_retries(self.max_retries)?;
+                    }
+
+                    let mut means = [0.0; 4];
+                    let mut variances = [0.0; 4];
+                    for _ in 0..self.pairs_per_step {
+                        let a = cam.read_frame_with_retries(self.max_retries)?;
+                        let b = cam.read_frame_with_retries(self.max_retries)?;
+                        let stats_a = bayer_stats_bpp(&a, width, height,
+                            self.pattern, bpp).map_err(Mu1603Error::InvalidFrame)?;
+                        let stats_b = bayer_stats_bpp(&b, width, height,
+                            self.pattern, bpp).map_err(Mu1603Error::InvalidFrame)?;
+                        let diff = bayer_diff_stats_bpp(&a, &b, width, height,
+                            self.pattern, bpp).map_err(Mu1603Error::InvalidFrame)?;
+                        for ch in 0..4 {
+                            means[ch] += (stats_a[ch].mean + stats_b[ch].mean) / 2.0;
+                            // NOTE: The difference has twice the variance
+                            // of a single frame.
+                            variances[ch] += diff[ch].variance / 2.0;
+                        }
+                    }
+                    let n = self.pairs_per_step.max(1) as f64;
+                    let point = PtcPoint {
+                        exposure: *exposure,
+                        means: means.map(|m| m / n),
+                        variances: variances.map(|v| v / n),
+                    };
+                    tracing::info!(exposure = %point.exposure,
+                        means = ?point.means, variances = ?point.variances,
+                        "ptc step"
+                    );
+                    points.push(point);
+                }
+                res.push(PtcSeries {
+                    analog_gain: *gain, bitdepth: *bitdepth, points
+                });
+            }
+        }
+        cam.stop_stream()?;
+        Ok(res)
+    }
+}
+
+/// Measurements for a single exposure time in a [PtcSeries].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PtcPoint {
+    pub exposure: ExposureTime,
+
+    /// Mean signal for each channel [in ADU]
+    /// (ordered like [BayerChannel::ALL])
+    pub means: [f64; 4],
+
+    /// Temporal variance for each channel [in ADU^2]
+    pub variances: [f64; 4],
+}
+
+/// Measurements over a range of exposures, with fixed gain and bit depth.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PtcSeries {
+    pub analog_gain: AnalogGain,
+    pub bitdepth: Mu1603BitDepth,
+    pub points: Vec<PtcPoint>,
+}
+impl PtcSeries {
+    /// Return 'true' if this series was captured with the same settings.
+    fn same_settings(&self, other: &Self) -> bool {
+        self.analog_gain == other.analog_gain && self.bitdepth == other.bitdepth
+    }
+
+    /// The point captured with some exposure time.
+    fn point_at(&self, exposure: ExposureTime) -> Option<&PtcPoint> {
+        self.points.iter().find(|p| p.exposure == exposure)
+    }
+}
+
+/// Flat and dark series from [PhotonTransfer::run].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PtcCapture {
+    pub flat: Vec<PtcSeries>,
+    pub dark: Vec<PtcSeries>,
+}
+impl PtcCapture {
+    /// The dark series captured with the same settings as a flat series.
+    pub fn dark_for(&self, flat: &PtcSeries) -> Option<&PtcSeries> {
+        self.dark.iter().find(|d| d.same_settings(flat))
+    }
+
+    /// Analyse each flat series (see [NoiseProfile::analyse]).
+    pub fn analyse(&self) -> Vec<NoiseProfile> {
+        self.flat.iter()
+            .map(|flat| NoiseProfile::analyse(flat, self.dark_for(flat)))
+            .collect()
+    }
+
+    /// Write the measurements as CSV, with one row per channel and exposure.
+    pub fn to_csv(&self) -> String {
+        use std::fmt::Write;
+        let mut s = String::new();
+        let _ = writeln!(s, "series,gain_percent,bits,exposure_us,channel,mean_adu,variance_adu2");
+        let all = self.flat.iter().map(|s| ("flat", s))
+            .chain(self.dark.iter().map(|s| ("dark", s)));
+        for (kind, series) in all {
+            for p in series.points.iter() {
+                for ch in BayerChannel::ALL {
+                    let _ = writeln!(s, "{},{},{},{},{},{},{}",
+                        kind, series.analog_gain.percent(), series.bitdepth.bits(),
+                        p.exposure.microseconds(), ch.name(),
+                        p.means[ch.index()], p.variances[ch.index()]
+                    );
+                }
+            }
+        }
+        s
+    }
+
+    /// Parse measurements written by [PtcCapture::to_csv].
+    pub fn parse_csv(src: &str) -> Result<Self, CalibrationError> {
+        let mut res = Self::default();
+        for (num, line) in src.lines().enumerate().skip(1) {
+            let err = |msg: &str| CalibrationError {
+                line: num + 1, msg: msg.to_string()
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+            let [kind, gain, bits, exposure, ch, mean, var] = fields[..] else {
+                return Err(err("expected 7 fields"));
+            };
+            let gain: usize = gain.parse().map_err(|_| err("invalid gain"))?;
+            let bitdepth = bits.parse().ok().and_then(Mu1603BitDepth::from_bits)
+                .ok_or_else(|| err("invalid bit depth"))?;
+            let exposure = exposure.parse().map(ExposureTime::new_from_us)
+                .map_err(|_| err("invalid exposure"))?;
+            let ch = BayerChannel::from_name(ch)
+                .ok_or_else(|| err("invalid channel"))?;
+            let mean: f64 = mean.parse().map_err(|_| err("invalid mean"))?;
+            let var: f64 = var.parse().map_err(|_| err("invalid variance"))?;
+
+            let list = match kind {
+                "flat" => &mut res.flat,
+                "dark" => &mut res.dark,
+                _ => return Err(err("expected 'flat' or 'dark'")),
+            };
+            let analog_gain = AnalogGain::new_from_percent(gain);
+            let series = match list.iter().position(|s| {
+                s.analog_gain == analog_gain && s.bitdepth == bitdepth
+            }) {
+                Some(idx) => &mut list[idx],
+                None => {
+                    list.push(PtcSeries { analog_gain, bitdepth, points: vec![] });
+                    list.last_mut().unwrap()
+                },
+            };
+            let point = match series.points.iter()
+                .position(|p| p.exposure == exposure)
+            {
+                Some(idx) => &mut series.points[idx],
+                None => {
+                    series.points.push(PtcPoint {
+                        exposure, means: [0.0; 4], variances: [0.0; 4]
+                    });
+                    series.points.last_mut().unwrap()
+                },
+            };
+            point.means[ch.index()] = mean;
+            point.variances[ch.index()] = var;
+        }
+        Ok(res)
+    }
+}
+
+/// Noise figures for a single Bayer channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelNoise {
+    /// Conversion gain [in e-/ADU]
+    pub conversion_gain: f64,
+
+    /// Read noise [in e- RMS]
+    pub read_noise: f64,
+
+    /// Signal where the variance peaks [in e-].
+    ///
+    /// NOTE: At high gain, the ADC may saturate before the pixel does,
+    /// in which case this is the largest signal that can be measured.
+    pub full_well: Option<f64>,
+
+    /// Dark current [in e-/s]
+    pub dark_current: Option<f64>,
+
+    /// Signal with no light [in ADU]
+    pub black_level: f64,
+}
+
+/// Noise figures for each channel, with fixed gain and bit depth.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoiseProfile {
+    pub analog_gain: AnalogGain,
+    pub bitdepth: Mu1603BitDepth,
+
+    /// Ordered like [BayerChannel::ALL]. Channels are 'None' when there
+    /// weren't enough usable points.
+    pub channels: [Option<ChannelNoise>; 4],
+}
+impl NoiseProfile {
+    /// Compute noise figures from a flat series, and optionally the dark
+    /// series captured with the same settings.
+    ///
+    /// Without a dark series, the black level is taken from the shortest
+    /// exposure, and the read noise is estimated from the intercept of the
+    /// photon transfer curve (which is much less accurate).
+    pub fn analyse(flat: &PtcSeries, dark: Option<&PtcSeries>) -> Self {
+        let channels = BayerChannel::ALL.map(|ch| {
+            Self::analyse_channel(flat, dark, ch.index())
+        });
+        Self { analog_gain: flat.analog_gain, bitdepth: flat.bitdepth, channels }
+    }
+
+    fn analyse_channel(flat: &PtcSeries, dark: Option<&PtcSeries>, ch: usize)
+        -> Option<ChannelNoise>
+    {
+        let max_adu = flat.bitdepth.max_value() as f64;
+
+        // Dark signal vs. exposure time [in seconds]
+        let dark_fit = dark.and_then(|d| {
+            let points: Vec<(f64, f64)> = d.points.iter()
+                .map(|p| (p.exposure.microseconds() as f64 / 1e6, p.means[ch]))
+                .collect();
+            LinearFit::new(&points)
+        });
+        let black_level = match dark_fit {
+            Some(f) => f.intercept,
+            None => flat.points.iter().map(|p| p.means[ch]).fold(max_adu, f64::min),
+        };
+
+        // Signal above the dark level, and the variance due to shot noise
+        let curve: Vec<(f64, f64)> = flat.points.iter().map(|p| {
+            match dark.and_then(|d| d.point_at(p.exposure)) {
+                Some(d) => (p.means[ch] - d.means[ch], p.variances[ch] - d.variances[ch]),
+                None => (p.means[ch] - black_level, p.variances[ch]),
+            }
+        }).collect();
+
+        // NOTE: If the variance peaks at the longest exposure, we haven't
+        // seen the sensor saturate.
+        let peak = (0..curve.len())
+            .max_by(|a, b| curve[*a].1.total_cmp(&curve[*b].1))?;
+        let saturated = peak + 1 < curve.len();
+
+        // NOTE: Signal close to the black level is distorted by clipping
+        // the noise at zero, and by quantisation (especially with 8-bit data).
+        let floor = max_adu / 128.0;
+        let points: Vec<(f64, f64)> = curve.iter().enumerate()
+            .filter(|(i, (s, _))| {
+                (!saturated || *i < peak) && *s > floor && *s + black_level < 0.9 * max_adu
+            })
+            .map(|(_, p)| *p)
+            .collect();
+        let fit = LinearFit::new(&points).filter(|f| f.slope > 0.0)?;
+        let conversion_gain = 1.0 / fit.slope;
+
+        let read_variance = match dark.and_then(|d| d.points.first()) {
+            Some(p) => p.variances[ch],
+            None => fit.intercept,
+        };
+        Some(ChannelNoise {
+            conversion_gain,
+            read_noise: read_variance.max(0.0).sqrt() * conversion_gain,
+            full_well: saturated.then(|| curve[peak].0 * conversion_gain),
+            dark_current: dark_fit.map(|f| f.slope * conversion_gain),
+            black_level,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(exposure: usize, mean: f64, variance: f64) -> PtcPoint {
+        PtcPoint {
+            exposure: ExposureTime::new_from_us(exposure),
+            means: [mean, mean + 1.0, mean + 2.0, mean + 3.0],
+            variances: [variance; 4],
+        }
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let capture = PtcCapture {
+            flat: vec![
+                PtcSeries {
+                    analog_gain: AnalogGain::new_from_percent(100),
+                    bitdepth: Mu1603BitDepth::Depth8,
+                    points: vec![point(1_000, 10.5, 0.25), point(2_000, 20.0, 0.5)],
+                },
+                PtcSeries {
+                    analog_gain: AnalogGain::new_from_percent(200),
+                    bitdepth: Mu1603BitDepth::Depth12,
+                    points: vec![point(1_000, 300.0, 12.0)],
+                },
+            ],
+            dark: vec![PtcSeries {
+                analog_gain: AnalogGain::new_from_percent(100),
+                bitdepth: Mu1603BitDepth::Depth8,
+                points: vec![point(1_000, 2.0, 0.125)],
+            }],
+        };
+        let csv = capture.to_csv();
+        assert_eq!(csv.lines().count(), 1 + 4 * 4);
+        assert_eq!(PtcCapture::parse_csv(&csv).unwrap(), capture);
+    }
+
+    #[test]
+    fn csv_errors() {
+        let header = "series,gain_percent,bits,exposure_us,channel,mean_adu,variance_adu2\n";
+        let cases = [
+            ("flat,100,8,1000,R,1.0\n", "expected 7 fields"),
+            ("flat,x,8,1000,R,1.0,0.5\n", "invalid gain"),
+            ("flat,100,10,1000,R,1.0,0.5\n", "invalid bit depth"),
+            ("flat,100,8,-1,R,1.0,0.5\n", "invalid exposure"),
+            ("flat,100,8,1000,G,1.0,0.5\n", "invalid channel"),
+            ("flat,100,8,1000,R,x,0.5\n", "invalid mean"),
+            ("flat,100,8,1000,R,1.0,x\n", "invalid variance"),
+            ("bright,100,8,1000,R,1.0,0.5\n", "expected 'flat' or 'dark'"),
+        ];
+        for (row, msg) in cases {
+            let src = format!("{}\n{}", header, row);
+            let err = PtcCapture::parse_csv(&src).unwrap_err();
+            assert_eq!(err.line, 3, "{:?}", row);
+            assert!(err.msg.contains(msg), "{:?}: {}", row, err.msg);
+        }
+    }
+
+    /// The analysis should recover the noise figures of the emulated sensor.
+    #[test]
+    fn analyse_emulator() {
+        let sensor = EmulatorSensor::default();
+        let mut emu = Mu1603Emulator::new(sensor, EmulatorScene::default())
+            .realtime(false);
+        let mut ptc = PhotonTransfer::new(Mu1603Mode::Mode2);
+        ptc.exposures = ExposureSweep::geometric(
+            ExposureTime::new_from_us(2_000), ExposureTime::new_from_us(400_000), 8
+        );
+        ptc.pairs_per_step = 1;
+        ptc.settle_frames = 0;
+        // NOTE: Read noise is well below one ADU with 8-bit data
+        ptc.bitdepths = vec![Mu1603BitDepth::Depth12];
+
+        let flat = ptc.run(&mut emu).unwrap();
+        emu.scene_mut().flux = [0.0; 4];
+        let dark = ptc.run(&mut emu).unwrap();
+        let capture = PtcCapture { flat, dark };
+        assert_eq!(capture.flat.len(), 1);
+        assert_eq!(capture.dark.len(), 1);
+        assert_eq!(capture.flat[0].points.len(), 8);
+
+        let profiles = capture.analyse();
+        assert_eq!(profiles.len(), 1);
+        assert_eq!(profiles[0].bitdepth, Mu1603BitDepth::Depth12);
+
+        // At 12 bits and 100% gain, one ADU is 1/16 of an 8-bit ADU
+        let conversion_gain = sensor.conversion_gain / 16.0;
+        for (ch, noise) in BayerChannel::ALL.iter().zip(profiles[0].channels) {
+            let noise = noise.unwrap_or_else(|| panic!("no fit for {:?}", ch));
+            assert!(rel_ok(noise.conversion_gain, conversion_gain, 0.05),
+                "{:?}: {:?}", ch, noise);
+            assert!(rel_ok(noise.read_noise, sensor.read_noise, 0.1),
+                "{:?}: {:?}", ch, noise);
+            assert!(rel_ok(noise.dark_current.unwrap(), sensor.dark_current, 0.1),
+                "{:?}: {:?}", ch, noise);
+            assert!((noise.black_level - sensor.black_level * 16.0).abs() < 0.5,
+                "{:?}: {:?}", ch, noise);
+
+            // NOTE: The variance peaks at the last exposure before the
+            // sensor saturates, and the exposures are about 2x apart.
+            let full_well = noise.full_well.unwrap();
+            assert!(full_well <= sensor.full_well * 1.05, "{:?}: {:?}", ch, noise);
+            assert!(full_well > sensor.full_well / 2.5, "{:?}: {:?}", ch, noise);
+        }
+    }
+
+    fn rel_ok(x: f64, expected: f64, tolerance: f64) -> bool {
+        (x - expected).abs() / expected < tolerance
+    }
+}
@@ -0,0 +1,218 @@
+//! Limiting the rate of frames delivered while streaming.
+//!
+//! The sensor produces frames at whatever rate the exposure settings allow,
+//! and every frame still has to be read over USB. For time-lapse and
+//! monitoring, most of them aren't wanted: a [FrameLimiter] decides which
+//! frames are kept as soon as each one starts arriving, so that the rest
+//! can be drained without being copied (see [Mu1603::set_frame_rate]).
+
+use super::*;
+
+/// Which frames should be delivered while streaming.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum FrameRate {
+    /// Deliver every frame
+    #[default]
+    All,
+
+    /// Deliver one out of every 'n' frames
+    Decimate(usize),
+
+    /// Deliver at most one frame per interval
+    Interval(Duration),
+}
+impl FrameRate {
+    /// Longest interval used by [FrameRate::max_fps].
+    pub const MAX_INTERVAL: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+    /// Deliver at most 'fps' frames per second.
+    ///
+    /// NOTE: Tiny rates are clamped to [FrameRate::MAX_INTERVAL].
+    pub fn max_fps(fps: f64) -> Self {
+        if fps.is_nan() || fps <= 0.0 || fps.is_infinite() {
+            return Self::All;
+        }
+        let interval = Duration::try_from_secs_f64(1.0 / fps)
+            .unwrap_or(Self::MAX_INTERVAL);
+        Self::Interval(interval.min(Self::MAX_INTERVAL))
+    }
+}
+impl std::fmt::Display for FrameRate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::All => write!(f, "all frames"),
+            Self::Decimate(n) => write!(f, "1 in {} frames", n),
+            Self::Interval(d) => write!(f, "1 frame every {:?}", d),
+        }
+    }
+}
+
+/// Number of frames read while streaming.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub struct FrameCounts {
+    /// Frames that were delivered
+    pub delivered: usize,
+
+    /// Frames that were read from the device and dropped
+    pub skipped: usize,
+}
+impl FrameCounts {
+    /// Total number of frames read from the device.
+    pub fn total(&self) -> usize {
+        self.delivered + self.skipped
+    }
+}
+
+/// Decides which frames are delivered according to a [FrameRate].
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FrameLimiter {
+    rate: FrameRate,
+
+    /// Deadline for the next frame [with 'FrameRate::Interval']
+    next_due: Option<Instant>,
+
+    counts: FrameCounts,
+}
+impl FrameLimiter {
+    pub fn new(rate: FrameRate) -> Self {
+        Self { rate, next_due: None, counts: FrameCounts::default() }
+    }
+
+    pub fn rate(&self) -> FrameRate {
+        self.rate
+    }
+
+    /// Change the rate. The next frame is always delivered.
+    pub fn set_rate(&mut self, rate: FrameRate) {
+        self.rate = rate;
+        self.next_due = None;
+    }
+
+    pub fn counts(&self) -> FrameCounts {
+        self.counts
+    }
+
+    /// Reset the counts (ie. when a new stream is started).
+    pub fn reset(&mut self) {
+        self.counts = FrameCounts::default();
+        self.next_due = None;
+    }
+
+    /// Return 'true' if a frame arriving at 'now' should be delivered.
+    pub fn admit(&self, now: Instant) -> bool {
+        match self.rate {
+            FrameRate::All => true,
+            FrameRate::Decimate(n) => self.counts.total().is_multiple_of(n.max(1)),
+            FrameRate::Interval(_) => self.next_due.is_none_or(|due| now >= due),
+        }
+    }
+
+    /// Account for a complete frame that arrived at 'now'.
+    pub fn record(&mut self, delivered: bool, now: Instant) {
+        if !delivered {
+            self.counts.skipped += 1;
+            return;
+        }
+        self.counts.delivered += 1;
+        if let FrameRate::Interval(interval) = self.rate {
+            // NOTE: Keep to the original schedule unless we've fallen
+            // more than an interval behind.
+            self.next_due = Some(match self.next_due {
+                Some(due) if now < due + interval => due + interval,
+                _ => now + interval,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_fps() {
+        assert_eq!(FrameRate::max_fps(4.0), 
+            FrameRate::Interval(Duration::from_millis(250)));
+        for fps in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert_eq!(FrameRate::max_fps(fps), FrameRate::All, "{fps}");
+        }
+        for fps in [1e-9, 1e-20, f64::MIN_POSITIVE] {
+            assert_eq!(FrameRate::max_fps(fps), 
+                FrameRate::Interval(FrameRate::MAX_INTERVAL), "{fps}");
+        }
+    }
+
+    /// Feed frames arriving at these times [in ms] through the limiter, 
+    /// and return the ones that were delivered.
+    fn run(limiter: &mut FrameLimiter, start: Instant, times: &[u64]) 
+        -> Vec<u64> 
+    {
+        times.iter().copied().filter(|&t| {
+            let now = start + Duration::from_millis(t);
+            let ok = limiter.admit(now);
+            limiter.record(ok, now);
+            ok
+        }).collect()
+    }
+
+    #[test]
+    fn interval_schedule() {
+        let start = Instant::now();
+        let mut limiter = FrameLimiter::new(
+            FrameRate::Interval(Duration::from_millis(100))
+        );
+        // Frames every 30ms: deliveries stay on the 100ms schedule rather
+        // than drifting later with each frame
+        let times: Vec<u64> = (0..12).map(|i| i * 30).collect();
+        assert_eq!(run(&mut limiter, start, &times), [0, 120, 210, 300]);
+        assert_eq!(limiter.counts(), FrameCounts { delivered: 4, skipped: 8 });
+
+        // 80ms late for the 400ms deadline: the next one is still 500ms
+        assert_eq!(run(&mut limiter, start, &[480, 490, 500]), [480, 500]);
+        // More than an interval behind: restart the schedule from now
+        assert_eq!(run(&mut limiter, start, &[750, 800, 850, 900]), [750, 850]);
+    }
+
+    #[test]
+    fn set_rate() {
+        let start = Instant::now();
+        let mut limiter = FrameLimiter::new(
+            FrameRate::Interval(Duration::from_secs(1))
+        );
+        assert_eq!(run(&mut limiter, start, &[0, 10, 20]), [0]);
+
+        // The next frame is always delivered after changing the rate
+        limiter.set_rate(FrameRate::Interval(Duration::from_millis(50)));
+        assert_eq!(limiter.rate(), 
+            FrameRate::Interval(Duration::from_millis(50)));
+        assert_eq!(run(&mut limiter, start, &[30, 40, 80, 90]), [30, 80]);
+
+        limiter.set_rate(FrameRate::Interval(Duration::from_secs(1)));
+        assert_eq!(run(&mut limiter, start, &[100, 110]), [100]);
+        assert_eq!(limiter.counts(), FrameCounts { delivered: 4, skipped: 5 });
+
+        limiter.reset();
+        assert_eq!(limiter.counts(), FrameCounts::default());
+        assert_eq!(run(&mut limiter, start, &[120]), [120]);
+    }
+
+    #[test]
+    fn decimate() {
+        let start = Instant::now();
+        let times: Vec<u64> = (0..10).collect();
+
+        let mut limiter = FrameLimiter::new(FrameRate::Decimate(3));
+        assert_eq!(run(&mut limiter, start, &times), [0, 3, 6, 9]);
+        assert_eq!(limiter.counts().total(), 10);
+
+        // Zero and one both mean every frame
+        for n in [0, 1] {
+            let mut limiter = FrameLimiter::new(FrameRate::Decimate(n));
+            assert_eq!(run(&mut limiter, start, &times), times, "{n}");
+            assert_eq!(limiter.counts().skipped, 0);
+        }
+
+        let mut limiter = FrameLimiter::new(FrameRate::All);
+        assert_eq!(run(&mut limiter, start, &times), times);
+    }
+}
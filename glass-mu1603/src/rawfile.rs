@@ -0,0 +1,512 @@
+//! A self-describing container for raw frames.
+//!
+//! Every value is little-endian. A file starts with a 16-byte header:
+//!
+//! ```text
+//! 0x00  [u8; 8]  magic ('GLASSRAW')
+//! 0x08  u16      version (1)
+//! 0x0a  u16      size of this header (16)
+//! 0x0c  u32      reserved (0)
+//! ```
+//!
+//! followed by any number of frames, each with a 64-byte header and then
+//! the pixels:
+//!
+//! ```text
+//! 0x00  [u8; 4]  magic ('FRAM')
+//! 0x04  u32      size of this header (64)
+//! 0x08  u64      frame id
+//! 0x10  u64      timestamp [in microseconds since the Unix epoch]
+//! 0x18  u32      width
+//! 0x1c  u32      height
+//! 0x20  u8       pixel format (see below)
+//! 0x21  u8       Bayer pattern (0 = RGGB, 1 = GRBG, 2 = GBRG, 3 = BGGR,
+//!                or 0xff when the format isn't Bayer data)
+//! 0x22  u8       significant bits in each sample
+//! 0x23  u8       flags (bit 0 is set when the options below are valid)
+//! 0x24  u8       sensor mode (0, 1 or 2)
+//! 0x25  u8       sensor bit depth (8 or 12)
+//! 0x26  u16      analog gain [in percent]
+//! 0x28  u32      exposure time [in microseconds]
+//! 0x2c  u32      reserved (0)
+//! 0x30  u64      options id (see [Mu1603Options])
+//! 0x38  u64      size of the pixels [in bytes]
+//! ```
+//!
+//! Pixel formats are 0 = Bayer8, 1 = Bayer16, 2 = Mono8, 3 = Mono16,
+//! 4 = RGB8, 5 = RGBA8, 6 = RGB16, 7 = RGBF32. Pixels are packed (without
+//! any padding between rows), and 16-bit samples aren't scaled (ie. 12-bit
+//! data has values up to 4095).
+//!
+//! Readers should skip anything past the end of a header that they don't
+//! understand, so new fields can be added without bumping the version.
+
+use super::*;
+use glass_common::*;
+use std::io::{ Read, Write, BufReader, BufWriter };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+/// An error while reading a raw container.
+#[derive(Debug)]
+pub enum RawFileError {
+    Io(std::io::Error),
+    Format(String),
+}
+impl std::fmt::Display for RawFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Format(e) => write!(f, "invalid raw file: {}", e),
+        }
+    }
+}
+impl std::error::Error for RawFileError {}
+impl From<std::io::Error> for RawFileError {
+    fn from(e: std::io::Error) -> Self { Self::Io(e) }
+}
+
+/// Everything about a frame except for the pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RawFrameHeader {
+    pub id: usize,
+    /// When the frame was captured
+    pub timestamp: SystemTime,
+    /// Sensor settings used to capture the frame (if known)
+    pub options: Option<Mu1603Options>,
+}
+impl RawFrameHeader {
+    pub fn new(id: usize, options: Option<Mu1603Options>) -> Self {
+        Self { id, timestamp: SystemTime::now(), options }
+    }
+}
+
+/// A frame read from a raw container.
+pub struct RawFrame {
+    pub header: RawFrameHeader,
+    pub data: PixelData,
+}
+impl RawFrame {
+    /// Read every frame from a file.
+    pub fn load_all(path: &str) -> Result<Vec<Self>, RawFileError> {
+        RawFileReader::open(path)?.collect()
+    }
+}
+
+/// Magic number at the start of a raw container.
+pub const RAW_FILE_MAGIC: [u8; 8] = *b"GLASSRAW";
+
+/// Version of the raw container format.
+pub const RAW_FILE_VERSION: u16 = 1;
+
+/// Largest width or height accepted when reading a frame.
+pub const RAW_FILE_MAX_DIMENSION: usize = u16::MAX as usize;
+
+pub struct RawFileReader<R: Read> {
+    inner: R,
+}
+impl RawFileReader<BufReader<std::fs::File>> {
+    pub fn open(path: &str) -> Result<Self, RawFileError> {
+        Self::new(BufReader::new(std::fs::File::open(path)?))
+    }
+}
+impl<R: Read> RawFileReader<R> {
+    /// Read the file header.
+    pub fn new(mut inner: R) -> Result<Self, RawFileError> {
+        let mut hdr = [0u8; 16];
+        inner.read_exact(&mut hdr)?;
+        if hdr[0..8] != RAW_FILE_MAGIC {
+            return Err(RawFileError::Format("bad magic".to_string()));
+        }
+        let version = u16::from_le_bytes([hdr[8], hdr[9]]);
+        if version != RAW_FILE_VERSION {
+            return Err(RawFileError::Format(
+                format!("unsupported version {}", version)
+            ));
+        }
+        let size = u16::from_le_bytes([hdr[10], hdr[11]]) as usize;
+        skip(&mut inner, size.saturating_sub(hdr.len()))?;
+        Ok(Self { inner })
+    }
+
+    /// Read the next frame, or 'None' at the end of the file.
+    pub fn read_frame(&mut self) -> Result<Option<RawFrame>, RawFileError> {
+        let mut hdr = [0u8; 64];
+        // NOTE: The end of the file is only expected between frames
+        match self.inner.read(&mut hdr[..1])? {
+            0 => return Ok(None),
+            _ => self.inner.read_exact(&mut hdr[1..])?,
+        }
+        if &hdr[0..4] != b"FRAM" {
+            return Err(RawFileError::Format("bad frame magic".to_string()));
+        }
+        let size = u32_at(&hdr, 0x04) as usize;
+        if size < hdr.len() {
+            return Err(RawFileError::Format("frame header is too short".to_string()));
+        }
+        skip(&mut self.inner, size - hdr.len())?;
+
+        let format = decode_format(hdr[0x20], hdr[0x21], hdr[0x22])?;
+        let width = u32_at(&hdr, 0x18) as usize;
+        let height = u32_at(&hdr, 0x1c) as usize;
+        if width > RAW_FILE_MAX_DIMENSION || height > RAW_FILE_MAX_DIMENSION {
+            return Err(RawFileError::Format(
+                format!("frame is too large ({}x{})", width, height)
+            ));
+        }
+        let len = u64_at(&hdr, 0x38);
+        let expected = width.checked_mul(height)
+            .and_then(|n| n.checked_mul(format.bytes_per_pixel()))
+            .ok_or_else(|| RawFileError::Format(
+                format!("frame is too large ({}x{})", width, height)
+            ))?;
+        if len != expected as u64 {
+            return Err(RawFileError::Format(format!(
+                "{} bytes of pixels for a {}x{} {:?} frame", len, width, height,
+                format
+            )));
+        }
+        let options = match hdr[0x23] & 1 {
+            0 => None,
+            _ => Some(decode_options(&hdr)?),
+        };
+        let header = RawFrameHeader {
+            id: u64_at(&hdr, 0x08) as usize,
+            timestamp: UNIX_EPOCH + Duration::from_micros(u64_at(&hdr, 0x10)),
+            options,
+        };
+
+        // NOTE: Read before allocating the whole frame, so that a bogus
+        // header in a truncated file doesn't allocate more than the file.
+        let mut buf = Vec::new();
+        (&mut self.inner).take(len).read_to_end(&mut buf)?;
+        if buf.len() != expected {
+            return Err(RawFileError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        let data = PixelData {
+            data: buf.into_boxed_slice(),
+            width,
+            height,
+            stride: width * format.bytes_per_pixel(),
+            format,
+            id: header.id,
+        };
+        Ok(Some(RawFrame { header, data }))
+    }
+}
+impl<R: Read> Iterator for RawFileReader<R> {
+    type Item = Result<RawFrame, RawFileError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+pub struct RawFileWriter<W: Write> {
+    inner: W,
+}
+impl RawFileWriter<BufWriter<std::fs::File>> {
+    pub fn create(path: &str) -> std::io::Result<Self> {
+        Self::new(BufWriter::new(std::fs::File::create(path)?))
+    }
+}
+impl<W: Write> RawFileWriter<W> {
+    /// Write the file header.
+    pub fn new(mut inner: W) -> std::io::Result<Self> {
+        let mut hdr = Vec::with_capacity(16);
+        hdr.extend_from_slice(&RAW_FILE_MAGIC);
+        hdr.extend_from_slice(&RAW_FILE_VERSION.to_le_bytes());
+        hdr.extend_from_slice(&16u16.to_le_bytes());
+        hdr.extend_from_slice(&0u32.to_le_bytes());
+        inner.write_all(&hdr)?;
+        Ok(Self { inner })
+    }
+
+    /// Append a frame.
+    pub fn write_frame(&mut self, header: &RawFrameHeader, view: &PixelView)
+        -> std::io::Result<()>
+    {
+        let fmt = view.format();
+        let len = view.width() * view.height() * fmt.bytes_per_pixel();
+        let (kind, pattern, bits) = encode_format(fmt);
+        let micros = header.timestamp.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64).unwrap_or(0);
+
+        let mut hdr = Vec::with_capacity(64);
+        hdr.extend_from_slice(b"FRAM");
+        hdr.extend_from_slice(&64u32.to_le_bytes());
+        hdr.extend_from_slice(&(header.id as u64).to_le_bytes());
+        hdr.extend_from_slice(&micros.to_le_bytes());
+        hdr.extend_from_slice(&(view.width() as u32).to_le_bytes());
+        hdr.extend_from_slice(&(view.height() as u32).to_le_bytes());
+        hdr.extend_from_slice(&[kind, pattern, bits]);
+        match &header.options {
+            Some(opts) => {
+                hdr.push(1);
+                hdr.push(match opts.mode {
+                    Mu1603Mode::Mode0 => 0,
+                    Mu1603Mode::Mode1 => 1,
+                    Mu1603Mode::Mode2 => 2,
+                });
+                hdr.push(opts.bitdepth.bits() as u8);
+                hdr.extend_from_slice(&(opts.analog_gain_percent() as u16).to_le_bytes());
+                hdr.extend_from_slice(&(opts.exposure_us() as u32).to_le_bytes());
+                hdr.extend_from_slice(&0u32.to_le_bytes());
+                hdr.extend_from_slice(&(opts.id as u64).to_le_bytes());
+            },
+            None => hdr.resize(0x38, 0),
+        }
+        hdr.extend_from_slice(&(len as u64).to_le_bytes());
+        self.inner.write_all(&hdr)?;
+
+        match view.is_packed() {
+            true => self.inner.write_all(view.bytes())?,
+            false => for y in 0..view.height() {
+                match view.row(y) {
+                    Some(row) => self.inner.write_all(row)?,
+                    None => for x in 0..view.width() {
+                        self.inner.write_all(view.pixel(x, y))?;
+                    },
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Flush any buffered frames and return the underlying writer.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+fn u32_at(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..off + 4].try_into().unwrap())
+}
+fn u64_at(buf: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(buf[off..off + 8].try_into().unwrap())
+}
+
+fn skip<R: Read>(r: &mut R, len: usize) -> std::io::Result<()> {
+    std::io::copy(&mut r.take(len as u64), &mut std::io::sink())?;
+    Ok(())
+}
+
+fn encode_format(fmt: PixelFormat) -> (u8, u8, u8) {
+    let kind = match fmt {
+        PixelFormat::Bayer8(_) => 0,
+        PixelFormat::Bayer16(..) => 1,
+        PixelFormat::Mono8 => 2,
+        PixelFormat::Mono16 => 3,
+        PixelFormat::RGB8 => 4,
+        PixelFormat::RGBA8 => 5,
+        PixelFormat::RGB16 => 6,
+        PixelFormat::RGBF32 => 7,
+    };
+    let pattern = fmt.bayer_pattern()
+        .and_then(|p| BayerPattern::ALL.iter().position(|&q| q == p))
+        .map(|idx| idx as u8)
+        .unwrap_or(0xff);
+    let bits = fmt.significant_bits()
+        .unwrap_or(fmt.sample_type().bytes() * 8);
+    (kind, pattern, bits as u8)
+}
+
+fn decode_format(kind: u8, pattern: u8, bits: u8)
+    -> Result<PixelFormat, RawFileError>
+{
+    let bayer = || BayerPattern::ALL.get(pattern as usize).copied()
+        .ok_or_else(|| RawFileError::Format(format!("bad Bayer pattern {}", pattern)));
+    Ok(match kind {
+        0 => PixelFormat::Bayer8(bayer()?),
+        1 => match bits {
+            1..=16 => PixelFormat::Bayer16(bayer()?, bits),
+            _ => return Err(RawFileError::Format(format!("bad bit depth {}", bits))),
+        },
+        2 => PixelFormat::Mono8,
+        3 => PixelFormat::Mono16,
+        4 => PixelFormat::RGB8,
+        5 => PixelFormat::RGBA8,
+        6 => PixelFormat::RGB16,
+        7 => PixelFormat::RGBF32,
+        _ => return Err(RawFileError::Format(format!("bad pixel format {}", kind))),
+    })
+}
+
+fn decode_options(hdr: &[u8]) -> Result<Mu1603Options, RawFileError> {
+    let mode = match hdr[0x24] {
+        0 => Mu1603Mode::Mode0,
+        1 => Mu1603Mode::Mode1,
+        2 => Mu1603Mode::Mode2,
+        m => return Err(RawFileError::Format(format!("bad sensor mode {}", m))),
+    };
+    let bitdepth = Mu1603BitDepth::from_bits(hdr[0x25] as usize)
+        .ok_or_else(|| RawFileError::Format(
+            format!("bad sensor bit depth {}", hdr[0x25])
+        ))?;
+    let gain = u16::from_le_bytes([hdr[0x26], hdr[0x27]]) as usize;
+    Ok(Mu1603Options {
+        id: u64_at(hdr, 0x30) as usize,
+        mode,
+        exposure: ExposureTime::new_from_us(u32_at(hdr, 0x28) as usize),
+        analog_gain: AnalogGain::new_from_percent(gain),
+        bitdepth,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ramp(fmt: PixelFormat, width: usize, height: usize) -> PixelData {
+        let mut res = PixelData::new(fmt, width, height);
+        for (i, b) in res.data.iter_mut().enumerate() {
+            *b = (i * 7 % 251) as u8;
+        }
+        // NOTE: Keep 16-bit samples within the significant bits
+        let bits = fmt.significant_bits().unwrap_or(16);
+        if fmt.sample_type() == SampleType::U16 && bits < 16 {
+            let src: Vec<u16> = res.data.chunks_exact(2)
+                .map(|s| u16::from_le_bytes([s[0], s[1]]) >> (16 - bits))
+                .collect();
+            res = PixelData::new_from_u16(fmt, width, height, &src).unwrap();
+        }
+        res
+    }
+
+    fn options(id: usize) -> Mu1603Options {
+        Mu1603Options {
+            id,
+            mode: Mu1603Mode::Mode1,
+            exposure: ExposureTime::new_from_us(50_000),
+            analog_gain: AnalogGain::new_from_percent(100),
+            bitdepth: Mu1603BitDepth::Depth12,
+        }
+    }
+
+    fn write(frames: &[(RawFrameHeader, &PixelData)]) -> Vec<u8> {
+        let mut w = RawFileWriter::new(Vec::new()).unwrap();
+        for (header, data) in frames {
+            w.write_frame(header, &data.view()).unwrap();
+        }
+        w.finish().unwrap()
+    }
+
+    /// A file with one 4x2 Mono8 frame.
+    fn mono8() -> Vec<u8> {
+        let header = RawFrameHeader::new(1, None);
+        write(&[(header, &ramp(PixelFormat::Mono8, 4, 2))])
+    }
+
+    #[test]
+    fn round_trip() {
+        let timestamp = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let frames = [
+            (PixelFormat::Bayer8(BayerPattern::GRBG), Some(options(3))),
+            (PixelFormat::Bayer16(BayerPattern::BGGR, 12), Some(options(4))),
+            (PixelFormat::Bayer16(BayerPattern::RGGB, 16), None),
+            (PixelFormat::RGB8, None),
+            (PixelFormat::RGBF32, Some(options(5))),
+        ].map(|(fmt, options)| {
+            let header = RawFrameHeader { id: 10 + fmt.bytes_per_pixel(), 
+                timestamp, options };
+            (header, ramp(fmt, 6, 3))
+        });
+        let refs: Vec<_> = frames.iter().map(|(h, d)| (*h, d)).collect();
+        let file = write(&refs);
+
+        let read: Vec<RawFrame> = RawFileReader::new(file.as_slice()).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert_eq!(read.len(), frames.len());
+        for ((header, data), frame) in frames.iter().zip(&read) {
+            assert_eq!(frame.header, *header);
+            assert_eq!(frame.data.format(), data.format());
+            assert_eq!((frame.data.width(), frame.data.height()), (6, 3));
+            assert_eq!(frame.data.as_slice(), data.as_slice());
+            assert_eq!(frame.data.frame_id(), header.id);
+        }
+    }
+
+    #[test]
+    fn sub_view() {
+        // Views that aren't packed are written row by row
+        let img = ramp(PixelFormat::Bayer8(BayerPattern::RGGB), 6, 4);
+        let view = img.view().sub(1, 1, 3, 2).unwrap();
+        let mut w = RawFileWriter::new(Vec::new()).unwrap();
+        w.write_frame(&RawFrameHeader::new(0, None), &view).unwrap();
+        let file = w.finish().unwrap();
+
+        let frame = RawFileReader::new(file.as_slice()).unwrap()
+            .next().unwrap().unwrap();
+        assert_eq!(frame.data.format(), PixelFormat::Bayer8(BayerPattern::BGGR));
+        assert_eq!(frame.data.as_slice(), view.to_pixel_data().as_slice());
+    }
+
+    #[test]
+    fn larger_headers() {
+        // Readers skip fields they don't know about
+        let mut file = mono8();
+        file[10] = 20;
+        file.splice(16..16, [0xaa; 4]);
+        file[20 + 4] = 72;
+        file.splice(20 + 64..20 + 64, [0xbb; 8]);
+        let frame = RawFileReader::new(file.as_slice()).unwrap()
+            .next().unwrap().unwrap();
+        assert_eq!(frame.data.as_slice(), ramp(PixelFormat::Mono8, 4, 2).as_slice());
+    }
+
+    #[test]
+    fn errors() {
+        fn read(file: &[u8]) -> Result<Vec<RawFrame>, RawFileError> {
+            RawFileReader::new(file)?.collect()
+        }
+        fn format_err(file: &[u8], msg: &str) {
+            match read(file) {
+                Err(RawFileError::Format(e)) => assert!(e.contains(msg), "{}", e),
+                Err(e) => panic!("expected '{}', got {}", msg, e),
+                Ok(_) => panic!("expected '{}'", msg),
+            }
+        }
+        fn eof(file: &[u8]) {
+            match read(file) {
+                Err(RawFileError::Io(e)) => 
+                    assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof),
+                Err(e) => panic!("expected the end of the file, got {}", e),
+                Ok(_) => panic!("expected the end of the file"),
+            }
+        }
+        let good = mono8();
+        let frame = 16;
+        let patch = |off: usize, bytes: &[u8]| {
+            let mut file = good.clone();
+            file[off..off + bytes.len()].copy_from_slice(bytes);
+            file
+        };
+
+        // Truncated anywhere
+        eof(&good[..8]);
+        eof(&good[..frame + 10]);
+        eof(&good[..good.len() - 1]);
+        assert_eq!(read(&good[..frame]).unwrap().len(), 0);
+
+        format_err(&patch(0, b"GLASSRAX"), "bad magic");
+        format_err(&patch(8, &2u16.to_le_bytes()), "unsupported version");
+        format_err(&patch(frame, b"JUNK"), "bad frame magic");
+        format_err(&patch(frame + 4, &16u32.to_le_bytes()), "too short");
+        format_err(&patch(frame + 0x20, &[9]), "bad pixel format");
+        format_err(&patch(frame + 0x20, &[0, 4]), "bad Bayer pattern");
+        format_err(&patch(frame + 0x20, &[1, 0, 0]), "bad bit depth");
+        format_err(&patch(frame + 0x20, &[1, 0, 17]), "bad bit depth");
+        format_err(&patch(frame + 0x23, &[1, 3, 12]), "bad sensor mode");
+        format_err(&patch(frame + 0x23, &[1, 0, 10]), "bad sensor bit depth");
+        format_err(&patch(frame + 0x38, &9u64.to_le_bytes()), "bytes of pixels");
+
+        // Sizes that would overflow (or just be enormous) are rejected
+        // before anything is allocated
+        let huge = u32::MAX.to_le_bytes();
+        format_err(&patch(frame + 0x18, &[huge, huge].concat()), "too large");
+        let big = (RAW_FILE_MAX_DIMENSION as u32).to_le_bytes();
+        let len = (RAW_FILE_MAX_DIMENSION * RAW_FILE_MAX_DIMENSION) as u64;
+        let mut file = patch(frame + 0x18, &[big, big].concat());
+        file[frame + 0x38..frame + 0x40].copy_from_slice(&len.to_le_bytes());
+        eof(&file);
+    }
+}
@@ -0,0 +1,428 @@
+//! A tiny text format for describing sequences of control transfers.
+//!
+//! This exists so that we can experiment with the initialization sequence
+//! (and poke at unknown registers) without recompiling anything.
+//! Each line in a script is a single command:
+//!
+//! ```text
+//! # Comments start with '#'
+//! sys       0x1200 0x0001          # system_cmd(idx, val)
+//! sensor    0x1008 0x4299          # sensor_cmd(idx, val)
+//! sleep     20ms                   # also accepts 'us' and 's'
+//! ven_write 0x01 0x000f 0x0003     # ven_write(req, idx, val, &[])
+//! ven_read  0x0a 0xffff 0x0000 2   # ven_read(req, idx, val, &mut [0; 2])
+//! ```
+//!
+//! A comment line that starts with a step number (ie. '# 1. Do something')
+//! marks the start of a new [ScriptPhase]. These only exist to make logs
+//! easier to follow.
+//!
+//! The built-in initialization sequences used by [Mu1603::start_stream] are
+//! written in this format (see the 'scripts/' directory in this crate).
+
+use super::*;
+use glass_common::strip_comment;
+
+/// Built-in script with the common part of the initialization sequence.
+pub const SCRIPT_INIT: &str = include_str!("../scripts/init.txt");
+/// Built-in script for entering [Mu1603Mode::Mode0] and starting the stream.
+pub const SCRIPT_MODE0: &str = include_str!("../scripts/mode0.txt");
+/// Built-in script for entering [Mu1603Mode::Mode1] and starting the stream.
+pub const SCRIPT_MODE1: &str = include_str!("../scripts/mode1.txt");
+/// Built-in script for entering [Mu1603Mode::Mode2] and starting the stream.
+pub const SCRIPT_MODE2: &str = include_str!("../scripts/mode2.txt");
+
+/// An error that occurred while parsing a script.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScriptError {
+    /// Line number (starting from 1)
+    pub line: usize,
+    pub msg: String,
+}
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+/// A single command in a [Mu1603Script].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScriptCmd {
+    /// See [Mu1603::system_cmd]
+    Sys(u16, u16),
+    /// See [Mu1603::sensor_cmd]
+    Sensor(u16, u16),
+    /// Wait for some amount of time
+    Sleep(Duration),
+    /// See [Mu1603::ven_write] (with an empty buffer)
+    VenWrite(u8, u16, u16),
+    /// See [Mu1603::ven_read] (with a buffer of the given length)
+    VenRead(u8, u16, u16, usize),
+}
+impl std::fmt::Display for ScriptCmd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sys(idx, val) => {
+                write!(f, "sys {:#06x} {:#06x}", idx, val)
+            },
+            Self::Sensor(idx, val) => {
+                write!(f, "sensor {:#06x} {:#06x}", idx, val)
+            },
+            Self::Sleep(d) => {
+                if d.subsec_nanos() % 1_000_000 == 0 {
+                    write!(f, "sleep {}ms", d.as_millis())
+                } else {
+                    write!(f, "sleep {}us", d.as_micros())
+                }
+            },
+            Self::VenWrite(req, idx, val) => {
+                write!(f, "ven_write {:#04x} {:#06x} {:#06x}", req, idx, val)
+            },
+            Self::VenRead(req, idx, val, len) => {
+                write!(f, "ven_read {:#04x} {:#06x} {:#06x} {}",
+                    req, idx, val, len)
+            },
+        }
+    }
+}
+
+impl ScriptCmd {
+    fn parse_u16(s: &str) -> Result<u16, String> {
+        let res = if let Some(hex) = s.strip_prefix("0x") {
+            u16::from_str_radix(hex, 16)
+        } else {
+            s.parse::<u16>()
+        };
+        res.map_err(|e| format!("invalid value '{}': {}", s, e))
+    }
+
+    fn parse_u8(s: &str) -> Result<u8, String> {
+        let val = Self::parse_u16(s)?;
+        u8::try_from(val).map_err(|_| format!("request '{}' out of range", s))
+    }
+
+    fn parse_duration(s: &str) -> Result<Duration, String> {
+        let (num, unit): (&str, fn(u64) -> Duration) =
+            if let Some(n) = s.strip_suffix("ms") {
+                (n, Duration::from_millis)
+            } else if let Some(n) = s.strip_suffix("us") {
+                (n, Duration::from_micros)
+            } else if let Some(n) = s.strip_suffix('s') {
+                (n, Duration::from_secs)
+            } else {
+                return Err(format!("missing unit on duration '{}'", s));
+            };
+        let val = num.parse::<u64>()
+            .map_err(|e| format!("invalid duration '{}': {}", s, e))?;
+        Ok(unit(val))
+    }
+
+    /// Parse a single (non-empty, comment-free) line.
+    fn parse(line: &str) -> Result<Self, String> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let expect = |n: usize| -> Result<(), String> {
+            if args.len() != n + 1 {
+                Err(format!("'{}' expects {} arguments, got {}",
+                    args[0], n, args.len() - 1))
+            } else {
+                Ok(())
+            }
+        };
+        match args[0] {
+            "sys" => {
+                expect(2)?;
+                Ok(Self::Sys(Self::parse_u16(args[1])?,
+                             Self::parse_u16(args[2])?))
+            },
+            "sensor" => {
+                expect(2)?;
+                Ok(Self::Sensor(Self::parse_u16(args[1])?,
+                                Self::parse_u16(args[2])?))
+            },
+            "sleep" => {
+                expect(1)?;
+                Ok(Self::Sleep(Self::parse_duration(args[1])?))
+            },
+            "ven_write" => {
+                expect(3)?;
+                Ok(Self::VenWrite(Self::parse_u8(args[1])?,
+                                  Self::parse_u16(args[2])?,
+                                  Self::parse_u16(args[3])?))
+            },
+            "ven_read" => {
+                expect(4)?;
+                let len = args[4].parse::<usize>()
+                    .map_err(|e| format!("invalid length '{}': {}", args[4], e))?;
+                Ok(Self::VenRead(Self::parse_u8(args[1])?,
+                                 Self::parse_u16(args[2])?,
+                                 Self::parse_u16(args[3])?,
+                                 len))
+            },
+            cmd => Err(format!("unknown command '{}'", cmd)),
+        }
+    }
+}
+
+/// A named group of commands in a [Mu1603Script].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScriptPhase {
+    /// Index of the first command in this phase
+    pub start: usize,
+    /// The step comment, ie. '1. Send the key `0x0000` to the device.'
+    pub name: String,
+}
+impl ScriptPhase {
+    /// Return the phase name if this line is a step comment.
+    fn parse(line: &str) -> Option<String> {
+        let text = line.trim().strip_prefix('#')?.trim();
+        let (num, rest) = text.split_once('.')?;
+        if num.is_empty() || !num.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        if rest.is_empty() || !rest.starts_with(' ') {
+            return None;
+        }
+        Some(text.to_string())
+    }
+}
+
+/// A sequence of [ScriptCmd] to be executed by [Mu1603::run_script].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Mu1603Script {
+    pub cmds: Vec<ScriptCmd>,
+    pub phases: Vec<ScriptPhase>,
+}
+impl Mu1603Script {
+    /// Parse a script from a string.
+    pub fn parse(src: &str) -> Result<Self, ScriptError> {
+        let mut cmds = Vec::new();
+        let mut phases = Vec::new();
+        for (num, line) in src.lines().enumerate() {
+            if let Some(name) = ScriptPhase::parse(line) {
+                phases.push(ScriptPhase { start: cmds.len(), name });
+                continue;
+            }
+            // NOTE: Phase names are comments, so this can't use 'config_lines'
+            let line = strip_comment(line);
+            if line.is_empty() {
+                continue;
+            }
+            let cmd = ScriptCmd::parse(line)
+                .map_err(|msg| ScriptError { line: num + 1, msg })?;
+            cmds.push(cmd);
+        }
+        Ok(Self { cmds, phases })
+    }
+
+    /// Read and parse a script from a file.
+    pub fn from_file(filename: &str) -> Result<Self, ScriptError> {
+        let src = std::fs::read_to_string(filename).map_err(|e| {
+            ScriptError { line: 0, msg: format!("{}: {}", filename, e) }
+        })?;
+        Self::parse(&src)
+    }
+
+    /// Append the commands from another script.
+    pub fn extend(&mut self, other: &Mu1603Script) {
+        let offset = self.cmds.len();
+        self.phases.extend(other.phases.iter().map(|p| ScriptPhase {
+            start: p.start + offset,
+            name: p.name.clone(),
+        }));
+        self.cmds.extend_from_slice(&other.cmds);
+    }
+
+    /// Return the built-in script used to start streaming in some mode.
+    pub fn builtin(mode: Mu1603Mode) -> Self {
+        let mode_src = match mode {
+            Mu1603Mode::Mode0 => SCRIPT_MODE0,
+            Mu1603Mode::Mode1 => SCRIPT_MODE1,
+            Mu1603Mode::Mode2 => SCRIPT_MODE2,
+        };
+        // NOTE: These are compiled in, so they had better be valid.
+        let mut res = Self::parse(SCRIPT_INIT).unwrap();
+        res.extend(&Self::parse(mode_src).unwrap());
+        res
+    }
+}
+impl std::fmt::Display for Mu1603Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (idx, cmd) in self.cmds.iter().enumerate() {
+            for phase in self.phases.iter().filter(|p| p.start == idx) {
+                writeln!(f, "# {}", phase.name)?;
+            }
+            writeln!(f, "{}", cmd)?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of executing a single [ScriptCmd].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScriptResponse {
+    pub cmd: ScriptCmd,
+    /// Bytes returned by the device (empty for writes and sleeps)
+    pub data: Vec<u8>,
+}
+
+impl Mu1603 {
+    /// Execute a single script command, returning any response data.
+    pub fn run_script_cmd(&mut self, cmd: ScriptCmd)
+        -> Result<Vec<u8>, Mu1603Error>
+    {
+        match cmd {
+            ScriptCmd::Sys(idx, val) => {
+                let mut buf: [u8; 1] = [ 0 ];
+                self.ven_read(0x0b, idx, val, &mut buf)?;
+                Ok(buf.to_vec())
+            },
+            ScriptCmd::Sensor(idx, val) => {
+                self.sensor_cmd(idx, val)?;
+                Ok(Vec::new())
+            },
+            ScriptCmd::Sleep(d) => {
+                std::thread::sleep(d);
+                Ok(Vec::new())
+            },
+            ScriptCmd::VenWrite(req, idx, val) => {
+                self.ven_write(req, idx, val, &[])?;
+                Ok(Vec::new())
+            },
+            ScriptCmd::VenRead(req, idx, val, len) => {
+                let mut buf = vec![0u8; len];
+                let rlen = self.ven_read(req, idx, val, &mut buf)?;
+                buf.truncate(rlen);
+                Ok(buf)
+            },
+        }
+    }
+
+    /// Execute a script, logging the responses from the device.
+    ///
+    /// Each [ScriptPhase] is executed in its own span.
+    /// Execution stops at the first command that fails.
+    pub fn run_script(&mut self, script: &Mu1603Script)
+        -> Result<Vec<ScriptResponse>, Mu1603Error>
+    {
+        let _span = debug_span!("run_script", cmds = script.cmds.len()).entered();
+        let mut phase = None;
+        let mut res = Vec::with_capacity(script.cmds.len());
+        for (idx, cmd) in script.cmds.iter().enumerate() {
+            if let Some(p) = script.phases.iter().rev().find(|p| p.start == idx) {
+                // NOTE: Exit the previous phase before entering the next one
+                drop(phase.take());
+                phase = Some(info_span!("phase", name = %p.name).entered());
+            }
+            let data = match self.run_script_cmd(*cmd) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!(%cmd, error = ?e, "script command failed");
+                    return Err(e);
+                },
+            };
+            if !data.is_empty() {
+                debug!(%cmd, data = %simple_hex(&data), "response");
+            }
+            res.push(ScriptResponse { cmd: *cmd, data });
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        let src = "\
+# A comment
+sys       0x1200 0x0001
+sensor    0x1008 17000     # decimal values are fine too
+
+sleep     20ms
+sleep     5us
+sleep     1s
+ven_write 0x01 0x000f 0x0003
+ven_read  0x0a 0xffff 0x0000 2
+";
+        let script = Mu1603Script::parse(src).unwrap();
+        assert_eq!(script.cmds, vec![
+            ScriptCmd::Sys(0x1200, 0x0001),
+            ScriptCmd::Sensor(0x1008, 17000),
+            ScriptCmd::Sleep(Duration::from_millis(20)),
+            ScriptCmd::Sleep(Duration::from_micros(5)),
+            ScriptCmd::Sleep(Duration::from_secs(1)),
+            ScriptCmd::VenWrite(0x01, 0x000f, 0x0003),
+            ScriptCmd::VenRead(0x0a, 0xffff, 0x0000, 2),
+        ]);
+        assert!(script.phases.is_empty());
+    }
+
+    #[test]
+    fn parse_phases() {
+        let src = "\
+# 1. First step.
+sys 0x1200 0x0001
+# NOTE: Not a step
+sys 0x1200 0x0002
+#2.Not a step either
+# 2. Second step.
+# 3. Empty step.
+# 4. Last step.
+sensor 0x1008 0x4299
+";
+        let script = Mu1603Script::parse(src).unwrap();
+        assert_eq!(script.cmds.len(), 3);
+        let phases: Vec<(usize, &str)> = script.phases.iter()
+            .map(|p| (p.start, p.name.as_str()))
+            .collect();
+        assert_eq!(phases, vec![
+            (0, "1. First step."),
+            (2, "2. Second step."),
+            (2, "3. Empty step."),
+            (2, "4. Last step."),
+        ]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases = [
+            ("sys 0x1200 0x0001\nbogus 1 2\n", 2, "unknown command"),
+            ("\n\nsys 0x1200\n", 3, "expects 2 arguments"),
+            ("# 1. Step.\nsensor 0x1008 0xzz\n", 2, "invalid value"),
+            ("ven_write 0x100 0x0000 0x0000\n", 1, "out of range"),
+            ("sleep 20\n", 1, "missing unit"),
+            ("sleep fast_ms\n", 1, "invalid duration"),
+            ("ven_read 0x0a 0xffff 0x0000 two\n", 1, "invalid length"),
+            ("sensor 0x1008 0x10000\n", 1, "invalid value"),
+        ];
+        for (src, line, msg) in cases {
+            let err = Mu1603Script::parse(src).unwrap_err();
+            assert_eq!(err.line, line, "{:?}", src);
+            assert!(err.msg.contains(msg), "{:?}: {}", src, err.msg);
+        }
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for mode in [Mu1603Mode::Mode0, Mu1603Mode::Mode1, Mu1603Mode::Mode2] {
+            let script = Mu1603Script::builtin(mode);
+            assert!(!script.cmds.is_empty());
+            assert!(!script.phases.is_empty());
+            let reparsed = Mu1603Script::parse(&script.to_string()).unwrap();
+            assert_eq!(reparsed, script);
+        }
+    }
+
+    #[test]
+    fn extend_offsets_phases() {
+        let mut a = Mu1603Script::parse("# 1. A.\nsys 0x1 0x1\nsys 0x2 0x2\n")
+            .unwrap();
+        let b = Mu1603Script::parse("sys 0x3 0x3\n# 2. B.\nsys 0x4 0x4\n")
+            .unwrap();
+        a.extend(&b);
+        assert_eq!(a.cmds.len(), 4);
+        assert_eq!(a.phases[1], ScriptPhase { start: 3, name: "2. B.".into() });
+    }
+}
@@ -0,0 +1,161 @@
+//! Things that produce frames like a [Mu1603].
+//!
+//! Tools that only need to stream frames and change settings (ie. the
+//! [ExposureSweep] and the [Mu1603Controller]) are written against
+//! [FrameSource], so that they can run against the [Mu1603Emulator] as
+//! well as the real device.
+
+use super::*;
+
+/// The streaming interface shared by [Mu1603] and [Mu1603Emulator].
+pub trait FrameSource {
+    /// Get the current stream settings.
+    fn state(&self) -> Option<Mu1603Options>;
+
+    /// Start streaming in the requested mode (see [Mu1603::start_stream]).
+    fn start_stream(&mut self, mode: Mu1603Mode)
+        -> Result<Mu1603Options, Mu1603Error>;
+
+    /// Start streaming with a user-provided initialization sequence
+    /// (see [Mu1603::start_stream_with_script]).
+    fn start_stream_with_script(&mut self, mode: Mu1603Mode,
+        script: &Mu1603Script) -> Result<Mu1603Options, Mu1603Error>;
+
+    /// Start streaming with some initial settings
+    /// (see [Mu1603::start_with_options]).
+    fn start_with_options(&mut self, opts: Mu1603Options)
+        -> Result<Mu1603Options, Mu1603Error>
+    {
+        self.start_stream(opts.mode)?;
+        self.apply_state(opts)
+    }
+
+    /// Stop streaming (see [Mu1603::stop_stream]).
+    fn stop_stream(&mut self) -> Result<(), Mu1603Error>;
+
+    /// Apply new settings while streaming (see [Mu1603::apply_state]).
+    fn apply_state(&mut self, opts: Mu1603Options)
+        -> Result<Mu1603Options, Mu1603Error>;
+
+    /// Try to read a frame (see [Mu1603::try_read_frame]).
+    fn try_read_frame(&mut self) -> Result<Vec<u8>, Mu1603Error>;
+
+    /// Read a frame, retrying when the read fails for some transient reason
+    /// (a truncated frame, or a timeout) up to 'max_retries' times.
+    ///
+    /// Frames skipped because of the [FrameRate] aren't counted as failures.
+    fn read_frame_with_retries(&mut self, max_retries: usize)
+        -> Result<Vec<u8>, Mu1603Error>
+    {
+        let mut retries = 0;
+        loop {
+            match self.try_read_frame() {
+                Ok(data) => return Ok(data),
+                // Not a failure, just a frame we asked not to see
+                Err(Mu1603Error::Skipped) => {},
+                Err(e @ Mu1603Error::FirstFrame) |
+                Err(e @ Mu1603Error::Rusb(rusb::Error::Timeout)) => {
+                    retries += 1;
+                    if retries > max_retries {
+                        return Err(e);
+                    }
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Try to read a frame, reporting progress during long exposures
+    /// (see [Mu1603::try_read_frame_with_progress]).
+    fn try_read_frame_with_progress(&mut self,
+        progress: &mut dyn FnMut(ExposureProgress))
+        -> Result<Vec<u8>, Mu1603Error>
+    {
+        let _ = progress;
+        self.try_read_frame()
+    }
+
+    /// Time when the last frame was read.
+    ///
+    /// NOTE: The [Mu1603Emulator] reports when the frame would have arrived
+    /// from the sensor, so this is meaningful even when it isn't running
+    /// in real time.
+    fn frame_timestamp(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// The model used to convert exposure times into register values.
+    fn exposure_model(&self) -> ExposureModel;
+
+    /// Change which frames are delivered (see [Mu1603::set_frame_rate]).
+    fn set_frame_rate(&mut self, rate: FrameRate);
+
+    /// Number of frames delivered and skipped since the stream started.
+    fn frame_counts(&self) -> FrameCounts;
+
+    /// Information identifying this device.
+    fn device_info(&self) -> DeviceInfo;
+
+    /// Statistics about the current stream (see [Mu1603::stream_stats]).
+    fn stream_stats(&self) -> StreamStats {
+        StreamStats::default()
+    }
+
+    /// Return 'true' if this source is currently streaming.
+    fn is_streaming(&self) -> bool {
+        self.state().is_some()
+    }
+}
+
+impl FrameSource for Mu1603 {
+    fn state(&self) -> Option<Mu1603Options> {
+        Mu1603::state(self)
+    }
+    fn start_stream(&mut self, mode: Mu1603Mode)
+        -> Result<Mu1603Options, Mu1603Error>
+    {
+        Mu1603::start_stream(self, mode)
+    }
+    fn start_stream_with_script(&mut self, mode: Mu1603Mode,
+        script: &Mu1603Script) -> Result<Mu1603Options, Mu1603Error>
+    {
+        Mu1603::start_stream_with_script(self, mode, script)
+    }
+    fn start_with_options(&mut self, opts: Mu1603Options)
+        -> Result<Mu1603Options, Mu1603Error>
+    {
+        Mu1603::start_with_options(self, opts)
+    }
+    fn stop_stream(&mut self) -> Result<(), Mu1603Error> {
+        Mu1603::stop_stream(self)
+    }
+    fn apply_state(&mut self, opts: Mu1603Options)
+        -> Result<Mu1603Options, Mu1603Error>
+    {
+        Mu1603::apply_state(self, opts)
+    }
+    fn try_read_frame(&mut self) -> Result<Vec<u8>, Mu1603Error> {
+        Mu1603::try_read_frame(self)
+    }
+    fn try_read_frame_with_progress(&mut self,
+        progress: &mut dyn FnMut(ExposureProgress))
+        -> Result<Vec<u8>, Mu1603Error>
+    {
+        Mu1603::try_read_frame_with_progress(self, progress)
+    }
+    fn exposure_model(&self) -> ExposureModel {
+        self.cfg.exposure_model
+    }
+    fn set_frame_rate(&mut self, rate: FrameRate) {
+        Mu1603::set_frame_rate(self, rate)
+    }
+    fn frame_counts(&self) -> FrameCounts {
+        Mu1603::frame_counts(self)
+    }
+    fn device_info(&self) -> DeviceInfo {
+        Mu1603::device_info(self).clone()
+    }
+    fn stream_stats(&self) -> StreamStats {
+        Mu1603::stream_stats(self)
+    }
+}
@@ -1,3 +1,4 @@
+use crate::ExposureModel;
 
 // NOTE: The number of lines per frame should coincide with the
 // number of vsync pulses. 
@@ -45,6 +46,20 @@ impl Mu1603Mode {
     pub fn dimensions(&self) -> (usize, usize) { 
         (self.width(), self.height())
     }
+
+    /// The number of pixel clock cycles per line (the 'num_lines' argument 
+    /// to [ExposureTime::convert]).
+    ///
+    /// NOTE: This is derived from the pairs observed for mode 1 
+    /// (94000us => 0x0cbd, 150000us => 0x144e). We haven't measured this 
+    /// for the other modes yet, so they're assumed to be the same. 
+    pub fn cycles_per_line(&self) -> u16 { 
+        match self { 
+            Self::Mode0 => 1561,
+            Self::Mode1 => 1561,
+            Self::Mode2 => 1561,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -59,16 +74,56 @@ impl Mu1603BitDepth {
             Self::Depth12 => 2,
         }
     }
+
+    /// Number of significant bits in each sample.
+    pub fn bits(&self) -> usize { 
+        match self { 
+            Self::Depth8 => 8,
+            Self::Depth12 => 12,
+        }
+    }
+    pub fn from_bits(bits: usize) -> Option<Self> { 
+        match bits { 
+            8 => Some(Self::Depth8),
+            12 => Some(Self::Depth12),
+            _ => None,
+        }
+    }
+    /// The largest possible sample value.
+    pub fn max_value(&self) -> usize { 
+        (1 << self.bits()) - 1
+    }
+
+    /// Format of frames captured at this bit depth.
+    pub fn format(&self) -> glass_common::PixelFormat { 
+        use glass_common::PixelFormat;
+        match self { 
+            Self::Depth8 => PixelFormat::Bayer8(crate::Mu1603::PATTERN),
+            Self::Depth12 => PixelFormat::Bayer16(crate::Mu1603::PATTERN, 12),
+        }
+    }
 }
 
 /// The exposure time [in microseconds].
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct ExposureTime(usize);
 impl ExposureTime {
-    pub const MIN: usize = 001_000;
-    pub const MAX: usize = 125_000;
+    /// NOTE: This is the lowest value offered by the vendor software. 
+    /// It corresponds to (0x08db, 0x08e3) in mode 1, which is 8 lines. 
+    pub const MIN: usize = 000_244;
+    pub const MAX: usize = 30_000_000;
     pub const DEFAULT: usize = 94_000;
 
+    /// Exposures longer than this are considered "long exposures", 
+    /// see [ExposureTime::is_long].
+    pub const LONG: usize = 1_000_000;
+
+    /// Sensor clock cycles per microsecond [an educated guess]
+    pub const CYCLES_PER_US: usize = 54;
+
+    /// Minimum number of lines between the shutter and vsync
+    pub const MIN_CYCLES_PER_LINE: usize = 10;
+
     pub fn new_from_ms(ms: usize) -> Self { 
         let res = (ms * 1000).clamp(Self::MIN, Self::MAX);
         Self(res)
@@ -88,21 +143,43 @@ impl ExposureTime {
     pub fn milliseconds(&self) -> usize { 
         self.0 / 1000
     }
+    pub fn duration(&self) -> std::time::Duration { 
+        std::time::Duration::from_micros(self.0 as u64)
+    }
 
+    /// Return 'true' if this exposure is long enough that we should bother 
+    /// reporting progress while waiting for a frame. 
+    pub fn is_long(&self) -> bool { 
+        self.0 >= Self::LONG
+    }
 
+    /// Convert this into values for 0x1064, 0x4000, and 0x5000 
+    /// (see [crate::Mu1603::set_exposure]).
+    ///
+    /// Returns `None` if the shutter offset doesn't fit into 0x1064. 
     pub fn convert(&self, mode: Mu1603Mode, num_lines: u16) 
         -> Option<(u16, u16, u16)>
     {
-        pub const CYCLES_PER_US:       usize = 54;
-        pub const MIN_CYCLES_PER_LINE: usize = 10;
+        self.convert_with(&ExposureModel::default(), mode, num_lines)
+    }
+
+    /// Like [ExposureTime::convert], but using a calibrated [ExposureModel].
+    pub fn convert_with(&self, model: &ExposureModel, mode: Mu1603Mode, 
+        num_lines: u16) -> Option<(u16, u16, u16)>
+    {
+        let min_lines        = model.min_lines;
         let req_exposure_us  = self.0;
         let lines            = num_lines as usize;
         let cycles_per_hsync = num_lines as usize;
 
         // ([us] * [cycles/us]) = [cycles]
-        let req_exposure_cycles = req_exposure_us * CYCLES_PER_US;
+        let req_exposure_cycles = req_exposure_us as f64 * model.cycles_per_us;
         // [cycles] / [lines] = [cycles/line]
-        let req_cycles_per_line = (req_exposure_cycles / lines) as usize;
+        //
+        // NOTE: At the shortest exposures this is only a handful of lines, 
+        // but it should never be zero.
+        let req_cycles_per_line = (req_exposure_cycles / lines as f64 
+            - model.line_offset).max(1.0) as usize;
 
         // NOTE: I think these values correspond to the maximum number of 
         // hsync strobes for a line. If we were using this value, we'd be 
@@ -110,16 +187,23 @@ impl ExposureTime {
         // exposure time is minimal (as fast as possible).
         let mut hsync_per_vsync = mode.max_hsync() as usize;
 
-        let mut eff_cycles_per_line = MIN_CYCLES_PER_LINE;
+        let mut eff_cycles_per_line = min_lines;
 
-        if req_cycles_per_line < (hsync_per_vsync - MIN_CYCLES_PER_LINE) {
+        // NOTE: For exposures longer than a single frame, we have to stretch 
+        // the frame by increasing the number of lines between vsync pulses. 
+        // This is a 32-bit value split between 0x4000 (upper) and 0x5000 
+        // (lower), so we can go for quite a while (on the order of hours). 
+        if req_cycles_per_line < hsync_per_vsync.saturating_sub(min_lines) {
             eff_cycles_per_line = hsync_per_vsync - req_cycles_per_line;
         } 
         else {
-            hsync_per_vsync = req_cycles_per_line + MIN_CYCLES_PER_LINE;
-            if eff_cycles_per_line >= 0x1fff4 {
-                hsync_per_vsync = 0x1ffff;
-            }
+            hsync_per_vsync = (req_cycles_per_line + min_lines)
+                .min(0xffff_ffff);
+        }
+
+        // NOTE: This is only 13 bits wide. 
+        if eff_cycles_per_line > 0x1fff {
+            return None;
         }
 
         let res_1064 = (eff_cycles_per_line & 0x1fff) as u16;
@@ -127,10 +211,39 @@ impl ExposureTime {
         let res_5000 = (hsync_per_vsync & 0xffff) as u16;
         Some((res_1064, res_4000, res_5000))
     }
+
+    /// Return the exposure time that the sensor will actually use after
+    /// truncating to a whole number of lines (see [ExposureTime::convert]). 
+    ///
+    /// NOTE: This is rounded up to the next microsecond so that converting 
+    /// it again gives the same number of lines. 
+    pub fn effective(&self, mode: Mu1603Mode) -> Self { 
+        self.effective_with(&ExposureModel::default(), mode)
+    }
+
+    /// Like [ExposureTime::effective], but using a calibrated [ExposureModel].
+    pub fn effective_with(&self, model: &ExposureModel, mode: Mu1603Mode) 
+        -> Self 
+    { 
+        let lines = mode.cycles_per_line() as f64;
+        let num = (self.0 as f64 * model.cycles_per_us / lines 
+            - model.line_offset).max(1.0).floor();
+        Self(((num + model.line_offset) * lines / model.cycles_per_us).ceil() 
+            as usize)
+    }
 }
 impl Default for ExposureTime {
     fn default() -> Self { Self(Self::DEFAULT) }
 }
+impl std::fmt::Display for ExposureTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 { 
+            0..=999 => write!(f, "{}us", self.0),
+            1_000..=999_999 => write!(f, "{:.3}ms", self.0 as f64 / 1e3),
+            _ => write!(f, "{:.3}s", self.0 as f64 / 1e6),
+        }
+    }
+}
 
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -173,6 +286,9 @@ pub struct Mu1603Options {
 }
 
 impl Mu1603Options {
+    pub fn exposure_us(&self) -> usize { 
+        self.exposure.microseconds()
+    }
     pub fn exposure_ms(&self) -> usize { 
         self.exposure.milliseconds()
     }
@@ -207,6 +323,21 @@ impl Mu1603Options {
         &mut self.bitdepth
     }
 
+    /// Metadata for saving frames captured with these options as DNG.
+    ///
+    /// NOTE: Analog gain is reported as an ISO speed (100% gain is ISO 100),
+    /// and the sensor hasn't been characterized, so the black level and
+    /// color matrix are only placeholders.
+    pub fn dng_metadata(&self) -> glass_common::DngMetadata {
+        glass_common::DngMetadata {
+            make: "AmScope".to_string(),
+            model: "MU1603".to_string(),
+            exposure_time: Some(self.exposure_us() as f64 / 1_000_000.0),
+            iso: Some(self.analog_gain_percent() as u32),
+            ..Default::default()
+        }
+    }
+
 }
 
 
@@ -214,3 +345,97 @@ impl Mu1603Options {
 
 
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(us: usize, mode: Mu1603Mode) -> Option<(u16, u16, u16)> {
+        ExposureTime::new_from_us(us).convert(mode, mode.cycles_per_line())
+    }
+
+    #[test]
+    fn convert_registers() {
+        // 94ms is 3251 lines: longer than a frame in every mode, so the 
+        // frame is stretched and the shutter sits at the minimum.
+        assert_eq!(convert(ExposureTime::DEFAULT, Mu1603Mode::Mode1), 
+            Some((0x000a, 0x0000, 0x0cbd)));
+        assert_eq!(convert(ExposureTime::DEFAULT, Mu1603Mode::Mode2), 
+            Some((0x000a, 0x0000, 0x0cbd)));
+
+        // 5s is 172966 lines, which spills over into 0x4000
+        assert_eq!(convert(5_000_000, Mu1603Mode::Mode0), 
+            Some((0x000a, 0x0002, 0xa3b0)));
+        assert_eq!(convert(ExposureTime::MAX, Mu1603Mode::Mode0), 
+            Some((0x000a, 0x000f, 0xd5ee)));
+        // Anything longer is clamped
+        assert_eq!(convert(ExposureTime::MAX * 2, Mu1603Mode::Mode0), 
+            convert(ExposureTime::MAX, Mu1603Mode::Mode0));
+    }
+
+    #[test]
+    fn convert_min() {
+        assert_eq!(convert(ExposureTime::MIN, Mu1603Mode::Mode1), 
+            Some((0x08db, 0x0000, 0x08e3)));
+        assert_eq!(ExposureTime::new_from_us(244)
+            .convert(Mu1603Mode::Mode1, 1561), 
+            Some((0x08db, 0x0000, 0x08e3)));
+        // Anything shorter is clamped
+        assert_eq!(convert(1, Mu1603Mode::Mode1), 
+            Some((0x08db, 0x0000, 0x08e3)));
+    }
+
+    #[test]
+    fn effective() {
+        // 8 lines of 1561 cycles is 231.26us
+        let exp = ExposureTime::new_from_us(ExposureTime::MIN)
+            .effective(Mu1603Mode::Mode1);
+        assert_eq!(exp.microseconds(), 232);
+
+        for us in [244, 1_000, 65_476, 94_000, 1_234_567, 5_000_000] {
+            let exp = ExposureTime::new_from_us(us);
+            for mode in [Mu1603Mode::Mode0, Mu1603Mode::Mode1, Mu1603Mode::Mode2] {
+                let eff = exp.effective(mode);
+                assert!(eff.microseconds() <= us, "{us}us {mode:?}");
+                // Less than a line shorter than requested
+                assert!(us - eff.microseconds() <= 1561 / 54, "{us}us {mode:?}");
+                // Converting it again lands on the same lines
+                assert_eq!(eff.convert(mode, mode.cycles_per_line()), 
+                    exp.convert(mode, mode.cycles_per_line()), "{us}us {mode:?}");
+                assert_eq!(eff.effective(mode), eff, "{us}us {mode:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn convert_min_lines() {
+        // 2264 lines still fits in a mode 1 frame (0x08e3 - 10 lines)
+        assert_eq!(convert(65_447, Mu1603Mode::Mode1), 
+            Some((0x000b, 0x0000, 0x08e3)));
+        // 2265 lines doesn't, so the shutter stops at the floor
+        assert_eq!(convert(65_476, Mu1603Mode::Mode1), 
+            Some((0x000a, 0x0000, 0x08e3)));
+        assert_eq!(convert(65_620, Mu1603Mode::Mode1), 
+            Some((0x000a, 0x0000, 0x08e8)));
+
+        let model = ExposureModel { min_lines: 16, ..Default::default() };
+        assert_eq!(ExposureTime::new_from_us(65_620)
+            .convert_with(&model, Mu1603Mode::Mode1, 1561), 
+            Some((0x0010, 0x0000, 0x08ee)));
+    }
+
+    #[test]
+    fn convert_out_of_range() {
+        // 0x1064 is only 13 bits wide
+        let model = ExposureModel { min_lines: 0x2000, ..Default::default() };
+        for us in [ExposureTime::MIN, ExposureTime::DEFAULT] {
+            assert_eq!(ExposureTime::new_from_us(us)
+                .convert_with(&model, Mu1603Mode::Mode0, 1561), None);
+        }
+        let model = ExposureModel { min_lines: 0x1fff, ..Default::default() };
+        assert_eq!(ExposureTime::new_from_us(ExposureTime::DEFAULT)
+            .convert_with(&model, Mu1603Mode::Mode0, 1561), 
+            Some((0x1fff, 0x0000, 0x2cb2)));
+    }
+}
@@ -0,0 +1,377 @@
+//! Characterising the exposure model with a sweep over exposure times.
+//!
+//! The idea is to point the camera at a static, uniformly lit target and
+//! step through a range of exposure times. For each step we record the
+//! register values used, the mean signal in each Bayer channel, and the
+//! interval between frames. Then:
+//!
+//! - Signal should be proportional to the number of lines integrated.
+//!   Where each channel's line crosses zero tells us how many lines are
+//!   integrated that the registers don't account for (the line offset).
+//!
+//! - Once an exposure is longer than a single frame, the frame is stretched
+//!   by adding lines (see [ExposureTime::convert]). The interval between
+//!   frames then tells us the line time, and the sensor clock rate.
+//!
+//! The result is a calibrated [ExposureModel].
+
+use super::*;
+use glass_common::*;
+
+/// Settings for an exposure sweep.
+#[derive(Clone, Debug)]
+pub struct ExposureSweep {
+    pub mode: Mu1603Mode,
+
+    /// Exposure times to capture (see [ExposureSweep::geometric])
+    pub exposures: Vec<ExposureTime>,
+
+    /// Number of frames to average for each exposure
+    pub frames_per_step: usize,
+
+    /// Number of frames to discard after changing the exposure
+    pub settle_frames: usize,
+
+    /// Bayer pattern of the sensor
+    pub pattern: BayerPattern,
+
+    /// Signal with no light [in ADU], ie. measured with the lens capped
+    pub black_level: f64,
+
+    /// Steps where any channel is above this fraction of full scale are
+    /// left out of the fit
+    pub saturation: f64,
+
+    /// Number of failed reads to tolerate for each frame
+    pub max_retries: usize,
+}
+impl ExposureSweep {
+    pub fn new(mode: Mu1603Mode) -> Self {
+        Self {
+            mode,
+            exposures: Self::geometric(
+                ExposureTime::new_from_us(1_000),
+                ExposureTime::new_from_us(250_000),
+                16
+            ),
+            frames_per_step: 4,
+            settle_frames: 2,
+            pattern: Mu1603::PATTERN,
+            black_level: 0.0,
+            saturation: 0.9,
+            max_retries: 10,
+        }
+    }
+
+    /// Return 'steps' exposure times spaced evenly on a log scale.
+    pub fn geometric(min: ExposureTime, max: ExposureTime, steps: usize)
+        -> Vec<ExposureTime>
+    {
+        if steps < 2 {
+            return vec![min];
+        }
+        let (lo, hi) = (min.microseconds() as f64, max.microseconds() as f64);
+        let ratio = (hi / lo).powf(1.0 / (steps - 1) as f64);
+        (0..steps)
+            .map(|i| ExposureTime::new_from_us((lo * ratio.powi(i as i32)) as usize))
+            .collect()
+    }
+
+    /// Run the sweep.
+    ///
+    /// NOTE: Only 8-bit data is supported for now.
+    pub fn run<S: FrameSource>(&self, cam: &mut S)
+        -> Result<SweepResult, Mu1603Error>
+    {
+        let model = cam.exposure_model();
+        let mut opts = cam.start_stream(self.mode)?;
+        if opts.bitdepth != Mu1603BitDepth::Depth8 {
+            return Err(Mu1603Error::Unimplemented);
+        }
+        let (width, height) = self.mode.dimensions();
+
+        let mut steps = Vec::with_capacity(self.exposures.len());
+        for exposure in self.exposures.iter() {
+            let registers = exposure.convert_with(&model, self.mode,
+                self.mode.cycles_per_line()
+            ).ok_or(Mu1603Error::Unimplemented)?;
+            opts.exposure = *exposure;
+            opts = cam.apply_state(opts)?;
+
+            for _ in 0..self.settle_frames {
+                cam.read_frame_with_retries(self.max_retries)?;
+            }
+
+            let mut sums = [0.0; 4];
+            let mut timestamps = Vec::with_capacity(self.frames_per_step);
+            for _ in 0..self.frames_per_step {
+                let data = cam.read_frame_with_retries(self.max_retries)?;
+                let timestamp = cam.frame_timestamp();
+                let stats = bayer_stats(&data, width, height, self.pattern)
+                    .map_err(Mu1603Error::InvalidFrame)?;
+                for (sum, s) in sums.iter_mut().zip(stats.iter()) {
+                    *sum += s.mean;
+                }
+                timestamps.push(timestamp);
+            }
+
+            // NOTE: The median is less sensitive to an occasional frame
+            // being dropped.
+            let mut intervals = TimingCheck::intervals(&timestamps);
+            intervals.sort();
+            let frame_interval = intervals.get(intervals.len() / 2).copied();
+
+            let step = SweepStep {
+                exposure: *exposure,
+                registers,
+                means: sums.map(|s| s / self.frames_per_step as f64),
+                frame_interval,
+            };
+            tracing::info!(exposure = %step.exposure, lines = step.lines(),
+                means = ?step.means, "sweep step"
+            );
+            steps.push(step);
+        }
+        cam.stop_stream()?;
+
+        Ok(SweepResult {
+            mode: self.mode,
+            model,
+            black_level: self.black_level,
+            saturation: self.saturation * 255.0,
+            steps,
+        })
+    }
+}
+
+/// Measurements for a single exposure time in an [ExposureSweep].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SweepStep {
+    pub exposure: ExposureTime,
+
+    /// Values for 0x1064, 0x4000, and 0x5000
+    pub registers: (u16, u16, u16),
+
+    /// Mean signal for each channel [in ADU]
+    /// (ordered like [BayerChannel::ALL])
+    pub means: [f64; 4],
+
+    /// Median time between frames
+    pub frame_interval: Option<Duration>,
+}
+impl SweepStep {
+    /// Number of lines between vsync pulses.
+    pub fn vsync(&self) -> usize {
+        let (_, hi, lo) = self.registers;
+        ((hi as usize) << 16) | lo as usize
+    }
+
+    /// Number of lines integrated according to the registers alone.
+    pub fn lines(&self) -> usize {
+        self.vsync().saturating_sub(self.registers.0 as usize)
+    }
+}
+
+/// The results of an [ExposureSweep].
+#[derive(Clone, Debug)]
+pub struct SweepResult {
+    pub mode: Mu1603Mode,
+
+    /// The model used to pick register values during the sweep
+    pub model: ExposureModel,
+
+    pub black_level: f64,
+
+    /// Steps with any channel above this level [in ADU] aren't used
+    pub saturation: f64,
+
+    pub steps: Vec<SweepStep>,
+}
+impl SweepResult {
+    /// Steps that are usable for fitting the signal.
+    fn usable_steps(&self) -> impl Iterator<Item = &SweepStep> {
+        // NOTE: Signal close to the black level is distorted by clipping
+        // the noise at zero.
+        let floor = self.black_level + 2.0;
+        self.steps.iter().filter(move |s| {
+            s.means.iter().all(|m| *m < self.saturation && *m > floor)
+        })
+    }
+
+    /// Fit the exposure model to the measurements.
+    pub fn fit(&self) -> SweepFit {
+        let channels = BayerChannel::ALL.map(|ch| {
+            let points: Vec<(f64, f64)> = self.usable_steps()
+                .map(|s| (s.lines() as f64, s.means[ch.index()] - self.black_level))
+                .collect();
+            LinearFit::new(&points)
+        });
+
+        // NOTE: This only uses exposures that were long enough to stretch
+        // the frame. Otherwise, the frame rate is often limited by something
+        // other than the sensor (ie. USB bandwidth).
+        let max_hsync = self.mode.max_hsync() as usize;
+        let points: Vec<(f64, f64)> = self.steps.iter()
+            .filter(|s| s.vsync() > max_hsync)
+            .filter_map(|s| {
+                s.frame_interval.map(|d| (s.vsync() as f64, d.as_secs_f64() * 1e6))
+            })
+            .collect();
+        let frame_time = LinearFit::new(&points);
+
+        let mut model = self.model;
+        if let Some(fit) = frame_time.filter(|f| f.slope > 0.0) {
+            model.cycles_per_us = self.mode.cycles_per_line() as f64 / fit.slope;
+        }
+        let offsets: Vec<f64> = channels.iter()
+            .flatten()
+            .filter(|f| f.slope > 0.0)
+            .map(|f| f.x_intercept())
+            .collect();
+        if !offsets.is_empty() {
+            // The line crosses zero at '-offset'
+            model.line_offset = -offsets.iter().sum::<f64>() / offsets.len() as f64;
+        }
+
+        SweepFit { channels, frame_time, model }
+    }
+
+    /// Write a human-readable report of the measurements and the fit.
+    pub fn report(&self, fit: &SweepFit) -> String {
+        use std::fmt::Write;
+        let mut s = String::new();
+        let (w, h) = self.mode.dimensions();
+        let _ = writeln!(s, "# Exposure sweep ({:?}, {}x{})", self.mode, w, h);
+        let _ = writeln!(s, "# black level = {:.2} ADU", self.black_level);
+        let _ = writeln!(s, "#");
+        let _ = write!(s, "# {:>11} {:>6} {:>10} {:>10} {:>12}",
+            "exposure_us", "0x1064", "vsync", "lines", "interval_us");
+        for ch in BayerChannel::ALL {
+            let _ = write!(s, " {:>8}", ch.name());
+        }
+        let _ = writeln!(s);
+        for step in self.steps.iter() {
+            let interval = step.frame_interval
+                .map(|d| format!("{:.1}", d.as_secs_f64() * 1e6))
+                .unwrap_or("-".to_string());
+            let _ = write!(s, "  {:>11} {:>6} {:>10} {:>10} {:>12}",
+                step.exposure.microseconds(), step.registers.0,
+                step.vsync(), step.lines(), interval
+            );
+            for m in step.means.iter() {
+                let _ = write!(s, " {:>8.3}", m);
+            }
+            let _ = writeln!(s);
+        }
+
+        let _ = writeln!(s);
+        let _ = writeln!(s, "# Signal vs. lines integrated");
+        for (ch, f) in BayerChannel::ALL.iter().zip(fit.channels.iter()) {
+            match f {
+                Some(f) => {
+                    let _ = writeln!(s, "{:>2}: {:.6} ADU/line, offset {:.2} lines, r^2 = {:.5} ({} points)",
+                        ch.name(), f.slope, -f.x_intercept(), f.r_squared, f.count
+                    );
+                },
+                None => {
+                    let _ = writeln!(s, "{:>2}: not enough unsaturated points", ch.name());
+                },
+            }
+        }
+        let _ = writeln!(s);
+        let _ = writeln!(s, "# Frame interval vs. vsync");
+        match fit.frame_time {
+            Some(f) => {
+                let _ = writeln!(s, "{:.4} us/line, r^2 = {:.5} ({} points)",
+                    f.slope, f.r_squared, f.count
+                );
+            },
+            None => {
+                let _ = writeln!(s, "not enough stretched frames; use longer exposures");
+            },
+        }
+        let _ = writeln!(s);
+        let _ = writeln!(s, "# Model (previous => fitted)");
+        let _ = writeln!(s, "cycles_per_us = {} => {:.4}",
+            self.model.cycles_per_us, fit.model.cycles_per_us);
+        let _ = writeln!(s, "min_lines     = {} => {}",
+            self.model.min_lines, fit.model.min_lines);
+        let _ = writeln!(s, "line_offset   = {} => {:.4}",
+            self.model.line_offset, fit.model.line_offset);
+        s
+    }
+}
+
+/// The exposure model fitted to a [SweepResult].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SweepFit {
+    /// Signal [in ADU above the black level] vs. lines integrated
+    /// (ordered like [BayerChannel::ALL])
+    pub channels: [Option<LinearFit>; 4],
+
+    /// Frame interval [in microseconds] vs. lines between vsync pulses
+    pub frame_time: Option<LinearFit>,
+
+    /// The resulting model. Parameters that couldn't be fitted are left
+    /// at their previous values.
+    pub model: ExposureModel,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geometric() {
+        let exposures = ExposureSweep::geometric(
+            ExposureTime::new_from_us(1_000), ExposureTime::new_from_us(100_000), 3
+        );
+        let us: Vec<usize> = exposures.iter().map(|e| e.microseconds()).collect();
+        assert_eq!(us.len(), 3);
+        assert_eq!(us[0], 1_000);
+        assert!((9_999..=10_000).contains(&us[1]));
+        assert!((99_999..=100_000).contains(&us[2]));
+    }
+
+    /// The fit should recover the emulated sensor's model, even though the
+    /// driver is using the default one.
+    #[test]
+    fn fit_emulator() {
+        let actual = ExposureModel {
+            cycles_per_us: 60.0,
+            line_offset: 3.0,
+            ..Default::default()
+        };
+        let sensor = EmulatorSensor {
+            exposure_model: actual,
+            ..Default::default()
+        };
+        let mut emu = Mu1603Emulator::new(sensor, EmulatorScene::default())
+            .realtime(false);
+
+        // NOTE: The frame interval is only fitted for exposures longer than
+        // a frame (about 35ms in mode 2). The longest ones saturate, so the
+        // shorter ones are used for fitting the line offset.
+        let mut sweep = ExposureSweep::new(Mu1603Mode::Mode2);
+        sweep.exposures = [10_000, 20_000, 40_000, 100_000, 250_000]
+            .map(ExposureTime::new_from_us).to_vec();
+        sweep.frames_per_step = 3;
+        sweep.settle_frames = 1;
+        sweep.black_level = sensor.black_level;
+
+        let result = sweep.run(&mut emu).unwrap();
+        assert_eq!(result.steps.len(), 5);
+        assert!(!emu.is_streaming());
+
+        let fit = result.fit();
+        assert!(fit.channels.iter().all(|f| f.is_some()), "{:?}", fit);
+        assert!(fit.frame_time.is_some());
+        let model = fit.model;
+        assert!((model.cycles_per_us - 60.0).abs() < 0.5, "{:?}", model);
+        assert!((model.line_offset - 3.0).abs() < 0.5, "{:?}", model);
+        assert_eq!(model.min_lines, actual.min_lines);
+
+        let report = result.report(&fit);
+        assert!(report.contains("# Model (previous => fitted)"));
+    }
+}
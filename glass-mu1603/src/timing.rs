@@ -0,0 +1,242 @@
+//! Predicting frame timing from the sensor settings.
+//!
+//! This is the same model used by [ExposureTime::convert_with]:
+//!
+//! - A line takes [Mu1603Mode::cycles_per_line] sensor clock cycles
+//!   (at [ExposureModel::cycles_per_us])
+//! - A frame takes some number of lines between vsync pulses. This is at
+//!   least [Mu1603Mode::max_hsync], and it's stretched for exposures that
+//!   don't fit inside a single frame
+//! - The exposure is the number of lines between the shutter and vsync
+//!
+
+use super::*;
+
+/// Predicted sensor timing for a particular mode and exposure time.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SensorTiming {
+    /// The mode these predictions are for
+    pub mode: Mu1603Mode,
+
+    /// Time spent on a single line
+    pub line_time: Duration,
+
+    /// Number of lines between vsync pulses
+    pub lines_per_frame: usize,
+
+    /// Time between the start of consecutive frames
+    pub frame_time: Duration,
+
+    /// Shortest possible frame time in this mode
+    pub min_frame_time: Duration,
+
+    /// Time spent reading out the active lines of a frame
+    pub readout_time: Duration,
+
+    /// The exposure time after truncating to a whole number of lines
+    pub exposure: ExposureTime,
+}
+impl SensorTiming {
+    /// Compute the timing for the given mode and exposure time.
+    ///
+    /// NOTE: This should be the same [ExposureModel] used by the device
+    /// (see [Mu1603Builder::exposure_model]).
+    pub fn new(mode: Mu1603Mode, exposure: ExposureTime, model: &ExposureModel)
+        -> Self
+    {
+        let line_ns = model.line_time_us(mode) * 1000.0;
+        let lines = |n: usize| Duration::from_nanos((n as f64 * line_ns) as u64);
+
+        let regs = exposure.convert_with(model, mode, mode.cycles_per_line());
+        let lines_per_frame = match regs {
+            Some((_, hi, lo)) => ((hi as usize) << 16) | lo as usize,
+            None => mode.max_hsync() as usize,
+        };
+
+        Self {
+            mode,
+            line_time: lines(1),
+            lines_per_frame,
+            frame_time: lines(lines_per_frame),
+            min_frame_time: lines(mode.max_hsync() as usize),
+            readout_time: lines(mode.height()),
+            exposure: exposure.effective_with(model, mode),
+        }
+    }
+
+    /// Compute the timing for some [Mu1603Options].
+    pub fn from_options(opts: &Mu1603Options, model: &ExposureModel) -> Self {
+        Self::new(opts.mode, opts.exposure, model)
+    }
+
+    /// Predicted frame rate [in frames per second]
+    pub fn fps(&self) -> f64 {
+        1.0 / self.frame_time.as_secs_f64()
+    }
+
+    /// Maximum frame rate for this mode [in frames per second]
+    ///
+    /// NOTE: This doesn't account for USB bandwidth.
+    pub fn max_fps(&self) -> f64 {
+        1.0 / self.min_frame_time.as_secs_f64()
+    }
+
+    /// Predicted data rate [in bytes per second] for the given bit depth.
+    pub fn bytes_per_sec(&self, bitdepth: Mu1603BitDepth) -> f64 {
+        let frame_len = self.mode.width() * self.mode.height() * bitdepth.bpp();
+        frame_len as f64 * self.fps()
+    }
+
+    /// Compare these predictions against measured intervals between frames.
+    pub fn check(&self, intervals: &[Duration]) -> Option<TimingCheck> {
+        TimingCheck::new(self.frame_time, intervals)
+    }
+}
+
+/// The result of comparing a [SensorTiming] with measured frame intervals.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TimingCheck {
+    /// The predicted frame time
+    pub predicted: Duration,
+    /// Number of measured intervals
+    pub samples: usize,
+    /// Mean of the measured intervals
+    pub mean: Duration,
+    /// Standard deviation of the measured intervals
+    pub std_dev: Duration,
+    /// (mean - predicted) / predicted
+    pub relative_error: f64,
+}
+impl TimingCheck {
+    /// Tolerance used by [TimingCheck::is_consistent].
+    pub const TOLERANCE: f64 = 0.05;
+
+    pub fn new(predicted: Duration, intervals: &[Duration]) -> Option<Self> {
+        if intervals.is_empty() {
+            return None;
+        }
+        let n = intervals.len() as f64;
+        let mean = intervals.iter().map(|d| d.as_secs_f64()).sum::<f64>() / n;
+        let var = intervals.iter()
+            .map(|d| (d.as_secs_f64() - mean).powi(2))
+            .sum::<f64>() / n;
+        let pred = predicted.as_secs_f64();
+        Some(Self {
+            predicted,
+            samples: intervals.len(),
+            mean: Duration::from_secs_f64(mean),
+            std_dev: Duration::from_secs_f64(var.sqrt()),
+            relative_error: (mean - pred) / pred,
+        })
+    }
+
+    /// Compute the intervals between a sequence of frame timestamps.
+    pub fn intervals(timestamps: &[Instant]) -> Vec<Duration> {
+        timestamps.windows(2).map(|w| w[1] - w[0]).collect()
+    }
+
+    /// Return 'true' if the measurements agree with the prediction.
+    ///
+    /// NOTE: Frames that are slower than predicted usually mean that we
+    /// aren't keeping up with the sensor (or that USB bandwidth is the
+    /// bottleneck), rather than the model being wrong.
+    pub fn is_consistent(&self) -> bool {
+        self.relative_error.abs() <= Self::TOLERANCE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing(mode: Mu1603Mode, us: usize) -> SensorTiming {
+        SensorTiming::new(mode, ExposureTime::new_from_us(us), 
+            &ExposureModel::default())
+    }
+
+    #[test]
+    fn modes() {
+        // (mode, lines per frame at 94ms, min frame time, readout) [in ns]
+        let cases = [
+            (Mu1603Mode::Mode0, 3620, 104_644_814, 100_829_037),
+            (Mu1603Mode::Mode1, 3261,  65_764_351,  50_298_888),
+            (Mu1603Mode::Mode2, 3261,  35_440_481,  33_532_592),
+        ];
+        for (mode, lines, min_frame_ns, readout_ns) in cases {
+            let t = timing(mode, ExposureTime::DEFAULT);
+            // 1561 cycles at 54 cycles/us
+            assert_eq!(t.line_time, Duration::from_nanos(28_907), "{mode:?}");
+            assert_eq!(t.lines_per_frame, lines, "{mode:?}");
+            assert_eq!(t.frame_time, Duration::from_nanos(
+                (lines as f64 * 1561.0 / 54.0 * 1000.0) as u64), "{mode:?}");
+            assert_eq!(t.min_frame_time, Duration::from_nanos(min_frame_ns), 
+                "{mode:?}");
+            assert_eq!(t.readout_time, Duration::from_nanos(readout_ns), 
+                "{mode:?}");
+            assert!((t.max_fps() - 1e9 / min_frame_ns as f64).abs() < 1e-9);
+            assert!(t.readout_time < t.min_frame_time);
+            // 3251 whole lines
+            assert_eq!(t.exposure.microseconds(), 93_978, "{mode:?}");
+        }
+        assert_eq!(timing(Mu1603Mode::Mode0, ExposureTime::DEFAULT).frame_time, 
+            Duration::from_nanos(104_644_814));
+        assert_eq!(timing(Mu1603Mode::Mode1, ExposureTime::DEFAULT).frame_time, 
+            Duration::from_nanos(94_267_055));
+    }
+
+    #[test]
+    fn short_and_long_exposures() {
+        // Short exposures run at the maximum frame rate
+        let t = timing(Mu1603Mode::Mode2, ExposureTime::MIN);
+        assert_eq!(t.lines_per_frame, 0x04ca);
+        assert_eq!(t.frame_time, t.min_frame_time);
+        assert_eq!(t.fps(), t.max_fps());
+
+        // Long exposures stretch the frame
+        let t = timing(Mu1603Mode::Mode2, 5_000_000);
+        assert_eq!(t.lines_per_frame, 0x2_a3b0);
+        assert!((t.fps() - 1.0 / 5.0).abs() < 1e-3, "{}", t.fps());
+
+        let bytes = 1536.0 * 1160.0 * 2.0 * t.fps();
+        assert_eq!(t.bytes_per_sec(Mu1603BitDepth::Depth12), bytes);
+        assert_eq!(t.bytes_per_sec(Mu1603BitDepth::Depth8), bytes / 2.0);
+    }
+
+    #[test]
+    fn intervals() {
+        let start = Instant::now();
+        let ms = |n: u64| start + Duration::from_millis(n);
+        assert_eq!(TimingCheck::intervals(&[ms(0), ms(10), ms(22), ms(36)]), 
+            [10, 12, 14].map(Duration::from_millis));
+        assert!(TimingCheck::intervals(&[ms(0)]).is_empty());
+        assert!(TimingCheck::intervals(&[]).is_empty());
+        assert!(TimingCheck::new(Duration::from_millis(12), &[]).is_none());
+
+        let check = TimingCheck::new(Duration::from_millis(10), 
+            &[10, 12, 14].map(Duration::from_millis)).unwrap();
+        assert_eq!(check.samples, 3);
+        assert!((check.mean.as_secs_f64() - 0.012).abs() < 1e-9);
+        assert!((check.std_dev.as_secs_f64() - (8e-6f64 / 3.0).sqrt()).abs() 
+            < 1e-9);
+        assert!((check.relative_error - 0.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn consistency() {
+        let t = timing(Mu1603Mode::Mode1, ExposureTime::DEFAULT);
+        let frame = t.frame_time;
+        let check = |scale: f64| t.check(&[
+            frame.mul_f64(scale * 0.99), 
+            frame.mul_f64(scale * 1.01),
+        ]).unwrap();
+
+        assert!(check(1.0).is_consistent());
+        assert!(check(1.04).is_consistent());
+        assert!(check(0.96).is_consistent());
+        assert!(!check(1.06).is_consistent());
+        assert!(!check(0.94).is_consistent());
+        // Dropping every other frame
+        assert!(!check(2.0).is_consistent());
+        assert!(t.check(&[]).is_none());
+    }
+}
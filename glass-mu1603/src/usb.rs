@@ -11,17 +11,36 @@ impl Mu1603 {
     pub fn ven_read(&mut self, req: u8, idx: u16, val: u16, buf: &mut [u8])
         -> Result<usize, Mu1603Error>
     {
-        self.handle.read_control(
-            Self::REQ_TYPE_IN, req, val, idx, buf, Self::TIMEOUT
-        ).map_err(Mu1603Error::from)
+        let start = Instant::now();
+        let res = self.handle.read_control(
+            Self::REQ_TYPE_IN, req, val, idx, buf, self.cfg.control_timeout
+        );
+        Self::trace_control("ven_read", req, idx, val, start, &res);
+        res.map_err(Mu1603Error::from)
     }
 
     pub fn ven_write(&mut self, req: u8, idx: u16, val: u16, buf: &[u8])
         -> Result<usize, Mu1603Error>
     {
-        self.handle.write_control(
-            Self::REQ_TYPE_OUT, req, val, idx, buf, Self::TIMEOUT
-        ).map_err(Mu1603Error::from)
+        let start = Instant::now();
+        let res = self.handle.write_control(
+            Self::REQ_TYPE_OUT, req, val, idx, buf, self.cfg.control_timeout
+        );
+        Self::trace_control("ven_write", req, idx, val, start, &res);
+        res.map_err(Mu1603Error::from)
+    }
+
+    fn trace_control(kind: &'static str, req: u8, idx: u16, val: u16, 
+        start: Instant, res: &rusb::Result<usize>)
+    {
+        let duration = start.elapsed();
+        let req = format_args!("{:#04x}", req);
+        let idx = format_args!("{:#06x}", idx);
+        let val = format_args!("{:#06x}", val);
+        match res {
+            Ok(len) => trace!(%req, %idx, %val, len, ?duration, "{}", kind),
+            Err(e) => warn!(%req, %idx, %val, error = ?e, ?duration, "{} failed", kind),
+        }
     }
 }
 
@@ -72,16 +91,32 @@ impl Mu1603 {
     //
     // - Seems like the lower limit on the value of 0x5000 is 0x08e3?
     //
-    pub fn set_exposure(&mut self, val1064: u16, val5000: u16) 
+    // - 0x4000 and 0x5000 are the upper and lower halves of a 32-bit value
+    //   (the number of lines between vsync pulses). The vendor software 
+    //   only ever seems to write zero to 0x4000. 
+    //
+    pub fn set_exposure(&mut self, val1064: u16, val4000: u16, val5000: u16) 
         -> Result<(), Mu1603Error>
     {
         self.sensor_cmd(0x1063, 0x0000)?;
         self.sensor_cmd(0x1064, val1064)?;
-        self.system_cmd(0x4000, 0x0000)?;
+        self.system_cmd(0x4000, val4000)?;
         self.system_cmd(0x5000, val5000)?;
         Ok(())
     }
 
+    /// Set the exposure time for the given mode. 
+    pub fn set_exposure_time(&mut self, mode: Mu1603Mode, exp: ExposureTime)
+        -> Result<(), Mu1603Error>
+    {
+        let (val1064, val4000, val5000) = exp.convert_with(
+            &self.cfg.exposure_model, mode, mode.cycles_per_line()
+        ).ok_or(Mu1603Error::Unimplemented)?;
+        self.set_exposure(val1064, val4000, val5000)?;
+        self.exposure_start = Instant::now();
+        Ok(())
+    }
+
     /// Sequence used to set the analog gain.
     ///
     /// NOTE: Observed values are between 0x610c and 0x61a1. 
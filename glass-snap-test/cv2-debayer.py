@@ -3,6 +3,7 @@
 import numpy as np
 import tifffile as tf
 import cv2
+import struct
 from sys import argv
 from hexdump import hexdump
 
@@ -13,13 +14,29 @@ if len(argv) < 2:
 with open(argv[1], "rb") as f:
     data = f.read()
 
-# 8-bit RGGB (Bayer pattern)
-arr = np.frombuffer(data, dtype=np.dtype(np.uint8))
-arr.shape = (1740, 2320, 1)
-#arr.shape = (3488, 4632, 1)
+# Raw containers record the size and format of each frame (see the format
+# described in 'glass-mu1603/src/rawfile.rs'). This only uses the first one.
+if data[:8] == b"GLASSRAW":
+    off = struct.unpack_from("<H", data, 10)[0]
+    hdr_len, = struct.unpack_from("<I", data, off + 4)
+    width, height, fmt, pattern, bits = struct.unpack_from("<IIBBB", data, off + 0x18)
+    size, = struct.unpack_from("<Q", data, off + 0x38)
+    assert fmt in (0, 1), "expected Bayer data"
+    assert pattern == 3, "expected BGGR data"
+    dtype = np.uint8 if fmt == 0 else np.dtype("<u2")
+    arr = np.frombuffer(data, dtype=dtype, count=width * height,
+        offset=off + hdr_len)
+    if fmt == 1:
+        arr = (arr >> (bits - 8)).astype(np.uint8)
+    arr.shape = (height, width, 1)
+else:
+    # Headerless 8-bit BGGR (Bayer pattern, see 'Mu1603::PATTERN')
+    arr = np.frombuffer(data, dtype=np.dtype(np.uint8))
+    arr.shape = (1740, 2320, 1)
+    #arr.shape = (3488, 4632, 1)
 print(arr)
 
-#colimg = cv2.cvtColor(arr, cv2.COLOR_BAYER_BGGR2RGB)
+# NOTE: OpenCV names patterns by the second row (BGGR is 'RG' here)
 colimg = cv2.cvtColor(arr, cv2.COLOR_BAYER_RGGB2RGB)
 #colimg = cv2.cvtColor(arr, cv2.COLOR_BAYER_GBRG2RGB)
 #colimg = cv2.cvtColor(arr, cv2.COLOR_BAYER_GRBG2RGB)
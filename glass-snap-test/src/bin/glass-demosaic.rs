@@ -0,0 +1,175 @@
+//! Demosaic a raw Bayer frame without a GL context.
+//!
+//! Usage: glass-demosaic <input> [options]
+//!
+//! The input is either a raw container (see 'RawFileWriter'), which already
+//! records the size and format of each frame, or headerless Bayer data.
+//!
+//!   --frame <n>          Frame to use from a raw container (default: 0)
+//!   --width <n>          Frame width (default: 2320)
+//!   --height <n>         Frame height (default: 1740)
+//!   --pattern <p>        Bayer pattern, 'rggb', 'grbg', 'gbrg' or 'bggr'
+//!                        (default: the MU1603 pattern)
+//!   --bits <n>           Significant bits in each sample, from 8 to 16; more
+//!                        than 8 means 16-bit little-endian input and RGB16
+//!                        output (default: 8)
+//!   --method <m>         'nearest', 'bilinear', 'malvar' or 'vng'
+//!                        (default: 'malvar')
+//!   --profile <file>     Develop with the '[color]' section of a camera
+//!                        profile (white balance, color matrix and tone curve)
+//!   --out <file>         Write RGB data to <file> (default: '<input>.rgb8.raw'
+//!                        or '<input>.rgb16.raw')
+//!   --ppm                Write an 8-bit binary PPM instead of raw RGB data
+//!   --compare <file>     Compare the result with some RGB8 data (ie. a frame
+//!                        saved from the shader in 'glass-gui')
+//!   --margin <n>         Ignore pixels this close to the edge when comparing
+//!                        (default: 2)
+
+use glass_common::*;
+use glass_mu1603::{ CameraProfile, Mu1603, RawFileReader, RAW_FILE_MAGIC };
+use glass_snap_test::*;
+use std::time::Instant;
+
+struct Args {
+    input: String,
+    frame: usize,
+    width: usize,
+    height: usize,
+    pattern: BayerPattern,
+    bits: u8,
+    method: DemosaicMethod,
+    profile: Option<String>,
+    out: Option<String>,
+    ppm: bool,
+    compare: Option<String>,
+    margin: usize,
+}
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut input = None;
+        let mut res = Self {
+            input: String::new(),
+            frame: 0,
+            width: 2320,
+            height: 1740,
+            pattern: Mu1603::PATTERN,
+            bits: 8,
+            method: DemosaicMethod::Malvar,
+            profile: None,
+            out: None,
+            ppm: false,
+            compare: None,
+            margin: 2,
+        };
+        let mut args = ArgParser::from_env();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--frame" => res.frame = args.num(&arg)?,
+                "--width" => res.width = args.num(&arg)?,
+                "--height" => res.height = args.num(&arg)?,
+                "--pattern" => {
+                    let s = args.value(&arg)?;
+                    res.pattern = BayerPattern::from_name(&s)
+                        .ok_or_else(|| format!("invalid pattern '{}'", s))?;
+                },
+                "--bits" => {
+                    res.bits = match args.num(&arg)? {
+                        b @ 8..=16 => b,
+                        b => return Err(format!("invalid bit depth '{}'", b)),
+                    };
+                },
+                "--method" => {
+                    let s = args.value(&arg)?;
+                    res.method = DemosaicMethod::from_name(&s)
+                        .ok_or_else(|| format!("invalid method '{}'", s))?;
+                },
+                "--profile" => res.profile = Some(args.value(&arg)?),
+                "--out" => res.out = Some(args.value(&arg)?),
+                "--ppm" => res.ppm = true,
+                "--compare" => res.compare = Some(args.value(&arg)?),
+                "--margin" => res.margin = args.num(&arg)?,
+                _ if !arg.starts_with("--") && input.is_none() => input = Some(arg),
+                _ => return Err(format!("unknown argument '{}'", arg)),
+            }
+        }
+        res.input = input.ok_or("expected an input file")?;
+        Ok(res)
+    }
+}
+
+fn main() {
+    let args = parse_or_exit(Args::parse());
+
+    let raw = std::fs::read(&args.input)
+        .unwrap_or_else(|e| panic!("[!] Couldn't read {}: {}", args.input, e));
+    let frame = if raw.starts_with(&RAW_FILE_MAGIC) {
+        let mut frames = RawFileReader::new(raw.as_slice())
+            .unwrap_or_else(|e| panic!("[!] {}: {}", args.input, e));
+        let frame = frames.nth(args.frame)
+            .unwrap_or_else(|| panic!("[!] {}: no frame {}", args.input, args.frame))
+            .unwrap_or_else(|e| panic!("[!] {}: {}", args.input, e));
+        println!("[*] Read frame {} ({}x{}, {:?})", frame.header.id,
+            frame.data.width(), frame.data.height(), frame.data.format());
+        frame.data
+    } else {
+        let format = match args.bits {
+            8 => PixelFormat::Bayer8(args.pattern),
+            bits => PixelFormat::Bayer16(args.pattern, bits),
+        };
+        PixelData::new_from_slice(format, args.width, args.height, &raw)
+            .unwrap_or_else(|e| panic!("[!] {}: {}", args.input, e))
+    };
+    let (width, height) = (frame.width(), frame.height());
+
+    let color = args.profile.as_ref().map(|path| {
+        let profile = CameraProfile::load(path)
+            .unwrap_or_else(|e| panic!("[!] {}: {}", path, e));
+        profile.color.unwrap_or_else(|| panic!("[!] {}: no '[color]' section", path))
+    });
+
+    let start = Instant::now();
+    let rgb = match &color {
+        Some(color) => color.develop(&frame.view(), args.method).unwrap(),
+        None => frame.demosaic(args.method).unwrap(),
+    };
+    println!("[*] Demosaiced {}x{} ({:?}, {}) in {:?}",
+        width, height, frame.format().bayer_pattern().unwrap_or(args.pattern),
+        args.method.name(),
+        start.elapsed());
+
+    let rgb8 = match rgb.format() {
+        PixelFormat::RGB8 => None,
+        _ => Some(rgb.convert(PixelFormat::RGB8).unwrap()),
+    };
+    let out = args.out.clone().unwrap_or_else(|| {
+        let ext = match (args.ppm, &rgb8) {
+            (true, _) => "ppm",
+            (false, None) => "rgb8.raw",
+            (false, Some(_)) => "rgb16.raw",
+        };
+        format!("{}.{}", args.input, ext)
+    });
+    let mut data = Vec::new();
+    if args.ppm {
+        data.extend_from_slice(
+            format!("P6\n{} {}\n255\n", width, height).as_bytes()
+        );
+        data.extend_from_slice(rgb8.as_ref().unwrap_or(&rgb).as_slice());
+    } else {
+        data.extend_from_slice(rgb.as_slice());
+    }
+    std::fs::write(&out, &data)
+        .unwrap_or_else(|e| panic!("[!] Couldn't write {}: {}", out, e));
+    println!("[*] Wrote {}", out);
+
+    if let Some(path) = &args.compare {
+        let other = PixelData::new_from_file(path, PixelFormat::RGB8,
+            width, height)
+            .unwrap_or_else(|e| panic!("[!] {}: {}", path, e));
+        let diff = ImageDiff::compare(&rgb8.as_ref().unwrap_or(&rgb).view(),
+            &other.view(), args.margin)
+            .unwrap_or_else(|e| panic!("[!] {}", e));
+        println!("[*] Compared with {}:", path);
+        print!("{}", diff);
+    }
+}
@@ -0,0 +1,174 @@
+//! Capture a photon transfer curve, and record the noise figures in the
+//! camera profile for the device.
+//!
+//! Usage: glass-ptc [options]
+//!
+//!   --emulator           Use the emulator instead of the real device
+//!   --mode <0|1|2>       Sensor mode (default: 1)
+//!   --min <us>           Shortest exposure (default: 1000)
+//!   --max <us>           Longest exposure (default: 500000)
+//!   --steps <n>          Number of exposures (default: 24)
+//!   --pairs <n>          Frame pairs averaged for each exposure (default: 2)
+//!   --gains <list>       Analog gain [in percent] (default: 100)
+//!   --bits <list>        Bit depths (default: 8)
+//!   --no-dark            Don't capture the dark series
+//!   --model <file>       Calibration file for the exposure model
+//!   --out <prefix>       Write measurements to '<prefix>.csv'
+//!                        (default: 'ptc')
+//!   --profile-dir <dir>  Where to keep camera profiles (default: '.')
+//!
+//! Lists are comma-separated, ie. '--gains 100,200,300 --bits 8,12'.
+//!
+//! The flat series is captured first. Unless '--no-dark' is given, you'll
+//! be asked to cap the lens before the dark series is captured.
+
+use glass_mu1603::*;
+use glass_snap_test::*;
+use rusb::Context;
+
+struct Args {
+    emulator: bool,
+    mode: Mu1603Mode,
+    min: usize,
+    max: usize,
+    steps: usize,
+    pairs: usize,
+    gains: Vec<AnalogGain>,
+    bitdepths: Vec<Mu1603BitDepth>,
+    dark: bool,
+    model: Option<String>,
+    out: String,
+    profile_dir: String,
+}
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut res = Self {
+            emulator: false,
+            mode: Mu1603Mode::Mode1,
+            min: 1_000,
+            max: 500_000,
+            steps: 24,
+            pairs: 2,
+            gains: vec![AnalogGain::default()],
+            bitdepths: vec![Mu1603BitDepth::Depth8],
+            dark: true,
+            model: None,
+            out: "ptc".to_string(),
+            profile_dir: ".".to_string(),
+        };
+        let mut args = ArgParser::from_env();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--emulator" => res.emulator = true,
+                "--mode" => res.mode = args.mode(&arg)?,
+                "--min" => res.min = args.num(&arg)?,
+                "--max" => res.max = args.num(&arg)?,
+                "--steps" => res.steps = args.num(&arg)?,
+                "--pairs" => res.pairs = args.num(&arg)?,
+                "--gains" => {
+                    res.gains = args.list(&arg)?.into_iter()
+                        .map(AnalogGain::new_from_percent)
+                        .collect();
+                },
+                "--bits" => {
+                    res.bitdepths = args.list(&arg)?.into_iter()
+                        .map(|b| Mu1603BitDepth::from_bits(b)
+                            .ok_or_else(|| format!("unsupported bit depth '{}'", b)))
+                        .collect::<Result<_, _>>()?;
+                },
+                "--no-dark" => res.dark = false,
+                "--model" => res.model = Some(args.value(&arg)?),
+                "--out" => res.out = args.value(&arg)?,
+                "--profile-dir" => res.profile_dir = args.value(&arg)?,
+                _ => return Err(format!("unknown argument '{}'", arg)),
+            }
+        }
+        Ok(res)
+    }
+}
+
+fn main() {
+    init_tracing("info");
+    let args = parse_or_exit(Args::parse());
+
+    let model = match &args.model {
+        Some(path) => ExposureModel::load(path)
+            .unwrap_or_else(|e| panic!("[!] Couldn't load {}: {}", path, e)),
+        None => ExposureModel::default(),
+    };
+
+    let mut ptc = PhotonTransfer::new(args.mode);
+    ptc.exposures = ExposureSweep::geometric(
+        ExposureTime::new_from_us(args.min),
+        ExposureTime::new_from_us(args.max),
+        args.steps,
+    );
+    ptc.pairs_per_step = args.pairs;
+    ptc.gains = args.gains.clone();
+    ptc.bitdepths = args.bitdepths.clone();
+
+    let mut capture = PtcCapture::default();
+    let serial = if args.emulator {
+        let mut emu = Mu1603Emulator::new(EmulatorSensor::default(),
+            EmulatorScene::default()
+        ).driver_model(model).realtime(false);
+        capture.flat = ptc.run(&mut emu)
+            .unwrap_or_else(|e| panic!("[!] Flat series failed: {:?}", e));
+        if args.dark {
+            emu.scene_mut().flux = [0.0; 4];
+            capture.dark = ptc.run(&mut emu)
+                .unwrap_or_else(|e| panic!("[!] Dark series failed: {:?}", e));
+        }
+        "emulator".to_string()
+    } else {
+        let mut ctx = Context::new()
+            .expect("[!] Couldn't create usb context");
+        let mut cam = Mu1603::builder()
+            .exposure_model(model)
+            .open(&mut ctx)
+            .expect("[!] Couldn't open camera");
+        print!("{}", cam.device_info());
+        capture.flat = ptc.run(&mut cam)
+            .unwrap_or_else(|e| panic!("[!] Flat series failed: {:?}", e));
+        if args.dark {
+            println!("[*] Cap the lens and press enter to capture the dark series");
+            let mut line = String::new();
+            let _ = std::io::stdin().read_line(&mut line);
+            capture.dark = ptc.run(&mut cam)
+                .unwrap_or_else(|e| panic!("[!] Dark series failed: {:?}", e));
+        }
+        cam.device_info().serial.clone().unwrap_or_else(|| {
+            println!("[!] Device has no serial number");
+            "unknown".to_string()
+        })
+    };
+
+    let csv_file = format!("{}.csv", args.out);
+    std::fs::write(&csv_file, capture.to_csv())
+        .unwrap_or_else(|e| panic!("[!] Couldn't write {}: {}", csv_file, e));
+    println!("[*] Wrote {}", csv_file);
+
+    // NOTE: Merge with the existing profile, so that figures for other
+    // gains and bit depths are kept. Don't clobber a profile that we can't
+    // read or parse.
+    let profile_file = format!("{}/{}", args.profile_dir,
+        CameraProfile::filename(&serial));
+    let mut profile = match std::fs::read_to_string(&profile_file) {
+        Ok(src) => match CameraProfile::parse(&src) {
+            Ok(profile) if profile.serial == serial => profile,
+            Ok(_) => panic!("[!] {} belongs to a different device", profile_file),
+            Err(e) => panic!("[!] Couldn't parse {}: {}", profile_file, e),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            CameraProfile::new(&serial)
+        },
+        Err(e) => panic!("[!] Couldn't read {}: {}", profile_file, e),
+    };
+    for noise in capture.analyse() {
+        profile.update(noise);
+    }
+    print!("{}", profile);
+    profile.save(&profile_file)
+        .unwrap_or_else(|e| panic!("[!] Couldn't write {}: {}", profile_file, e));
+    println!("[*] Wrote {}", profile_file);
+}
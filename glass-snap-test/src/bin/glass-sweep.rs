@@ -0,0 +1,121 @@
+//! Run an [ExposureSweep] and write a report and calibration file.
+//!
+//! Usage: glass-sweep [options]
+//!
+//!   --emulator           Use the emulator instead of the real device
+//!   --mode <0|1|2>       Sensor mode (default: 1)
+//!   --min <us>           Shortest exposure (default: 1000)
+//!   --max <us>           Longest exposure (default: 250000)
+//!   --steps <n>          Number of exposures (default: 16)
+//!   --frames <n>         Frames averaged for each exposure (default: 4)
+//!   --black <adu>        Black level, measured with the lens capped (default: 0)
+//!   --model <file>       Calibration file to start from
+//!   --out <prefix>       Write '<prefix>.txt' and '<prefix>.cal'
+//!                        (default: 'exposure-sweep')
+//!
+//! The emulated sensor can be made to disagree with the default model:
+//!
+//!   --emu-cycles-per-us <f>
+//!   --emu-line-offset <f>
+
+use glass_mu1603::*;
+use glass_snap_test::*;
+use rusb::Context;
+
+struct Args {
+    emulator: bool,
+    mode: Mu1603Mode,
+    min: usize,
+    max: usize,
+    steps: usize,
+    frames: usize,
+    black: f64,
+    model: Option<String>,
+    out: String,
+    emu_model: ExposureModel,
+}
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut res = Self {
+            emulator: false,
+            mode: Mu1603Mode::Mode1,
+            min: 1_000,
+            max: 250_000,
+            steps: 16,
+            frames: 4,
+            black: 0.0,
+            model: None,
+            out: "exposure-sweep".to_string(),
+            emu_model: ExposureModel::default(),
+        };
+        let mut args = ArgParser::from_env();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--emulator" => res.emulator = true,
+                "--mode" => res.mode = args.mode(&arg)?,
+                "--min" => res.min = args.num(&arg)?,
+                "--max" => res.max = args.num(&arg)?,
+                "--steps" => res.steps = args.num(&arg)?,
+                "--frames" => res.frames = args.num(&arg)?,
+                "--black" => res.black = args.num(&arg)?,
+                "--model" => res.model = Some(args.value(&arg)?),
+                "--out" => res.out = args.value(&arg)?,
+                "--emu-cycles-per-us" => res.emu_model.cycles_per_us = args.num(&arg)?,
+                "--emu-line-offset" => res.emu_model.line_offset = args.num(&arg)?,
+                _ => return Err(format!("unknown argument '{}'", arg)),
+            }
+        }
+        Ok(res)
+    }
+}
+
+fn main() {
+    init_tracing("info");
+    let args = parse_or_exit(Args::parse());
+
+    let model = match &args.model {
+        Some(path) => ExposureModel::load(path)
+            .unwrap_or_else(|e| panic!("[!] Couldn't load {}: {}", path, e)),
+        None => ExposureModel::default(),
+    };
+
+    let mut sweep = ExposureSweep::new(args.mode);
+    sweep.exposures = ExposureSweep::geometric(
+        ExposureTime::new_from_us(args.min),
+        ExposureTime::new_from_us(args.max),
+        args.steps,
+    );
+    sweep.frames_per_step = args.frames;
+    sweep.black_level = args.black;
+
+    let result = if args.emulator {
+        let sensor = EmulatorSensor {
+            exposure_model: args.emu_model,
+            ..Default::default()
+        };
+        let mut emu = Mu1603Emulator::new(sensor, EmulatorScene::default())
+            .driver_model(model);
+        sweep.run(&mut emu)
+    } else {
+        let mut ctx = Context::new()
+            .expect("[!] Couldn't create usb context");
+        let mut cam = Mu1603::builder()
+            .exposure_model(model)
+            .open(&mut ctx)
+            .expect("[!] Couldn't open camera");
+        print!("{}", cam.device_info());
+        sweep.run(&mut cam)
+    }.unwrap_or_else(|e| panic!("[!] Sweep failed: {:?}", e));
+
+    let fit = result.fit();
+    let report = result.report(&fit);
+    print!("{}", report);
+
+    let report_file = format!("{}.txt", args.out);
+    let cal_file = format!("{}.cal", args.out);
+    std::fs::write(&report_file, &report)
+        .unwrap_or_else(|e| panic!("[!] Couldn't write {}: {}", report_file, e));
+    fit.model.save(&cal_file)
+        .unwrap_or_else(|e| panic!("[!] Couldn't write {}: {}", cal_file, e));
+    println!("[*] Wrote {} and {}", report_file, cal_file);
+}
@@ -0,0 +1,77 @@
+//! Helpers shared by the command-line tools in this crate.
+
+use glass_mu1603::Mu1603Mode;
+use std::str::FromStr;
+
+/// Command-line arguments, consumed one at a time.
+///
+/// Each tool matches on the flags returned by [Iterator::next], and uses
+/// the other methods to take the value that follows a flag.
+pub struct ArgParser {
+    args: std::vec::IntoIter<String>,
+}
+impl ArgParser {
+    pub fn new(args: impl IntoIterator<Item = String>) -> Self {
+        Self { args: args.into_iter().collect::<Vec<_>>().into_iter() }
+    }
+
+    /// Arguments to this process (without the program name).
+    pub fn from_env() -> Self {
+        Self::new(std::env::args().skip(1))
+    }
+
+    /// The value following some flag.
+    pub fn value(&mut self, flag: &str) -> Result<String, String> {
+        self.args.next().ok_or_else(|| format!("'{}' expects a value", flag))
+    }
+
+    /// The number following some flag.
+    pub fn num<T: FromStr>(&mut self, flag: &str) -> Result<T, String> {
+        let s = self.value(flag)?;
+        parse_num(&s)
+    }
+
+    /// A comma-separated list of numbers following some flag
+    /// (ie. '--gains 100,200,300').
+    pub fn list<T: FromStr>(&mut self, flag: &str) -> Result<Vec<T>, String> {
+        self.value(flag)?.split(',').map(|s| parse_num(s.trim())).collect()
+    }
+
+    /// A sensor mode ('0', '1' or '2') following some flag.
+    pub fn mode(&mut self, flag: &str) -> Result<Mu1603Mode, String> {
+        match self.value(flag)?.as_str() {
+            "0" => Ok(Mu1603Mode::Mode0),
+            "1" => Ok(Mu1603Mode::Mode1),
+            "2" => Ok(Mu1603Mode::Mode2),
+            s => Err(format!("invalid mode '{}'", s)),
+        }
+    }
+}
+impl Iterator for ArgParser {
+    type Item = String;
+    fn next(&mut self) -> Option<String> {
+        self.args.next()
+    }
+}
+
+fn parse_num<T: FromStr>(s: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("invalid number '{}'", s))
+}
+
+/// Return the parsed arguments, or print the error and exit.
+pub fn parse_or_exit<T>(res: Result<T, String>) -> T {
+    res.unwrap_or_else(|e| {
+        eprintln!("[!] {}", e);
+        std::process::exit(1);
+    })
+}
+
+/// Print logs, filtered with 'RUST_LOG' (or 'default' if it isn't set).
+pub fn init_tracing(default: &str) {
+    let default = default.to_string();
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| default.into())
+        )
+        .init();
+}
@@ -1,33 +1,54 @@
 
 use glass_mu1603::*;
-use rusb::{ Context, UsbContext, Device, DeviceHandle, DeviceDescriptor };
-use std::io::Write;
 
 fn main() {
 
-    let mut ctx = Context::new()
-        .expect("[!] Couldn't create usb context");
+    // Driver logs are filtered with 'RUST_LOG' (ie. 'RUST_LOG=glass_mu1603=trace')
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| "debug".into())
+        )
+        .init();
 
-    let mut cam = Mu1603::try_open(&mut ctx)
-        .expect("[!] Couldn't open camera");
+    // Optionally use an init script instead of the built-in sequence
+    let script = match std::env::args().nth(1) {
+        Some(path) => Mu1603Script::from_file(&path)
+            .unwrap_or_else(|e| panic!("[!] Couldn't parse script: {}", e)),
+        None => Mu1603Script::builtin(Mu1603Mode::Mode1),
+    };
 
-    cam.start_stream(Mu1603Mode::Mode1)
-        .expect("[!] Couldn't start stream");
+    let builder = Mu1603::builder();
+    let mut controller = Mu1603Controller::spawn(builder);
+    let frames_rx = controller.subscribe(DropPolicy::Lossless);
+    controller.open().unwrap();
+    controller.start_with_script(Mu1603Mode::Mode1, script).unwrap();
+
+    // Wait for the stream to start
+    let state = loop {
+        match controller.events().recv() {
+            Ok(ControllerEvent::Connected(info)) => print!("{}", info),
+            Ok(ControllerEvent::ConnectFailure(e)) => {
+                panic!("[!] Couldn't open camera: {:?}", e)
+            },
+            Ok(ControllerEvent::StreamStarted(state)) => break state,
+            Ok(ControllerEvent::Failure(e)) => {
+                panic!("[!] Couldn't start stream: {:?}", e)
+            },
+            Ok(_) => continue,
+            Err(_) => panic!("[!] Controller exited"),
+        }
+    };
 
     let mut frames = Vec::new();
+    let mut timestamps = Vec::new();
+    let mut captured = Vec::new();
     while frames.len() < 5 {
-        match cam.try_read_frame() {
-            Ok(data) => {
+        match frames_rx.recv_timeout(std::time::Duration::from_secs(5)) {
+            Ok(frame) => {
                 println!("[*] Got frame");
-                frames.push(data);
-            },
-            Err(Mu1603Error::FirstFrame) => {
-                println!("[*] Skipped first frame");
-                continue;
-            },
-            Err(Mu1603Error::NotStreaming) => {
-                println!("[*] Not streaming?");
-                break;
+                timestamps.push(frame.timestamp);
+                captured.push(std::time::SystemTime::now());
+                frames.push(frame);
             },
             Err(e) => {
                 println!("Error: {:?}", e);
@@ -35,16 +56,51 @@ fn main() {
             },
         }
     }
-    cam.stop_stream().unwrap();
+    print!("{}", controller.stream_stats());
+    controller.shutdown().unwrap();
+
+    // Compare the measured frame rate with the timing model
+    let timing = SensorTiming::from_options(&state,
+        &builder.config().exposure_model
+    );
+    let intervals = TimingCheck::intervals(&timestamps);
+    if let Some(check) = timing.check(&intervals) {
+        println!("[*] Predicted frame time {:?}, measured {:?} (+/- {:?})",
+            check.predicted, check.mean, check.std_dev
+        );
+        if !check.is_consistent() {
+            println!("[!] Measured frame time is off by {:.1}%",
+                check.relative_error * 100.0
+            );
+        }
+    }
 
 
+    // All frames go into a single container (see 'RawFileWriter')
+    let (width, height) = state.mode().dimensions();
+    let path = "/tmp/frames.glraw";
+    let mut out = RawFileWriter::create(path)
+        .unwrap_or_else(|e| panic!("[!] Couldn't create {}: {}", path, e));
     for (idx, frame) in frames.iter().enumerate() {
-        let name = format!("{:04}.rggb8.raw", idx);
-        let path = format!("/tmp/{}", name);
-        let mut f = std::fs::File::create(&path).unwrap();
-        f.write(frame).unwrap();
-        println!("[*] Wrote frame to {}", &path);
+        let data = glass_common::PixelData::new_from_slice(
+            state.bitdepth().format(), width, height, &frame.data
+        ).unwrap();
+        let header = RawFrameHeader {
+            id: idx,
+            timestamp: captured[idx],
+            options: Some(frame.options),
+        };
+        out.write_frame(&header, &data.view()).unwrap();
 
+        // Also write a DNG for developing in other raw editors
+        let dng_path = format!("/tmp/{:04}.dng", idx);
+        let mut meta = frame.options.dng_metadata();
+        meta.export.timestamp = Some(captured[idx]);
+        data.save_dng(&dng_path, &meta)
+            .unwrap_or_else(|e| panic!("[!] Couldn't write {}: {}", dng_path, e));
+        println!("[*] Wrote frame to {}", &dng_path);
     }
+    out.finish().unwrap();
+    println!("[*] Wrote {} frames to {}", frames.len(), path);
 
 }